@@ -7,8 +7,9 @@ use std::{
 use anyhow::{anyhow, Result};
 use clap::Parser;
 use rockfile::boot::{
-    RkBootEntry, RkBootEntryBytes, RkBootHeader, RkBootHeaderBytes, RkBootHeaderEntry,
+    BootFile, RkBootEntry, RkBootEntryBytes, RkBootHeader, RkBootHeaderBytes, RkBootHeaderEntry,
 };
+use rockfile::resource::ResourceImage;
 
 fn parse_entry(header: RkBootHeaderEntry, name: &str, file: &mut File) -> Result<()> {
     for i in 0..header.count {
@@ -41,20 +42,75 @@ fn parse_boot(path: &Path) -> Result<()> {
         RkBootHeader::from_bytes(&header).ok_or_else(|| anyhow!("Failed to parse header"))?;
 
     println!("Raw Header: {:?}", header);
+    println!(
+        "flavor: {:?}, loader version: {}, merger version: {}",
+        header.flavor(),
+        header.loader_version(),
+        header.merger_version()
+    );
     println!(
         "chip: {:?} - {}",
         header.supported_chip,
         String::from_utf8_lossy(&header.supported_chip)
     );
-    parse_entry(header.entry_471, "0x471", &mut file)?;
-    parse_entry(header.entry_472, "0x472", &mut file)?;
-    parse_entry(header.entry_loader, "loader", &mut file)?;
+    println!("signed: {}", header.is_signed());
+    for (kind, table) in header.tables() {
+        parse_entry(table.clone(), &kind.to_string(), &mut file)?;
+    }
+    Ok(())
+}
+
+fn unpack_loader(path: &Path, dir: &Path) -> Result<()> {
+    let boot = BootFile::from_bytes(std::fs::read(path)?)
+        .ok_or_else(|| anyhow!("Failed to parse header"))?;
+    boot.extract_all(dir)?;
+    println!("Extracted to {}", dir.display());
+    Ok(())
+}
+
+fn parse_resource(path: &Path) -> Result<()> {
+    let resource = ResourceImage::from_bytes(std::fs::read(path)?)
+        .ok_or_else(|| anyhow!("Failed to parse header"))?;
+    println!("Raw Header: {:?}", resource.header());
+    for (i, entry) in resource.entries().enumerate() {
+        println!(
+            "{:>3}: {:<32} offset: {:#010x} size: {:>8}",
+            i,
+            entry.name(),
+            entry.offset * 512,
+            entry.size
+        );
+    }
+    Ok(())
+}
+
+fn unpack_resource(path: &Path, dir: &Path) -> Result<()> {
+    let resource = ResourceImage::from_bytes(std::fs::read(path)?)
+        .ok_or_else(|| anyhow!("Failed to parse header"))?;
+    resource.extract_all(dir)?;
+    println!("Extracted to {}", dir.display());
     Ok(())
 }
 
 #[derive(Debug, clap::Subcommand)]
 enum Command {
-    BootFile { path: PathBuf },
+    BootFile {
+        path: PathBuf,
+    },
+    /// Extract all blobs of a boot file into a directory
+    UnpackLoader {
+        path: PathBuf,
+        dir: PathBuf,
+    },
+    /// List the files in a resource image
+    Resource {
+        path: PathBuf,
+    },
+    /// Extract all files of a resource image into a directory
+    UnpackResource {
+        path: PathBuf,
+        dir: PathBuf,
+    },
 }
 
 #[derive(clap::Parser)]
@@ -69,5 +125,8 @@ fn main() -> Result<()> {
     // Commands that don't talk a device
     match opt.command {
         Command::BootFile { path } => parse_boot(&path),
+        Command::UnpackLoader { path, dir } => unpack_loader(&path, &dir),
+        Command::Resource { path } => parse_resource(&path),
+        Command::UnpackResource { path, dir } => unpack_resource(&path, &dir),
     }
 }
@@ -1,3 +1,6 @@
+use std::io::Write;
+use std::path::Path;
+
 use bytes::Buf;
 
 pub type RkTimeBytes = [u8; 7];
@@ -78,6 +81,16 @@ pub struct RkBootEntry {
 }
 
 impl RkBootEntry {
+    /// Name of the entry, up to the first NUL character
+    pub fn name(&self) -> String {
+        let end = self
+            .name
+            .iter()
+            .position(|&c| c == 0)
+            .unwrap_or(self.name.len());
+        String::from_utf16_lossy(&self.name[..end])
+    }
+
     pub fn from_bytes(bytes: &RkBootEntryBytes) -> RkBootEntry {
         let mut bytes = &bytes[..];
 
@@ -102,6 +115,90 @@ impl RkBootEntry {
     }
 }
 
+/// Kind of boot entry, determined by the header table it's listed in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    /// Blob to upload to the bootrom sram (0x471), typically DDR initialisation
+    Sram471,
+    /// Blob to upload to the bootrom ddr (0x472), typically the usb loader
+    Ddr472,
+    /// Blob meant to be used for a normal boot
+    Loader,
+}
+
+impl std::fmt::Display for EntryKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EntryKind::Sram471 => write!(f, "471"),
+            EntryKind::Ddr472 => write!(f, "472"),
+            EntryKind::Loader => write!(f, "loader"),
+        }
+    }
+}
+
+/// Version number as stored in the boot header
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct RkVersion {
+    pub major: u8,
+    pub minor: u8,
+    pub patch: u16,
+}
+
+impl RkVersion {
+    /// Decode a loader version; stored as BCD major and minor bytes in the lower 16 bits
+    pub fn from_loader(version: u32) -> RkVersion {
+        let bcd = |b: u8| (b >> 4) * 10 + (b & 0xf);
+        RkVersion {
+            major: bcd((version >> 8) as u8),
+            minor: bcd(version as u8),
+            patch: 0,
+        }
+    }
+
+    /// Decode a merger version; stored as `0xMMmmpppp`
+    pub fn from_merger(version: u32) -> RkVersion {
+        RkVersion {
+            major: (version >> 24) as u8,
+            minor: (version >> 16) as u8,
+            patch: version as u16,
+        }
+    }
+}
+
+impl std::fmt::Display for RkVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// Flavor of boot file, determined by the header tag
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootFlavor {
+    /// Classic boot file with a `BOOT` tag
+    Boot,
+    /// Boot file with a `LDR ` tag as generated for newer SoCs (e.g. rk3588); The layout is
+    /// identical but the loader entries are meant to be written using the new idblock format
+    /// without rc4 scrambling
+    Ldr,
+}
+
+impl BootFlavor {
+    pub fn from_tag(tag: &[u8; 4]) -> Option<BootFlavor> {
+        match tag {
+            b"BOOT" => Some(BootFlavor::Boot),
+            b"LDR " => Some(BootFlavor::Ldr),
+            _ => None,
+        }
+    }
+
+    pub fn tag(self) -> &'static [u8; 4] {
+        match self {
+            BootFlavor::Boot => b"BOOT",
+            BootFlavor::Ldr => b"LDR ",
+        }
+    }
+}
+
 pub type RkBootHeaderBytes = [u8; 102];
 /// Boot header which can be found at the start of a boot file
 ///
@@ -125,14 +222,61 @@ pub struct RkBootHeader {
 }
 
 impl RkBootHeader {
+    /// Flavor of the boot file as indicated by the tag
+    pub fn flavor(&self) -> BootFlavor {
+        // Only valid tags are accepted when parsing
+        BootFlavor::from_tag(&self.tag).unwrap_or(BootFlavor::Boot)
+    }
+
+    /// Whether the loader is marked as signed
+    ///
+    /// This only reflects the flag in the header; The signatures themselves aren't checked
+    pub fn is_signed(&self) -> bool {
+        self.sign_flag == b'S'
+    }
+
+    /// Decoded loader version
+    pub fn loader_version(&self) -> RkVersion {
+        RkVersion::from_loader(self.version)
+    }
+
+    /// Decoded version of the tool used to merge the boot file
+    pub fn merger_version(&self) -> RkVersion {
+        RkVersion::from_merger(self.merge_version)
+    }
+
+    /// The header entry tables with their kind
+    pub fn tables(&self) -> [(EntryKind, &RkBootHeaderEntry); 3] {
+        [
+            (EntryKind::Sram471, &self.entry_471),
+            (EntryKind::Ddr472, &self.entry_472),
+            (EntryKind::Loader, &self.entry_loader),
+        ]
+    }
+
+    /// Iterate over the entries of all three header tables, parsed from the complete boot file
+    /// data
+    ///
+    /// Iteration over a table stops at the first entry not within `file`
+    pub fn entries<'a>(
+        &'a self,
+        file: &'a [u8],
+    ) -> impl Iterator<Item = (EntryKind, RkBootEntry)> + 'a {
+        self.tables().into_iter().flat_map(move |(kind, table)| {
+            (0..table.count as usize).map_while(move |i| {
+                let offset = table.offset as usize + table.size as usize * i;
+                let bytes = file.get(offset..offset + 57)?;
+                Some((kind, RkBootEntry::from_bytes(bytes.try_into().unwrap())))
+            })
+        })
+    }
+
     pub fn from_bytes(bytes: &RkBootHeaderBytes) -> Option<RkBootHeader> {
         let mut bytes = &bytes[..];
         let mut tag = [0u8; 4];
         bytes.copy_to_slice(&mut tag);
 
-        if &tag != b"BOOT" && &tag != b"LDR " {
-            return None;
-        }
+        BootFlavor::from_tag(&tag)?;
         let size = bytes.get_u16_le();
         let version = bytes.get_u32_le();
         let merge_version = bytes.get_u32_le();
@@ -166,3 +310,149 @@ impl RkBootHeader {
         })
     }
 }
+
+const CRC16: crc::Crc<u16> = crc::Crc::<u16>::new(&crc::CRC_16_IBM_3740);
+
+/// Complete boot file loaded in memory
+#[derive(Debug, Clone)]
+pub struct BootFile {
+    header: RkBootHeader,
+    data: Vec<u8>,
+}
+
+impl BootFile {
+    /// Parse a boot file; Returns None if the boot file header is invalid
+    pub fn from_bytes(data: Vec<u8>) -> Option<BootFile> {
+        let header = RkBootHeader::from_bytes(data.get(0..102)?.try_into().unwrap())?;
+        Some(BootFile { header, data })
+    }
+
+    pub fn header(&self) -> &RkBootHeader {
+        &self.header
+    }
+
+    /// Raw boot file data
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Iterate over all entries, see [RkBootHeader::entries]
+    pub fn all_entries(&self) -> impl Iterator<Item = (EntryKind, RkBootEntry)> + '_ {
+        self.header.entries(&self.data)
+    }
+
+    /// Entries described by one of the boot header entries; Returns None if the entries are
+    /// out of the file bounds
+    pub fn entries(&self, header_entry: &RkBootHeaderEntry) -> Option<Vec<RkBootEntry>> {
+        (0..header_entry.count as usize)
+            .map(|i| {
+                let offset = header_entry.offset as usize + header_entry.size as usize * i;
+                let bytes = self.data.get(offset..offset + 57)?;
+                Some(RkBootEntry::from_bytes(bytes.try_into().unwrap()))
+            })
+            .collect()
+    }
+
+    /// Data of a boot entry; Returns None if the data is out of the file bounds
+    pub fn entry_data(&self, entry: &RkBootEntry) -> Option<&[u8]> {
+        let start = entry.data_offset as usize;
+        self.data.get(start..start + entry.data_size as usize)
+    }
+
+    /// Extract all entries into a directory
+    ///
+    /// Each blob is written to a file named after its type, index and (sanitized) name. A
+    /// `manifest.txt` is written alongside describing the offset, size, delay and CRC of each
+    /// blob.
+    pub fn extract_all(&self, dir: &Path) -> std::io::Result<()> {
+        let invalid = || {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Boot entry out of file bounds",
+            )
+        };
+        std::fs::create_dir_all(dir)?;
+        let mut manifest = std::fs::File::create(dir.join("manifest.txt"))?;
+        writeln!(manifest, "# file type index name offset size delay crc16")?;
+        for (kind, header_entry) in self.header.tables() {
+            for (i, entry) in self
+                .entries(header_entry)
+                .ok_or_else(invalid)?
+                .iter()
+                .enumerate()
+            {
+                let data = self.entry_data(entry).ok_or_else(invalid)?;
+                let name = entry.name();
+                let file = format!("{kind}_{i}_{}.bin", sanitize(&name));
+                std::fs::write(dir.join(&file), data)?;
+                writeln!(
+                    manifest,
+                    "{file} {kind} {i} {name:?} {:#x} {} {} {:04x}",
+                    entry.data_offset,
+                    entry.data_size,
+                    entry.data_delay,
+                    CRC16.checksum(data)
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Make a name safe to use as (part of) a file name
+pub(crate) fn sanitize(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '.' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let name = name.trim_start_matches('.');
+    if name.is_empty() {
+        "entry".to_string()
+    } else {
+        name.to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sanitized_names() {
+        assert_eq!(sanitize("rk3588_ddr_lp4.bin"), "rk3588_ddr_lp4.bin");
+        assert_eq!(sanitize("../../etc/passwd"), "_.._etc_passwd");
+        assert_eq!(sanitize(""), "entry");
+    }
+
+    #[test]
+    fn version() {
+        let v = RkVersion::from_merger(0x0103_0002);
+        assert_eq!(v.major, 1);
+        assert_eq!(v.minor, 3);
+        assert_eq!(v.patch, 2);
+        assert_eq!(v.to_string(), "1.3.2");
+        assert!(v > RkVersion::from_merger(0x0102_ffff));
+
+        let v = RkVersion::from_loader(0x0112);
+        assert_eq!(v.to_string(), "1.12.0");
+    }
+
+    #[test]
+    fn flavor() {
+        let mut bytes = [0u8; 102];
+        bytes[0..4].copy_from_slice(b"LDR ");
+        let header = RkBootHeader::from_bytes(&bytes).unwrap();
+        assert_eq!(header.flavor(), BootFlavor::Ldr);
+        bytes[0..4].copy_from_slice(b"BOOT");
+        let header = RkBootHeader::from_bytes(&bytes).unwrap();
+        assert_eq!(header.flavor(), BootFlavor::Boot);
+        bytes[0..4].copy_from_slice(b"FOO!");
+        assert!(RkBootHeader::from_bytes(&bytes).is_none());
+    }
+}
@@ -2,3 +2,9 @@
 
 /// Rockchip boot file parsers
 pub mod boot;
+/// Rockchip parameter file parser
+pub mod parameter;
+/// Rockchip resource image parser
+pub mod resource;
+/// Legacy KRNL/PARM wrapped images
+pub mod wrapped;
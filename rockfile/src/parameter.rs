@@ -0,0 +1,361 @@
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::str::FromStr;
+
+use bytes::Buf;
+
+use crate::wrapped::{wrap, WrappedImage, WrappedKind, WRAPPED_OVERHEAD};
+
+/// CRC32 variant used by Rockchip tools
+pub const RK_CRC32: crc::Algorithm<u32> = crc::Algorithm {
+    width: 32,
+    poly: 0x04c10db7,
+    init: 0,
+    refin: false,
+    refout: false,
+    xorout: 0,
+    check: 0x889a9615,
+    residue: 0,
+};
+
+/// Tag of a parameter block
+pub const PARAMETER_TAG: &[u8; 4] = b"PARM";
+/// Sector of the first copy of the parameter block on flash
+pub const PARAMETER_SECTOR: u64 = 0;
+/// Number of copies of the parameter block on flash
+pub const PARAMETER_COPIES: u64 = 5;
+/// Distance in sectors between the copies of the parameter block
+pub const PARAMETER_COPY_STRIDE: u64 = 0x400;
+
+/// Default mtd id used in the mtdparts of parameter files
+pub const DEFAULT_MTD_ID: &str = "rk29xxnand";
+
+const SECTOR_SIZE: u64 = 512;
+
+/// Errors when parsing a parameter file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParameterError {
+    /// Line that isn't a `KEY: value` pair
+    InvalidLine(String),
+    /// Partition definition in mtdparts that couldn't be parsed
+    InvalidPartition(String),
+}
+
+impl std::fmt::Display for ParameterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParameterError::InvalidLine(l) => write!(f, "Invalid parameter line: {l}"),
+            ParameterError::InvalidPartition(p) => write!(f, "Invalid partition: {p}"),
+        }
+    }
+}
+
+impl std::error::Error for ParameterError {}
+
+/// Partition as defined by the mtdparts in the parameter CMDLINE
+///
+/// Offset and size are in sectors
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParameterPartition {
+    pub name: String,
+    pub offset: u32,
+    /// Size of the partition; None if the partition grows to the end of the flash
+    pub size: Option<u32>,
+}
+
+impl ParameterPartition {
+    fn parse(part: &str) -> Result<ParameterPartition, ParameterError> {
+        let invalid = || ParameterError::InvalidPartition(part.to_string());
+        let (size, rest) = part.split_once('@').ok_or_else(invalid)?;
+        let (offset, name) = rest.split_once('(').ok_or_else(invalid)?;
+        let name = name.strip_suffix(')').ok_or_else(invalid)?;
+        let name = name.strip_suffix(":grow").unwrap_or(name);
+        let size = match size {
+            "-" => None,
+            s => Some(parse_hex(s).ok_or_else(invalid)?),
+        };
+        let offset = parse_hex(offset).ok_or_else(invalid)?;
+        Ok(ParameterPartition {
+            name: name.to_string(),
+            offset,
+            size,
+        })
+    }
+}
+
+impl std::fmt::Display for ParameterPartition {
+    /// Partition in mtdparts notation, e.g. `0x00002000@0x00004000(uboot)`
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.size {
+            Some(size) => write!(f, "{size:#010x}@{:#010x}({})", self.offset, self.name),
+            None => write!(f, "-@{:#010x}({}:grow)", self.offset, self.name),
+        }
+    }
+}
+
+fn parse_hex(s: &str) -> Option<u32> {
+    let s = s.trim();
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+/// Rockchip parameter file (parameter.txt) as used by legacy partition layouts
+///
+/// The file consists of `KEY: value` lines; the partition layout is defined by the mtdparts
+/// option in the `CMDLINE` value
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Parameter {
+    entries: Vec<(String, String)>,
+}
+
+impl Parameter {
+    /// Value for a given key
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Set the value of a key, adding it if it's not set yet
+    pub fn set(&mut self, key: &str, value: &str) {
+        match self.entries.iter_mut().find(|(k, _)| k == key) {
+            Some((_, v)) => *v = value.to_string(),
+            None => self.entries.push((key.to_string(), value.to_string())),
+        }
+    }
+
+    /// All entries in order
+    pub fn entries(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    /// Partitions as defined by mtdparts in the CMDLINE
+    pub fn partitions(&self) -> Result<Vec<ParameterPartition>, ParameterError> {
+        let Some(mtdparts) = self.get("CMDLINE").and_then(|c| {
+            c.split_whitespace()
+                .find_map(|o| o.strip_prefix("mtdparts="))
+        }) else {
+            return Ok(vec![]);
+        };
+        // Strip the mtd id
+        let parts = mtdparts
+            .split_once(':')
+            .map(|(_, p)| p)
+            .ok_or_else(|| ParameterError::InvalidPartition(mtdparts.to_string()))?;
+        parts.split(',').map(ParameterPartition::parse).collect()
+    }
+
+    /// Set the partitions in the mtdparts of the CMDLINE, replacing existing partitions
+    ///
+    /// Only the last partition may grow to the end of the flash and names can't contain any of
+    /// the characters used by the mtdparts syntax. `mtd_id` is typically [DEFAULT_MTD_ID]
+    pub fn set_partitions(
+        &mut self,
+        mtd_id: &str,
+        partitions: &[ParameterPartition],
+    ) -> Result<(), ParameterError> {
+        for (i, p) in partitions.iter().enumerate() {
+            if (p.size.is_none() && i + 1 != partitions.len())
+                || p.name.is_empty()
+                || p.name.contains([',', '(', ')', ':', ' '])
+            {
+                return Err(ParameterError::InvalidPartition(p.to_string()));
+            }
+        }
+        let parts: Vec<String> = partitions.iter().map(|p| p.to_string()).collect();
+        let mtdparts = format!("mtdparts={mtd_id}:{}", parts.join(","));
+        let mut options: Vec<&str> = self
+            .get("CMDLINE")
+            .map(|c| c.split_whitespace().collect())
+            .unwrap_or_default();
+        match options.iter_mut().find(|o| o.starts_with("mtdparts=")) {
+            Some(o) => *o = &mtdparts,
+            None => options.push(&mtdparts),
+        }
+        let cmdline = options.join(" ");
+        self.set("CMDLINE", &cmdline);
+        Ok(())
+    }
+
+    /// Create a parameter file defining the given partitions
+    pub fn with_partitions(
+        mtd_id: &str,
+        partitions: &[ParameterPartition],
+    ) -> Result<Parameter, ParameterError> {
+        let mut parameter = Parameter::default();
+        parameter.set_partitions(mtd_id, partitions)?;
+        Ok(parameter)
+    }
+
+    /// Parse a parameter block as stored on flash
+    ///
+    /// The block consists of the `PARM` tag, the length of the parameter file, the file
+    /// itself and its CRC. Returns None if the block isn't valid.
+    pub fn from_block(bytes: &[u8]) -> Option<Parameter> {
+        let image = WrappedImage::from_bytes(bytes).ok()?;
+        if image.kind != WrappedKind::Parameter {
+            return None;
+        }
+        std::str::from_utf8(image.data).ok()?.parse().ok()
+    }
+
+    /// Serialize into a parameter block as stored on flash
+    pub fn to_block(&self) -> Vec<u8> {
+        wrap(WrappedKind::Parameter, self.to_string().as_bytes())
+    }
+}
+
+impl FromStr for Parameter {
+    type Err = ParameterError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut entries = vec![];
+        for line in s.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = line
+                .split_once(':')
+                .ok_or_else(|| ParameterError::InvalidLine(line.to_string()))?;
+            entries.push((key.trim().to_string(), value.trim().to_string()));
+        }
+        Ok(Parameter { entries })
+    }
+}
+
+impl std::fmt::Display for Parameter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (k, v) in &self.entries {
+            writeln!(f, "{k}: {v}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Read the parameter block from flash
+///
+/// The copies at the standard offsets are tried in order; the first valid one is returned
+pub fn read_parameter<R: Read + Seek>(io: &mut R) -> std::io::Result<Parameter> {
+    for copy in 0..PARAMETER_COPIES {
+        io.seek(SeekFrom::Start(
+            (PARAMETER_SECTOR + copy * PARAMETER_COPY_STRIDE) * SECTOR_SIZE,
+        ))?;
+        let mut header = [0u8; 8];
+        io.read_exact(&mut header)?;
+        let length = (&header[4..]).get_u32_le() as usize;
+        if &header[0..4] != PARAMETER_TAG
+            || length + WRAPPED_OVERHEAD > (PARAMETER_COPY_STRIDE * SECTOR_SIZE) as usize
+        {
+            continue;
+        }
+
+        let mut block = header.to_vec();
+        block.resize(length + WRAPPED_OVERHEAD, 0);
+        io.read_exact(&mut block[8..])?;
+        if let Some(parameter) = Parameter::from_block(&block) {
+            return Ok(parameter);
+        }
+    }
+    Err(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        "No valid parameter block found",
+    ))
+}
+
+/// Write the parameter block to all copies on flash
+pub fn write_parameter<W: Write + Seek>(io: &mut W, parameter: &Parameter) -> std::io::Result<()> {
+    let mut block = parameter.to_block();
+    if block.len() as u64 > PARAMETER_COPY_STRIDE * SECTOR_SIZE {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "Parameter block too big",
+        ));
+    }
+    block.resize(
+        (block.len() as u64).next_multiple_of(SECTOR_SIZE) as usize,
+        0,
+    );
+    for copy in 0..PARAMETER_COPIES {
+        io.seek(SeekFrom::Start(
+            (PARAMETER_SECTOR + copy * PARAMETER_COPY_STRIDE) * SECTOR_SIZE,
+        ))?;
+        io.write_all(&block)?;
+    }
+    io.flush()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const PARAMETER: &str = "FIRMWARE_VER: 8.1
+MACHINE_MODEL: RK3288
+# Comment
+CMDLINE: console=ttyFIQ0 mtdparts=rk29xxnand:0x00002000@0x00004000(uboot),0x00002000@0x00006000(trust),-@0x00030000(rootfs:grow)
+";
+
+    #[test]
+    fn parse() {
+        let p: Parameter = PARAMETER.parse().unwrap();
+        assert_eq!(p.get("MACHINE_MODEL"), Some("RK3288"));
+        let parts = p.partitions().unwrap();
+        assert_eq!(parts.len(), 3);
+        assert_eq!(
+            parts[1],
+            ParameterPartition {
+                name: "trust".to_string(),
+                offset: 0x6000,
+                size: Some(0x2000)
+            }
+        );
+        assert_eq!(parts[2].name, "rootfs");
+        assert_eq!(parts[2].size, None);
+    }
+
+    #[test]
+    fn generate() {
+        let p: Parameter = PARAMETER.parse().unwrap();
+        let parts = p.partitions().unwrap();
+        let mut generated = p.clone();
+        generated.set_partitions("rk29xxnand", &parts).unwrap();
+        assert_eq!(generated, p);
+
+        let mut parts = vec![ParameterPartition {
+            name: "boot".to_string(),
+            offset: 0x2000,
+            size: Some(0x8000),
+        }];
+        let p = Parameter::with_partitions(DEFAULT_MTD_ID, &parts).unwrap();
+        assert_eq!(
+            p.to_string(),
+            "CMDLINE: mtdparts=rk29xxnand:0x00008000@0x00002000(boot)\n"
+        );
+        assert_eq!(p.partitions().unwrap(), parts);
+
+        parts.insert(
+            0,
+            ParameterPartition {
+                name: "grow".to_string(),
+                offset: 0,
+                size: None,
+            },
+        );
+        assert!(Parameter::with_partitions(DEFAULT_MTD_ID, &parts).is_err());
+        parts[0].size = Some(0x2000);
+        parts[0].name = "a,b".to_string();
+        assert!(Parameter::with_partitions(DEFAULT_MTD_ID, &parts).is_err());
+    }
+
+    #[test]
+    fn on_flash() {
+        let p: Parameter = PARAMETER.parse().unwrap();
+        let mut flash = std::io::Cursor::new(vec![0u8; 0x2000 * 512]);
+        write_parameter(&mut flash, &p).unwrap();
+        // Corrupt the first copy
+        flash.get_mut()[20] ^= 0xff;
+        assert_eq!(read_parameter(&mut flash).unwrap(), p);
+    }
+}
@@ -0,0 +1,253 @@
+use std::io::Write;
+use std::path::Path;
+
+use bytes::Buf;
+
+use crate::boot::sanitize;
+
+/// Magic at the start of a resource image
+pub const RESOURCE_MAGIC: &[u8; 4] = b"RSCE";
+/// Tag at the start of each resource entry
+pub const RESOURCE_ENTRY_TAG: &[u8; 4] = b"ENTR";
+/// Size of the blocks offsets in a resource image are expressed in
+pub const RESOURCE_BLOCK_SIZE: usize = 512;
+
+pub type RkResourceHeaderBytes = [u8; 15];
+/// Header at the start of a resource image
+///
+/// Sizes and offsets are in blocks of [RESOURCE_BLOCK_SIZE] bytes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RkResourceHeader {
+    pub magic: [u8; 4],
+    pub partition_version: u16,
+    pub index_version: u16,
+    /// Size of the header
+    pub header_size: u8,
+    /// Offset of the entry table
+    pub table_offset: u8,
+    /// Size of each entry in the entry table
+    pub entry_size: u8,
+    /// Number of entries in the entry table
+    pub entry_count: u32,
+}
+
+impl RkResourceHeader {
+    pub fn from_bytes(bytes: &RkResourceHeaderBytes) -> Option<RkResourceHeader> {
+        let mut bytes = &bytes[..];
+        let mut magic = [0u8; 4];
+        bytes.copy_to_slice(&mut magic);
+        if &magic != RESOURCE_MAGIC {
+            return None;
+        }
+        let partition_version = bytes.get_u16_le();
+        let index_version = bytes.get_u16_le();
+        let header_size = bytes.get_u8();
+        let table_offset = bytes.get_u8();
+        let entry_size = bytes.get_u8();
+        let entry_count = bytes.get_u32_le();
+
+        Some(RkResourceHeader {
+            magic,
+            partition_version,
+            index_version,
+            header_size,
+            table_offset,
+            entry_size,
+            entry_count,
+        })
+    }
+}
+
+pub type RkResourceEntryBytes = [u8; 268];
+/// Entry in the resource image entry table, describing a single file (e.g. a device tree or
+/// boot logo)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RkResourceEntry {
+    pub tag: [u8; 4],
+    /// NUL terminated file name
+    pub name: [u8; 220],
+    /// Hash of the file data, of which the first `hash_size` bytes are used
+    pub hash: [u8; 32],
+    pub hash_size: u32,
+    /// Offset of the file data in blocks
+    pub offset: u32,
+    /// Size of the file data in bytes
+    pub size: u32,
+}
+
+impl RkResourceEntry {
+    /// Name of the entry, up to the first NUL character
+    pub fn name(&self) -> String {
+        let end = self
+            .name
+            .iter()
+            .position(|&c| c == 0)
+            .unwrap_or(self.name.len());
+        String::from_utf8_lossy(&self.name[..end]).into_owned()
+    }
+
+    /// Hash of the file data; Empty if the entry has no hash
+    pub fn hash(&self) -> &[u8] {
+        &self.hash[..(self.hash_size as usize).min(self.hash.len())]
+    }
+
+    pub fn from_bytes(bytes: &RkResourceEntryBytes) -> Option<RkResourceEntry> {
+        let mut bytes = &bytes[..];
+        let mut tag = [0u8; 4];
+        bytes.copy_to_slice(&mut tag);
+        if &tag != RESOURCE_ENTRY_TAG {
+            return None;
+        }
+        let mut name = [0u8; 220];
+        bytes.copy_to_slice(&mut name);
+        let mut hash = [0u8; 32];
+        bytes.copy_to_slice(&mut hash);
+        let hash_size = bytes.get_u32_le();
+        let offset = bytes.get_u32_le();
+        let size = bytes.get_u32_le();
+
+        Some(RkResourceEntry {
+            tag,
+            name,
+            hash,
+            hash_size,
+            offset,
+            size,
+        })
+    }
+}
+
+/// Complete resource image (resource.img) loaded in memory
+///
+/// Resource images bundle the files used by the bootloader, typically device trees and boot
+/// logos
+#[derive(Debug, Clone)]
+pub struct ResourceImage {
+    header: RkResourceHeader,
+    data: Vec<u8>,
+}
+
+impl ResourceImage {
+    /// Parse a resource image; Returns None if the header is invalid
+    pub fn from_bytes(data: Vec<u8>) -> Option<ResourceImage> {
+        let header = RkResourceHeader::from_bytes(data.get(0..15)?.try_into().unwrap())?;
+        Some(ResourceImage { header, data })
+    }
+
+    pub fn header(&self) -> &RkResourceHeader {
+        &self.header
+    }
+
+    /// Raw resource image data
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Iterate over the entries in the entry table
+    ///
+    /// Iteration stops at the first entry that's invalid or not within the image
+    pub fn entries(&self) -> impl Iterator<Item = RkResourceEntry> + '_ {
+        (0..self.header.entry_count as usize).map_while(move |i| {
+            let offset = (self.header.table_offset as usize + self.header.entry_size as usize * i)
+                * RESOURCE_BLOCK_SIZE;
+            let bytes = self.data.get(offset..offset + 268)?;
+            RkResourceEntry::from_bytes(bytes.try_into().unwrap())
+        })
+    }
+
+    /// Find an entry by name
+    pub fn find(&self, name: &str) -> Option<RkResourceEntry> {
+        self.entries().find(|e| e.name() == name)
+    }
+
+    /// Data of an entry; Returns None if the data is out of the image bounds
+    pub fn entry_data(&self, entry: &RkResourceEntry) -> Option<&[u8]> {
+        let start = entry.offset as usize * RESOURCE_BLOCK_SIZE;
+        self.data.get(start..start + entry.size as usize)
+    }
+
+    /// Extract all entries into a directory
+    ///
+    /// Each file is written using its (sanitized) name. A `manifest.txt` is written alongside
+    /// describing the name, offset, size and hash of each file.
+    pub fn extract_all(&self, dir: &Path) -> std::io::Result<()> {
+        std::fs::create_dir_all(dir)?;
+        let mut manifest = std::fs::File::create(dir.join("manifest.txt"))?;
+        writeln!(manifest, "# file name offset size hash")?;
+        for entry in self.entries() {
+            let data = self.entry_data(&entry).ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "Resource entry out of image bounds",
+                )
+            })?;
+            let name = entry.name();
+            let file = sanitize(&name);
+            std::fs::write(dir.join(&file), data)?;
+            let hash: String = entry.hash().iter().map(|b| format!("{b:02x}")).collect();
+            writeln!(
+                manifest,
+                "{file} {name:?} {:#x} {} {}",
+                entry.offset * RESOURCE_BLOCK_SIZE as u32,
+                entry.size,
+                if hash.is_empty() { "-" } else { &hash }
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn image(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let block = RESOURCE_BLOCK_SIZE;
+        let mut data = vec![0u8; block * (1 + files.len())];
+        data[0..4].copy_from_slice(RESOURCE_MAGIC);
+        // header size, table offset, entry size
+        data[8..11].copy_from_slice(&[1, 1, 1]);
+        data[11..15].copy_from_slice(&(files.len() as u32).to_le_bytes());
+        for (i, (name, content)) in files.iter().enumerate() {
+            let offset = data.len() / block;
+            let entry = &mut data[(1 + i) * block..];
+            entry[0..4].copy_from_slice(RESOURCE_ENTRY_TAG);
+            entry[4..4 + name.len()].copy_from_slice(name.as_bytes());
+            entry[260..264].copy_from_slice(&(offset as u32).to_le_bytes());
+            entry[264..268].copy_from_slice(&(content.len() as u32).to_le_bytes());
+            data.extend_from_slice(content);
+            data.resize(data.len().next_multiple_of(block), 0);
+        }
+        data
+    }
+
+    #[test]
+    fn parse() {
+        let data = image(&[("rk-kernel.dtb", b"dtb"), ("logo.bmp", &[0x42; 600])]);
+        let resource = ResourceImage::from_bytes(data).unwrap();
+        assert_eq!(resource.header().entry_count, 2);
+
+        let entries: Vec<_> = resource.entries().collect();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].name(), "rk-kernel.dtb");
+        assert_eq!(entries[0].offset, 3);
+        assert!(entries[0].hash().is_empty());
+        assert_eq!(resource.entry_data(&entries[0]).unwrap(), b"dtb");
+
+        let logo = resource.find("logo.bmp").unwrap();
+        assert_eq!(resource.entry_data(&logo).unwrap(), &[0x42; 600][..]);
+        assert!(resource.find("missing").is_none());
+    }
+
+    #[test]
+    fn invalid() {
+        let mut data = image(&[("rk-kernel.dtb", b"dtb")]);
+        assert!(ResourceImage::from_bytes(data[..10].to_vec()).is_none());
+        // Entries with an invalid tag end the iteration
+        data[RESOURCE_BLOCK_SIZE] = b'X';
+        let resource = ResourceImage::from_bytes(data.clone()).unwrap();
+        assert_eq!(resource.entries().count(), 0);
+        data[0] = b'X';
+        assert!(ResourceImage::from_bytes(data).is_none());
+    }
+}
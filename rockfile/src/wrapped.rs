@@ -0,0 +1,154 @@
+use bytes::{Buf, BufMut};
+
+use crate::parameter::{PARAMETER_TAG, RK_CRC32};
+
+const CRC32: crc::Crc<u32> = crc::Crc::<u32>::new(&RK_CRC32);
+
+/// Tag of a wrapped kernel image
+pub const KERNEL_TAG: &[u8; 4] = b"KRNL";
+/// Size of the tag and length in front of the wrapped data
+pub const WRAPPED_HEADER_SIZE: usize = 8;
+/// Size of the header and CRC trailer around the wrapped data
+pub const WRAPPED_OVERHEAD: usize = WRAPPED_HEADER_SIZE + 4;
+
+/// Kind of a wrapped image, determined by its tag
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WrappedKind {
+    /// Kernel image as used by older Rockchip kernels (`KRNL`)
+    Kernel,
+    /// Parameter file as stored in the parameter partition (`PARM`)
+    Parameter,
+}
+
+impl WrappedKind {
+    pub fn from_tag(tag: &[u8; 4]) -> Option<WrappedKind> {
+        match tag {
+            b"KRNL" => Some(WrappedKind::Kernel),
+            b"PARM" => Some(WrappedKind::Parameter),
+            _ => None,
+        }
+    }
+
+    pub fn tag(self) -> &'static [u8; 4] {
+        match self {
+            WrappedKind::Kernel => KERNEL_TAG,
+            WrappedKind::Parameter => PARAMETER_TAG,
+        }
+    }
+}
+
+/// Errors when unwrapping an image
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WrappedError {
+    /// The image doesn't start with a known tag
+    UnknownTag([u8; 4]),
+    /// The image is smaller than indicated by its length
+    Truncated,
+    /// The CRC of the data doesn't match the trailer
+    CrcMismatch { expected: u32, actual: u32 },
+}
+
+impl std::fmt::Display for WrappedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WrappedError::UnknownTag(t) => {
+                write!(f, "Unknown tag: {:?}", String::from_utf8_lossy(t))
+            }
+            WrappedError::Truncated => write!(f, "Wrapped image truncated"),
+            WrappedError::CrcMismatch { expected, actual } => {
+                write!(f, "CRC mismatch: expected {expected:08x}, got {actual:08x}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for WrappedError {}
+
+/// Image wrapped in the legacy Rockchip format
+///
+/// The wrapped format consists of a tag, the length of the data as little endian u32, the data
+/// itself and a trailing CRC32 (see [RK_CRC32]) of the data. Any bytes after the trailer (e.g.
+/// padding up to a sector) are ignored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WrappedImage<'a> {
+    pub kind: WrappedKind,
+    pub data: &'a [u8],
+}
+
+impl<'a> WrappedImage<'a> {
+    /// Parse a wrapped image, checking its CRC
+    pub fn from_bytes(bytes: &'a [u8]) -> Result<WrappedImage<'a>, WrappedError> {
+        let mut b = bytes;
+        if b.remaining() < WRAPPED_OVERHEAD {
+            return Err(WrappedError::Truncated);
+        }
+        let tag = b[0..4].try_into().unwrap();
+        let kind = WrappedKind::from_tag(&tag).ok_or(WrappedError::UnknownTag(tag))?;
+        b.advance(4);
+        let length = b.get_u32_le() as usize;
+        if b.remaining() < length.checked_add(4).ok_or(WrappedError::Truncated)? {
+            return Err(WrappedError::Truncated);
+        }
+        let data = &b[..length];
+        b.advance(length);
+        let expected = b.get_u32_le();
+        let actual = CRC32.checksum(data);
+        if expected != actual {
+            return Err(WrappedError::CrcMismatch { expected, actual });
+        }
+        Ok(WrappedImage { kind, data })
+    }
+
+    /// Wrap data into the legacy format
+    pub fn to_bytes(&self) -> Vec<u8> {
+        wrap(self.kind, self.data)
+    }
+}
+
+/// Wrap data into the legacy format, see [WrappedImage]
+pub fn wrap(kind: WrappedKind, data: &[u8]) -> Vec<u8> {
+    let mut wrapped = Vec::with_capacity(data.len() + WRAPPED_OVERHEAD);
+    wrapped.put_slice(kind.tag());
+    wrapped.put_u32_le(data.len() as u32);
+    wrapped.put_slice(data);
+    wrapped.put_u32_le(CRC32.checksum(data));
+    wrapped
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let kernel = vec![0x5a; 1000];
+        let mut wrapped = wrap(WrappedKind::Kernel, &kernel);
+        assert_eq!(&wrapped[0..4], b"KRNL");
+        assert_eq!(wrapped.len(), kernel.len() + WRAPPED_OVERHEAD);
+        // Padding is ignored
+        wrapped.resize(1536, 0);
+        let image = WrappedImage::from_bytes(&wrapped).unwrap();
+        assert_eq!(image.kind, WrappedKind::Kernel);
+        assert_eq!(image.data, &kernel[..]);
+        assert_eq!(image.to_bytes(), wrapped[..kernel.len() + WRAPPED_OVERHEAD]);
+    }
+
+    #[test]
+    fn invalid() {
+        let mut wrapped = wrap(WrappedKind::Parameter, b"CMDLINE: foo\n");
+        assert_eq!(
+            WrappedImage::from_bytes(&wrapped[..wrapped.len() - 1]),
+            Err(WrappedError::Truncated)
+        );
+        wrapped[10] ^= 0xff;
+        assert!(matches!(
+            WrappedImage::from_bytes(&wrapped),
+            Err(WrappedError::CrcMismatch { .. })
+        ));
+        wrapped[0..4].copy_from_slice(b"ABCD");
+        assert_eq!(
+            WrappedImage::from_bytes(&wrapped),
+            Err(WrappedError::UnknownTag(*b"ABCD"))
+        );
+    }
+}
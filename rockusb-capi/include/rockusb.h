@@ -0,0 +1,144 @@
+#ifndef ROCKUSB_H
+#define ROCKUSB_H
+
+/* Generated with cbindgen:0.29.4 */
+
+/* Generated by cbindgen from src/lib.rs; Don't edit manually */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * Opaque handle to an opened device
+ */
+typedef struct RockusbDevice RockusbDevice;
+
+/**
+ * Rockchip device found on the usb bus
+ */
+typedef struct RockusbDeviceInfo {
+  uint8_t bus;
+  uint8_t address;
+  uint16_t vendor_id;
+  uint16_t product_id;
+  /**
+   * Whether the device is in maskrom mode rather then running a loader
+   */
+  bool maskrom;
+} RockusbDeviceInfo;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Message describing the last error on the calling thread
+ *
+ * Returns NULL if no error occurred. The string is valid until the next call on the same thread
+ */
+const char *rockusb_last_error(void);
+
+/**
+ * Find Rockchip devices without opening them
+ *
+ * Information on at most `len` devices is stored in `devices`. Returns the total number of
+ * devices found, which may be more then `len`, or -1 on error
+ *
+ * # Safety
+ * `devices` must point to an array of at least `len` elements or be NULL if `len` is 0
+ */
+int rockusb_list_devices(struct RockusbDeviceInfo *devices, uintptr_t len);
+
+/**
+ * Open the device at a given bus and address
+ *
+ * Returns NULL on error. The device has to be closed using [rockusb_close]
+ */
+struct RockusbDevice *rockusb_open(uint8_t bus, uint8_t address);
+
+/**
+ * Close a device opened by [rockusb_open]
+ *
+ * # Safety
+ * `device` must be returned by [rockusb_open] and not be used afterwards; NULL is ignored
+ */
+void rockusb_close(struct RockusbDevice *device);
+
+/**
+ * Size of the flash in sectors of 512 bytes
+ *
+ * # Safety
+ * `device` must be a valid device and `sectors` valid for writes
+ */
+int rockusb_flash_sectors(struct RockusbDevice *device, uint32_t *sectors);
+
+/**
+ * Read the 16 bytes of chip info
+ *
+ * # Safety
+ * `device` must be a valid device and `info` valid for writes of 16 bytes
+ */
+int rockusb_chip_info(struct RockusbDevice *device, uint8_t *info);
+
+/**
+ * Read `len` bytes starting at `start_sector`; `len` must be a multiple of 512
+ *
+ * # Safety
+ * `device` must be a valid device and `data` valid for writes of `len` bytes
+ */
+int rockusb_read_lba(struct RockusbDevice *device,
+                     uint32_t start_sector,
+                     uint8_t *data,
+                     uintptr_t len);
+
+/**
+ * Write `len` bytes starting at `start_sector`; `len` must be a multiple of 512
+ *
+ * # Safety
+ * `device` must be a valid device and `data` valid for reads of `len` bytes
+ */
+int rockusb_write_lba(struct RockusbDevice *device,
+                      uint32_t start_sector,
+                      const uint8_t *data,
+                      uintptr_t len);
+
+/**
+ * Allow writes and erases touching the partition table or IDBlock, which are refused by default
+ *
+ * # Safety
+ * `device` must be a valid device
+ */
+void rockusb_allow_critical_regions(struct RockusbDevice *device);
+
+/**
+ * Erase `sectors` sectors starting at `start_sector`
+ *
+ * # Safety
+ * `device` must be a valid device
+ */
+int rockusb_erase_lba(struct RockusbDevice *device, uint32_t start_sector, uint32_t sectors);
+
+/**
+ * Download a boot file (e.g. as created by boot_merger) to a device in maskrom mode
+ *
+ * # Safety
+ * `device` must be a valid device and `path` a NUL-terminated string
+ */
+int rockusb_download_boot(struct RockusbDevice *device, const char *path);
+
+/**
+ * Reset the device; `opcode` is 0 to reset, 1 for mass-storage mode, 2 to power off, 3 for
+ * maskrom mode and 4 to disconnect
+ *
+ * # Safety
+ * `device` must be a valid device
+ */
+int rockusb_reset(struct RockusbDevice *device, uint8_t opcode);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* ROCKUSB_H */
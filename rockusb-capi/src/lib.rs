@@ -0,0 +1,261 @@
+#![doc = include_str!("../README.md")]
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, CStr, CString};
+
+use rockfile::boot::BootFile;
+use rockusb::libusb::{Devices, Transport};
+use rockusb::protocol::{DeviceFilter, ResetOpcode, UsbMode, DEFAULT_DEVICE_FILTERS, SECTOR_SIZE};
+
+const SECTOR: usize = SECTOR_SIZE as usize;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_error<E: std::fmt::Display>(e: E) -> c_int {
+    let msg = CString::new(e.to_string().replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|l| *l.borrow_mut() = Some(msg));
+    -1
+}
+
+fn check<T, E: std::fmt::Display>(r: Result<T, E>) -> c_int {
+    match r {
+        Ok(_) => 0,
+        Err(e) => set_error(e),
+    }
+}
+
+/// Opaque handle to an opened device
+pub struct RockusbDevice(Transport);
+
+/// Rockchip device found on the usb bus
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RockusbDeviceInfo {
+    pub bus: u8,
+    pub address: u8,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    /// Whether the device is in maskrom mode rather then running a loader
+    pub maskrom: bool,
+}
+
+/// Message describing the last error on the calling thread
+///
+/// Returns NULL if no error occurred. The string is valid until the next call on the same thread
+#[no_mangle]
+pub extern "C" fn rockusb_last_error() -> *const c_char {
+    LAST_ERROR.with(|l| {
+        l.borrow()
+            .as_ref()
+            .map(|e| e.as_ptr())
+            .unwrap_or(std::ptr::null())
+    })
+}
+
+/// Find Rockchip devices without opening them
+///
+/// Information on at most `len` devices is stored in `devices`. Returns the total number of
+/// devices found, which may be more then `len`, or -1 on error
+///
+/// # Safety
+/// `devices` must point to an array of at least `len` elements or be NULL if `len` is 0
+#[no_mangle]
+pub unsafe extern "C" fn rockusb_list_devices(
+    devices: *mut RockusbDeviceInfo,
+    len: usize,
+) -> c_int {
+    let list = match rusb::devices() {
+        Ok(list) => list,
+        Err(e) => return set_error(e),
+    };
+    let mut found = 0;
+    for device in list.iter() {
+        let Ok(desc) = device.device_descriptor() else {
+            continue;
+        };
+        if !DeviceFilter::any_matches(DEFAULT_DEVICE_FILTERS, desc.vendor_id(), desc.product_id()) {
+            continue;
+        }
+        if found < len {
+            *devices.add(found) = RockusbDeviceInfo {
+                bus: device.bus_number(),
+                address: device.address(),
+                vendor_id: desc.vendor_id(),
+                product_id: desc.product_id(),
+                maskrom: rockusb::libusb::device_mode(&device)
+                    .is_ok_and(|mode| mode == UsbMode::Maskrom),
+            };
+        }
+        found += 1;
+    }
+    found as c_int
+}
+
+/// Open the device at a given bus and address
+///
+/// Returns NULL on error. The device has to be closed using [rockusb_close]
+#[no_mangle]
+pub extern "C" fn rockusb_open(bus: u8, address: u8) -> *mut RockusbDevice {
+    let devices = match Devices::new() {
+        Ok(devices) => devices,
+        Err(e) => {
+            set_error(e);
+            return std::ptr::null_mut();
+        }
+    };
+    let found = devices.iter().find(|d| match d {
+        Ok(t) => t.bus_number() == bus && t.address() == address,
+        Err(e) => e.device.bus_number() == bus && e.device.address() == address,
+    });
+    match found {
+        Some(Ok(transport)) => Box::into_raw(Box::new(RockusbDevice(transport))),
+        Some(Err(e)) => {
+            set_error(e);
+            std::ptr::null_mut()
+        }
+        None => {
+            set_error("Device not found");
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Close a device opened by [rockusb_open]
+///
+/// # Safety
+/// `device` must be returned by [rockusb_open] and not be used afterwards; NULL is ignored
+#[no_mangle]
+pub unsafe extern "C" fn rockusb_close(device: *mut RockusbDevice) {
+    if !device.is_null() {
+        drop(Box::from_raw(device));
+    }
+}
+
+/// Size of the flash in sectors of 512 bytes
+///
+/// # Safety
+/// `device` must be a valid device and `sectors` valid for writes
+#[no_mangle]
+pub unsafe extern "C" fn rockusb_flash_sectors(
+    device: *mut RockusbDevice,
+    sectors: *mut u32,
+) -> c_int {
+    match (*device).0.flash_info() {
+        Ok(info) => {
+            *sectors = info.sectors();
+            0
+        }
+        Err(e) => set_error(e),
+    }
+}
+
+/// Read the 16 bytes of chip info
+///
+/// # Safety
+/// `device` must be a valid device and `info` valid for writes of 16 bytes
+#[no_mangle]
+pub unsafe extern "C" fn rockusb_chip_info(device: *mut RockusbDevice, info: *mut u8) -> c_int {
+    match (*device).0.chip_info() {
+        Ok(chip) => {
+            let data = chip.inner();
+            std::ptr::copy_nonoverlapping(data.as_ptr(), info, data.len());
+            0
+        }
+        Err(e) => set_error(e),
+    }
+}
+
+/// Read `len` bytes starting at `start_sector`; `len` must be a multiple of 512
+///
+/// # Safety
+/// `device` must be a valid device and `data` valid for writes of `len` bytes
+#[no_mangle]
+pub unsafe extern "C" fn rockusb_read_lba(
+    device: *mut RockusbDevice,
+    start_sector: u32,
+    data: *mut u8,
+    len: usize,
+) -> c_int {
+    if len % SECTOR != 0 {
+        return set_error("Length isn't a multiple of the sector size");
+    }
+    let data = std::slice::from_raw_parts_mut(data, len);
+    check((*device).0.read_lba(start_sector, data))
+}
+
+/// Write `len` bytes starting at `start_sector`; `len` must be a multiple of 512
+///
+/// # Safety
+/// `device` must be a valid device and `data` valid for reads of `len` bytes
+#[no_mangle]
+pub unsafe extern "C" fn rockusb_write_lba(
+    device: *mut RockusbDevice,
+    start_sector: u32,
+    data: *const u8,
+    len: usize,
+) -> c_int {
+    if len % SECTOR != 0 {
+        return set_error("Length isn't a multiple of the sector size");
+    }
+    let data = std::slice::from_raw_parts(data, len);
+    check((*device).0.write_lba(start_sector, data))
+}
+
+/// Allow writes and erases touching the partition table or IDBlock, which are refused by default
+///
+/// # Safety
+/// `device` must be a valid device
+#[no_mangle]
+pub unsafe extern "C" fn rockusb_allow_critical_regions(device: *mut RockusbDevice) {
+    (*device).0.allow_critical_regions();
+}
+
+/// Erase `sectors` sectors starting at `start_sector`
+///
+/// # Safety
+/// `device` must be a valid device
+#[no_mangle]
+pub unsafe extern "C" fn rockusb_erase_lba(
+    device: *mut RockusbDevice,
+    start_sector: u32,
+    sectors: u32,
+) -> c_int {
+    check((*device).0.erase_lba(start_sector, sectors))
+}
+
+/// Download a boot file (e.g. as created by boot_merger) to a device in maskrom mode
+///
+/// # Safety
+/// `device` must be a valid device and `path` a NUL-terminated string
+#[no_mangle]
+pub unsafe extern "C" fn rockusb_download_boot(
+    device: *mut RockusbDevice,
+    path: *const c_char,
+) -> c_int {
+    let path = match CStr::from_ptr(path).to_str() {
+        Ok(path) => path,
+        Err(e) => return set_error(e),
+    };
+    let data = match std::fs::read(path) {
+        Ok(data) => data,
+        Err(e) => return set_error(e),
+    };
+    let Some(boot) = BootFile::from_bytes(data) else {
+        return set_error("Failed to parse boot file");
+    };
+    check((*device).0.download_boot(&boot))
+}
+
+/// Reset the device; `opcode` is 0 to reset, 1 for mass-storage mode, 2 to power off, 3 for
+/// maskrom mode and 4 to disconnect
+///
+/// # Safety
+/// `device` must be a valid device
+#[no_mangle]
+pub unsafe extern "C" fn rockusb_reset(device: *mut RockusbDevice, opcode: u8) -> c_int {
+    match ResetOpcode::try_from(opcode) {
+        Ok(opcode) => check((*device).0.reset_device(opcode)),
+        Err(e) => set_error(e),
+    }
+}
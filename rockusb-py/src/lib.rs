@@ -0,0 +1,251 @@
+#![doc = include_str!("../README.md")]
+use pyo3::create_exception;
+use pyo3::exceptions::{PyException, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+use rockfile::boot::BootFile;
+use rockusb::libusb::{Devices, Transport};
+use rockusb::protocol::{DeviceFilter, ResetOpcode, UsbMode, DEFAULT_DEVICE_FILTERS, SECTOR_SIZE};
+
+create_exception!(
+    rockusb,
+    RockusbError,
+    PyException,
+    "Error talking to a Rockchip device"
+);
+
+fn error<E: std::fmt::Display>(e: E) -> PyErr {
+    RockusbError::new_err(e.to_string())
+}
+
+// Progress callback forwarding to an optional python callable; The first exception raised by
+// the callable is kept and reported once the transfer finishes
+struct Progress {
+    callback: Option<PyObject>,
+    total: u64,
+    error: Option<PyErr>,
+}
+
+impl Progress {
+    fn new(callback: Option<PyObject>, total: u64) -> Self {
+        Self {
+            callback,
+            total,
+            error: None,
+        }
+    }
+
+    fn update(&mut self, done: u64) {
+        let Some(callback) = &self.callback else {
+            return;
+        };
+        if self.error.is_none() {
+            if let Err(e) = Python::with_gil(|py| callback.call1(py, (done, self.total))) {
+                self.error = Some(e);
+            }
+        }
+    }
+
+    fn finish(self) -> PyResult<()> {
+        self.error.map_or(Ok(()), Err)
+    }
+}
+
+/// Rockchip device found on the usb bus
+#[pyclass(frozen, get_all, module = "rockusb")]
+#[derive(Debug, Clone)]
+pub struct DeviceInfo {
+    bus: u8,
+    address: u8,
+    vendor_id: u16,
+    product_id: u16,
+    /// Whether the device is in maskrom mode rather then running a loader
+    maskrom: bool,
+}
+
+#[pymethods]
+impl DeviceInfo {
+    /// Open the device
+    fn open(&self) -> PyResult<Device> {
+        Device::new(self.bus, self.address)
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "DeviceInfo(bus={}, address={}, id={:04x}:{:04x}, maskrom={})",
+            self.bus,
+            self.address,
+            self.vendor_id,
+            self.product_id,
+            if self.maskrom { "True" } else { "False" }
+        )
+    }
+}
+
+/// List Rockchip devices without opening them
+#[pyfunction]
+fn devices() -> PyResult<Vec<DeviceInfo>> {
+    let mut found = vec![];
+    for device in rusb::devices().map_err(error)?.iter() {
+        let Ok(desc) = device.device_descriptor() else {
+            continue;
+        };
+        if !DeviceFilter::any_matches(DEFAULT_DEVICE_FILTERS, desc.vendor_id(), desc.product_id()) {
+            continue;
+        }
+        found.push(DeviceInfo {
+            bus: device.bus_number(),
+            address: device.address(),
+            vendor_id: desc.vendor_id(),
+            product_id: desc.product_id(),
+            maskrom: rockusb::libusb::device_mode(&device)
+                .is_ok_and(|mode| mode == UsbMode::Maskrom),
+        });
+    }
+    Ok(found)
+}
+
+/// Opened Rockchip device
+///
+/// Progress callbacks passed to transfers are called with the number of bytes transferred so
+/// far and the total number of bytes
+#[pyclass(module = "rockusb")]
+pub struct Device(Transport);
+
+#[pymethods]
+impl Device {
+    /// Open the device at a given bus and address
+    #[new]
+    fn new(bus: u8, address: u8) -> PyResult<Self> {
+        let devices = Devices::new().map_err(error)?;
+        let found = devices.iter().find(|d| match d {
+            Ok(t) => t.bus_number() == bus && t.address() == address,
+            Err(e) => e.device.bus_number() == bus && e.device.address() == address,
+        });
+        match found {
+            Some(Ok(transport)) => Ok(Device(transport)),
+            Some(Err(e)) => Err(error(e)),
+            None => Err(error("Device not found")),
+        }
+    }
+
+    #[getter]
+    fn bus(&self) -> u8 {
+        self.0.bus_number()
+    }
+
+    #[getter]
+    fn address(&self) -> u8 {
+        self.0.address()
+    }
+
+    /// The 16 bytes of chip info
+    fn chip_info<'py>(&mut self, py: Python<'py>) -> PyResult<Bound<'py, PyBytes>> {
+        let info = self.0.chip_info().map_err(error)?;
+        Ok(PyBytes::new(py, info.inner()))
+    }
+
+    /// Identifier of the flash
+    fn flash_id(&mut self) -> PyResult<String> {
+        let id = self.0.flash_id().map_err(error)?;
+        Ok(id.to_str().into_owned())
+    }
+
+    /// Size of the flash in sectors of 512 bytes
+    fn flash_sectors(&mut self) -> PyResult<u32> {
+        Ok(self.0.flash_info().map_err(error)?.sectors())
+    }
+
+    /// Read `sectors` sectors starting at `start_sector`
+    #[pyo3(signature = (start_sector, sectors, progress=None))]
+    fn read<'py>(
+        &mut self,
+        py: Python<'py>,
+        start_sector: u32,
+        sectors: u32,
+        progress: Option<PyObject>,
+    ) -> PyResult<Bound<'py, PyBytes>> {
+        // Check the range before allocating the buffer for it
+        let flash_sectors = self.flash_sectors()?;
+        let end = start_sector
+            .checked_add(sectors)
+            .filter(|&end| end <= flash_sectors)
+            .ok_or_else(|| PyValueError::new_err("Sector range out of bounds"))?;
+        let mut data = Vec::with_capacity(sectors as usize * SECTOR_SIZE as usize);
+        let mut progress = Progress::new(progress, sectors as u64 * SECTOR_SIZE);
+        let transport = &mut self.0;
+        py.allow_threads(|| {
+            transport.read_to_writer(start_sector..end, &mut data, |done| progress.update(done))
+        })
+        .map_err(error)?;
+        progress.finish()?;
+        Ok(PyBytes::new(py, &data))
+    }
+
+    /// Write data starting at `start_sector`
+    ///
+    /// If the data isn't a multiple of 512 bytes, the remainder of the last sector is preserved
+    #[pyo3(signature = (start_sector, data, progress=None))]
+    fn write(
+        &mut self,
+        py: Python<'_>,
+        start_sector: u32,
+        data: &[u8],
+        progress: Option<PyObject>,
+    ) -> PyResult<()> {
+        let len = data.len() as u64;
+        let mut progress = Progress::new(progress, len);
+        let transport = &mut self.0;
+        py.allow_threads(|| {
+            transport.write_from_reader(start_sector, data, len, |done| progress.update(done))
+        })
+        .map_err(error)?;
+        progress.finish()
+    }
+
+    /// Allow writes and erases touching the partition table or IDBlock, which are refused by
+    /// default
+    fn allow_critical_regions(&mut self) {
+        self.0.allow_critical_regions();
+    }
+
+    /// Erase `sectors` sectors starting at `start_sector`
+    fn erase(&mut self, py: Python<'_>, start_sector: u32, sectors: u32) -> PyResult<()> {
+        let transport = &mut self.0;
+        py.allow_threads(|| transport.erase_lba(start_sector, sectors))
+            .map_err(error)
+    }
+
+    /// Download a boot file (e.g. as created by boot_merger) to a device in maskrom mode
+    fn download_boot(&mut self, py: Python<'_>, path: std::path::PathBuf) -> PyResult<()> {
+        let data = std::fs::read(path)?;
+        let boot = BootFile::from_bytes(data).ok_or_else(|| error("Failed to parse boot file"))?;
+        let transport = &mut self.0;
+        py.allow_threads(|| transport.download_boot(&boot))
+            .map_err(error)
+    }
+
+    /// Reset the device; `opcode` is 0 to reset, 1 for mass-storage mode, 2 to power off, 3 for
+    /// maskrom mode and 4 to disconnect
+    #[pyo3(signature = (opcode=0))]
+    fn reset(&mut self, opcode: u8) -> PyResult<()> {
+        let opcode =
+            ResetOpcode::try_from(opcode).map_err(|e| PyValueError::new_err(e.to_string()))?;
+        self.0.reset_device(opcode).map_err(error)
+    }
+
+    fn __repr__(&self) -> String {
+        format!("Device({})", self.0)
+    }
+}
+
+/// Python bindings for the Rockchip usb protocol
+#[pymodule]
+#[pyo3(name = "rockusb")]
+fn rockusb_py(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add("RockusbError", m.py().get_type::<RockusbError>())?;
+    m.add_class::<DeviceInfo>()?;
+    m.add_class::<Device>()?;
+    m.add_function(wrap_pyfunction!(devices, m)?)?;
+    Ok(())
+}
@@ -1,27 +1,29 @@
 use std::{
     ffi::OsStr,
-    io::SeekFrom,
     path::{Path, PathBuf},
-    thread::sleep,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use anyhow::{anyhow, Result};
 use async_compression::futures::bufread::GzipDecoder;
 use bmap_parser::Bmap;
-use clap::{Parser, ValueEnum};
+use clap::{CommandFactory, Parser, ValueEnum};
 use clap_num::maybe_hex;
 use futures::io::{BufReader, BufWriter};
-use rockfile::boot::{
-    RkBootEntry, RkBootEntryBytes, RkBootHeader, RkBootHeaderBytes, RkBootHeaderEntry,
-};
-use rockusb::nusb::Transport;
-use rockusb::protocol::ResetOpcode;
+use futures::StreamExt;
+use rockfile::boot::BootFile;
+use rockusb::flasher::{FlashPlan, Image, Target};
+use rockusb::gpt_layout::GptLayout;
+use rockusb::manifest::HashManifest;
+use rockusb::nusb::{AvailableDevice, HotplugEvent, Transport, UnavailableKind};
+use rockusb::operation::MAX_LBA_SECTORS;
+use rockusb::protocol::{ResetOpcode, UsbMode};
+use rockusb::stats::Stats;
 use tokio::{
     fs::File,
-    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    io::{AsyncReadExt, AsyncWriteExt},
 };
-use tokio_util::compat::{FuturesAsyncReadCompatExt, TokioAsyncReadCompatExt};
+use tokio_util::compat::{TokioAsyncReadCompatExt, TokioAsyncWriteCompatExt};
 
 async fn read_flash_info(mut transport: Transport) -> Result<()> {
     let info = transport.flash_info().await?;
@@ -40,6 +42,56 @@ async fn reset_device(mut transport: Transport, opcode: ResetOpcode) -> Result<(
     Ok(())
 }
 
+async fn reset_maskrom(mut transport: Transport) -> Result<()> {
+    // Start watching before resetting to not miss the device reappearing
+    let mut watch = rockusb::nusb::watch_devices()?;
+    transport.reset_device(ResetOpcode::Maskrom).await?;
+    drop(transport);
+
+    println!("Waiting for the device to reappear in maskrom mode");
+    let device = tokio::time::timeout(Duration::from_secs(10), async {
+        while let Some(event) = watch.next().await {
+            match event {
+                HotplugEvent::Connected(device) if device.mode() == UsbMode::Maskrom => {
+                    return Some(device)
+                }
+                _ => (),
+            }
+        }
+        None
+    })
+    .await
+    .ok()
+    .flatten()
+    .ok_or_else(|| anyhow!("Timeout waiting for the maskrom device"))?;
+
+    println!("Maskrom device: {}", device);
+    Ok(())
+}
+
+async fn print_info(mut transport: Transport) -> Result<()> {
+    let chip_info = transport.chip_info().await?;
+    println!("Device: {}", transport);
+    println!("Chip Info: {:0x?}", chip_info.inner());
+    println!("Flash id: {}", transport.flash_id().await?.to_str());
+    let info = transport.flash_info().await?;
+    println!(
+        "Flash size: {} MB ({} sectors)",
+        info.sectors() / 2048,
+        info.sectors()
+    );
+    // Older loaders don't implement these
+    match transport.capability().await {
+        Ok(capability) => println!("Capability: {:0x?}", capability.inner()),
+        Err(e) => println!("Capability: unavailable ({})", e),
+    }
+    match transport.storage().await {
+        Ok(storage) => println!("Storage: {}", storage),
+        Err(e) => println!("Storage: unavailable ({})", e),
+    }
+    Ok(())
+}
+
 async fn read_chip_info(mut transport: Transport) -> Result<()> {
     println!("Chip Info: {:0x?}", transport.chip_info().await?);
     Ok(())
@@ -70,21 +122,179 @@ async fn write_lba(mut transport: Transport, offset: u32, length: u16, path: &Pa
     Ok(())
 }
 
-async fn read_file(transport: Transport, offset: u32, length: u16, path: &Path) -> Result<()> {
-    let mut file = tokio::fs::File::create(path).await?;
-    let mut io = transport.into_io().await?.compat();
+async fn read_file(mut transport: Transport, offset: u32, length: u16, path: &Path) -> Result<()> {
+    let file = tokio::fs::File::create(path).await?;
+    let mut file = file.compat_write();
 
-    io.seek(SeekFrom::Start(offset as u64 * 512)).await?;
-    tokio::io::copy(&mut io.take(length as u64 * 512), &mut file).await?;
+    transport
+        .read_to_writer(offset..offset + u32::from(length), &mut file, |_| {})
+        .await?;
+    file.into_inner().flush().await?;
     Ok(())
 }
 
-async fn write_file(transport: Transport, offset: u32, path: &Path) -> Result<()> {
-    let mut file = tokio::fs::File::open(path).await?;
-    let mut io = transport.into_io().await?.compat();
+async fn write_gpt(mut transport: Transport, layout: &Path) -> Result<()> {
+    let layout: GptLayout = toml::from_str(&std::fs::read_to_string(layout)?)?;
+    let sectors = transport.flash_info().await?.sectors();
+    let gpt = layout.to_gpt(sectors as u64)?;
+    transport
+        .run_plan(&FlashPlan::new().write_gpt(gpt.clone()), |_| {})
+        .await?;
+    println!("Disk GUID: {}", gpt.header.disk_guid);
+    for p in &gpt.partitions {
+        println!(
+            "{}: {:#x}..{:#x} ({})",
+            p.name,
+            p.first_lba,
+            p.last_lba + 1,
+            p.type_guid
+        );
+    }
+    Ok(())
+}
 
-    io.seek(SeekFrom::Start(offset as u64 * 512)).await?;
-    tokio::io::copy(&mut file, &mut io).await?;
+async fn repair_gpt(mut transport: Transport) -> Result<()> {
+    let repair = transport.repair_gpt().await?;
+    let valid = |v| if v { "valid" } else { "invalid" };
+    println!("Primary table: {}", valid(repair.primary_valid));
+    println!(
+        "Backup table at {:#x}: {}",
+        repair.old_backup_lba,
+        valid(repair.backup_valid)
+    );
+    println!(
+        "Rewrote table with backup at {:#x}, usable sectors {:#x}..{:#x}",
+        repair.gpt.header.alternate_lba,
+        repair.gpt.header.first_usable_lba,
+        repair.gpt.header.last_usable_lba + 1
+    );
+    Ok(())
+}
+
+async fn verify_hashes(mut transport: Transport, manifest: &Path) -> Result<()> {
+    let manifest: HashManifest = toml::from_str(&std::fs::read_to_string(manifest)?)?;
+    let plan = manifest.verify(FlashPlan::new())?;
+    let report = transport.run_plan(&plan, |_| {}).await?;
+    for step in &report.steps {
+        println!("{}: ok", step.description);
+    }
+    Ok(())
+}
+
+async fn write_images(mut transport: Transport, images: Vec<(Target, PathBuf)>) -> Result<()> {
+    let plan = images
+        .into_iter()
+        .fold(FlashPlan::new(), |plan, (target, path)| {
+            plan.write(Image::File(path), target)
+        });
+    let report = transport
+        .run_plan(&plan, |p| {
+            if p.done == p.total {
+                println!("Step {}/{} done: {} bytes", p.step + 1, p.steps, p.total);
+            }
+        })
+        .await?;
+    for step in &report.steps {
+        println!(
+            "{}: {} bytes in {:.2?}",
+            step.description, step.bytes, step.duration
+        );
+    }
+    Ok(())
+}
+
+async fn write_file(mut transport: Transport, offset: u32, path: &Path) -> Result<()> {
+    let file = tokio::fs::File::open(path).await?;
+    let len = file.metadata().await?.len();
+
+    transport
+        .write_from_reader(offset, file.compat(), len, |_| {})
+        .await?;
+    Ok(())
+}
+
+// Sectors per command to compare in benchmarks
+//
+// There is no queue depth to compare: The protocol only allows a single command in flight, and
+// queueing the data phase of a command would swallow the command status after a short read
+const BENCH_CHUNKS: [u32; 3] = [8, 32, MAX_LBA_SECTORS as u32];
+
+async fn ping(mut transport: Transport, count: Option<u32>, interval: u64) -> Result<()> {
+    let mut failed = 0;
+    let mut seq = 0;
+    while count != Some(seq) {
+        if seq > 0 {
+            tokio::time::sleep(Duration::from_millis(interval)).await;
+        }
+        seq += 1;
+        let start = Instant::now();
+        match transport.test_unit_ready().await {
+            Ok(()) => println!("seq={} time={:.2?}", seq, start.elapsed()),
+            Err(e) => {
+                failed += 1;
+                println!("seq={} failed after {:.2?}: {}", seq, start.elapsed(), e);
+            }
+        }
+    }
+    println!("{} commands, {} failed", seq, failed);
+    Ok(())
+}
+
+fn print_bench(name: &str, sectors_per_command: u32, throughput: f64, stats: &Stats) {
+    println!(
+        "{:<5} {:>3} sectors/command: {:>8.2} MB/s, {:>8.2?} per command",
+        name,
+        sectors_per_command,
+        throughput / 1_000_000.0,
+        stats.busy / stats.operations.max(1) as u32
+    );
+}
+
+async fn bench(mut transport: Transport, sectors: u32, scratch: Option<u32>) -> Result<()> {
+    let info = transport.flash_info().await?;
+    let sectors = sectors.min(info.sectors());
+    let mut buffer = vec![0; sectors as usize * 512];
+
+    transport.reset_stats();
+    for _ in 0..100 {
+        transport.read_lba(0, &mut buffer[..512]).await?;
+    }
+    let stats = transport.stats();
+    print_bench("read", 1, stats.read_throughput(), stats);
+
+    for chunk in BENCH_CHUNKS {
+        transport.reset_stats();
+        for sector in (0..sectors).step_by(chunk as usize) {
+            let len = (sectors - sector).min(chunk) as usize * 512;
+            transport.read_lba(sector, &mut buffer[..len]).await?;
+        }
+        let stats = transport.stats();
+        print_bench("read", chunk, stats.read_throughput(), stats);
+    }
+
+    let Some(scratch) = scratch else {
+        return Ok(());
+    };
+    match scratch.checked_add(sectors) {
+        Some(end) if end <= info.sectors() => (),
+        _ => return Err(anyhow!("Scratch area outside of the flash")),
+    }
+    buffer
+        .iter_mut()
+        .enumerate()
+        .for_each(|(i, b)| *b = i as u8);
+    for chunk in BENCH_CHUNKS {
+        transport.reset_stats();
+        for sector in (0..sectors).step_by(chunk as usize) {
+            let start = sector as usize * 512;
+            let len = (sectors - sector).min(chunk) as usize * 512;
+            transport
+                .write_lba(scratch + sector, &buffer[start..start + len])
+                .await?;
+        }
+        let stats = transport.stats();
+        print_bench("write", chunk, stats.write_throughput(), stats);
+    }
     Ok(())
 }
 
@@ -137,50 +347,24 @@ async fn write_bmap(transport: Transport, path: &Path) -> Result<()> {
     Ok(())
 }
 
-async fn download_entry(
-    header: RkBootHeaderEntry,
-    code: u16,
-    file: &mut File,
-    transport: &mut Transport,
+async fn download_boot(
+    mut transport: Transport,
+    path: &Path,
+    require_sign_flag: bool,
 ) -> Result<()> {
-    for i in 0..header.count {
-        let mut entry: RkBootEntryBytes = [0; 57];
-
-        file.seek(SeekFrom::Start(
-            header.offset as u64 + (header.size * i) as u64,
-        ))
-        .await?;
-        file.read_exact(&mut entry).await?;
-
-        let entry = RkBootEntry::from_bytes(&entry);
-        println!("{} Name: {}", i, String::from_utf16(entry.name.as_slice())?);
-
-        let mut data = vec![0; entry.data_size as usize];
-
-        file.seek(SeekFrom::Start(entry.data_offset as u64)).await?;
-        file.read_exact(&mut data).await?;
-
-        transport.write_maskrom_area(code, &data).await?;
-
-        println!("Done!... waiting {}ms", entry.data_delay);
-        if entry.data_delay > 0 {
-            sleep(Duration::from_millis(entry.data_delay as u64));
-        }
+    let boot = BootFile::from_bytes(tokio::fs::read(path).await?)
+        .ok_or_else(|| anyhow!("Failed to parse header"))?;
+    if require_sign_flag && !boot.header().is_signed() {
+        return Err(anyhow!(
+            "Refusing to download loader without the signed flag"
+        ));
     }
 
-    Ok(())
-}
-
-async fn download_boot(mut transport: Transport, path: &Path) -> Result<()> {
-    let mut file = File::open(path).await?;
-    let mut header: RkBootHeaderBytes = [0; 102];
-    file.read_exact(&mut header).await?;
-
-    let header =
-        RkBootHeader::from_bytes(&header).ok_or_else(|| anyhow!("Failed to parse header"))?;
-
-    download_entry(header.entry_471, 0x471, &mut file, &mut transport).await?;
-    download_entry(header.entry_472, 0x472, &mut file, &mut transport).await?;
+    for (kind, entry) in boot.all_entries() {
+        println!("{} Name: {}", kind, entry.name());
+    }
+    transport.download_boot(&boot).await?;
+    println!("Done!");
 
     Ok(())
 }
@@ -188,8 +372,28 @@ async fn download_boot(mut transport: Transport, path: &Path) -> Result<()> {
 #[derive(Debug, clap::Parser)]
 enum Command {
     List,
+    /// Print rockchip devices being connected or disconnected
+    Watch,
+    /// Query all connected devices for their identification and flash information
+    Inventory {
+        /// Print the records as JSON
+        #[clap(long)]
+        json: bool,
+    },
+    /// Print shell completions for this tool
+    Completions {
+        #[clap(value_enum)]
+        shell: clap_complete::Shell,
+    },
+    /// Print the man page for this tool
+    Manpage,
     DownloadBoot {
         path: PathBuf,
+        /// Refuse loaders whose header isn't flagged as signed
+        ///
+        /// Only the flag in the loader header is checked, not the signatures themselves
+        #[clap(long)]
+        require_sign_flag: bool,
     },
     Read {
         #[clap(value_parser=maybe_hex::<u32>)]
@@ -217,16 +421,61 @@ enum Command {
         offset: u32,
         path: PathBuf,
     },
+    /// Write multiple images in one session, e.g. 0x40=idbloader.img 0x4000=u-boot.itb
+    WriteImages {
+        /// Images as <target>=<file>, where the target is a start sector or a partition name
+        #[clap(value_parser = parse_image, required = true)]
+        images: Vec<(Target, PathBuf)>,
+    },
     WriteBmap {
         path: PathBuf,
     },
+    /// Verify the flash against the sha256 hashes of a TOML hash manifest
+    VerifyHashes {
+        manifest: PathBuf,
+    },
+    /// GUID partition table handling
+    Gpt {
+        #[clap(subcommand)]
+        command: GptCommand,
+    },
     ChipInfo,
     FlashId,
     FlashInfo,
+    /// Print chip, flash, capability and storage information
+    Info,
+    /// Check the loader is alive by repeatedly issuing test unit ready, printing the latency
+    Ping {
+        /// Number of commands to issue; Runs until interrupted if not given
+        #[clap(short, long)]
+        count: Option<u32>,
+        /// Interval between commands in milliseconds
+        #[clap(short, long, default_value = "1000")]
+        interval: u64,
+    },
+    /// Measure command latency and read (and optionally write) throughput
+    Bench {
+        /// Number of sectors to transfer per test
+        #[clap(long, default_value = "0x8000", value_parser=maybe_hex::<u32>)]
+        sectors: u32,
+        /// Also measure writes to a scratch area starting at this sector; Its content is lost
+        #[clap(long, value_parser=maybe_hex::<u32>)]
+        write_scratch: Option<u32>,
+    },
     ResetDevice {
         #[clap(value_enum, default_value_t=ArgResetOpcode::Reset)]
         opcode: ArgResetOpcode,
     },
+    /// Reset to maskrom mode and wait for the device to reappear
+    ResetMaskrom,
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum GptCommand {
+    /// Write a partition table described by a TOML layout, replacing any existing table
+    Write { layout: PathBuf },
+    /// Validate the partition table and rewrite it with the backup at the end of the flash
+    Repair,
 }
 
 #[derive(ValueEnum, Clone, Debug)]
@@ -261,6 +510,17 @@ struct DeviceArg {
     address: u8,
 }
 
+fn parse_image(image: &str) -> Result<(Target, PathBuf)> {
+    let (target, path) = image
+        .split_once('=')
+        .ok_or_else(|| anyhow!("Image should be specified as <sector or partition>=<file>"))?;
+    let target = match maybe_hex::<u32>(target) {
+        Ok(sector) => Target::Sector(sector),
+        Err(_) => Target::Partition(target.to_string()),
+    };
+    Ok((target, PathBuf::from(path)))
+}
+
 fn parse_device(device: &str) -> Result<DeviceArg> {
     let mut parts = device.split(':');
     let bus_number = parts
@@ -284,26 +544,135 @@ fn parse_device(device: &str) -> Result<DeviceArg> {
 
 #[derive(clap::Parser)]
 struct Opts {
-    #[arg(short, long, value_parser = parse_device)]
+    #[arg(short, long, value_parser = parse_device, value_name = "BUS:ADDRESS")]
     /// Device type specified as <bus>:<address>
     device: Option<DeviceArg>,
+    /// Detach kernel drivers (e.g. usb-storage) claiming the device
+    #[arg(long)]
+    detach: bool,
+    /// Allow writes and erases touching the partition table or IDBlock
+    #[arg(long)]
+    allow_critical_regions: bool,
+    /// Limit writes to the device to this many bytes per second
+    #[arg(long, value_name = "BYTES")]
+    max_write_rate: Option<u64>,
     #[command(subcommand)]
     command: Command,
 }
 
+async fn watch_devices() -> Result<()> {
+    let mut watch = rockusb::nusb::watch_devices()?;
+    println!("Watching for rockchip devices");
+    while let Some(event) = watch.next().await {
+        match event {
+            HotplugEvent::Connected(device) => println!("+ {}", device),
+            HotplugEvent::Disconnected(device) => println!("- {}", device),
+        }
+    }
+    Ok(())
+}
+
+fn port_chain(ports: &[u8]) -> String {
+    ports
+        .iter()
+        .map(|p| p.to_string())
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
 fn list_available_devices() -> Result<()> {
     let devices = rockusb::nusb::devices()?;
     println!("Available rockchip devices:");
     for d in devices {
-        println!(
-            "* Bus {} Device {} ID {}:{}",
-            d.bus_number(),
-            d.device_address(),
-            d.vendor_id(),
-            d.product_id()
-        );
+        match d.port_chain() {
+            Some(ports) => println!("* {} - Port {}", d, port_chain(&ports)),
+            None => println!("* {}", d),
+        }
+    }
+
+    Ok(())
+}
+
+// Inventory record of a device; Information the device doesn't provide is null
+async fn device_record(device: &AvailableDevice, detach: bool) -> serde_json::Value {
+    let info = device.info();
+    let mut record = serde_json::json!({
+        "bus": device.bus_number(),
+        "address": device.device_address(),
+        "port": device.port_chain().map(|p| port_chain(&p)),
+        "vendor_id": format!("{:04x}", info.vendor_id()),
+        "product_id": format!("{:04x}", info.product_id()),
+        "serial": info.serial_number(),
+        "soc": device.soc(),
+        "mode": device.mode().to_string(),
+    });
+    // Only the loader implements the information commands
+    if device.mode() != UsbMode::Loader {
+        return record;
+    }
+    let transport = if detach {
+        device.open_detached()
+    } else {
+        device.open()
+    };
+    let mut transport = match transport {
+        Ok(transport) => transport,
+        Err(e) => {
+            record["error"] = e.to_string().into();
+            return record;
+        }
+    };
+    let chip_info = transport.chip_info().await.ok();
+    let flash_id = transport.flash_id().await.ok();
+    let flash_info = transport.flash_info().await.ok();
+    let capability = transport.capability().await.ok();
+    record["chip"] = chip_info.map(|c| c.to_string()).into();
+    record["flash_id"] = flash_id.map(|f| f.to_str().into_owned()).into();
+    record["flash_sectors"] = flash_info.map(|f| f.sectors()).into();
+    record["flash_size"] = flash_info.map(|f| f.size()).into();
+    record["capability"] = capability.map(|c| hex(c.inner())).into();
+    record
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn print_records(records: &[serde_json::Value], json: bool) -> Result<()> {
+    if json {
+        println!("{}", serde_json::to_string_pretty(records)?);
+        return Ok(());
     }
+    for record in records {
+        println!("*");
+        for (key, value) in record.as_object().into_iter().flatten() {
+            match value {
+                serde_json::Value::Null => (),
+                serde_json::Value::String(s) => println!("  {}: {}", key, s),
+                v => println!("  {}: {}", key, v),
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn inventory(detach: bool, json: bool) -> Result<()> {
+    let mut records = vec![];
+    for device in rockusb::nusb::devices()? {
+        records.push(device_record(&device, detach).await);
+    }
+    print_records(&records, json)
+}
 
+fn print_completions(shell: clap_complete::Shell) -> Result<()> {
+    let mut command = Opts::command();
+    clap_complete::generate(shell, &mut command, "rockusb-nusb", &mut std::io::stdout());
+    Ok(())
+}
+
+fn print_manpage() -> Result<()> {
+    let command = Opts::command().name("rockusb-nusb");
+    clap_mangen::Man::new(command).render(&mut std::io::stdout())?;
     Ok(())
 }
 
@@ -312,12 +681,17 @@ async fn main() -> Result<()> {
     let opt = Opts::parse();
 
     // Commands that don't talk a device
-    if matches!(opt.command, Command::List) {
-        return list_available_devices();
+    match opt.command {
+        Command::List => return list_available_devices(),
+        Command::Watch => return watch_devices().await,
+        Command::Completions { shell } => return print_completions(shell),
+        Command::Manpage => return print_manpage(),
+        Command::Inventory { json } => return inventory(opt.detach, json).await,
+        _ => (),
     }
 
     let mut devices = rockusb::nusb::devices()?;
-    let info = if let Some(dev) = opt.device {
+    let device = if let Some(dev) = opt.device {
         devices
             .find(|d| d.bus_number() == dev.bus_number && d.device_address() == dev.address)
             .ok_or_else(|| anyhow!("Specified device not found"))?
@@ -337,11 +711,31 @@ async fn main() -> Result<()> {
         }?
     };
 
-    let mut transport = Transport::from_usb_device_info(info)?;
+    let transport = if opt.detach {
+        device.open_detached()
+    } else {
+        device.open()
+    };
+    let mut transport = transport.map_err(|e| match e.kind {
+        UnavailableKind::ClaimedByKernel => anyhow!("{}; Use --detach to detach it", e.error),
+        UnavailableKind::Busy => anyhow!("Device is in use by another application"),
+        UnavailableKind::MissingDriver | UnavailableKind::Other => e.into(),
+    })?;
+    if opt.allow_critical_regions {
+        transport.allow_critical_regions();
+    }
+    transport.set_max_write_rate(opt.max_write_rate);
 
     match opt.command {
-        Command::List => unreachable!(),
-        Command::DownloadBoot { path } => download_boot(transport, &path).await,
+        Command::List
+        | Command::Watch
+        | Command::Inventory { .. }
+        | Command::Completions { .. }
+        | Command::Manpage => unreachable!(),
+        Command::DownloadBoot {
+            path,
+            require_sign_flag,
+        } => download_boot(transport, &path, require_sign_flag).await,
         Command::Read {
             offset,
             length,
@@ -358,7 +752,13 @@ async fn main() -> Result<()> {
             path,
         } => write_lba(transport, offset, length, &path).await,
         Command::WriteFile { offset, path } => write_file(transport, offset, &path).await,
+        Command::WriteImages { images } => write_images(transport, images).await,
         Command::WriteBmap { path } => write_bmap(transport, &path).await,
+        Command::VerifyHashes { manifest } => verify_hashes(transport, &manifest).await,
+        Command::Gpt { command } => match command {
+            GptCommand::Write { layout } => write_gpt(transport, &layout).await,
+            GptCommand::Repair => repair_gpt(transport).await,
+        },
         Command::ChipInfo => read_chip_info(transport).await,
         Command::FlashId => {
             let id = transport.flash_id().await?;
@@ -367,6 +767,13 @@ async fn main() -> Result<()> {
             Ok(())
         }
         Command::FlashInfo => read_flash_info(transport).await,
+        Command::Info => print_info(transport).await,
+        Command::Ping { count, interval } => ping(transport, count, interval).await,
+        Command::Bench {
+            sectors,
+            write_scratch,
+        } => bench(transport, sectors, write_scratch).await,
         Command::ResetDevice { opcode } => reset_device(transport, opcode.into()).await,
+        Command::ResetMaskrom => reset_maskrom(transport).await,
     }
 }
@@ -5,19 +5,22 @@ use std::{
     net::TcpListener,
     path::{Path, PathBuf},
     thread::sleep,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use anyhow::{anyhow, Result};
 use bmap_parser::Bmap;
-use clap::{Parser, ValueEnum};
+use clap::{CommandFactory, Parser, ValueEnum};
 use clap_num::maybe_hex;
 use flate2::read::GzDecoder;
-use rockfile::boot::{
-    RkBootEntry, RkBootEntryBytes, RkBootHeader, RkBootHeaderBytes, RkBootHeaderEntry,
-};
-use rockusb::libusb::{DeviceUnavalable, Transport};
-use rockusb::protocol::ResetOpcode;
+use rockfile::boot::BootFile;
+use rockusb::flasher::{FlashPlan, Image, Target};
+use rockusb::gpt_layout::GptLayout;
+use rockusb::libusb::{DeviceUnavalable, HotplugEvent, Transport, UnavailableKind};
+use rockusb::manifest::HashManifest;
+use rockusb::operation::MAX_LBA_SECTORS;
+use rockusb::protocol::{soc_name, DeviceFilter, ResetOpcode, UsbMode, DEFAULT_DEVICE_FILTERS};
+use rockusb::stats::Stats;
 
 fn read_flash_info(mut transport: Transport) -> Result<()> {
     let info = transport.flash_info()?;
@@ -36,21 +39,96 @@ fn reset_device(mut transport: Transport, opcode: ResetOpcode) -> Result<()> {
     Ok(())
 }
 
+fn is_maskrom_device(device: &rusb::Device<rusb::GlobalContext>) -> bool {
+    device.device_descriptor().is_ok_and(|desc| {
+        DeviceFilter::any_matches(DEFAULT_DEVICE_FILTERS, desc.vendor_id(), desc.product_id())
+    }) && rockusb::libusb::device_mode(device).is_ok_and(|mode| mode == UsbMode::Maskrom)
+}
+
+fn maskrom_devices() -> Result<Vec<(u8, u8)>> {
+    Ok(rusb::devices()?
+        .iter()
+        .filter(is_maskrom_device)
+        .map(|device| (device.bus_number(), device.address()))
+        .collect())
+}
+
+fn reset_maskrom(mut transport: Transport) -> Result<()> {
+    // Start watching before resetting to not miss the device reappearing
+    let watch = match rockusb::libusb::watch_devices() {
+        Ok(watch) => Some(watch),
+        Err(rockusb::libusb::Error::UsbError(rusb::Error::NotSupported)) => None,
+        Err(e) => return Err(e.into()),
+    };
+    // Without hotplug support fall back to polling for new maskrom devices
+    let existing = if watch.is_none() {
+        maskrom_devices()?
+    } else {
+        vec![]
+    };
+    transport.reset_device(ResetOpcode::Maskrom)?;
+    drop(transport);
+
+    println!("Waiting for the device to reappear in maskrom mode");
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+        let found = match &watch {
+            Some(watch) => match watch.next_timeout(remaining) {
+                Some(HotplugEvent::Connected(device)) if is_maskrom_device(&device) => {
+                    Some((device.bus_number(), device.address()))
+                }
+                _ => None,
+            },
+            None => {
+                sleep(Duration::from_millis(200));
+                maskrom_devices()?
+                    .into_iter()
+                    .find(|d| !existing.contains(d))
+            }
+        };
+        if let Some((bus, address)) = found {
+            println!("Maskrom device: Bus {:03} Device {:03}", bus, address);
+            return Ok(());
+        }
+    }
+    Err(anyhow!("Timeout waiting for the maskrom device"))
+}
+
+fn print_info(mut transport: Transport) -> Result<()> {
+    let chip_info = transport.chip_info()?;
+    println!("Device: {}", transport);
+    println!("Chip Info: {:0x?}", chip_info.inner());
+    println!("Flash id: {}", transport.flash_id()?.to_str());
+    let info = transport.flash_info()?;
+    println!(
+        "Flash size: {} MB ({} sectors)",
+        info.sectors() / 2048,
+        info.sectors()
+    );
+    // Older loaders don't implement these
+    match transport.capability() {
+        Ok(capability) => println!("Capability: {:0x?}", capability.inner()),
+        Err(e) => println!("Capability: unavailable ({})", e),
+    }
+    match transport.storage() {
+        Ok(storage) => println!("Storage: {}", storage),
+        Err(e) => println!("Storage: unavailable ({})", e),
+    }
+    Ok(())
+}
+
 fn read_chip_info(mut transport: Transport) -> Result<()> {
     println!("Chip Info: {:0x?}", transport.chip_info()?);
     Ok(())
 }
 
 fn read_lba(mut transport: Transport, offset: u32, length: u16, path: &Path) -> Result<()> {
-    let mut data = vec![0; length as usize * 512];
-    transport.read_lba(offset, &mut data)?;
-
-    let mut file = std::fs::OpenOptions::new()
+    let file = std::fs::OpenOptions::new()
         .create(true)
         .write(true)
         .truncate(true)
         .open(path)?;
-    file.write_all(&data)?;
+    transport.read_to_writer(offset..offset + u32::from(length), file, |_| {})?;
     Ok(())
 }
 
@@ -65,12 +143,174 @@ fn write_lba(mut transport: Transport, offset: u32, length: u16, path: &Path) ->
     Ok(())
 }
 
+fn read_file(transport: Transport, offset: u32, length: u16, path: &Path) -> Result<()> {
+    let mut io = transport.into_io()?;
+    io.seek(SeekFrom::Start(u64::from(offset) * 512))?;
+    let mut file = BufWriter::new(File::create(path)?);
+
+    std::io::copy(&mut io.take(u64::from(length) * 512), &mut file)?;
+    file.flush()?;
+    Ok(())
+}
+
+fn write_gpt(mut transport: Transport, layout: &Path) -> Result<()> {
+    let layout: GptLayout = toml::from_str(&std::fs::read_to_string(layout)?)?;
+    let sectors = transport.flash_info()?.sectors();
+    let gpt = layout.to_gpt(sectors as u64)?;
+    transport.run_plan(&FlashPlan::new().write_gpt(gpt.clone()), |_| {})?;
+    println!("Disk GUID: {}", gpt.header.disk_guid);
+    for p in &gpt.partitions {
+        println!(
+            "{}: {:#x}..{:#x} ({})",
+            p.name,
+            p.first_lba,
+            p.last_lba + 1,
+            p.type_guid
+        );
+    }
+    Ok(())
+}
+
+fn repair_gpt(mut transport: Transport) -> Result<()> {
+    let repair = transport.repair_gpt()?;
+    let valid = |v| if v { "valid" } else { "invalid" };
+    println!("Primary table: {}", valid(repair.primary_valid));
+    println!(
+        "Backup table at {:#x}: {}",
+        repair.old_backup_lba,
+        valid(repair.backup_valid)
+    );
+    println!(
+        "Rewrote table with backup at {:#x}, usable sectors {:#x}..{:#x}",
+        repair.gpt.header.alternate_lba,
+        repair.gpt.header.first_usable_lba,
+        repair.gpt.header.last_usable_lba + 1
+    );
+    Ok(())
+}
+
+fn verify_hashes(mut transport: Transport, manifest: &Path) -> Result<()> {
+    let manifest: HashManifest = toml::from_str(&std::fs::read_to_string(manifest)?)?;
+    let plan = manifest.verify(FlashPlan::new())?;
+    let report = transport.run_plan(&plan, |_| {})?;
+    for step in &report.steps {
+        println!("{}: ok", step.description);
+    }
+    Ok(())
+}
+
+fn write_images(mut transport: Transport, images: Vec<(Target, PathBuf)>) -> Result<()> {
+    let plan = images
+        .into_iter()
+        .fold(FlashPlan::new(), |plan, (target, path)| {
+            plan.write(Image::File(path), target)
+        });
+    let report = transport.run_plan(&plan, |p| {
+        if p.done == p.total {
+            println!("Step {}/{} done: {} bytes", p.step + 1, p.steps, p.total);
+        }
+    })?;
+    for step in &report.steps {
+        println!(
+            "{}: {} bytes in {:.2?}",
+            step.description, step.bytes, step.duration
+        );
+    }
+    Ok(())
+}
+
 fn write_file(mut transport: Transport, offset: u32, path: &Path) -> Result<()> {
     let mut file = File::open(path)?;
-    let mut io = transport.io()?;
+    let info = transport.flash_info()?;
+
+    let written = transport.write_lba_from_reader(offset..info.sectors(), &mut file)?;
+    if written < file.metadata()?.len() {
+        return Err(anyhow!("File doesn't fit on the flash"));
+    }
+    println!("{}", transport.stats());
+    Ok(())
+}
+
+// Sectors per command to compare in benchmarks
+//
+// There is no queue depth to compare: The protocol only allows a single command in flight, and
+// queueing the data phase of a command would swallow the command status after a short read
+const BENCH_CHUNKS: [u32; 3] = [8, 32, MAX_LBA_SECTORS as u32];
+
+fn ping(mut transport: Transport, count: Option<u32>, interval: u64) -> Result<()> {
+    let mut failed = 0;
+    let mut seq = 0;
+    while count != Some(seq) {
+        if seq > 0 {
+            sleep(Duration::from_millis(interval));
+        }
+        seq += 1;
+        let start = Instant::now();
+        match transport.test_unit_ready() {
+            Ok(()) => println!("seq={} time={:.2?}", seq, start.elapsed()),
+            Err(e) => {
+                failed += 1;
+                println!("seq={} failed after {:.2?}: {}", seq, start.elapsed(), e);
+            }
+        }
+    }
+    println!("{} commands, {} failed", seq, failed);
+    Ok(())
+}
 
-    io.seek(SeekFrom::Start(offset as u64 * 512))?;
-    std::io::copy(&mut file, &mut io)?;
+fn print_bench(name: &str, sectors_per_command: u32, throughput: f64, stats: &Stats) {
+    println!(
+        "{:<5} {:>3} sectors/command: {:>8.2} MB/s, {:>8.2?} per command",
+        name,
+        sectors_per_command,
+        throughput / 1_000_000.0,
+        stats.busy / stats.operations.max(1) as u32
+    );
+}
+
+fn bench(mut transport: Transport, sectors: u32, scratch: Option<u32>) -> Result<()> {
+    let info = transport.flash_info()?;
+    let sectors = sectors.min(info.sectors());
+    let mut buffer = vec![0; sectors as usize * 512];
+
+    transport.reset_stats();
+    for _ in 0..100 {
+        transport.read_lba(0, &mut buffer[..512])?;
+    }
+    let stats = transport.stats();
+    print_bench("read", 1, stats.read_throughput(), stats);
+
+    for chunk in BENCH_CHUNKS {
+        transport.reset_stats();
+        for sector in (0..sectors).step_by(chunk as usize) {
+            let len = (sectors - sector).min(chunk) as usize * 512;
+            transport.read_lba(sector, &mut buffer[..len])?;
+        }
+        let stats = transport.stats();
+        print_bench("read", chunk, stats.read_throughput(), stats);
+    }
+
+    let Some(scratch) = scratch else {
+        return Ok(());
+    };
+    match scratch.checked_add(sectors) {
+        Some(end) if end <= info.sectors() => (),
+        _ => return Err(anyhow!("Scratch area outside of the flash")),
+    }
+    buffer
+        .iter_mut()
+        .enumerate()
+        .for_each(|(i, b)| *b = i as u8);
+    for chunk in BENCH_CHUNKS {
+        transport.reset_stats();
+        for sector in (0..sectors).step_by(chunk as usize) {
+            let start = sector as usize * 512;
+            let len = (sectors - sector).min(chunk) as usize * 512;
+            transport.write_lba(scratch + sector, &buffer[start..start + len])?;
+        }
+        let stats = transport.stats();
+        print_bench("write", chunk, stats.write_throughput(), stats);
+    }
     Ok(())
 }
 
@@ -122,62 +362,38 @@ fn write_bmap(transport: Transport, path: &Path) -> Result<()> {
     Ok(())
 }
 
-fn download_entry(
-    header: RkBootHeaderEntry,
-    code: u16,
-    file: &mut File,
-    transport: &mut Transport,
-) -> Result<()> {
-    for i in 0..header.count {
-        let mut entry: RkBootEntryBytes = [0; 57];
-
-        file.seek(SeekFrom::Start(
-            header.offset as u64 + (header.size * i) as u64,
-        ))?;
-        file.read_exact(&mut entry)?;
-
-        let entry = RkBootEntry::from_bytes(&entry);
-        println!("{} Name: {}", i, String::from_utf16(entry.name.as_slice())?);
-
-        let mut data = vec![0; entry.data_size as usize];
-
-        file.seek(SeekFrom::Start(entry.data_offset as u64))?;
-        file.read_exact(&mut data)?;
-
-        transport.write_maskrom_area(code, &data)?;
-
-        println!("Done!... waiting {}ms", entry.data_delay);
-        if entry.data_delay > 0 {
-            sleep(Duration::from_millis(entry.data_delay as u64));
-        }
+fn download_boot(mut transport: Transport, path: &Path, require_sign_flag: bool) -> Result<()> {
+    let boot = BootFile::from_bytes(std::fs::read(path)?)
+        .ok_or_else(|| anyhow!("Failed to parse header"))?;
+    if require_sign_flag && !boot.header().is_signed() {
+        return Err(anyhow!(
+            "Refusing to download loader without the signed flag"
+        ));
     }
 
-    Ok(())
-}
-
-fn download_boot(mut transport: Transport, path: &Path) -> Result<()> {
-    let mut file = File::open(path)?;
-    let mut header: RkBootHeaderBytes = [0; 102];
-    file.read_exact(&mut header)?;
-
-    let header =
-        RkBootHeader::from_bytes(&header).ok_or_else(|| anyhow!("Failed to parse header"))?;
-
-    download_entry(header.entry_471, 0x471, &mut file, &mut transport)?;
-    download_entry(header.entry_472, 0x472, &mut file, &mut transport)?;
+    for (kind, entry) in boot.all_entries() {
+        println!("{} Name: {}", kind, entry.name());
+    }
+    transport.download_boot(&boot)?;
+    println!("Done!");
 
     Ok(())
 }
 
-fn run_nbd(transport: Transport) -> Result<()> {
-    let listener = TcpListener::bind("127.0.0.1:10809").unwrap();
+fn run_nbd(mut transport: Transport, listen: &str, readonly: bool, persistent: bool) -> Result<()> {
+    let listener = TcpListener::bind(listen)?;
 
     println!(
         "Listening for nbd connection on: {:?}",
         listener.local_addr()?
     );
 
-    let mut stream = listener
+    if persistent {
+        rockusb::nbd::serve_clients(&mut transport, &listener, readonly)?;
+        return Ok(());
+    }
+
+    let stream = listener
         .incoming()
         .next()
         .transpose()?
@@ -185,23 +401,8 @@ fn run_nbd(transport: Transport) -> Result<()> {
     // Stop listening for new connections
     drop(listener);
 
-    let io = transport.into_io()?;
-
     println!("Connection!");
-
-    nbd::server::handshake(&mut stream, |_s| {
-        Ok(nbd::Export {
-            size: io.size(),
-            readonly: false,
-            resizeable: false,
-            rotational: false,
-            send_trim: false,
-            send_flush: true,
-            data: (),
-        })
-    })?;
-    println!("Shook hands!");
-    nbd::server::transmission(&mut stream, io)?;
+    rockusb::nbd::serve(stream, transport.into_io()?, readonly)?;
     println!("nbd client disconnected");
     Ok(())
 }
@@ -209,8 +410,28 @@ fn run_nbd(transport: Transport) -> Result<()> {
 #[derive(Debug, clap::Parser)]
 enum Command {
     List,
+    /// Print rockchip devices being connected or disconnected
+    Watch,
+    /// Query all connected devices for their identification and flash information
+    Inventory {
+        /// Print the records as JSON
+        #[clap(long)]
+        json: bool,
+    },
+    /// Print shell completions for this tool
+    Completions {
+        #[clap(value_enum)]
+        shell: clap_complete::Shell,
+    },
+    /// Print the man page for this tool
+    Manpage,
     DownloadBoot {
         path: PathBuf,
+        /// Refuse loaders whose header isn't flagged as signed
+        ///
+        /// Only the flag in the loader header is checked, not the signatures themselves
+        #[clap(long)]
+        require_sign_flag: bool,
     },
     Read {
         #[clap(value_parser=maybe_hex::<u32>)]
@@ -219,6 +440,13 @@ enum Command {
         length: u16,
         path: PathBuf,
     },
+    ReadFile {
+        #[clap(value_parser=maybe_hex::<u32>)]
+        offset: u32,
+        #[clap(value_parser=maybe_hex::<u16>)]
+        length: u16,
+        path: PathBuf,
+    },
     Write {
         #[clap(value_parser=maybe_hex::<u32>)]
         offset: u32,
@@ -231,18 +459,73 @@ enum Command {
         offset: u32,
         path: PathBuf,
     },
+    /// Write multiple images in one session, e.g. 0x40=idbloader.img 0x4000=u-boot.itb
+    WriteImages {
+        /// Images as <target>=<file>, where the target is a start sector or a partition name
+        #[clap(value_parser = parse_image, required = true)]
+        images: Vec<(Target, PathBuf)>,
+    },
     WriteBmap {
         path: PathBuf,
     },
+    /// Verify the flash against the sha256 hashes of a TOML hash manifest
+    VerifyHashes {
+        manifest: PathBuf,
+    },
+    /// GUID partition table handling
+    Gpt {
+        #[clap(subcommand)]
+        command: GptCommand,
+    },
     ChipInfo,
     FlashId,
     FlashInfo,
+    /// Print chip, flash, capability and storage information
+    Info,
+    /// Check the loader is alive by repeatedly issuing test unit ready, printing the latency
+    Ping {
+        /// Number of commands to issue; Runs until interrupted if not given
+        #[clap(short, long)]
+        count: Option<u32>,
+        /// Interval between commands in milliseconds
+        #[clap(short, long, default_value = "1000")]
+        interval: u64,
+    },
+    /// Measure command latency and read (and optionally write) throughput
+    Bench {
+        /// Number of sectors to transfer per test
+        #[clap(long, default_value = "0x8000", value_parser=maybe_hex::<u32>)]
+        sectors: u32,
+        /// Also measure writes to a scratch area starting at this sector; Its content is lost
+        #[clap(long, value_parser=maybe_hex::<u32>)]
+        write_scratch: Option<u32>,
+    },
     ResetDevice {
         #[clap(value_enum, default_value_t=ArgResetOpcode::Reset)]
         opcode: ArgResetOpcode,
     },
+    /// Reset to maskrom mode and wait for the device to reappear
+    ResetMaskrom,
     // Run/expose device as a network block device
-    Nbd,
+    Nbd {
+        /// Address to listen on
+        #[clap(long, default_value = "127.0.0.1:10809")]
+        listen: String,
+        /// Export the flash read-only
+        #[clap(long)]
+        readonly: bool,
+        /// Keep serving clients after the first one disconnects
+        #[clap(long)]
+        persistent: bool,
+    },
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum GptCommand {
+    /// Write a partition table described by a TOML layout, replacing any existing table
+    Write { layout: PathBuf },
+    /// Validate the partition table and rewrite it with the backup at the end of the flash
+    Repair,
 }
 
 #[derive(ValueEnum, Clone, Debug)]
@@ -277,6 +560,17 @@ struct DeviceArg {
     address: u8,
 }
 
+fn parse_image(image: &str) -> Result<(Target, PathBuf)> {
+    let (target, path) = image
+        .split_once('=')
+        .ok_or_else(|| anyhow!("Image should be specified as <sector or partition>=<file>"))?;
+    let target = match maybe_hex::<u32>(target) {
+        Ok(sector) => Target::Sector(sector),
+        Err(_) => Target::Partition(target.to_string()),
+    };
+    Ok((target, PathBuf::from(path)))
+}
+
 fn parse_device(device: &str) -> Result<DeviceArg> {
     let mut parts = device.split(':');
     let bus_number = parts
@@ -300,20 +594,54 @@ fn parse_device(device: &str) -> Result<DeviceArg> {
 
 #[derive(clap::Parser)]
 struct Opts {
-    #[arg(short, long, value_parser = parse_device)]
+    #[arg(short, long, value_parser = parse_device, value_name = "BUS:ADDRESS")]
     /// Device type specified as <bus>:<address>
     device: Option<DeviceArg>,
+    /// Detach kernel drivers (e.g. usb-storage) claiming the device
+    #[arg(long)]
+    detach: bool,
+    /// Allow writes and erases touching the partition table or IDBlock
+    #[arg(long)]
+    allow_critical_regions: bool,
+    /// Limit writes to the device to this many bytes per second
+    #[arg(long, value_name = "BYTES")]
+    max_write_rate: Option<u64>,
     #[command(subcommand)]
     command: Command,
 }
 
+fn port_chain(ports: &[u8]) -> String {
+    ports
+        .iter()
+        .map(|p| p.to_string())
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
 fn list_available_devices() -> Result<()> {
     let devices = rockusb::libusb::Devices::new()?;
     println!("Available rockchip devices");
     for d in devices.iter() {
         match d {
-            Ok(mut d) => println!("* {:?}", d.handle().device()),
-            Err(DeviceUnavalable { device, error }) => {
+            Ok(mut d) => match d.port_numbers() {
+                Ok(ports) => println!("* {:?} - Port {}", d.handle().device(), port_chain(&ports)),
+                Err(_) => println!("* {:?}", d.handle().device()),
+            },
+            Err(DeviceUnavalable {
+                device,
+                kind: UnavailableKind::Busy,
+                ..
+            }) => {
+                println!("* {:?} - Busy: in use by another application", device)
+            }
+            Err(DeviceUnavalable {
+                device,
+                kind: UnavailableKind::ClaimedByKernel,
+                ..
+            }) => {
+                println!("* {:?} - Busy: claimed by a kernel driver", device)
+            }
+            Err(DeviceUnavalable { device, error, .. }) => {
                 println!("* {:?} - Unavailable: {}", device, error)
             }
         }
@@ -322,15 +650,113 @@ fn list_available_devices() -> Result<()> {
     Ok(())
 }
 
+// Inventory record of a device; Information the device doesn't provide is null
+fn device_record(mut transport: Transport) -> serde_json::Value {
+    let device = transport.handle().device();
+    let desc = device.device_descriptor().ok();
+    let serial = desc
+        .as_ref()
+        .and_then(|d| transport.handle().read_serial_number_string_ascii(d).ok());
+    let mode = rockusb::libusb::device_mode(&device).ok();
+    // Only the loader implements the information commands
+    let loader = mode == Some(UsbMode::Loader);
+    let chip_info = loader.then(|| transport.chip_info().ok()).flatten();
+    let flash_id = loader.then(|| transport.flash_id().ok()).flatten();
+    let flash_info = loader.then(|| transport.flash_info().ok()).flatten();
+    let capability = loader.then(|| transport.capability().ok()).flatten();
+    serde_json::json!({
+        "bus": transport.bus_number(),
+        "address": transport.address(),
+        "port": transport.port_numbers().ok().map(|p| port_chain(&p)),
+        "vendor_id": desc.as_ref().map(|d| format!("{:04x}", d.vendor_id())),
+        "product_id": desc.as_ref().map(|d| format!("{:04x}", d.product_id())),
+        "serial": serial,
+        "soc": desc.as_ref().and_then(|d| soc_name(d.product_id())),
+        "mode": mode.map(|m| m.to_string()),
+        "chip": chip_info.map(|c| c.to_string()),
+        "flash_id": flash_id.map(|f| f.to_str().into_owned()),
+        "flash_sectors": flash_info.map(|f| f.sectors()),
+        "flash_size": flash_info.map(|f| f.size()),
+        "capability": capability.map(|c| hex(c.inner())),
+    })
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn print_records(records: &[serde_json::Value], json: bool) -> Result<()> {
+    if json {
+        println!("{}", serde_json::to_string_pretty(records)?);
+        return Ok(());
+    }
+    for record in records {
+        println!("*");
+        for (key, value) in record.as_object().into_iter().flatten() {
+            match value {
+                serde_json::Value::Null => (),
+                serde_json::Value::String(s) => println!("  {}: {}", key, s),
+                v => println!("  {}: {}", key, v),
+            }
+        }
+    }
+    Ok(())
+}
+
+fn inventory(detach: bool, json: bool) -> Result<()> {
+    let devices = rockusb::libusb::Devices::new()?.detach_kernel_driver(detach);
+    let records: Vec<_> = devices
+        .iter()
+        .map(|d| match d {
+            Ok(transport) => device_record(transport),
+            Err(DeviceUnavalable { device, error, .. }) => serde_json::json!({
+                "bus": device.bus_number(),
+                "address": device.address(),
+                "error": error.to_string(),
+            }),
+        })
+        .collect();
+    print_records(&records, json)
+}
+
+fn watch_devices() -> Result<()> {
+    let watch = rockusb::libusb::watch_devices()?;
+    println!("Watching for rockchip devices");
+    for event in watch {
+        match event {
+            HotplugEvent::Connected(device) => println!("+ {:?}", device),
+            HotplugEvent::Disconnected(device) => println!("- {:?}", device),
+        }
+    }
+    Ok(())
+}
+
+fn print_completions(shell: clap_complete::Shell) -> Result<()> {
+    let mut command = Opts::command();
+    clap_complete::generate(shell, &mut command, "rockusb", &mut std::io::stdout());
+    Ok(())
+}
+
+fn print_manpage() -> Result<()> {
+    let command = Opts::command().name("rockusb");
+    clap_mangen::Man::new(command).render(&mut std::io::stdout())?;
+    Ok(())
+}
+
 fn main() -> Result<()> {
     let opt = Opts::parse();
 
     // Commands that don't talk a device
-    if matches!(opt.command, Command::List) {
-        return list_available_devices();
+    match opt.command {
+        Command::List => return list_available_devices(),
+        Command::Watch => return watch_devices(),
+        Command::Completions { shell } => return print_completions(shell),
+        Command::Manpage => return print_manpage(),
+        Command::Inventory { json } => return inventory(opt.detach, json),
+        _ => (),
     }
 
-    let devices = rockusb::libusb::Devices::new()?;
+    let devices = rockusb::libusb::Devices::new()?.detach_kernel_driver(opt.detach);
     let mut transport = if let Some(dev) = opt.device {
         devices
             .iter()
@@ -357,23 +783,52 @@ fn main() -> Result<()> {
                 ))
             }
         }?
-    }?;
+    }
+    .map_err(|e| match e.kind {
+        UnavailableKind::ClaimedByKernel => anyhow!(
+            "Device is claimed by a kernel driver; Unmount any volumes on it or use --detach"
+        ),
+        UnavailableKind::Busy => anyhow!("Device is in use by another application"),
+        UnavailableKind::Other => e.into(),
+    })?;
+    if opt.allow_critical_regions {
+        transport.allow_critical_regions();
+    }
+    transport.set_max_write_rate(opt.max_write_rate);
 
     match opt.command {
-        Command::List => unreachable!(),
-        Command::DownloadBoot { path } => download_boot(transport, &path),
+        Command::List
+        | Command::Watch
+        | Command::Inventory { .. }
+        | Command::Completions { .. }
+        | Command::Manpage => unreachable!(),
+        Command::DownloadBoot {
+            path,
+            require_sign_flag,
+        } => download_boot(transport, &path, require_sign_flag),
         Command::Read {
             offset,
             length,
             path,
         } => read_lba(transport, offset, length, &path),
+        Command::ReadFile {
+            offset,
+            length,
+            path,
+        } => read_file(transport, offset, length, &path),
         Command::Write {
             offset,
             length,
             path,
         } => write_lba(transport, offset, length, &path),
         Command::WriteFile { offset, path } => write_file(transport, offset, &path),
+        Command::WriteImages { images } => write_images(transport, images),
         Command::WriteBmap { path } => write_bmap(transport, &path),
+        Command::VerifyHashes { manifest } => verify_hashes(transport, &manifest),
+        Command::Gpt { command } => match command {
+            GptCommand::Write { layout } => write_gpt(transport, &layout),
+            GptCommand::Repair => repair_gpt(transport),
+        },
         Command::ChipInfo => read_chip_info(transport),
         Command::FlashId => {
             let id = transport.flash_id()?;
@@ -382,7 +837,18 @@ fn main() -> Result<()> {
             Ok(())
         }
         Command::FlashInfo => read_flash_info(transport),
+        Command::Info => print_info(transport),
+        Command::Ping { count, interval } => ping(transport, count, interval),
+        Command::Bench {
+            sectors,
+            write_scratch,
+        } => bench(transport, sectors, write_scratch),
         Command::ResetDevice { opcode } => reset_device(transport, opcode.into()),
-        Command::Nbd => run_nbd(transport),
+        Command::ResetMaskrom => reset_maskrom(transport),
+        Command::Nbd {
+            listen,
+            readonly,
+            persistent,
+        } => run_nbd(transport, &listen, readonly, persistent),
     }
 }
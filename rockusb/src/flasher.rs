@@ -0,0 +1,561 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use thiserror::Error;
+
+use crate::gpt::{Gpt, GptError};
+use crate::protocol::{ResetOpcode, SECTOR_SIZE};
+
+#[derive(Debug, Error)]
+pub enum FlashError {
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("Partition table error: {0}")]
+    GptError(#[from] GptError),
+    #[error("Unknown partition: {0}")]
+    UnknownPartition(String),
+    #[error("Image of {size} bytes doesn't fit in {available} bytes")]
+    ImageTooLarge { size: u64, available: u64 },
+    #[error("Verification failed: sector {0:#x} differs from the image")]
+    VerifyMismatch(u32),
+    #[error("Verification failed: sha256 {actual} doesn't match the expected {expected}")]
+    HashMismatch { expected: String, actual: String },
+    #[error("Step {step} ({description}) failed: {source}")]
+    Step {
+        step: usize,
+        description: String,
+        #[source]
+        source: Box<FlashError>,
+    },
+}
+
+/// Location on the flash to write an image to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    /// Absolute start sector
+    Sector(u32),
+    /// Start of a partition in the GPT of the flash
+    Partition(String),
+}
+
+impl Target {
+    /// Resolve into the range of sectors available at the target on a flash of `flash_sectors`
+    /// sectors
+    pub fn resolve(&self, flash_sectors: u32, gpt: Option<&Gpt>) -> Result<Range<u32>, FlashError> {
+        match self {
+            Target::Sector(sector) => Ok(*sector..flash_sectors.max(*sector)),
+            Target::Partition(name) => gpt
+                .and_then(|gpt| gpt.partitions.iter().find(|p| &p.name == name))
+                .map(|p| p.first_lba as u32..p.last_lba as u32 + 1)
+                .ok_or_else(|| FlashError::UnknownPartition(name.clone())),
+        }
+    }
+}
+
+impl std::fmt::Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Target::Sector(sector) => write!(f, "sector {:#x}", sector),
+            Target::Partition(name) => write!(f, "partition {}", name),
+        }
+    }
+}
+
+/// Image data to write to the flash
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Image {
+    File(PathBuf),
+    /// Gzip compressed file, decompressed while flashing
+    #[cfg(feature = "gzip")]
+    GzipFile(PathBuf),
+    Data(Vec<u8>),
+}
+
+impl Image {
+    /// Open the image for reading; Returns a reader and the size of the image
+    pub fn open(&self) -> std::io::Result<(Box<dyn Read + Send + '_>, u64)> {
+        match self {
+            Image::File(path) => {
+                let file = std::fs::File::open(path)?;
+                let len = file.metadata()?.len();
+                Ok((Box::new(file), len))
+            }
+            #[cfg(feature = "gzip")]
+            Image::GzipFile(path) => {
+                // The gzip trailer only stores the size modulo 4GiB, so decompress once to
+                // determine the real size
+                let file = std::fs::File::open(path)?;
+                let len = std::io::copy(
+                    &mut flate2::read::MultiGzDecoder::new(std::io::BufReader::new(file)),
+                    &mut std::io::sink(),
+                )?;
+                let file = std::fs::File::open(path)?;
+                let decoder = flate2::read::MultiGzDecoder::new(std::io::BufReader::new(file));
+                Ok((Box::new(decoder), len))
+            }
+            Image::Data(data) => Ok((Box::new(data.as_slice()), data.len() as u64)),
+        }
+    }
+}
+
+impl std::fmt::Display for Image {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Image::File(path) => write!(f, "{}", path.display()),
+            #[cfg(feature = "gzip")]
+            Image::GzipFile(path) => write!(f, "{} (gzip)", path.display()),
+            Image::Data(data) => write!(f, "<{} bytes>", data.len()),
+        }
+    }
+}
+
+/// Single step of a [FlashPlan]
+#[derive(Debug, Clone)]
+pub enum FlashStep {
+    /// Erase a range of sectors
+    Erase { sectors: Range<u32> },
+    /// Write an image to a target
+    Write { image: Image, target: Target },
+    /// Write a partition table, keeping the boot code in the protective MBR
+    WriteGpt(Box<Gpt>),
+    /// Read back the flash at a target and compare it with an image
+    Verify { image: Image, target: Target },
+    /// Read back `len` bytes of the flash at a target and compare their sha256 with a known hash,
+    /// e.g. as produced at build time
+    VerifyHash {
+        target: Target,
+        len: u64,
+        sha256: [u8; 32],
+    },
+    /// Reset the device
+    Reset(ResetOpcode),
+}
+
+impl FlashStep {
+    /// Whether the step only reads back and checks the flash
+    pub fn is_verify(&self) -> bool {
+        matches!(
+            self,
+            FlashStep::Verify { .. } | FlashStep::VerifyHash { .. }
+        )
+    }
+}
+
+impl std::fmt::Display for FlashStep {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FlashStep::Erase { sectors } => {
+                write!(f, "erase sectors {:#x}..{:#x}", sectors.start, sectors.end)
+            }
+            FlashStep::Write { image, target } => write!(f, "write {} to {}", image, target),
+            FlashStep::WriteGpt(_) => write!(f, "write partition table"),
+            FlashStep::Verify { image, target } => write!(f, "verify {} at {}", image, target),
+            FlashStep::VerifyHash {
+                target,
+                len,
+                sha256,
+            } => write!(
+                f,
+                "verify {} bytes at {} against sha256 {}",
+                len,
+                target,
+                hex(sha256)
+            ),
+            FlashStep::Reset(opcode) => write!(f, "reset device ({:?})", opcode),
+        }
+    }
+}
+
+/// Sequence of steps to provision a device
+///
+/// A plan is executed by a transport, e.g. using `Transport::run_plan`, stopping at the first
+/// step that fails. Data read back by consecutive verification steps is compared on worker
+/// threads while the next region is read from the device; Verification failures are reported
+/// before any later step that isn't a verification is executed
+#[derive(Debug, Clone, Default)]
+pub struct FlashPlan {
+    pub steps: Vec<FlashStep>,
+    /// Erase the sectors of images right before writing them
+    pub erase_before_write: bool,
+}
+
+impl FlashPlan {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Erase the target sectors of each chunk of an image right before writing it
+    ///
+    /// Useful for NAND and SPI flash where writing over data that wasn't erased may fail or wear
+    /// the flash more
+    pub fn erase_before_write(mut self, erase: bool) -> Self {
+        self.erase_before_write = erase;
+        self
+    }
+
+    /// Add a step erasing a range of sectors
+    pub fn erase(mut self, sectors: Range<u32>) -> Self {
+        self.steps.push(FlashStep::Erase { sectors });
+        self
+    }
+
+    /// Add a step writing an image to a target
+    pub fn write(mut self, image: Image, target: Target) -> Self {
+        self.steps.push(FlashStep::Write { image, target });
+        self
+    }
+
+    /// Add a step writing a partition table
+    pub fn write_gpt(mut self, gpt: Gpt) -> Self {
+        self.steps.push(FlashStep::WriteGpt(Box::new(gpt)));
+        self
+    }
+
+    /// Add a step verifying an image at a target
+    pub fn verify(mut self, image: Image, target: Target) -> Self {
+        self.steps.push(FlashStep::Verify { image, target });
+        self
+    }
+
+    /// Add a step verifying `len` bytes at a target against their sha256
+    pub fn verify_hash(mut self, target: Target, len: u64, sha256: [u8; 32]) -> Self {
+        self.steps.push(FlashStep::VerifyHash {
+            target,
+            len,
+            sha256,
+        });
+        self
+    }
+
+    /// Add a step resetting the device
+    pub fn reset(mut self, opcode: ResetOpcode) -> Self {
+        self.steps.push(FlashStep::Reset(opcode));
+        self
+    }
+
+    /// Checksum of the description of all steps, identifying the plan in a [Journal]
+    pub fn checksum(&self) -> u64 {
+        let mut digest = CRC64.digest();
+        for step in &self.steps {
+            digest.update(step.to_string().as_bytes());
+            digest.update(b"\n");
+        }
+        digest.finalize()
+    }
+}
+
+/// Progress of executing a [FlashPlan]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    /// Index of the current step
+    pub step: usize,
+    /// Total number of steps
+    pub steps: usize,
+    /// Bytes processed in the current step
+    pub done: u64,
+    /// Total bytes to process in the current step
+    pub total: u64,
+}
+
+/// Result of a single executed step
+#[derive(Debug, Clone)]
+pub struct StepResult {
+    /// Index of the step in the plan
+    pub step: usize,
+    pub description: String,
+    /// Bytes written, erased or verified
+    pub bytes: u64,
+    pub duration: Duration,
+}
+
+/// Results of executing a [FlashPlan]
+#[derive(Debug, Clone, Default)]
+pub struct FlashReport {
+    pub steps: Vec<StepResult>,
+}
+
+impl FlashReport {
+    /// Total bytes processed by all steps
+    pub fn bytes(&self) -> u64 {
+        self.steps.iter().map(|s| s.bytes).sum()
+    }
+
+    /// Total time spent executing all steps
+    pub fn duration(&self) -> Duration {
+        self.steps.iter().map(|s| s.duration).sum()
+    }
+}
+
+const CRC64: crc::Crc<u64> = crc::Crc::<u64>::new(&crc::CRC_64_XZ);
+const JOURNAL_MAGIC: &str = "rockusb-journal";
+
+/// Amount of image data written between journal updates
+pub const JOURNAL_CHUNK: usize = 16 * 1024 * 1024;
+
+/// Running checksum of the image data written by a step
+pub type JournalChecksum = crc::Digest<'static, u64>;
+
+/// Progress of a single step recorded in a [Journal]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JournalEntry {
+    /// Bytes of the image written
+    pub done: u64,
+    /// Checksum of the first `done` bytes of the image
+    pub checksum: u64,
+    /// Whether the step completed
+    pub complete: bool,
+}
+
+/// Persistent journal of the progress of executing a [FlashPlan]
+///
+/// The journal is an append-only text file; Each line records the progress of a step, with later
+/// lines superseding earlier ones. When flashing gets interrupted, running the same plan with the
+/// same journal skips completed steps and resumes writing images from the last recorded chunk,
+/// after checking the image data up to that point is unchanged.
+#[derive(Debug)]
+pub struct Journal {
+    file: File,
+    entries: HashMap<usize, JournalEntry>,
+}
+
+impl Journal {
+    /// Open the journal at `path` for a plan
+    ///
+    /// If the journal doesn't exist or was recorded for a different plan a new journal is started
+    pub fn open<P: AsRef<Path>>(path: P, plan: &FlashPlan) -> std::io::Result<Self> {
+        let path = path.as_ref();
+        let header = format!("{} {:016x}", JOURNAL_MAGIC, plan.checksum());
+        let mut entries = HashMap::new();
+        let mut valid = false;
+        if let Ok(file) = File::open(path) {
+            let mut lines = BufReader::new(file).lines();
+            if let Some(Ok(first)) = lines.next() {
+                valid = first == header;
+            }
+            if valid {
+                // A partially written last line is simply ignored
+                for line in lines.map_while(Result::ok) {
+                    if let Some((step, entry)) = Self::parse_line(&line) {
+                        entries.insert(step, entry);
+                    }
+                }
+            }
+        }
+
+        let file = if valid {
+            std::fs::OpenOptions::new().append(true).open(path)?
+        } else {
+            let mut file = File::create(path)?;
+            writeln!(file, "{}", header)?;
+            file.sync_data()?;
+            file
+        };
+        Ok(Self { file, entries })
+    }
+
+    fn parse_line(line: &str) -> Option<(usize, JournalEntry)> {
+        let mut fields = line.split(' ');
+        let step = fields.next()?.parse().ok()?;
+        let done = fields.next()?.parse().ok()?;
+        let checksum = u64::from_str_radix(fields.next()?, 16).ok()?;
+        let complete = match fields.next()? {
+            "complete" => true,
+            "partial" => false,
+            _ => return None,
+        };
+        fields.next().is_none().then_some((
+            step,
+            JournalEntry {
+                done,
+                checksum,
+                complete,
+            },
+        ))
+    }
+
+    /// Recorded progress of a step
+    pub fn entry(&self, step: usize) -> Option<JournalEntry> {
+        self.entries.get(&step).copied()
+    }
+
+    /// Record the progress of a step, syncing it to disk
+    pub fn record(&mut self, step: usize, entry: JournalEntry) -> std::io::Result<()> {
+        writeln!(
+            self.file,
+            "{} {} {:016x} {}",
+            step,
+            entry.done,
+            entry.checksum,
+            if entry.complete {
+                "complete"
+            } else {
+                "partial"
+            }
+        )?;
+        self.file.sync_data()?;
+        self.entries.insert(step, entry);
+        Ok(())
+    }
+
+    /// Record a step as complete, keeping its recorded progress
+    pub fn complete(&mut self, step: usize) -> std::io::Result<()> {
+        let mut entry = self.entry(step).unwrap_or(JournalEntry {
+            done: 0,
+            checksum: 0,
+            complete: false,
+        });
+        entry.complete = true;
+        self.record(step, entry)
+    }
+
+    /// Whether a step was recorded as complete
+    pub fn is_complete(&self, step: usize) -> bool {
+        self.entry(step).is_some_and(|e| e.complete)
+    }
+
+    /// Skip the part of an image written by an interrupted step
+    ///
+    /// Reads the data written according to the journal from the image, returning the number of
+    /// bytes skipped and the checksum so far if the data matches. Returns `None` if there is
+    /// nothing to resume or the image changed, in which case the image has to be reopened
+    pub fn resume<R: Read + ?Sized>(
+        &self,
+        step: usize,
+        reader: &mut R,
+        len: u64,
+    ) -> std::io::Result<Option<(u64, JournalChecksum)>> {
+        let Some(entry) = self.entry(step) else {
+            return Ok(None);
+        };
+        if entry.done == 0 || entry.done > len || entry.done % SECTOR_SIZE != 0 {
+            return Ok(None);
+        }
+        let mut checksum = journal_checksum();
+        let mut buffer = vec![0; 64 * 1024];
+        let mut left = entry.done;
+        while left > 0 {
+            let chunk = left.min(buffer.len() as u64) as usize;
+            reader.read_exact(&mut buffer[..chunk])?;
+            checksum.update(&buffer[..chunk]);
+            left -= chunk as u64;
+        }
+        Ok((checksum.clone().finalize() == entry.checksum).then_some((entry.done, checksum)))
+    }
+}
+
+/// Start a new checksum for journaling a write
+pub fn journal_checksum() -> JournalChecksum {
+    CRC64.digest()
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::gpt::{GptHeader, GptPartition, Guid};
+
+    #[test]
+    fn resolve() {
+        let header = GptHeader {
+            revision: 0x10000,
+            header_size: 92,
+            header_crc32: 0,
+            my_lba: 1,
+            alternate_lba: 0xffff,
+            first_usable_lba: 34,
+            last_usable_lba: 0xffde,
+            disk_guid: Guid::default(),
+            partition_entry_lba: 2,
+            num_partition_entries: 128,
+            partition_entry_size: 128,
+            partition_entries_crc32: 0,
+        };
+        let gpt = Gpt {
+            header,
+            backup: None,
+            partitions: vec![GptPartition {
+                type_guid: Guid([1; 16]),
+                unique_guid: Guid([2; 16]),
+                first_lba: 0x8000,
+                last_lba: 0xbfff,
+                attributes: 0,
+                name: "boot".to_string(),
+            }],
+        };
+
+        assert_eq!(
+            Target::Partition("boot".to_string())
+                .resolve(0x10000, Some(&gpt))
+                .unwrap(),
+            0x8000..0xc000
+        );
+        assert!(matches!(
+            Target::Partition("rootfs".to_string()).resolve(0x10000, Some(&gpt)),
+            Err(FlashError::UnknownPartition(_))
+        ));
+        assert_eq!(
+            Target::Sector(0x40).resolve(0x10000, None).unwrap(),
+            0x40..0x10000
+        );
+    }
+
+    #[test]
+    fn journal() {
+        let path = std::env::temp_dir().join(format!("rockusb-journal-{}", std::process::id()));
+        let data = vec![0x5a; 4096];
+        let plan = FlashPlan::new()
+            .write(Image::Data(data.clone()), Target::Sector(0x40))
+            .reset(ResetOpcode::Reset);
+
+        let mut journal = Journal::open(&path, &plan).unwrap();
+        assert_eq!(journal.entry(0), None);
+        let mut checksum = journal_checksum();
+        checksum.update(&data[..1024]);
+        let entry = JournalEntry {
+            done: 1024,
+            checksum: checksum.finalize(),
+            complete: false,
+        };
+        journal.record(0, entry).unwrap();
+        journal
+            .record(
+                1,
+                JournalEntry {
+                    done: 0,
+                    checksum: 0,
+                    complete: true,
+                },
+            )
+            .unwrap();
+        drop(journal);
+
+        let journal = Journal::open(&path, &plan).unwrap();
+        assert_eq!(journal.entry(0), Some(entry));
+        assert!(journal.is_complete(1));
+        let (done, _) = journal
+            .resume(0, &mut data.as_slice(), data.len() as u64)
+            .unwrap()
+            .unwrap();
+        assert_eq!(done, 1024);
+        let changed = vec![0xa5; 4096];
+        assert!(journal
+            .resume(0, &mut changed.as_slice(), changed.len() as u64)
+            .unwrap()
+            .is_none());
+        drop(journal);
+
+        // A journal of another plan is discarded
+        let other = plan.clone().reset(ResetOpcode::Maskrom);
+        let journal = Journal::open(&path, &other).unwrap();
+        assert_eq!(journal.entry(0), None);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,767 @@
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use bytes::{Buf, BufMut};
+use thiserror::Error;
+
+use crate::layout::{GPT_PRIMARY_ENTRIES, GPT_PRIMARY_HEADER};
+use crate::protocol::SECTOR_SIZE;
+
+const CRC32: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
+
+/// GPT header signature
+pub const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+/// Minimal size of a GPT header
+pub const GPT_HEADER_SIZE: u32 = 92;
+/// Size of a GPT partition entry
+pub const GPT_ENTRY_SIZE: u32 = 128;
+/// Largest partition entries array accepted when parsing a header
+pub const GPT_MAX_ENTRIES_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Errors when reading or parsing a GPT
+#[derive(Debug, Error)]
+pub enum GptError {
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("Missing protective MBR")]
+    InvalidProtectiveMbr,
+    #[error("Invalid GPT header signature: {0:x?}")]
+    InvalidSignature([u8; 8]),
+    #[error("Invalid GPT header size: {0}")]
+    InvalidHeaderSize(u32),
+    #[error("GPT header CRC mismatch: stored {stored:#x} calculated {calculated:#x}")]
+    HeaderCrcMismatch { stored: u32, calculated: u32 },
+    #[error("Invalid GPT partition entry size: {0}")]
+    InvalidEntrySize(u32),
+    #[error("GPT partition entries array of {entries} entries of {entry_size} bytes is too large")]
+    EntriesTooLarge { entries: u32, entry_size: u32 },
+    #[error("GPT partition entries truncated: {actual} of {expected} bytes")]
+    EntriesTruncated { expected: usize, actual: usize },
+    #[error("GPT partition entries CRC mismatch: stored {stored:#x} calculated {calculated:#x}")]
+    EntriesCrcMismatch { stored: u32, calculated: u32 },
+    #[error("Partition table doesn't fit in {0} sectors")]
+    TooSmall(u64),
+    #[error(
+        "Partition {name} ({first_lba:#x}..={last_lba:#x}) is outside the usable sectors \
+         {first_usable_lba:#x}..={last_usable_lba:#x}"
+    )]
+    PartitionOutOfRange {
+        name: String,
+        first_lba: u64,
+        last_lba: u64,
+        first_usable_lba: u64,
+        last_usable_lba: u64,
+    },
+    #[error("Invalid GUID: {0}")]
+    InvalidGuid(String),
+}
+
+/// GUID as used in a GPT
+///
+/// Stored in the on-disk mixed-endian format
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    /// All-zero GUID, used for unused partition entries
+    pub const UNUSED: Guid = Guid([0; 16]);
+
+    pub fn is_unused(&self) -> bool {
+        *self == Self::UNUSED
+    }
+
+    /// Random (version 4) GUID
+    pub fn random() -> Guid {
+        let mut g = [0u8; 16];
+        fastrand::fill(&mut g);
+        // Version is in the high nibble of the mixed-endian third group, variant in the fourth
+        g[7] = (g[7] & 0x0f) | 0x40;
+        g[8] = (g[8] & 0x3f) | 0x80;
+        Guid(g)
+    }
+}
+
+impl std::str::FromStr for Guid {
+    type Err = GptError;
+
+    /// Parse a GUID in the usual textual form, e.g. `0FC63DAF-8483-4772-8E79-3D69D8477DE4`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || GptError::InvalidGuid(s.to_string());
+        let groups: Vec<&str> = s.split('-').collect();
+        if groups.iter().map(|g| g.len()).ne([8, 4, 4, 4, 12])
+            || !s.bytes().all(|b| b == b'-' || b.is_ascii_hexdigit())
+        {
+            return Err(invalid());
+        }
+        let hex: String = groups.concat();
+        let mut b = [0u8; 16];
+        for (i, byte) in b.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| invalid())?;
+        }
+        // The first three groups are stored little endian
+        b[0..4].reverse();
+        b[4..6].reverse();
+        b[6..8].reverse();
+        Ok(Guid(b))
+    }
+}
+
+impl std::fmt::Display for Guid {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let g = &self.0;
+        write!(
+            f,
+            "{:02X}{:02X}{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}{:02X}{:02X}{:02X}{:02X}",
+            g[3], g[2], g[1], g[0], g[5], g[4], g[7], g[6], g[8], g[9], g[10], g[11], g[12], g[13],
+            g[14], g[15]
+        )
+    }
+}
+
+/// GPT header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GptHeader {
+    pub revision: u32,
+    pub header_size: u32,
+    pub header_crc32: u32,
+    /// LBA of this header
+    pub my_lba: u64,
+    /// LBA of the other (primary or backup) header
+    pub alternate_lba: u64,
+    pub first_usable_lba: u64,
+    pub last_usable_lba: u64,
+    pub disk_guid: Guid,
+    /// Start of the partition entries array
+    pub partition_entry_lba: u64,
+    pub num_partition_entries: u32,
+    pub partition_entry_size: u32,
+    pub partition_entries_crc32: u32,
+}
+
+impl GptHeader {
+    /// Parse and validate a GPT header from a sector
+    pub fn from_bytes(bytes: &[u8]) -> Result<GptHeader, GptError> {
+        if bytes.len() < GPT_HEADER_SIZE as usize {
+            return Err(GptError::InvalidHeaderSize(bytes.len() as u32));
+        }
+        let mut b = bytes;
+        let mut signature = [0u8; 8];
+        b.copy_to_slice(&mut signature);
+        if &signature != GPT_SIGNATURE {
+            return Err(GptError::InvalidSignature(signature));
+        }
+        let revision = b.get_u32_le();
+        let header_size = b.get_u32_le();
+        if header_size < GPT_HEADER_SIZE || header_size as usize > bytes.len() {
+            return Err(GptError::InvalidHeaderSize(header_size));
+        }
+        let header_crc32 = b.get_u32_le();
+        let _reserved = b.get_u32_le();
+        let my_lba = b.get_u64_le();
+        let alternate_lba = b.get_u64_le();
+        let first_usable_lba = b.get_u64_le();
+        let last_usable_lba = b.get_u64_le();
+        let mut disk_guid = Guid::default();
+        b.copy_to_slice(&mut disk_guid.0);
+        let partition_entry_lba = b.get_u64_le();
+        let num_partition_entries = b.get_u32_le();
+        let partition_entry_size = b.get_u32_le();
+        let partition_entries_crc32 = b.get_u32_le();
+
+        // CRC is calculated over the header with the crc field zeroed
+        let mut digest = CRC32.digest();
+        digest.update(&bytes[0..16]);
+        digest.update(&[0; 4]);
+        digest.update(&bytes[20..header_size as usize]);
+        let calculated = digest.finalize();
+        if calculated != header_crc32 {
+            return Err(GptError::HeaderCrcMismatch {
+                stored: header_crc32,
+                calculated,
+            });
+        }
+
+        if partition_entry_size < GPT_ENTRY_SIZE || !partition_entry_size.is_power_of_two() {
+            return Err(GptError::InvalidEntrySize(partition_entry_size));
+        }
+        if num_partition_entries as u64 * partition_entry_size as u64 > GPT_MAX_ENTRIES_SIZE {
+            return Err(GptError::EntriesTooLarge {
+                entries: num_partition_entries,
+                entry_size: partition_entry_size,
+            });
+        }
+
+        Ok(GptHeader {
+            revision,
+            header_size,
+            header_crc32,
+            my_lba,
+            alternate_lba,
+            first_usable_lba,
+            last_usable_lba,
+            disk_guid,
+            partition_entry_lba,
+            num_partition_entries,
+            partition_entry_size,
+            partition_entries_crc32,
+        })
+    }
+
+    /// Serialize the header into a sector
+    ///
+    /// The stored CRC fields are used as-is, see [GptHeader::update_crc]
+    pub fn to_bytes(&self) -> [u8; SECTOR_SIZE as usize] {
+        let mut bytes = [0u8; SECTOR_SIZE as usize];
+        let mut b = &mut bytes[..];
+        b.put_slice(GPT_SIGNATURE);
+        b.put_u32_le(self.revision);
+        b.put_u32_le(self.header_size);
+        b.put_u32_le(self.header_crc32);
+        b.put_u32_le(0);
+        b.put_u64_le(self.my_lba);
+        b.put_u64_le(self.alternate_lba);
+        b.put_u64_le(self.first_usable_lba);
+        b.put_u64_le(self.last_usable_lba);
+        b.put_slice(&self.disk_guid.0);
+        b.put_u64_le(self.partition_entry_lba);
+        b.put_u32_le(self.num_partition_entries);
+        b.put_u32_le(self.partition_entry_size);
+        b.put_u32_le(self.partition_entries_crc32);
+        bytes
+    }
+
+    /// Recalculate the header CRC
+    pub fn update_crc(&mut self) {
+        self.header_crc32 = 0;
+        let bytes = self.to_bytes();
+        self.header_crc32 = CRC32.checksum(&bytes[..self.header_size as usize]);
+    }
+
+    /// Size in bytes of the partition entries array
+    pub fn entries_size(&self) -> usize {
+        self.num_partition_entries as usize * self.partition_entry_size as usize
+    }
+
+    /// Size in sectors of the partition entries array
+    pub fn entries_sectors(&self) -> u64 {
+        (self.entries_size() as u64).div_ceil(SECTOR_SIZE)
+    }
+
+    /// Parse and validate the partition entries array belonging to this header
+    ///
+    /// Unused entries are skipped
+    pub fn parse_entries(&self, bytes: &[u8]) -> Result<Vec<GptPartition>, GptError> {
+        let bytes = bytes
+            .get(..self.entries_size())
+            .ok_or(GptError::EntriesTruncated {
+                expected: self.entries_size(),
+                actual: bytes.len(),
+            })?;
+        let calculated = CRC32.checksum(bytes);
+        if calculated != self.partition_entries_crc32 {
+            return Err(GptError::EntriesCrcMismatch {
+                stored: self.partition_entries_crc32,
+                calculated,
+            });
+        }
+        Ok(bytes
+            .chunks_exact(self.partition_entry_size as usize)
+            .map(GptPartition::from_bytes)
+            .filter(|p| !p.type_guid.is_unused())
+            .collect())
+    }
+}
+
+/// Partition entry in a GPT
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GptPartition {
+    pub type_guid: Guid,
+    pub unique_guid: Guid,
+    pub first_lba: u64,
+    /// Last LBA of the partition (inclusive)
+    pub last_lba: u64,
+    pub attributes: u64,
+    pub name: String,
+}
+
+impl GptPartition {
+    /// Parse a partition entry
+    pub fn from_bytes(mut bytes: &[u8]) -> GptPartition {
+        let mut type_guid = Guid::default();
+        bytes.copy_to_slice(&mut type_guid.0);
+        let mut unique_guid = Guid::default();
+        bytes.copy_to_slice(&mut unique_guid.0);
+        let first_lba = bytes.get_u64_le();
+        let last_lba = bytes.get_u64_le();
+        let attributes = bytes.get_u64_le();
+        let name: Vec<u16> = (0..36)
+            .map(|_| bytes.get_u16_le())
+            .take_while(|c| *c != 0)
+            .collect();
+        GptPartition {
+            type_guid,
+            unique_guid,
+            first_lba,
+            last_lba,
+            attributes,
+            name: String::from_utf16_lossy(&name),
+        }
+    }
+
+    /// Size of the partition in sectors
+    pub fn sectors(&self) -> u64 {
+        self.last_lba.saturating_sub(self.first_lba) + 1
+    }
+
+    /// Serialize the partition into an entry of `entry_size` bytes
+    pub fn to_bytes(&self, entry_size: usize) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(entry_size);
+        bytes.put_slice(&self.type_guid.0);
+        bytes.put_slice(&self.unique_guid.0);
+        bytes.put_u64_le(self.first_lba);
+        bytes.put_u64_le(self.last_lba);
+        bytes.put_u64_le(self.attributes);
+        for c in self.name.encode_utf16().take(36) {
+            bytes.put_u16_le(c);
+        }
+        bytes.resize(entry_size, 0);
+        bytes
+    }
+}
+
+/// Parsed GUID partition table
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Gpt {
+    /// Primary header; If the primary header was corrupted this is the backup header
+    pub header: GptHeader,
+    /// Backup header if it was found and is valid
+    pub backup: Option<GptHeader>,
+    /// Used partitions
+    pub partitions: Vec<GptPartition>,
+}
+
+impl Gpt {
+    /// Empty table with the default 128 partition entries
+    ///
+    /// The table has to be laid out for a device with [Gpt::relocate] before it can be written
+    pub fn new(disk_guid: Guid) -> Gpt {
+        let header = GptHeader {
+            revision: 0x10000,
+            header_size: GPT_HEADER_SIZE,
+            header_crc32: 0,
+            my_lba: GPT_PRIMARY_HEADER as u64,
+            alternate_lba: 0,
+            first_usable_lba: 0,
+            last_usable_lba: 0,
+            disk_guid,
+            partition_entry_lba: GPT_PRIMARY_ENTRIES as u64,
+            num_partition_entries: 128,
+            partition_entry_size: GPT_ENTRY_SIZE,
+            partition_entries_crc32: 0,
+        };
+        Gpt {
+            header,
+            backup: None,
+            partitions: Vec::new(),
+        }
+    }
+
+    /// Serialize the partition entries array
+    pub fn entries_to_bytes(&self) -> Vec<u8> {
+        let entry_size = self.header.partition_entry_size as usize;
+        let mut bytes: Vec<u8> = self
+            .partitions
+            .iter()
+            .flat_map(|p| p.to_bytes(entry_size))
+            .collect();
+        bytes.resize(self.header.entries_size(), 0);
+        bytes
+    }
+
+    /// Lay out the table for a device of `sectors` sectors
+    ///
+    /// The primary header and entries are placed at the start of the device, the backup entries
+    /// and header at the very end. The usable area and all CRCs are updated accordingly. Fails if
+    /// any partition doesn't fit in the resulting usable area
+    pub fn relocate(&mut self, sectors: u64) -> Result<(), GptError> {
+        let entries_sectors = self.header.entries_sectors();
+        if self.partitions.len() > self.header.num_partition_entries as usize
+            || sectors < 3 + 2 * entries_sectors
+        {
+            return Err(GptError::TooSmall(sectors));
+        }
+        let first_usable_lba = self
+            .header
+            .first_usable_lba
+            .max(GPT_PRIMARY_ENTRIES as u64 + entries_sectors);
+        let last_usable_lba = sectors - 2 - entries_sectors;
+        if let Some(p) = self.partitions.iter().find(|p| {
+            p.first_lba < first_usable_lba
+                || p.last_lba > last_usable_lba
+                || p.first_lba > p.last_lba
+        }) {
+            return Err(GptError::PartitionOutOfRange {
+                name: p.name.clone(),
+                first_lba: p.first_lba,
+                last_lba: p.last_lba,
+                first_usable_lba,
+                last_usable_lba,
+            });
+        }
+
+        let entries_crc32 = CRC32.checksum(&self.entries_to_bytes());
+        let header = &mut self.header;
+        header.my_lba = GPT_PRIMARY_HEADER as u64;
+        header.alternate_lba = sectors - 1;
+        header.partition_entry_lba = GPT_PRIMARY_ENTRIES as u64;
+        header.first_usable_lba = first_usable_lba;
+        header.last_usable_lba = last_usable_lba;
+        header.partition_entries_crc32 = entries_crc32;
+        header.update_crc();
+
+        let mut backup = self.header.clone();
+        backup.my_lba = sectors - 1;
+        backup.alternate_lba = 1;
+        backup.partition_entry_lba = sectors - 1 - entries_sectors;
+        backup.update_crc();
+        self.backup = Some(backup);
+        Ok(())
+    }
+
+    /// Grow the last partition up to the end of the usable area
+    ///
+    /// Should be called after [Gpt::relocate]; Fails like it if a partition lies outside the
+    /// usable area
+    pub fn grow_last_partition(&mut self) -> Result<(), GptError> {
+        let last_usable_lba = self.header.last_usable_lba;
+        if let Some(last) = self.partitions.iter_mut().max_by_key(|p| p.last_lba) {
+            last.last_lba = last.last_lba.max(last_usable_lba);
+        }
+        self.relocate(self.header.alternate_lba + 1)
+    }
+
+    /// Sectors to write to store the table on a device, as pairs of LBA and data
+    ///
+    /// `mbr` is the current first sector of the device; Its partition table is replaced by a
+    /// protective one while any boot code is kept. Panics if the table wasn't laid out with
+    /// [Gpt::relocate]
+    pub fn to_sectors(&self, mbr: &[u8]) -> Vec<(u64, Vec<u8>)> {
+        let backup = self
+            .backup
+            .as_ref()
+            .expect("Table should be relocated before writing");
+        let entries = self.entries_to_bytes();
+
+        let mut mbr = mbr[..SECTOR_SIZE as usize].to_vec();
+        mbr[446..510].fill(0);
+        let mut p = &mut mbr[446..462];
+        // Not bootable, CHS start 0/0/2, protective type, CHS end maxed out
+        p.put_slice(&[0x00, 0x00, 0x02, 0x00, 0xee, 0xff, 0xff, 0xff]);
+        p.put_u32_le(1);
+        p.put_u32_le(backup.my_lba.min(u32::MAX as u64) as u32);
+        mbr[510..512].copy_from_slice(&[0x55, 0xaa]);
+
+        vec![
+            (backup.partition_entry_lba, entries.clone()),
+            (backup.my_lba, backup.to_bytes().to_vec()),
+            (self.header.partition_entry_lba, entries),
+            (self.header.my_lba, self.header.to_bytes().to_vec()),
+            (0, mbr),
+        ]
+    }
+}
+
+/// Outcome of repairing the GPT of a device
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GptRepair {
+    /// Repaired partition table as written to the device
+    pub gpt: Gpt,
+    /// Whether the primary header and partition entries were valid
+    pub primary_valid: bool,
+    /// Whether the backup header and partition entries were valid
+    pub backup_valid: bool,
+    /// Location of the backup header before the repair
+    pub old_backup_lba: u64,
+}
+
+/// Validate a protective MBR
+pub fn check_protective_mbr(sector: &[u8]) -> Result<(), GptError> {
+    if sector.len() < 512 || sector[510..512] != [0x55, 0xaa] {
+        return Err(GptError::InvalidProtectiveMbr);
+    }
+    // Any of the 4 partitions being of the protective type is good enough
+    if !sector[446..510].chunks_exact(16).any(|p| p[4] == 0xee) {
+        return Err(GptError::InvalidProtectiveMbr);
+    }
+    Ok(())
+}
+
+fn read_header<R: Read + Seek>(io: &mut R, lba: u64) -> Result<GptHeader, GptError> {
+    let mut sector = [0u8; SECTOR_SIZE as usize];
+    io.seek(SeekFrom::Start(lba * SECTOR_SIZE))?;
+    io.read_exact(&mut sector)?;
+    GptHeader::from_bytes(&sector)
+}
+
+fn read_entries<R: Read + Seek>(
+    io: &mut R,
+    header: &GptHeader,
+) -> Result<Vec<GptPartition>, GptError> {
+    let mut entries = vec![0u8; header.entries_size()];
+    io.seek(SeekFrom::Start(header.partition_entry_lba * SECTOR_SIZE))?;
+    io.read_exact(&mut entries)?;
+    header.parse_entries(&entries)
+}
+
+/// Read and validate the GPT from a device, e.g. a [crate::libusb::TransportIO]
+///
+/// The protective MBR, primary and backup headers and partition entries are validated. If the
+/// primary header or its entries are corrupted, the backup at the end of the device is used
+/// instead.
+pub fn read_gpt<R: Read + Seek>(io: &mut R) -> Result<Gpt, GptError> {
+    let mut mbr = [0u8; SECTOR_SIZE as usize];
+    io.seek(SeekFrom::Start(0))?;
+    io.read_exact(&mut mbr)?;
+    check_protective_mbr(&mbr)?;
+
+    let primary = read_header(io, GPT_PRIMARY_HEADER as u64).and_then(|h| {
+        let partitions = read_entries(io, &h)?;
+        Ok((h, partitions))
+    });
+
+    match primary {
+        Ok((header, partitions)) => {
+            let backup = read_header(io, header.alternate_lba).ok();
+            Ok(Gpt {
+                header,
+                backup,
+                partitions,
+            })
+        }
+        Err(e) => {
+            let sectors = io.seek(SeekFrom::End(0))? / SECTOR_SIZE;
+            let last = sectors.checked_sub(1).ok_or(GptError::TooSmall(sectors))?;
+            let header = read_header(io, last).map_err(|_| e)?;
+            let partitions = read_entries(io, &header)?;
+            Ok(Gpt {
+                header: header.clone(),
+                backup: Some(header),
+                partitions,
+            })
+        }
+    }
+}
+
+/// Write a GPT to a device of `sectors` sectors
+///
+/// The table is first relocated, see [Gpt::relocate], such that the backup header ends up at the
+/// true end of the device (e.g. as reported by [crate::protocol::FlashInfo::sectors]). The
+/// protective MBR is updated for the device size while keeping any existing boot code.
+pub fn write_gpt<D: Read + Write + Seek>(
+    io: &mut D,
+    gpt: &mut Gpt,
+    sectors: u64,
+) -> Result<(), GptError> {
+    gpt.relocate(sectors)?;
+
+    let mut mbr = [0u8; SECTOR_SIZE as usize];
+    io.seek(SeekFrom::Start(0))?;
+    io.read_exact(&mut mbr)?;
+
+    for (lba, data) in gpt.to_sectors(&mbr) {
+        io.seek(SeekFrom::Start(lba * SECTOR_SIZE))?;
+        io.write_all(&data)?;
+    }
+    io.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn header_bytes(
+        my_lba: u64,
+        alternate_lba: u64,
+        entries_lba: u64,
+        entries_crc: u32,
+    ) -> Vec<u8> {
+        let mut b = vec![];
+        b.put_slice(GPT_SIGNATURE);
+        b.put_u32_le(0x10000);
+        b.put_u32_le(GPT_HEADER_SIZE);
+        b.put_u32_le(0);
+        b.put_u32_le(0);
+        b.put_u64_le(my_lba);
+        b.put_u64_le(alternate_lba);
+        b.put_u64_le(34);
+        b.put_u64_le(990);
+        b.put_slice(&[0x11; 16]);
+        b.put_u64_le(entries_lba);
+        b.put_u32_le(128);
+        b.put_u32_le(GPT_ENTRY_SIZE);
+        b.put_u32_le(entries_crc);
+        let crc = CRC32.checksum(&b);
+        b[16..20].copy_from_slice(&crc.to_le_bytes());
+        b.resize(512, 0);
+        b
+    }
+
+    fn disk() -> Vec<u8> {
+        let mut disk = vec![0u8; 1024 * 512];
+        // protective mbr
+        disk[446 + 4] = 0xee;
+        disk[510] = 0x55;
+        disk[511] = 0xaa;
+
+        let mut entries = vec![0u8; 128 * GPT_ENTRY_SIZE as usize];
+        let mut e = &mut entries[..];
+        e.put_slice(&[0xaa; 16]);
+        e.put_slice(&[0xbb; 16]);
+        e.put_u64_le(64);
+        e.put_u64_le(127);
+        e.put_u64_le(0);
+        for c in "rootfs".encode_utf16() {
+            e.put_u16_le(c);
+        }
+        let crc = CRC32.checksum(&entries);
+
+        disk[512..1024].copy_from_slice(&header_bytes(1, 1023, 2, crc));
+        disk[1024..1024 + entries.len()].copy_from_slice(&entries);
+        disk[991 * 512..991 * 512 + entries.len()].copy_from_slice(&entries);
+        disk[1023 * 512..].copy_from_slice(&header_bytes(1023, 1, 991, crc));
+        disk
+    }
+
+    #[test]
+    fn parse() {
+        let gpt = read_gpt(&mut std::io::Cursor::new(disk())).unwrap();
+        assert_eq!(gpt.header.my_lba, 1);
+        assert_eq!(gpt.backup.as_ref().unwrap().my_lba, 1023);
+        assert_eq!(gpt.partitions.len(), 1);
+        let p = &gpt.partitions[0];
+        assert_eq!(p.name, "rootfs");
+        assert_eq!(p.first_lba, 64);
+        assert_eq!(p.sectors(), 64);
+        assert_eq!(
+            p.type_guid.to_string(),
+            "AAAAAAAA-AAAA-AAAA-AAAA-AAAAAAAAAAAA"
+        );
+    }
+
+    #[test]
+    fn oversized_entries() {
+        let mut b = header_bytes(1, 1023, 2, 0);
+        b[80..84].copy_from_slice(&u32::MAX.to_le_bytes());
+        b[16..20].fill(0);
+        let crc = CRC32.checksum(&b[..GPT_HEADER_SIZE as usize]);
+        b[16..20].copy_from_slice(&crc.to_le_bytes());
+        assert!(matches!(
+            GptHeader::from_bytes(&b),
+            Err(GptError::EntriesTooLarge {
+                entries: u32::MAX,
+                entry_size: GPT_ENTRY_SIZE
+            })
+        ));
+    }
+
+    #[test]
+    fn truncated_entries() {
+        let gpt = read_gpt(&mut std::io::Cursor::new(disk())).unwrap();
+        assert!(matches!(
+            gpt.header.parse_entries(&[0u8; 512]),
+            Err(GptError::EntriesTruncated {
+                expected: 16384,
+                actual: 512
+            })
+        ));
+    }
+
+    #[test]
+    fn mbr_only() {
+        let mut disk = disk();
+        disk.truncate(512);
+        assert!(read_gpt(&mut std::io::Cursor::new(disk)).is_err());
+    }
+
+    #[test]
+    fn corrupted_primary() {
+        let mut disk = disk();
+        // Corrupt the primary entries
+        disk[1024 + 40] ^= 0xff;
+        let gpt = read_gpt(&mut std::io::Cursor::new(disk)).unwrap();
+        assert_eq!(gpt.header.my_lba, 1023);
+        assert_eq!(gpt.partitions[0].name, "rootfs");
+    }
+
+    #[test]
+    fn write_relocated() {
+        let mut gpt = read_gpt(&mut std::io::Cursor::new(disk())).unwrap();
+        let mut bigger = disk();
+        bigger.resize(4096 * 512, 0);
+        let mut io = std::io::Cursor::new(bigger);
+        write_gpt(&mut io, &mut gpt, 4096).unwrap();
+
+        let written = read_gpt(&mut io).unwrap();
+        assert_eq!(written, gpt);
+        assert_eq!(written.header.alternate_lba, 4095);
+        assert_eq!(written.header.last_usable_lba, 4095 - 33);
+        assert_eq!(written.backup.unwrap().partition_entry_lba, 4095 - 32);
+        assert_eq!(written.partitions[0].name, "rootfs");
+    }
+
+    #[test]
+    fn grow_last() {
+        let mut gpt = read_gpt(&mut std::io::Cursor::new(disk())).unwrap();
+        gpt.relocate(4096).unwrap();
+        gpt.grow_last_partition().unwrap();
+        assert_eq!(gpt.partitions[0].first_lba, 64);
+        assert_eq!(gpt.partitions[0].last_lba, 4095 - 33);
+        assert_eq!(
+            gpt.header.partition_entries_crc32,
+            CRC32.checksum(&gpt.entries_to_bytes())
+        );
+    }
+
+    #[test]
+    fn relocate_out_of_range() {
+        let mut gpt = read_gpt(&mut std::io::Cursor::new(disk())).unwrap();
+        // The backup entries of a 128 sector device overlap the partition
+        let original = gpt.clone();
+        assert!(matches!(
+            gpt.relocate(128),
+            Err(GptError::PartitionOutOfRange {
+                first_lba: 64,
+                last_lba: 127,
+                last_usable_lba: 94,
+                ..
+            })
+        ));
+        assert_eq!(gpt, original);
+
+        gpt.partitions[0].first_lba = 16;
+        assert!(matches!(
+            gpt.relocate(4096),
+            Err(GptError::PartitionOutOfRange {
+                first_usable_lba: 34,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn guid() {
+        let text = "0FC63DAF-8483-4772-8E79-3D69D8477DE4";
+        let guid: Guid = text.parse().unwrap();
+        assert_eq!(guid.0[0..4], [0xaf, 0x3d, 0xc6, 0x0f]);
+        assert_eq!(guid.to_string(), text);
+        assert_eq!(guid, text.to_lowercase().parse().unwrap());
+        assert!("0FC63DAF-8483-4772-8E79".parse::<Guid>().is_err());
+        assert!("0FC63DAF-8483-4772-8E79-3D69D8477DEX"
+            .parse::<Guid>()
+            .is_err());
+
+        let random = Guid::random();
+        assert_eq!(random.to_string().as_bytes()[14], b'4');
+        assert_ne!(random, Guid::random());
+    }
+}
@@ -0,0 +1,226 @@
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::gpt::{Gpt, GptError, GptPartition, Guid};
+
+/// Type GUID of Linux filesystem data partitions, the default partition type
+pub const LINUX_FILESYSTEM: &str = "0FC63DAF-8483-4772-8E79-3D69D8477DE4";
+
+#[derive(Debug, Error)]
+pub enum GptLayoutError {
+    #[error("GPT error: {0}")]
+    GptError(#[from] GptError),
+    #[error("Partition {0} has no size and doesn't grow")]
+    NoSize(String),
+    #[error("Partition {0} grows but isn't the last partition")]
+    GrowNotLast(String),
+    #[error("Partition {0} overlaps the previous partition")]
+    Overlap(String),
+    #[error("Partition {name} doesn't fit in the {sectors} sectors of the device")]
+    DoesNotFit { name: String, sectors: u64 },
+}
+
+fn default_alignment() -> u64 {
+    // 1 MiB
+    2048
+}
+
+fn default_type() -> String {
+    LINUX_FILESYSTEM.to_string()
+}
+
+/// Partition as described in a [GptLayout]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LayoutPartition {
+    pub name: String,
+    /// Size in sectors; For a growing partition this is the minimal size
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    /// Start sector; Defaults to right after the previous partition, aligned
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub offset: Option<u64>,
+    /// Partition type GUID
+    #[serde(default = "default_type", rename = "type")]
+    pub type_guid: String,
+    /// Unique partition GUID; Randomly generated if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub guid: Option<String>,
+    /// Grow the partition up to the end of the device; Only valid for the last partition
+    #[serde(default)]
+    pub grow: bool,
+}
+
+/// Declarative description of a GUID partition table
+///
+/// Offsets and sizes are in sectors. A layout can be stored in any format supported by serde,
+/// e.g. TOML:
+/// ```toml
+/// [[partition]]
+/// name = "boot"
+/// offset = 0x8000
+/// size = 0x40000
+/// type = "C12A7328-F81F-11D2-BA4B-00A0C93EC93B"
+///
+/// [[partition]]
+/// name = "rootfs"
+/// grow = true
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GptLayout {
+    /// Disk GUID; Randomly generated if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub disk_guid: Option<String>,
+    /// Alignment in sectors of partitions without an explicit offset
+    #[serde(default = "default_alignment")]
+    pub alignment: u64,
+    /// Partitions, in order
+    #[serde(default, rename = "partition")]
+    pub partitions: Vec<LayoutPartition>,
+}
+
+fn guid_or_random(guid: &Option<String>) -> Result<Guid, GptError> {
+    guid.as_deref()
+        .map_or_else(|| Ok(Guid::random()), str::parse)
+}
+
+impl GptLayout {
+    /// Build the partition table for a device of `sectors` sectors
+    ///
+    /// The returned table is laid out for the device (see [Gpt::relocate]) and can be written
+    /// as-is, e.g. with [crate::gpt::write_gpt] or a [crate::flasher::FlashPlan]
+    pub fn to_gpt(&self, sectors: u64) -> Result<Gpt, GptLayoutError> {
+        let mut gpt = Gpt::new(guid_or_random(&self.disk_guid)?);
+        gpt.relocate(sectors)?;
+        let alignment = self.alignment.max(1);
+        let last_usable = gpt.header.last_usable_lba;
+
+        let mut next = gpt.header.first_usable_lba;
+        for (i, p) in self.partitions.iter().enumerate() {
+            if p.grow && i != self.partitions.len() - 1 {
+                return Err(GptLayoutError::GrowNotLast(p.name.clone()));
+            }
+            let first_lba = match p.offset {
+                Some(offset) if offset < next => {
+                    return Err(GptLayoutError::Overlap(p.name.clone()))
+                }
+                Some(offset) => offset,
+                None => next.div_ceil(alignment) * alignment,
+            };
+            let size = match (p.size, p.grow) {
+                (Some(size), _) if size > 0 => size,
+                (_, true) => 1,
+                _ => return Err(GptLayoutError::NoSize(p.name.clone())),
+            };
+            let last_lba = first_lba
+                .checked_add(size - 1)
+                .filter(|l| *l <= last_usable)
+                .ok_or_else(|| GptLayoutError::DoesNotFit {
+                    name: p.name.clone(),
+                    sectors,
+                })?;
+
+            gpt.partitions.push(GptPartition {
+                type_guid: p.type_guid.parse()?,
+                unique_guid: guid_or_random(&p.guid)?,
+                first_lba,
+                last_lba: if p.grow { last_usable } else { last_lba },
+                attributes: 0,
+                name: p.name.clone(),
+            });
+            next = last_lba + 1;
+        }
+
+        // Update the CRCs for the added partitions
+        gpt.relocate(sectors)?;
+        Ok(gpt)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn partition(name: &str, size: Option<u64>) -> LayoutPartition {
+        LayoutPartition {
+            name: name.to_string(),
+            size,
+            offset: None,
+            type_guid: default_type(),
+            guid: None,
+            grow: false,
+        }
+    }
+
+    #[test]
+    fn layout() {
+        let mut boot = partition("boot", Some(0x1000));
+        boot.offset = Some(0x4000);
+        let mut rootfs = partition("rootfs", None);
+        rootfs.grow = true;
+        let layout = GptLayout {
+            disk_guid: None,
+            alignment: default_alignment(),
+            partitions: vec![partition("uboot", Some(100)), boot, rootfs],
+        };
+
+        let gpt = layout.to_gpt(0x8000).unwrap();
+        let p = &gpt.partitions;
+        assert_eq!(p.len(), 3);
+        assert_eq!((p[0].first_lba, p[0].sectors()), (2048, 100));
+        assert_eq!((p[1].first_lba, p[1].sectors()), (0x4000, 0x1000));
+        assert_eq!(p[2].first_lba, 0x5000);
+        assert_eq!(p[2].last_lba, gpt.header.last_usable_lba);
+        assert_eq!(p[2].type_guid.to_string(), LINUX_FILESYSTEM);
+        assert_ne!(p[0].unique_guid, p[1].unique_guid);
+
+        // Written and read back unchanged
+        let mut io = std::io::Cursor::new(vec![0; 0x8000 * 512]);
+        io.get_mut()[510..512].copy_from_slice(&[0x55, 0xaa]);
+        let mut written = gpt.clone();
+        crate::gpt::write_gpt(&mut io, &mut written, 0x8000).unwrap();
+        assert_eq!(crate::gpt::read_gpt(&mut io).unwrap(), gpt);
+    }
+
+    #[test]
+    fn invalid() {
+        let mut layout = GptLayout {
+            disk_guid: None,
+            alignment: 1,
+            partitions: vec![partition("a", Some(100)), partition("b", None)],
+        };
+        assert!(matches!(
+            layout.to_gpt(4096),
+            Err(GptLayoutError::NoSize(n)) if n == "b"
+        ));
+
+        layout.partitions[0].grow = true;
+        layout.partitions[1].size = Some(1);
+        assert!(matches!(
+            layout.to_gpt(4096),
+            Err(GptLayoutError::GrowNotLast(n)) if n == "a"
+        ));
+
+        layout.partitions[0].grow = false;
+        layout.partitions[1].offset = Some(50);
+        assert!(matches!(
+            layout.to_gpt(4096),
+            Err(GptLayoutError::Overlap(n)) if n == "b"
+        ));
+
+        layout.partitions[1].offset = None;
+        layout.partitions[1].size = Some(4096);
+        assert!(matches!(
+            layout.to_gpt(4096),
+            Err(GptLayoutError::DoesNotFit { .. })
+        ));
+
+        layout.partitions[1].size = Some(1);
+        layout.partitions[1].type_guid = "linux".to_string();
+        assert!(matches!(
+            layout.to_gpt(4096),
+            Err(GptLayoutError::GptError(GptError::InvalidGuid(_)))
+        ));
+    }
+}
@@ -0,0 +1,107 @@
+use std::ops::Range;
+
+/// Sector of the primary GPT header
+pub const GPT_PRIMARY_HEADER: u32 = 1;
+/// Sector of the primary GPT partition entries
+pub const GPT_PRIMARY_ENTRIES: u32 = 2;
+/// Sectors reserved for the protective MBR and primary GPT at the start of the flash
+pub const GPT_RESERVED_SECTORS: u32 = 34;
+
+/// Sector of the backup GPT header for a flash of `sectors` sectors
+pub const fn gpt_backup_header(sectors: u32) -> u32 {
+    sectors - 1
+}
+
+/// Sector of the backup GPT partition entries for a flash of `sectors` sectors
+pub const fn gpt_backup_entries(sectors: u32) -> u32 {
+    sectors - (GPT_RESERVED_SECTORS - 1)
+}
+
+/// Sector ranges holding data critical for booting: The protective MBR and primary GPT, and the
+/// IDBlock
+///
+/// Overwriting these by accident, e.g. due to an off-by-one offset, can leave a device unable to
+/// boot, so the transports refuse to do so unless explicitly allowed
+pub const CRITICAL_SECTORS: [Range<u32>; 2] = [
+    0..GPT_RESERVED_SECTORS,
+    Region::IdBlock.offset()..Region::IdBlock.offset() + Region::IdBlock.size(),
+];
+
+/// First range of [CRITICAL_SECTORS] overlapping the given sectors, if any
+pub fn critical_overlap(sectors: &Range<u32>) -> Option<Range<u32>> {
+    CRITICAL_SECTORS
+        .iter()
+        .find(|c| sectors.start < c.end && c.start < sectors.end)
+        .cloned()
+}
+
+/// Standard regions of the Rockchip flash layout
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Region {
+    /// IDBlock (idbloader), holding the DDR init and miniloader or SPL
+    IdBlock,
+    /// U-Boot (uboot.img or u-boot.itb)
+    UBoot,
+    /// ARM trusted firmware / OP-TEE (trust.img)
+    Trust,
+    /// Boot partition (kernel, device tree and initramfs)
+    Boot,
+}
+
+impl Region {
+    /// All standard regions in flash order
+    pub const ALL: [Region; 4] = [Region::IdBlock, Region::UBoot, Region::Trust, Region::Boot];
+
+    /// Start sector of the region
+    pub const fn offset(self) -> u32 {
+        match self {
+            Region::IdBlock => 0x40,
+            Region::UBoot => 0x4000,
+            Region::Trust => 0x6000,
+            Region::Boot => 0x8000,
+        }
+    }
+
+    /// Size of the region in sectors
+    pub const fn size(self) -> u32 {
+        match self {
+            Region::IdBlock => 0x1bc0,
+            Region::UBoot => 0x2000,
+            Region::Trust => 0x2000,
+            Region::Boot => 0x38000,
+        }
+    }
+
+    /// Partition name conventionally used for the region
+    pub const fn name(self) -> &'static str {
+        match self {
+            Region::IdBlock => "loader1",
+            Region::UBoot => "loader2",
+            Region::Trust => "trust",
+            Region::Boot => "boot",
+        }
+    }
+}
+
+/// Number of sectors in the first 4MiB of the flash; Only accessible if the loader reports
+/// [crate::protocol::Capability::first_4m_access]
+pub const FIRST_4M_SECTORS: u32 = 0x2000;
+
+/// Start sector of the root filesystem in the standard layout
+pub const ROOTFS_OFFSET: u32 = 0x40000;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn critical() {
+        assert_eq!(critical_overlap(&(0..1)), Some(0..GPT_RESERVED_SECTORS));
+        assert_eq!(critical_overlap(&(33..40)), Some(0..GPT_RESERVED_SECTORS));
+        assert_eq!(critical_overlap(&(34..0x40)), None);
+        assert_eq!(critical_overlap(&(0x3f..0x41)), Some(0x40..0x1c00));
+        assert_eq!(critical_overlap(&(0x2000..0x4000)), None);
+        // Empty ranges never overlap
+        assert_eq!(critical_overlap(&(0..0)), None);
+    }
+}
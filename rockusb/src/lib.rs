@@ -1,12 +1,35 @@
 #![cfg_attr(docsrs, feature(doc_auto_cfg))]
 #![doc = include_str!("../README.md")]
 
+/// High-level flashing of devices following a plan
+pub mod flasher;
+/// GUID partition table parsing
+pub mod gpt;
+/// Declarative GPT partition layouts
+#[cfg(feature = "serde")]
+pub mod gpt_layout;
+/// Well-known flash offsets
+///
+/// Offsets and sizes are in sectors of [protocol::SECTOR_SIZE] bytes, following the standard
+/// Rockchip partition layout
+pub mod layout;
 /// libusb transport implementation
 #[cfg(feature = "libusb")]
 pub mod libusb;
+/// Async adapter running a libusb transport on its own thread
+#[cfg(feature = "libusb-async")]
+pub mod libusb_async;
+/// Declarative flash plan manifests
+#[cfg(feature = "serde")]
+pub mod manifest;
+/// Network block device export of the flash
+#[cfg(feature = "nbd")]
+pub mod nbd;
 /// nusb transport implementation
 #[cfg(feature = "nusb")]
 pub mod nusb;
+/// Observing the protocol activity of transports
+pub mod observer;
 /// sans-io protocol implementations
 ///
 /// This module contains all protocol logic; Each operation implements the [operation::OperationSteps]
@@ -15,3 +38,14 @@ pub mod nusb;
 pub mod operation;
 /// low-level usb protocol data structures
 pub mod protocol;
+/// Known loader oddities of specific devices
+pub mod quirks;
+/// Policies for retrying failed operations
+pub mod retry;
+/// Execution of flash plans shared by the transports
+#[cfg(any(feature = "libusb", feature = "nusb"))]
+mod runner;
+/// Transfer statistics
+pub mod stats;
+/// Pacing of transfers
+pub mod throttle;
@@ -1,16 +1,56 @@
 use std::{
     borrow::BorrowMut,
-    io::{Read, Seek, SeekFrom, Write},
-    time::Duration,
+    io::{BufRead, Read, Seek, SeekFrom, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
 };
 
 use crate::{
-    operation::{OperationSteps, UsbStep},
-    protocol::{ChipInfo, FlashId, FlashInfo, ResetOpcode, SECTOR_SIZE},
+    flasher::{FlashError, FlashPlan, FlashReport, Journal, Progress},
+    gpt::{Gpt, GptError, GptRepair},
+    layout::{critical_overlap, Region, FIRST_4M_SECTORS},
+    observer::{observe_read, observe_write, OperationObserver},
+    operation::{
+        transfer_sectors, BulkPhase, Encoding, OperationContext, OperationSteps, Transferred,
+        UsbStep, MAX_LBA_SECTORS, MAX_RESIDUE_RETRIES, SDRAM_ALIGNMENT, SDRAM_CHUNK_SIZE,
+    },
+    protocol::{
+        Area, Capability, ChipInfo, CommandStatus, DeviceFilter, FlashId, FlashInfo, ResetOpcode,
+        SocFamily, Storage, UsbMode, DEFAULT_DEVICE_FILTERS, SECTOR_SIZE,
+    },
+    quirks::Quirks,
+    retry::RetryPolicy,
+    runner::{self, block_on, collect_unerased, FlashDevice},
+    stats::Stats,
+    throttle::Throttle,
 };
-use rusb::{DeviceHandle, GlobalContext};
+use rockfile::boot::{BootFile, EntryKind};
+use rusb::{DeviceHandle, GlobalContext, UsbContext};
 use thiserror::Error;
 
+/// Reason for a device not being available
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum UnavailableKind {
+    /// Claimed by someone else, e.g. another process already talking to the device.
+    ///
+    /// Claiming the interface is exclusive, so two applications can never interleave commands to
+    /// the same device
+    Busy,
+    /// Claimed by a kernel driver, e.g. the OS binding its mass-storage driver to a loader
+    /// exposing a mass-storage like interface
+    ///
+    /// Unmount any volumes of the device and either unbind the driver or use
+    /// [Devices::detach_kernel_driver]. Detection relies on libusb, which only supports this on
+    /// Linux and macOS; Elsewhere such devices are reported as [UnavailableKind::Busy]
+    ClaimedByKernel,
+    /// Any other failure, see the usb error for details
+    Other,
+}
+
 /// Error indicate a device is not available
 #[derive(Debug, Clone, Eq, PartialEq, Error)]
 #[error("Device is not available: {device:?} {error}")]
@@ -18,6 +58,47 @@ pub struct DeviceUnavalable {
     pub device: rusb::Device<GlobalContext>,
     #[source]
     pub error: rusb::Error,
+    pub kind: UnavailableKind,
+}
+
+impl DeviceUnavalable {
+    fn new(device: rusb::Device<GlobalContext>, error: rusb::Error) -> Self {
+        let kind = match error {
+            rusb::Error::Busy if claimed_by_kernel(&device) => UnavailableKind::ClaimedByKernel,
+            rusb::Error::Busy => UnavailableKind::Busy,
+            _ => UnavailableKind::Other,
+        };
+        Self {
+            device,
+            error,
+            kind,
+        }
+    }
+
+    /// Whether the device is unavailable due to being claimed by someone else, either another
+    /// process or a kernel driver
+    pub fn is_busy(&self) -> bool {
+        matches!(
+            self.kind,
+            UnavailableKind::Busy | UnavailableKind::ClaimedByKernel
+        )
+    }
+
+    /// Whether the device is busy due to a kernel driver claiming it; See
+    /// [UnavailableKind::ClaimedByKernel]
+    pub fn is_claimed_by_kernel(&self) -> bool {
+        self.kind == UnavailableKind::ClaimedByKernel
+    }
+}
+
+// Whether a kernel driver is bound to any of the device interfaces
+fn claimed_by_kernel(device: &rusb::Device<GlobalContext>) -> bool {
+    let (Ok(handle), Ok(config)) = (device.open(), device.active_config_descriptor()) else {
+        return false;
+    };
+    config
+        .interfaces()
+        .any(|i| handle.kernel_driver_active(i.number()).unwrap_or(false))
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Error)]
@@ -26,30 +107,162 @@ pub enum Error {
     UsbError(#[from] rusb::Error),
     #[error("Operation error: {0}")]
     OperationError(#[from] crate::operation::UsbOperationError),
+    #[error("Short transfer: expected {expected} bytes, transferred {actual}")]
+    ShortTransfer { expected: usize, actual: usize },
+    #[error("Sector {sector:#x} is beyond the range addressable by the protocol")]
+    AddressOutOfRange { sector: u64 },
+    #[error("Partial write: only {written} of {requested} bytes written")]
+    PartialTransfer { written: usize, requested: usize },
+    #[error("Switching to storage {expected} failed, storage in use: {actual:?}")]
+    StorageMismatch { expected: u8, actual: Option<u8> },
+    #[error("Invalid size {size} bytes for the {region:?} region")]
+    InvalidRegionSize { region: Region, size: usize },
+    #[error("Loader doesn't support {0}")]
+    Unsupported(&'static str),
+    #[error("Access of {len} bytes at offset {offset:#x} is out of bounds")]
+    OutOfBounds { offset: u32, len: usize },
+    #[error("Address {address:#x} isn't aligned to {alignment} bytes")]
+    UnalignedAddress { address: u32, alignment: u32 },
+    #[error("Sectors {sectors:#x?} overlap the critical region {region:#x?}")]
+    CriticalRegion {
+        sectors: std::ops::Range<u32>,
+        region: std::ops::Range<u32>,
+    },
+    #[error("{context} failed: {source}")]
+    Context {
+        context: OperationContext,
+        #[source]
+        source: Box<Error>,
+    },
 }
 type Result<T> = std::result::Result<T, Error>;
 
+impl Error {
+    fn context(self, context: OperationContext) -> Self {
+        Error::Context {
+            context,
+            source: Box::new(self),
+        }
+    }
+
+    /// Context of the operation that failed, if known
+    pub fn operation_context(&self) -> Option<&OperationContext> {
+        match self {
+            Error::Context { context, .. } => Some(context),
+            _ => None,
+        }
+    }
+
+    fn io_error_kind(&self) -> std::io::ErrorKind {
+        match self {
+            Error::UsbError(e) => match e {
+                rusb::Error::Timeout => std::io::ErrorKind::TimedOut,
+                rusb::Error::Access => std::io::ErrorKind::PermissionDenied,
+                rusb::Error::NoDevice => std::io::ErrorKind::NotConnected,
+                rusb::Error::NotFound => std::io::ErrorKind::NotFound,
+                rusb::Error::Interrupted => std::io::ErrorKind::Interrupted,
+                rusb::Error::InvalidParam => std::io::ErrorKind::InvalidInput,
+                rusb::Error::NoMem => std::io::ErrorKind::OutOfMemory,
+                rusb::Error::NotSupported => std::io::ErrorKind::Unsupported,
+                rusb::Error::Overflow => std::io::ErrorKind::InvalidData,
+                _ => std::io::ErrorKind::BrokenPipe,
+            },
+            Error::OperationError(crate::operation::UsbOperationError::PartialTransfer {
+                ..
+            }) => std::io::ErrorKind::WriteZero,
+            Error::OperationError(
+                crate::operation::UsbOperationError::InvalidLbaLength(_)
+                | crate::operation::UsbOperationError::InvalidSdramLength(_),
+            ) => std::io::ErrorKind::InvalidInput,
+            Error::OperationError(_) => std::io::ErrorKind::InvalidData,
+            Error::ShortTransfer { .. } => std::io::ErrorKind::UnexpectedEof,
+            Error::AddressOutOfRange { .. } => std::io::ErrorKind::InvalidInput,
+            Error::PartialTransfer { .. } => std::io::ErrorKind::WriteZero,
+            Error::StorageMismatch { .. } => std::io::ErrorKind::Other,
+            Error::InvalidRegionSize { .. } => std::io::ErrorKind::InvalidInput,
+            Error::Unsupported(_) => std::io::ErrorKind::Unsupported,
+            Error::OutOfBounds { .. } => std::io::ErrorKind::InvalidInput,
+            Error::UnalignedAddress { .. } => std::io::ErrorKind::InvalidInput,
+            Error::CriticalRegion { .. } => std::io::ErrorKind::PermissionDenied,
+            Error::Context { source, .. } => source.io_error_kind(),
+        }
+    }
+}
+
+impl From<Error> for std::io::Error {
+    fn from(e: Error) -> Self {
+        std::io::Error::new(e.io_error_kind(), e)
+    }
+}
+
+impl From<Error> for FlashError {
+    fn from(e: Error) -> Self {
+        FlashError::IoError(e.into())
+    }
+}
+
+impl From<Error> for GptError {
+    fn from(e: Error) -> Self {
+        GptError::IoError(e.into())
+    }
+}
+
+// Biggest lba transfer that fits in a single operation
+const MAX_LBA_CHUNK: usize = MAX_LBA_SECTORS as usize * SECTOR_SIZE as usize;
+
 /// Rockchip devices
 pub struct Devices {
     devices: rusb::DeviceList<GlobalContext>,
+    filters: Vec<DeviceFilter>,
+    detach: bool,
 }
 
 impl Devices {
     pub fn new() -> Result<Self> {
+        Self::with_filters(DEFAULT_DEVICE_FILTERS)
+    }
+
+    /// Find devices matching any of the given filters rather then the
+    /// [DEFAULT_DEVICE_FILTERS], e.g. for custom bootloaders using their own usb ids
+    pub fn with_filters(filters: &[DeviceFilter]) -> Result<Self> {
         let devices = rusb::DeviceList::new()?;
-        Ok(Self { devices })
+        Ok(Self {
+            devices,
+            filters: filters.to_vec(),
+            detach: false,
+        })
+    }
+
+    /// Detach kernel drivers bound to devices (e.g. usb-storage) when opening them
+    ///
+    /// The kernel driver is reattached once the transport is dropped. Only supported by libusb on
+    /// Linux and macOS; Elsewhere this has no effect
+    pub fn detach_kernel_driver(mut self, detach: bool) -> Self {
+        self.detach = detach;
+        self
+    }
+
+    /// Find devices of a specific SoC family
+    pub fn matching(family: SocFamily) -> Result<Self> {
+        Self::with_filters(&[family.filter()])
     }
 
     /// Create an Iterator over found Rockchip device
-    pub fn iter(&self) -> DevicesIter {
+    pub fn iter(&self) -> DevicesIter<'_> {
         let iter = self.devices.iter();
-        DevicesIter { iter }
+        DevicesIter {
+            iter,
+            filters: &self.filters,
+            detach: self.detach,
+        }
     }
 }
 
 /// Iterator over found Rockchip device
 pub struct DevicesIter<'a> {
     iter: rusb::Devices<'a, GlobalContext>,
+    filters: &'a [DeviceFilter],
+    detach: bool,
 }
 
 impl Iterator for DevicesIter<'_> {
@@ -61,13 +274,17 @@ impl Iterator for DevicesIter<'_> {
                 Ok(desc) => desc,
                 _ => continue,
             };
-            if desc.vendor_id() != 0x2207 {
+            if !DeviceFilter::any_matches(self.filters, desc.vendor_id(), desc.product_id()) {
                 continue;
             }
             let handle = match device.open() {
                 Ok(handle) => handle,
-                Err(error) => return Some(Err(DeviceUnavalable { device, error })),
+                Err(error) => return Some(Err(DeviceUnavalable::new(device, error))),
             };
+            if self.detach {
+                // Not supported on all platforms, in which case claiming reports the device busy
+                let _ = handle.set_auto_detach_kernel_driver(true);
+            }
 
             return Some(Transport::from_usb_device(handle));
         }
@@ -75,31 +292,286 @@ impl Iterator for DevicesIter<'_> {
     }
 }
 
+/// Mode a device is currently in, based on its descriptors (see [UsbMode::detect])
+///
+/// Doesn't require opening the device, so can be used to decide whether a boot download is
+/// needed before claiming it. The product string is not considered as reading it requires
+/// opening the device
+pub fn device_mode(device: &rusb::Device<GlobalContext>) -> Result<UsbMode> {
+    let desc = device.device_descriptor()?;
+    let version = desc.device_version();
+    let bcd =
+        (version.major() as u16) << 8 | (version.minor() as u16) << 4 | version.sub_minor() as u16;
+    let classes: Vec<u8> = match device.active_config_descriptor() {
+        Ok(config) => config
+            .interfaces()
+            .flat_map(|i| i.descriptors().map(|d| d.class_code()).collect::<Vec<_>>())
+            .collect(),
+        Err(_) => vec![],
+    };
+    Ok(UsbMode::detect(bcd, None, &classes))
+}
+
+/// Whether a device is in maskrom mode, see [device_mode]
+pub fn is_maskrom(device: &rusb::Device<GlobalContext>) -> Result<bool> {
+    Ok(device_mode(device)? == UsbMode::Maskrom)
+}
+
+/// Hotplug event for a rockchip device, see [watch_devices]
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum HotplugEvent {
+    /// A device has been connected
+    Connected(rusb::Device<GlobalContext>),
+    /// A device has been disconnected
+    Disconnected(rusb::Device<GlobalContext>),
+}
+
+struct HotplugSender {
+    filters: Vec<DeviceFilter>,
+    sender: mpsc::Sender<HotplugEvent>,
+}
+
+impl HotplugSender {
+    fn matches(&self, device: &rusb::Device<GlobalContext>) -> bool {
+        // The device descriptor is cached by libusb, so safe to get from a hotplug callback
+        device.device_descriptor().is_ok_and(|desc| {
+            DeviceFilter::any_matches(&self.filters, desc.vendor_id(), desc.product_id())
+        })
+    }
+}
+
+impl rusb::Hotplug<GlobalContext> for HotplugSender {
+    fn device_arrived(&mut self, device: rusb::Device<GlobalContext>) {
+        if self.matches(&device) {
+            let _ = self.sender.send(HotplugEvent::Connected(device));
+        }
+    }
+
+    fn device_left(&mut self, device: rusb::Device<GlobalContext>) {
+        if self.matches(&device) {
+            let _ = self.sender.send(HotplugEvent::Disconnected(device));
+        }
+    }
+}
+
+/// Watch for rockchip devices being connected or disconnected
+///
+/// Events are handled by a background thread and delivered through a channel, which can be
+/// received from directly or by iterating over the watch. Devices already connected when starting
+/// to watch are not reported, but their disconnection is. Hotplug support in libusb is not
+/// available on all platforms (notably Windows), in which case this fails with
+/// [rusb::Error::NotSupported]
+pub fn watch_devices() -> Result<DeviceWatch> {
+    watch_devices_with_filters(DEFAULT_DEVICE_FILTERS)
+}
+
+/// Watch for devices matching any of the given filters being connected or disconnected, see
+/// [watch_devices]
+pub fn watch_devices_with_filters(filters: &[DeviceFilter]) -> Result<DeviceWatch> {
+    if !rusb::has_hotplug() {
+        return Err(rusb::Error::NotSupported.into());
+    }
+    let (sender, events) = mpsc::channel();
+    let mut builder = rusb::HotplugBuilder::new();
+    // libusb can only filter on a single vendor; Products are filtered in the callback
+    if let Some(first) = filters.first() {
+        if filters.iter().all(|f| f.vendor_id == first.vendor_id) {
+            builder.vendor_id(first.vendor_id);
+        }
+    }
+    let registration = builder.register(
+        GlobalContext::default(),
+        Box::new(HotplugSender {
+            filters: filters.to_vec(),
+            sender,
+        }),
+    )?;
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let thread = std::thread::spawn({
+        let stop = stop.clone();
+        move || {
+            while !stop.load(Ordering::Relaxed) {
+                match GlobalContext::default().handle_events(Some(Duration::from_millis(100))) {
+                    Ok(()) | Err(rusb::Error::Interrupted) => (),
+                    Err(_) => break,
+                }
+            }
+        }
+    });
+
+    Ok(DeviceWatch {
+        events,
+        stop,
+        registration: Some(registration),
+        thread: Some(thread),
+    })
+}
+
+/// Watch for hotplug events of rockchip devices, see [watch_devices]
+///
+/// Dropping the watch stops watching for events
+pub struct DeviceWatch {
+    events: mpsc::Receiver<HotplugEvent>,
+    stop: Arc<AtomicBool>,
+    registration: Option<rusb::Registration<GlobalContext>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl DeviceWatch {
+    /// Channel the hotplug events are delivered through
+    pub fn receiver(&self) -> &mpsc::Receiver<HotplugEvent> {
+        &self.events
+    }
+
+    /// Wait for the next event for at most `timeout`; Returns None if no event happened in time
+    pub fn next_timeout(&self, timeout: Duration) -> Option<HotplugEvent> {
+        self.events.recv_timeout(timeout).ok()
+    }
+}
+
+impl Iterator for DeviceWatch {
+    type Item = HotplugEvent;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.events.recv().ok()
+    }
+}
+
+impl Drop for DeviceWatch {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        // Deregistering wakes up the event handling thread
+        self.registration.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// Convert a sector number to the 32 bits addressable by the protocol
+fn to_lba(sector: u64) -> Result<u32> {
+    u32::try_from(sector).map_err(|_| Error::AddressOutOfRange { sector })
+}
+
+// End of `sectors` sectors starting at start_sector, failing if the last one isn't addressable
+//
+// The end is exclusive, so a range ending at the very last sector has an end of 2^32; That's
+// clamped to u32::MAX for the 32 bit ranges used to check critical regions and report errors,
+// which are all well before it
+fn sectors_end(start_sector: u32, sectors: u64) -> Result<u32> {
+    let end = u64::from(start_sector) + sectors;
+    to_lba(end.saturating_sub(1))?;
+    Ok(end.min(u32::MAX.into()) as u32)
+}
+
+// End of a transfer of `len` bytes starting at start_sector, see sectors_end
+fn lba_end(start_sector: u32, len: usize) -> Result<u32> {
+    sectors_end(start_sector, (len as u64).div_ceil(SECTOR_SIZE))
+}
+
+// Bytes read by an lba read, failing if the device returned less than requested
+fn full_read(transferred: Transferred, len: usize) -> Result<u32> {
+    let actual = u32::from(transferred);
+    if actual as usize == len {
+        Ok(actual)
+    } else {
+        Err(Error::ShortTransfer {
+            expected: len,
+            actual: actual as usize,
+        })
+    }
+}
+
 /// libusb based Transport for rockusb operation
 pub struct Transport {
     handle: DeviceHandle<rusb::GlobalContext>,
     ep_in: u8,
     ep_out: u8,
+    retry: RetryPolicy,
+    stats: Stats,
+    maskrom_encoding: Encoding,
+    vendor_id: u16,
+    product_id: u16,
+    chip_info: Option<ChipInfo>,
+    // Cached device information, see [Transport::refresh]
+    flash_info: Option<FlashInfo>,
+    capability: Option<Capability>,
+    allow_critical: bool,
+    max_transfer: u16,
+    quirks: Quirks,
+    // End of the last operation, for keeping the loader alive
+    last_active: Instant,
+    observer: Option<Box<dyn OperationObserver>>,
+    write_throttle: Option<Throttle>,
+}
+
+impl std::fmt::Debug for Transport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Transport")
+            .field("bus_number", &self.bus_number())
+            .field("address", &self.address())
+            .field("vendor_id", &format_args!("{:04x}", self.vendor_id))
+            .field("product_id", &format_args!("{:04x}", self.product_id))
+            .field("soc", &crate::protocol::soc_name(self.product_id))
+            .field("chip_info", &self.chip_info)
+            .field("ep_in", &self.ep_in)
+            .field("ep_out", &self.ep_out)
+            .finish()
+    }
+}
+
+impl std::fmt::Display for Transport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Bus {:03} Device {:03} ID {:04x}:{:04x} {}",
+            self.bus_number(),
+            self.address(),
+            self.vendor_id,
+            self.product_id,
+            crate::protocol::soc_name(self.product_id).unwrap_or("Unknown SoC")
+        )?;
+        if let Some(info) = &self.chip_info {
+            write!(f, " (chip {})", info)?;
+        }
+        Ok(())
+    }
 }
 
 impl Transport {
     fn new(
         handle: DeviceHandle<rusb::GlobalContext>,
+        desc: &rusb::DeviceDescriptor,
         interface: u8,
         ep_in: u8,
         ep_out: u8,
+        max_packet_size: usize,
     ) -> std::result::Result<Self, DeviceUnavalable> {
         handle
             .claim_interface(interface)
-            .map_err(|error| DeviceUnavalable {
-                device: handle.device(),
-                error,
-            })?;
-        Ok(Self {
+            .map_err(|error| DeviceUnavalable::new(handle.device(), error))?;
+        let mut transport = Self {
             handle,
             ep_in,
             ep_out,
-        })
+            retry: RetryPolicy::default(),
+            stats: Stats::new(),
+            maskrom_encoding: Encoding::for_product_id(desc.product_id()),
+            vendor_id: desc.vendor_id(),
+            product_id: desc.product_id(),
+            chip_info: None,
+            flash_info: None,
+            capability: None,
+            allow_critical: false,
+            max_transfer: transfer_sectors(max_packet_size),
+            quirks: Quirks::NONE,
+            last_active: Instant::now(),
+            observer: None,
+            write_throttle: None,
+        };
+        transport.apply_quirks(crate::quirks::lookup(desc.vendor_id(), desc.product_id()));
+        Ok(transport)
     }
 
     /// Create a new transport from an exist device handle
@@ -109,17 +581,11 @@ impl Transport {
         let device = handle.device();
         let desc = device
             .device_descriptor()
-            .map_err(|error| DeviceUnavalable {
-                device: device.clone(),
-                error,
-            })?;
+            .map_err(|error| DeviceUnavalable::new(device.clone(), error))?;
         for c in 0..desc.num_configurations() {
             let config = device
                 .config_descriptor(c)
-                .map_err(|error| DeviceUnavalable {
-                    device: device.clone(),
-                    error,
-                })?;
+                .map_err(|error| DeviceUnavalable::new(device.clone(), error))?;
             for i in config.interfaces() {
                 for i_desc in i.descriptors() {
                     let output = i_desc.endpoint_descriptors().find(|e| {
@@ -134,18 +600,17 @@ impl Transport {
                     if let (Some(input), Some(output)) = (input, output) {
                         return Transport::new(
                             handle,
+                            &desc,
                             i_desc.setting_number(),
                             input.address(),
                             output.address(),
+                            input.max_packet_size().min(output.max_packet_size()).into(),
                         );
                     }
                 }
             }
         }
-        Err(DeviceUnavalable {
-            device,
-            error: rusb::Error::NotFound,
-        })
+        Err(DeviceUnavalable::new(device, rusb::Error::NotFound))
     }
 
     /// Create an IO object which implements [Read], [Write] and
@@ -165,6 +630,19 @@ impl Transport {
         &mut self.handle
     }
 
+    /// Convert into the underlying device handle
+    pub fn into_handle(self) -> DeviceHandle<GlobalContext> {
+        self.handle
+    }
+
+    /// Hub ports the device is connected through, starting at the root hub
+    ///
+    /// Identifies the physical usb port of the device, which unlike the device address is stable
+    /// over reconnects
+    pub fn port_numbers(&self) -> Result<Vec<u8>> {
+        Ok(self.handle.device().port_numbers()?)
+    }
+
     /// Get the bus number of the current device
     pub fn bus_number(&self) -> u8 {
         self.handle.device().bus_number()
@@ -175,22 +653,236 @@ impl Transport {
         self.handle.device().address()
     }
 
-    fn handle_operation<O, T>(&mut self, mut operation: O) -> Result<T>
+    /// Set the encoding used for writes in maskrom mode
+    ///
+    /// By default the encoding is selected based on the usb product id if known, see
+    /// [Encoding::for_product_id]
+    pub fn set_maskrom_encoding(&mut self, encoding: Encoding) {
+        self.maskrom_encoding = encoding;
+    }
+
+    /// Encoding used for writes in maskrom mode
+    pub fn maskrom_encoding(&self) -> Encoding {
+        self.maskrom_encoding
+    }
+
+    /// Allow writes and erases overlapping the [CRITICAL_SECTORS](crate::layout::CRITICAL_SECTORS)
+    ///
+    /// By default raw sector writes and erases (including those done through [TransportIO] or a
+    /// [FlashPlan]) touching the partition table or IDBlock are refused to prevent accidental
+    /// bricks. Writes meant for these regions, like [Transport::write_idblock],
+    /// [Transport::write_first_4m] or a [FlashStep::WriteGpt](crate::flasher::FlashStep::WriteGpt),
+    /// are always allowed
+    pub fn allow_critical_regions(&mut self) {
+        self.allow_critical = true;
+    }
+
+    // Refuse modifying critical sectors unless allowed
+    fn check_critical(&self, sectors: std::ops::Range<u32>) -> Result<()> {
+        match critical_overlap(&sectors) {
+            Some(region) if !self.allow_critical => Err(Error::CriticalRegion { sectors, region }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Known quirks of the device's loader, see [crate::quirks::lookup]
+    pub fn quirks(&self) -> &Quirks {
+        &self.quirks
+    }
+
+    // Apply quirks looked up for the device
+    fn apply_quirks(&mut self, quirks: Quirks) {
+        if let Some(max) = quirks.max_transfer_sectors {
+            self.max_transfer = self.max_transfer.min(max.max(1));
+        }
+        if quirks.unreliable_lba {
+            self.retry = self.retry.with_min_attempts(3);
+        }
+        self.quirks = quirks;
+    }
+
+    /// Maximum number of sectors transferred by a single operation when streaming data
+    ///
+    /// Used as the chunk size when flashing, reading back or verifying, and as the default maximum
+    /// direct I/O size of [TransportIO]. Derived from the endpoint max packet size, see
+    /// [transfer_sectors]
+    pub fn max_transfer_sectors(&self) -> u16 {
+        self.max_transfer
+    }
+
+    /// Override the maximum number of sectors transferred by a single operation when streaming
+    /// data, e.g. for loaders misbehaving with big transfers
+    pub fn set_max_transfer_sectors(&mut self, sectors: u16) {
+        self.max_transfer = sectors.max(1);
+    }
+
+    // Maximum transfer size in bytes
+    fn max_transfer_size(&self) -> usize {
+        self.max_transfer as usize * SECTOR_SIZE as usize
+    }
+
+    /// Set an observer of the protocol activity of this transport, replacing the current one
+    pub fn set_observer(&mut self, observer: Option<Box<dyn OperationObserver>>) {
+        self.observer = observer;
+    }
+
+    /// Limit the rate of writes to the device to `bytes_per_second`, or remove the limit
+    ///
+    /// Writes are paced between transfers, e.g. for boards that brown out when written to at the
+    /// maximum rate. Applies to all data sent to the device, including flash plans
+    pub fn set_max_write_rate(&mut self, bytes_per_second: Option<u64>) {
+        self.write_throttle = bytes_per_second.map(Throttle::new);
+    }
+
+    /// Current limit of the rate of writes to the device in bytes per second
+    pub fn max_write_rate(&self) -> Option<u64> {
+        self.write_throttle.as_ref().map(Throttle::bytes_per_second)
+    }
+
+    // Wait as required by the write rate limit after writing `bytes`
+    fn pace_write(&mut self, bytes: usize) {
+        if let Some(throttle) = self.write_throttle.as_mut() {
+            std::thread::sleep(throttle.delay(bytes));
+        }
+    }
+
+    /// Set the policy for retrying failed operations
+    pub fn set_retry_policy(&mut self, retry: RetryPolicy) {
+        self.retry = retry;
+    }
+
+    /// Current policy for retrying failed operations
+    pub fn retry_policy(&self) -> RetryPolicy {
+        self.retry
+    }
+
+    // Run f, retrying on failure as allowed by the retry policy
+    fn retried<T, F>(&mut self, write: bool, mut f: F) -> Result<T>
+    where
+        F: FnMut(&mut Self) -> Result<T>,
+    {
+        let mut attempt = 1;
+        loop {
+            match f(self) {
+                Err(e) => match self.retry.on_failure(
+                    &e,
+                    e.io_error_kind(),
+                    (attempt, write),
+                    &mut self.stats,
+                    self.observer.as_deref_mut(),
+                ) {
+                    Some(delay) => {
+                        std::thread::sleep(delay);
+                        attempt += 1;
+                    }
+                    None => break Err(e),
+                },
+                r => break r,
+            }
+        }
+    }
+
+    /// Statistics of the current session
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Reset the statistics, starting a new session
+    pub fn reset_stats(&mut self) {
+        self.stats = Stats::new();
+    }
+
+    /// Execute a series of sans-io operations in order
+    ///
+    /// Execution stops at the first failing operation; Otherwise the results of all operations
+    /// are returned. Operations are not retried, as their state is consumed by executing them
+    pub fn handle_operations<O, T, I>(&mut self, operations: I) -> Result<Vec<T>>
+    where
+        O: OperationSteps<T>,
+        I: IntoIterator<Item = O>,
+    {
+        let mut results = Vec::new();
+        for operation in operations {
+            results.push(self.handle_operation(operation)?);
+        }
+        Ok(results)
+    }
+
+    fn handle_operation<O, T>(&mut self, operation: O) -> Result<T>
     where
         O: OperationSteps<T>,
     {
+        let start = Instant::now();
+        let r = self.execute_operation(operation);
+        self.stats.record_operation(start.elapsed(), r.is_ok());
+        self.last_active = Instant::now();
+        r
+    }
+
+    fn execute_operation<O, T>(&mut self, mut operation: O) -> Result<T>
+    where
+        O: OperationSteps<T>,
+    {
+        // Short data phase read or write, which is followed by the command status
+        let mut short_read = None;
+        let mut short_write = None;
         loop {
             let step = operation.step();
             match step {
-                UsbStep::WriteBulk { data } => {
-                    let _written =
+                UsbStep::WriteBulk { data, phase } => {
+                    let written =
                         self.handle
                             .write_bulk(self.ep_out, data, Duration::from_secs(5))?;
+                    match phase {
+                        // The device still sends its command status after a short data phase,
+                        // which is read before failing to keep the protocol in sync
+                        BulkPhase::Data if written < data.len() => {
+                            short_write = Some((data.len(), written))
+                        }
+                        _ if written != data.len() => {
+                            return Err(Error::ShortTransfer {
+                                expected: data.len(),
+                                actual: written,
+                            });
+                        }
+                        _ => (),
+                    }
+                    if let Some(observer) = self.observer.as_mut() {
+                        observe_write(observer.as_mut(), phase, &data[..written]);
+                    }
+                    self.pace_write(data.len());
                 }
-                UsbStep::ReadBulk { data } => {
-                    let _read = self
+                UsbStep::ReadBulk { data, phase } => {
+                    let read = self
                         .handle
                         .read_bulk(self.ep_in, data, Duration::from_secs(5))?;
+                    match phase {
+                        // A device reports a residue by sending less data than requested, which
+                        // still leaves its command status to be read
+                        BulkPhase::Data if read < data.len() => {
+                            short_read = Some((data.len(), read))
+                        }
+                        _ if read != data.len() => {
+                            return Err(Error::ShortTransfer {
+                                expected: data.len(),
+                                actual: read,
+                            });
+                        }
+                        _ => (),
+                    }
+                    if let (BulkPhase::Status, Some((expected, actual))) = (phase, short_read) {
+                        // The status must account for exactly the data the device didn't send
+                        let residue = CommandStatus::from_bytes(data).map(|csw| csw.residue);
+                        if residue.is_ok_and(|r| r as usize != expected - actual) {
+                            return Err(Error::ShortTransfer { expected, actual });
+                        }
+                    }
+                    if let Some(observer) = self.observer.as_mut() {
+                        observe_read(observer.as_mut(), phase, &data[..read]);
+                    }
+                    if let (BulkPhase::Status, Some((expected, actual))) = (phase, short_write) {
+                        return Err(Error::ShortTransfer { expected, actual });
+                    }
                 }
                 UsbStep::Finished(r) => break r.map_err(|e| e.into()),
                 UsbStep::WriteControl {
@@ -208,81 +900,796 @@ impl Transport {
                         data,
                         Duration::from_secs(5),
                     )?;
+                    if let Some(observer) = self.observer.as_mut() {
+                        observer.on_data(crate::protocol::Direction::Out, data);
+                    }
+                    self.pace_write(data.len());
                 }
             }
         }
     }
 
+    /// Check whether the usb loader is ready to handle commands
+    ///
+    /// Not retried, such that it can be used to check whether the loader is alive
+    pub fn test_unit_ready(&mut self) -> Result<()> {
+        self.handle_operation(crate::operation::test_unit_ready())
+            .map_err(|e| e.context(OperationContext::new("test_unit_ready")))
+    }
+
+    /// Time since the last operation finished
+    pub fn idle_time(&self) -> Duration {
+        self.last_active.elapsed()
+    }
+
+    /// Keep the loader alive during idle gaps in long running sessions
+    ///
+    /// Issues a test unit ready if no operation was done for at least `threshold`, as some loaders
+    /// drop their state or time out after a long time of inactivity. Meant to be called
+    /// periodically while the host is busy otherwise, e.g. decompressing an image. Returns whether
+    /// a command was issued
+    pub fn keep_alive(&mut self, threshold: Duration) -> Result<bool> {
+        if self.idle_time() < threshold {
+            return Ok(false);
+        }
+        self.test_unit_ready()?;
+        Ok(true)
+    }
+
     /// retrieve SoC flash identifier
     pub fn flash_id(&mut self) -> Result<FlashId> {
-        self.handle_operation(crate::operation::flash_id())
+        self.retried(false, |t| t.handle_operation(crate::operation::flash_id()))
+            .map_err(|e| e.context(OperationContext::new("flash_id")))
     }
 
     /// retrieve SoC flash info
+    ///
+    /// The flash info is only queried from the device once and cached afterwards, see
+    /// [Transport::refresh]
     pub fn flash_info(&mut self) -> Result<FlashInfo> {
-        self.handle_operation(crate::operation::flash_info())
+        if let Some(info) = self.flash_info {
+            return Ok(info);
+        }
+        let info = self
+            .retried(false, |t| {
+                t.handle_operation(crate::operation::flash_info())
+            })
+            .map_err(|e| e.context(OperationContext::new("flash_info")))?;
+        self.flash_info = Some(info);
+        Ok(info)
+    }
+
+    /// retrieve the capabilities of the usb loader
+    ///
+    /// The capabilities are only queried from the device once and cached afterwards, see
+    /// [Transport::refresh]
+    pub fn capability(&mut self) -> Result<Capability> {
+        if let Some(capability) = self.capability {
+            return Ok(capability);
+        }
+        let capability = self
+            .retried(false, |t| {
+                t.handle_operation(crate::operation::capability())
+            })
+            .map_err(|e| e.context(OperationContext::new("capability")))?;
+        self.capability = Some(capability);
+        Ok(capability)
+    }
+
+    /// Size of the flash in bytes if the flash info was retrieved before
+    pub fn size(&self) -> Option<u64> {
+        self.flash_info.map(|info| info.size())
+    }
+
+    /// Drop the cached device information and query the flash info again
+    ///
+    /// Should be used when the device state changed outside of this transport, e.g. after
+    /// switching storage media. The capabilities are queried again on next use
+    pub fn refresh(&mut self) -> Result<FlashInfo> {
+        self.flash_info = None;
+        self.capability = None;
+        self.flash_info()
+    }
+
+    /// retrieve the storage media currently in use
+    pub fn storage(&mut self) -> Result<Storage> {
+        self.retried(false, |t| {
+            t.handle_operation(crate::operation::read_storage())
+        })
+        .map_err(|e| e.context(OperationContext::new("storage")))
+    }
+
+    /// Switch the loader to the storage media `storage` (e.g. [Storage::SD])
+    ///
+    /// The switch is verified by reading back the storage in use, after which the flash info of
+    /// the new media is retrieved
+    pub fn change_storage(&mut self, storage: u8) -> Result<FlashInfo> {
+        self.flash_info = None;
+        self.capability = None;
+        self.retried(false, |t| {
+            t.handle_operation(crate::operation::change_storage(storage))
+        })
+        .map_err(|e| e.context(OperationContext::new("change_storage")))?;
+        let actual = self.storage()?.id();
+        if actual != Some(storage) {
+            return Err(Error::StorageMismatch {
+                expected: storage,
+                actual,
+            });
+        }
+        self.flash_info()
+    }
+
+    /// Run `f` with the loader switched to the storage media `storage`
+    ///
+    /// The original storage media is always restored afterwards, also when switching or `f`
+    /// failed. Errors of `f` take precedence over errors restoring the original media
+    pub fn with_storage<T>(
+        &mut self,
+        storage: u8,
+        f: impl FnOnce(&mut Self) -> Result<T>,
+    ) -> Result<T> {
+        let original = self.storage()?.id();
+        if original == Some(storage) {
+            return f(self);
+        }
+        let r = self.change_storage(storage).and_then(|_| f(self));
+        let restored = match original {
+            Some(original) => self.change_storage(original).map(|_| ()),
+            None => self.refresh().map(|_| ()),
+        };
+        let r = r?;
+        restored?;
+        Ok(r)
     }
 
     /// retrieve SoC chip info
     pub fn chip_info(&mut self) -> Result<ChipInfo> {
-        self.handle_operation(crate::operation::chip_info())
+        let info = self
+            .retried(false, |t| t.handle_operation(crate::operation::chip_info()))
+            .map_err(|e| e.context(OperationContext::new("chip_info")))?;
+        self.chip_info = Some(info);
+        Ok(info)
     }
 
     /// read from the flash
     ///
     /// start_sector with [SECTOR_SIZE] sectors. the data to be read
-    /// must be a multiple of [SECTOR_SIZE] bytes
+    /// must be a multiple of [SECTOR_SIZE] bytes. Reads bigger then
+    /// [Transport::max_transfer_sectors] are split up in multiple operations
+    ///
+    /// A read the device only partially completes fails with [Error::ShortTransfer]
     pub fn read_lba(&mut self, start_sector: u32, read: &mut [u8]) -> Result<u32> {
-        self.handle_operation(crate::operation::read_lba(start_sector, read))
-            .map(|t| t.into())
+        lba_end(start_sector, read.len())?;
+        let mut transferred = 0;
+        let max = self.max_transfer_size();
+        for (i, chunk) in read.chunks_mut(max).enumerate() {
+            let sector = start_sector + i as u32 * u32::from(self.max_transfer);
+            let len = chunk.len();
+            let t = self
+                .retried(false, |t| {
+                    t.handle_operation(crate::operation::read_lba(sector, &mut *chunk)?)
+                        .and_then(|r| full_read(r, len))
+                })
+                .map_err(|e| {
+                    let end = sector.saturating_add((len / SECTOR_SIZE as usize) as u32);
+                    e.context(OperationContext::with_sectors("read_lba", sector..end))
+                })?;
+            transferred += t;
+        }
+        self.stats.bytes_read += u64::from(transferred);
+        Ok(transferred)
     }
 
     /// Create operation to read an lba from the flash
     ///
     /// start_sector based on [SECTOR_SIZE] sectors. the data to be
-    /// written must be a multiple of [SECTOR_SIZE] bytes
+    /// written must be a multiple of [SECTOR_SIZE] bytes. Writes bigger then
+    /// [Transport::max_transfer_sectors] are split up in multiple operations
+    ///
+    /// If the device reports a residue, i.e. only part of the data landed, the write of the
+    /// remaining sectors is re-issued up to [MAX_RESIDUE_RETRIES] times, after which
+    /// [Error::PartialTransfer] reports how much of the data was written. Writes overlapping the
+    /// critical regions are refused, see [Transport::allow_critical_regions]
     pub fn write_lba(&mut self, start_sector: u32, write: &[u8]) -> Result<u32> {
-        self.handle_operation(crate::operation::write_lba(start_sector, write))
-            .map(|t| t.into())
+        let end = lba_end(start_sector, write.len())?;
+        self.check_critical(start_sector..end)?;
+        self.write_lba_unchecked(start_sector, write)
+    }
+
+    // Write to the flash without checking for critical regions
+    fn write_lba_unchecked(&mut self, start_sector: u32, write: &[u8]) -> Result<u32> {
+        lba_end(start_sector, write.len())?;
+        let mut transferred = 0;
+        let max = self.max_transfer_size();
+        for (i, chunk) in write.chunks(max).enumerate() {
+            let sector = start_sector + i as u32 * u32::from(self.max_transfer);
+            let end = sector.saturating_add((chunk.len() / SECTOR_SIZE as usize) as u32);
+            let mut done = 0;
+            let mut residue_retries = 0;
+            while done < chunk.len() {
+                let start = sector + (done / SECTOR_SIZE as usize) as u32;
+                let data = &chunk[done..];
+                let t: u32 = self
+                    .retried(true, |t| {
+                        t.handle_operation(crate::operation::write_lba(start, data)?)
+                    })
+                    .map_err(|e| {
+                        e.context(OperationContext::with_sectors("write_lba", start..end))
+                    })?
+                    .into();
+                if t as usize == data.len() {
+                    break;
+                }
+                // Only whole sectors are known to have landed; Re-issue the write for the rest
+                done += t as usize / SECTOR_SIZE as usize * SECTOR_SIZE as usize;
+                if residue_retries == MAX_RESIDUE_RETRIES {
+                    let written = transferred as usize + done;
+                    self.stats.bytes_written += written as u64;
+                    return Err(Error::PartialTransfer {
+                        written,
+                        requested: write.len(),
+                    }
+                    .context(OperationContext::with_sectors("write_lba", start..end)));
+                }
+                residue_retries += 1;
+                self.stats.retries += 1;
+            }
+            transferred += chunk.len() as u32;
+        }
+        self.stats.bytes_written += u64::from(transferred);
+        Ok(transferred)
+    }
+
+    /// Erase a range of sectors on the flash
+    ///
+    /// Erases bigger then [MAX_LBA_SECTORS] (or the maximum erase size of the device's
+    /// [Quirks]) are split up in multiple operations. Erases
+    /// overlapping the critical regions are refused, see [Transport::allow_critical_regions]
+    pub fn erase_lba(&mut self, start_sector: u32, sectors: u32) -> Result<()> {
+        self.check_critical(start_sector..sectors_end(start_sector, sectors.into())?)?;
+        let max = self
+            .quirks
+            .max_erase_sectors
+            .unwrap_or(MAX_LBA_SECTORS)
+            .max(1);
+        let end = u64::from(start_sector) + u64::from(sectors);
+        for sector in (u64::from(start_sector)..end).step_by(max.into()) {
+            let count = (end - sector).min(max.into()) as u16;
+            let sector = sector as u32;
+            self.retried(true, |t| {
+                t.handle_operation(crate::operation::erase_lba(sector, count))
+            })
+            .map_err(|e| {
+                let end = sector.saturating_add(count.into());
+                e.context(OperationContext::with_sectors("erase_lba", sector..end))
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Erase a range of sectors and read them back to confirm the erase
+    ///
+    /// Returns the sectors that still hold data, i.e. don't read back as all 0x00 or all 0xff
+    /// (depending on the media); An empty list means the whole range was erased. The range is read
+    /// back in chunks of up to [Transport::max_transfer_sectors] sectors
+    pub fn erase_verified(
+        &mut self,
+        sectors: std::ops::Range<u32>,
+    ) -> Result<Vec<std::ops::Range<u32>>> {
+        self.erase_lba(sectors.start, sectors.len() as u32)?;
+        let mut buffer = vec![0u8; self.max_transfer_size()];
+        let mut unerased = Vec::new();
+        for sector in sectors.clone().step_by(self.max_transfer.into()) {
+            let chunk = (sectors.end - sector).min(self.max_transfer.into()) as usize;
+            let data = &mut buffer[..chunk * SECTOR_SIZE as usize];
+            self.read_lba(sector, data)?;
+            collect_unerased(data, sector, &mut unerased);
+        }
+        Ok(unerased)
+    }
+
+    /// Write data to one of the standard flash regions
+    ///
+    /// The data is padded with zeros to a sector boundary; Data that's empty or doesn't fit the
+    /// region is rejected
+    pub fn write_region(&mut self, region: Region, data: &[u8]) -> Result<()> {
+        if data.is_empty() || data.len() > region.size() as usize * SECTOR_SIZE as usize {
+            return Err(Error::InvalidRegionSize {
+                region,
+                size: data.len(),
+            });
+        }
+        let padded = data.len().next_multiple_of(SECTOR_SIZE as usize);
+        if padded == data.len() {
+            self.write_lba_unchecked(region.offset(), data)?;
+        } else {
+            let mut buffer = data.to_vec();
+            buffer.resize(padded, 0);
+            self.write_lba_unchecked(region.offset(), &buffer)?;
+        }
+        Ok(())
+    }
+
+    /// Read from the first 4MiB of the flash at a byte offset
+    ///
+    /// Only available if the loader reports [Capability::first_4m_access]
+    pub fn read_first_4m(&mut self, offset: u32, read: &mut [u8]) -> Result<()> {
+        let sectors = self.first_4m_sectors(offset, read.len())?;
+        let mut data = vec![0; sectors.len() * SECTOR_SIZE as usize];
+        self.read_lba(sectors.start, &mut data)?;
+        let start = (offset % SECTOR_SIZE as u32) as usize;
+        read.copy_from_slice(&data[start..start + read.len()]);
+        Ok(())
+    }
+
+    /// Write to the first 4MiB of the flash at a byte offset
+    ///
+    /// Data surrounding the written range in partially written sectors is preserved. Only
+    /// available if the loader reports [Capability::first_4m_access]; Meant for IDBlock and
+    /// bootloader surgery, so writes are allowed to touch the critical regions
+    pub fn write_first_4m(&mut self, offset: u32, write: &[u8]) -> Result<()> {
+        let sectors = self.first_4m_sectors(offset, write.len())?;
+        let mut data = vec![0; sectors.len() * SECTOR_SIZE as usize];
+        let start = (offset % SECTOR_SIZE as u32) as usize;
+        if start != 0 || data.len() != write.len() {
+            self.read_lba(sectors.start, &mut data)?;
+        }
+        data[start..start + write.len()].copy_from_slice(write);
+        self.write_lba_unchecked(sectors.start, &data)?;
+        Ok(())
+    }
+
+    // Sectors covering a byte range in the first 4MiB, if the loader allows accessing it
+    fn first_4m_sectors(&mut self, offset: u32, len: usize) -> Result<std::ops::Range<u32>> {
+        if !self.capability()?.first_4m_access() {
+            return Err(Error::Unsupported("first 4MiB access"));
+        }
+        let end = u64::from(offset) + len as u64;
+        if end > u64::from(FIRST_4M_SECTORS) * SECTOR_SIZE {
+            return Err(Error::OutOfBounds { offset, len });
+        }
+        Ok(offset / SECTOR_SIZE as u32..end.div_ceil(SECTOR_SIZE) as u32)
+    }
+
+    /// Write an IDBlock (e.g. idbloader.img) to the standard offset
+    pub fn write_idblock(&mut self, data: &[u8]) -> Result<()> {
+        self.write_region(Region::IdBlock, data)
+    }
+
+    /// Write U-Boot (e.g. uboot.img or u-boot.itb) to the standard offset
+    pub fn write_uboot(&mut self, itb: &[u8]) -> Result<()> {
+        self.write_region(Region::UBoot, itb)
+    }
+
+    /// Write the trusted firmware (trust.img) to the standard offset
+    pub fn write_trust(&mut self, img: &[u8]) -> Result<()> {
+        self.write_region(Region::Trust, img)
+    }
+
+    /// Write data pulled from a reader to a range of sectors
+    ///
+    /// Writing stops once the reader is exhausted or the end of the range is reached; a partial
+    /// last sector is padded with zeros. As the data is consumed from the reader, failed writes
+    /// are not retried; Writes the device only partially completes are re-issued for the
+    /// remaining sectors up to [MAX_RESIDUE_RETRIES] times. Returns the number of bytes taken from
+    /// the reader
+    ///
+    /// The whole range is refused if it overlaps the critical regions, see
+    /// [Transport::allow_critical_regions]
+    pub fn write_lba_from_reader(
+        &mut self,
+        sectors: std::ops::Range<u32>,
+        reader: &mut (dyn std::io::Read + Send),
+    ) -> Result<u64> {
+        self.check_critical(sectors.clone())?;
+        let context = OperationContext::with_sectors("write_lba_from_reader", sectors.clone());
+        let mut operation = crate::operation::write_lba_from_reader(sectors, reader);
+        let r = self.handle_operation(&mut operation);
+        self.stats.bytes_written += operation.written();
+        r.map_err(|e| e.context(context))
+    }
+
+    /// Write a specific area while in maskrom mode; typically [Area::Sram] or [Area::Ddr] data as
+    /// retrieved from a rockchip boot file
+    pub fn write_maskrom_area(&mut self, area: Area, data: &[u8]) -> Result<()> {
+        self.handle_operation(
+            crate::operation::write_area(area, data).with_encoding(self.maskrom_encoding),
+        )
+        .map_err(|e| e.context(OperationContext::new("write_maskrom_area")))?;
+        self.stats.bytes_written += data.len() as u64;
+        Ok(())
+    }
+
+    /// Write a specific area while in maskrom mode with data pulled from a reader
+    ///
+    /// Unlike [Transport::write_maskrom_area] the data doesn't need to be fully buffered
+    pub fn write_maskrom_area_from_reader(
+        &mut self,
+        area: Area,
+        reader: &mut (dyn std::io::Read + Send),
+    ) -> Result<()> {
+        let mut operation = crate::operation::write_area_from_reader(area, reader)
+            .with_encoding(self.maskrom_encoding);
+        let r = self.handle_operation(&mut operation);
+        self.stats.bytes_written += operation.written() as u64;
+        r.map_err(|e| e.context(OperationContext::new("write_maskrom_area")))
+    }
+
+    /// Download a boot file (e.g. as created by boot_merger) to a device in maskrom mode
+    ///
+    /// The SRAM (0x471) and DDR (0x472) entries are written using
+    /// [Transport::write_maskrom_area], waiting for the delay of each entry plus the download
+    /// delay quirk of the device afterwards. Loader entries are skipped
+    pub fn download_boot(&mut self, boot: &BootFile) -> Result<()> {
+        for (kind, entry) in boot.all_entries() {
+            let area = match kind {
+                EntryKind::Sram471 => Area::Sram,
+                EntryKind::Ddr472 => Area::Ddr,
+                EntryKind::Loader => continue,
+            };
+            let data = boot.entry_data(&entry).ok_or(Error::OutOfBounds {
+                offset: entry.data_offset,
+                len: entry.data_size as usize,
+            })?;
+            self.write_maskrom_area(area, data)?;
+            std::thread::sleep(
+                Duration::from_millis(entry.data_delay.into()) + self.quirks.download_delay,
+            );
+        }
+        Ok(())
+    }
+
+    /// Read the primary GPT from the flash
+    pub fn read_gpt(&mut self) -> std::result::Result<Gpt, GptError> {
+        block_on(runner::read_gpt(self))
+    }
+
+    /// Execute a flash plan
+    ///
+    /// `progress` is called while executing the steps of the plan. Execution stops at the first
+    /// step that fails
+    pub fn run_plan<P>(
+        &mut self,
+        plan: &FlashPlan,
+        mut progress: P,
+    ) -> std::result::Result<FlashReport, FlashError>
+    where
+        P: FnMut(&Progress),
+    {
+        block_on(runner::run_plan(self, plan, None, &mut progress))
+    }
+
+    /// Execute a flash plan, recording progress in a journal
+    ///
+    /// Steps the journal records as complete are skipped and interrupted image writes are resumed
+    /// from the last recorded chunk. Skipped steps are reported as having processed 0 bytes
+    pub fn run_plan_journaled<P>(
+        &mut self,
+        plan: &FlashPlan,
+        journal: &mut Journal,
+        mut progress: P,
+    ) -> std::result::Result<FlashReport, FlashError>
+    where
+        P: FnMut(&Progress),
+    {
+        block_on(runner::run_plan(self, plan, Some(journal), &mut progress))
     }
 
-    /// Write a specific area while in maskrom mode; typically 0x471 or 0x472 data as retrieved from a
-    /// rockchip boot file
-    pub fn write_maskrom_area(&mut self, area: u16, data: &[u8]) -> Result<()> {
-        self.handle_operation(crate::operation::write_area(area, data))
+    /// Repair the GPT on the flash
+    ///
+    /// The primary header and partition entries are validated, falling back to the backup at the
+    /// end of the flash if they're corrupted. The table is then written back with recalculated
+    /// CRCs and the backup at the true end of the flash, e.g. to fix up an image built for
+    /// smaller media; A valid backup found elsewhere is cleared
+    pub fn repair_gpt(&mut self) -> std::result::Result<GptRepair, GptError> {
+        block_on(runner::repair_gpt(self))
+    }
+
+    /// Fix up the GPT after writing a disk image smaller than the flash
+    ///
+    /// The backup GPT is moved to the end of the flash and, if `grow_last` is set, the last
+    /// partition is grown to fill the remaining space. Returns the updated partition table
+    pub fn fixup_gpt_after_image(&mut self, grow_last: bool) -> std::result::Result<Gpt, GptError> {
+        block_on(runner::fixup_gpt_after_image(self, grow_last))
     }
 
     /// Reset the device
     pub fn reset_device(&mut self, opcode: ResetOpcode) -> Result<()> {
+        self.flash_info = None;
+        self.capability = None;
         self.handle_operation(crate::operation::reset_device(opcode))
+            .map_err(|e| e.context(OperationContext::new("reset_device")))
+    }
+
+    /// Upload code to the sdram and jump to it
+    ///
+    /// The payload is written to `address`, relative to the start of the sdram as used by the
+    /// loader, in chunks of at most [SDRAM_CHUNK_SIZE] bytes; The address must be aligned to
+    /// [SDRAM_ALIGNMENT] bytes and the payload is padded with zeros to a multiple of it. Useful
+    /// for running DDR testers or custom stage-2 tools from a loader
+    pub fn run_code(&mut self, address: u32, payload: &[u8]) -> Result<()> {
+        if address & (SDRAM_ALIGNMENT - 1) != 0 {
+            return Err(Error::UnalignedAddress {
+                address,
+                alignment: SDRAM_ALIGNMENT,
+            });
+        }
+        let len = payload.len().next_multiple_of(SDRAM_ALIGNMENT as usize);
+        if u64::from(address) + len as u64 > u64::from(u32::MAX) + 1 {
+            return Err(Error::OutOfBounds {
+                offset: address,
+                len: payload.len(),
+            });
+        }
+        let mut padded = payload.to_vec();
+        padded.resize(len, 0);
+        for (i, chunk) in padded.chunks(SDRAM_CHUNK_SIZE).enumerate() {
+            let chunk_address = address + (i * SDRAM_CHUNK_SIZE) as u32;
+            let written: u32 = self
+                .handle_operation(crate::operation::write_sdram(chunk_address, chunk)?)
+                .map_err(|e| e.context(OperationContext::new("write_sdram")))?
+                .into();
+            if written as usize != chunk.len() {
+                return Err(Error::ShortTransfer {
+                    expected: chunk.len(),
+                    actual: written as usize,
+                });
+            }
+        }
+        // The uploaded code may take over from the loader
+        self.flash_info = None;
+        self.capability = None;
+        self.handle_operation(crate::operation::execute_sdram(address))
+            .map_err(|e| e.context(OperationContext::new("execute_sdram")))
+    }
+
+    /// Write `len` bytes from a reader to the flash starting at start_sector
+    ///
+    /// The data is written in chunks of up to [Transport::max_transfer_sectors] sectors, aligned
+    /// to the erase block size of the flash where possible. If `len` isn't a multiple of [SECTOR_SIZE] the
+    /// remainder of the last sector is preserved by reading it back from the flash first. After
+    /// each chunk `progress` is called with the total number of bytes written
+    pub fn write_from_reader<R, P>(
+        &mut self,
+        start_sector: u32,
+        mut reader: R,
+        len: u64,
+        mut progress: P,
+    ) -> std::io::Result<()>
+    where
+        R: Read,
+        P: FnMut(u64),
+    {
+        let info = self.flash_info()?;
+        let mut buffer = vec![0u8; self.max_transfer_size()];
+        let mut sector = start_sector;
+        let mut written = 0;
+        while written < len {
+            let sectors = (len - written)
+                .div_ceil(SECTOR_SIZE)
+                .min(self.max_transfer.into()) as u32;
+            let sectors = info.block_aligned_sectors(sector, sectors);
+            let chunk = (len - written).min(u64::from(sectors) * SECTOR_SIZE) as usize;
+            let padded = chunk.next_multiple_of(SECTOR_SIZE as usize);
+            if padded != chunk {
+                let last = padded - SECTOR_SIZE as usize;
+                let last_sector = sector + (last / SECTOR_SIZE as usize) as u32;
+                self.read_lba(last_sector, &mut buffer[last..padded])?;
+            }
+            reader.read_exact(&mut buffer[..chunk])?;
+            self.write_lba(sector, &buffer[..padded])?;
+            sector += (padded / SECTOR_SIZE as usize) as u32;
+            written += chunk as u64;
+            progress(written);
+        }
+        Ok(())
+    }
+
+    /// Read a range of sectors from the flash into a writer
+    ///
+    /// The data is read in chunks of up to [Transport::max_transfer_sectors] sectors. After each
+    /// chunk `progress` is called with the total number of bytes read
+    pub fn read_to_writer<W, P>(
+        &mut self,
+        sectors: std::ops::Range<u32>,
+        mut writer: W,
+        mut progress: P,
+    ) -> std::io::Result<()>
+    where
+        W: Write,
+        P: FnMut(u64),
+    {
+        let mut buffer = vec![0u8; self.max_transfer_size()];
+        let mut read = 0;
+        for sector in sectors.clone().step_by(self.max_transfer.into()) {
+            let chunk = (sectors.end - sector).min(self.max_transfer.into()) as usize;
+            let chunk = &mut buffer[..chunk * SECTOR_SIZE as usize];
+            self.read_lba(sector, chunk)?;
+            writer.write_all(chunk)?;
+            read += chunk.len() as u64;
+            progress(read);
+        }
+        Ok(())
+    }
+
+    // Fill the remainder of the partial last sector of `buffer`, which holds `len` bytes of data
+    // to be written at start_sector, from the flash
+    fn read_tail(&mut self, start_sector: u32, buffer: &mut [u8], len: usize) -> Result<()> {
+        let last = buffer.len() - SECTOR_SIZE as usize;
+        let mut tail = [0; SECTOR_SIZE as usize];
+        self.read_lba(
+            start_sector + (last / SECTOR_SIZE as usize) as u32,
+            &mut tail,
+        )?;
+        buffer[len..].copy_from_slice(&tail[len - last..]);
+        Ok(())
+    }
+
+    /// Write all data from a reader to the flash starting at start_sector
+    ///
+    /// Reading the next chunk from the reader (e.g. decompressing) is done in a separate thread
+    /// while the current chunk is being transferred to the device. Chunks are up to
+    /// [Transport::max_transfer_sectors] sectors. If the data isn't a multiple of [SECTOR_SIZE]
+    /// the remainder of the last sector is preserved by reading it back from the flash first.
+    /// Returns the number of bytes read from the reader.
+    pub fn write_stream<R>(&mut self, start_sector: u32, mut reader: R) -> std::io::Result<u64>
+    where
+        R: Read + Send,
+    {
+        let (filled_tx, filled_rx) = std::sync::mpsc::sync_channel(STREAM_BUFFERS);
+        let (empty_tx, empty_rx) = std::sync::mpsc::sync_channel(STREAM_BUFFERS);
+        for _ in 0..STREAM_BUFFERS {
+            let _ = empty_tx.send(vec![0u8; self.max_transfer_size()]);
+        }
+
+        std::thread::scope(|s| {
+            s.spawn(move || {
+                while let Ok(mut buffer) = empty_rx.recv() {
+                    let r = read_full(&mut reader, &mut buffer);
+                    let done = !matches!(r, Ok(len) if len == buffer.len());
+                    if filled_tx.send(r.map(|len| (buffer, len))).is_err() || done {
+                        break;
+                    }
+                }
+            });
+
+            // Make sure the empty buffer channel gets closed when bailing out early so the
+            // reader thread finishes
+            let empty_tx = empty_tx;
+            let mut sector = start_sector;
+            let mut written = 0;
+            while let Ok(r) = filled_rx.recv() {
+                let (mut buffer, len) = r?;
+                if len == 0 {
+                    break;
+                }
+                let padded = len.next_multiple_of(SECTOR_SIZE as usize);
+                if padded != len {
+                    self.read_tail(sector, &mut buffer[..padded], len)?;
+                }
+                self.write_lba(sector, &buffer[..padded])
+                    .map_err(std::io::Error::from)?;
+                sector += (padded / SECTOR_SIZE as usize) as u32;
+                written += len as u64;
+                // Receiving side is gone once the reader hits the end of the stream
+                let _ = empty_tx.send(buffer);
+            }
+            Ok(written)
+        })
     }
 }
 
-/// IO object which implements [Read], [Write] and [Seek]
+impl FlashDevice for Transport {
+    fn max_transfer_size(&self) -> usize {
+        Transport::max_transfer_size(self)
+    }
+
+    fn to_lba(sector: u64) -> std::io::Result<u32> {
+        Ok(to_lba(sector)?)
+    }
+
+    async fn flash_info(&mut self) -> std::io::Result<FlashInfo> {
+        Ok(Transport::flash_info(self)?)
+    }
+
+    async fn read_lba(&mut self, start_sector: u32, data: &mut [u8]) -> std::io::Result<()> {
+        Transport::read_lba(self, start_sector, data)?;
+        Ok(())
+    }
+
+    async fn write_lba_unchecked(&mut self, start_sector: u32, data: &[u8]) -> std::io::Result<()> {
+        Transport::write_lba_unchecked(self, start_sector, data)?;
+        Ok(())
+    }
+
+    async fn erase_lba(&mut self, start_sector: u32, sectors: u32) -> std::io::Result<()> {
+        Ok(Transport::erase_lba(self, start_sector, sectors)?)
+    }
+
+    async fn reset_device(&mut self, opcode: ResetOpcode) -> std::io::Result<()> {
+        Ok(Transport::reset_device(self, opcode)?)
+    }
+
+    async fn write_from_reader(
+        &mut self,
+        start_sector: u32,
+        reader: &mut (dyn Read + Send),
+        len: u64,
+        progress: &mut dyn FnMut(u64),
+    ) -> std::io::Result<()> {
+        Transport::write_from_reader(self, start_sector, reader, len, progress)
+    }
+}
+
+// Number of buffers used for streaming writes
+const STREAM_BUFFERS: usize = 2;
+
+// Read until the buffer is full or the end of the stream is reached
+fn read_full<R: Read>(reader: &mut R, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// IO object which implements [Read], [BufRead], [Write] and [Seek]
+///
+/// The [BufRead] implementation exposes the internal sector buffer, so parsers reading small
+/// structures can use it without adding another layer of buffering
 pub struct TransportIO<T> {
     transport: T,
     size: u64,
+    // Maximum size of a single direct I/O operation in bytes
+    maxio_size: u64,
     // Read/Write offset in bytes
     offset: u64,
     buffer: [u8; 512],
     // Whether or not the buffer is dirty
     state: BufferState,
+    // Failure writing out the buffer when consuming past it, reported by the next operation
+    error: Option<std::io::Error>,
 }
 
 impl<T> TransportIO<T>
 where
     T: BorrowMut<Transport>,
 {
-    const MAXIO_SIZE: u64 = 128 * crate::protocol::SECTOR_SIZE;
+    /// Maximum size of a single direct I/O operation in bytes for full speed devices; Faster
+    /// devices use bigger operations by default, see [Transport::max_transfer_sectors]
+    pub const DEFAULT_MAXIO_SIZE: u64 = 128 * crate::protocol::SECTOR_SIZE;
+
     /// Create a new IO object around a given transport
-    pub fn new(mut transport: T) -> Result<Self> {
+    ///
+    /// The maximum size of direct I/O operations is the transport's
+    /// [Transport::max_transfer_sectors]
+    pub fn new(transport: T) -> Result<Self> {
+        let maxio_size = u64::from(transport.borrow().max_transfer_sectors()) * SECTOR_SIZE;
+        Self::new_with_maxio_size(transport, maxio_size)
+    }
+
+    /// Create a new IO object around a given transport using a specific maximum size for
+    /// single direct I/O operations
+    ///
+    /// The size is rounded down to a multiple of [SECTOR_SIZE] and clamped between one sector and
+    /// [MAX_LBA_SECTORS] sectors
+    pub fn new_with_maxio_size(mut transport: T, maxio_size: u64) -> Result<Self> {
         let info = transport.borrow_mut().flash_info()?;
         Ok(Self {
             transport,
             size: info.size(),
+            maxio_size: (maxio_size / SECTOR_SIZE * SECTOR_SIZE)
+                .clamp(SECTOR_SIZE, MAX_LBA_CHUNK as u64),
             offset: 0,
             buffer: [0u8; 512],
             state: BufferState::Invalid,
+            error: None,
         })
     }
 
@@ -301,12 +1708,22 @@ where
         self.size
     }
 
+    /// Maximum size of a single direct I/O operation in bytes
+    pub fn maxio_size(&self) -> u64 {
+        self.maxio_size
+    }
+
     fn current_sector(&self) -> u64 {
         self.offset / SECTOR_SIZE
     }
 
+    fn take_error(&mut self) -> std::io::Result<()> {
+        self.error.take().map_or(Ok(()), Err)
+    }
+
     // Want to start an i/o operation with a given maximum length
     fn pre_io(&mut self, len: u64) -> std::result::Result<IOOperation, std::io::Error> {
+        self.take_error()?;
         if self.offset >= self.size {
             return Ok(IOOperation::Eof);
         }
@@ -321,9 +1738,17 @@ where
         if sector_offset == 0 && len >= SECTOR_SIZE {
             // At most read the amount of bytes left
             let left = self.size - self.offset;
-            let io_len = len.min(left) / SECTOR_SIZE * SECTOR_SIZE;
+            let sectors = len.min(left).min(self.maxio_size) / SECTOR_SIZE;
+            // End on an erase block boundary if possible so following operations are aligned;
+            // The flash info is cached by the transport
+            let info = self
+                .transport
+                .borrow_mut()
+                .flash_info()
+                .map_err(std::io::Error::from)?;
+            let sectors = info.block_aligned_sectors(self.current_sector() as u32, sectors as u32);
             Ok(IOOperation::Direct {
-                len: io_len.min(Self::MAXIO_SIZE) as usize,
+                len: (u64::from(sectors) * SECTOR_SIZE) as usize,
             })
         } else {
             if self.state == BufferState::Invalid {
@@ -331,7 +1756,7 @@ where
                 self.transport
                     .borrow_mut()
                     .read_lba(sector, &mut self.buffer)
-                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::BrokenPipe, e))?;
+                    .map_err(std::io::Error::from)?;
                 self.state = BufferState::Valid;
             }
             Ok(IOOperation::Buffered {
@@ -362,7 +1787,7 @@ where
             self.transport
                 .borrow_mut()
                 .write_lba(sector, &self.buffer)
-                .map_err(|e| std::io::Error::new(std::io::ErrorKind::BrokenPipe, e))?;
+                .map_err(std::io::Error::from)?;
             self.state = BufferState::Valid;
         }
         Ok(())
@@ -373,7 +1798,7 @@ where
         self.transport
             .borrow_mut()
             .read_lba(sector, buf)
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::BrokenPipe, e))?;
+            .map_err(std::io::Error::from)?;
         Ok(buf.len())
     }
 
@@ -382,7 +1807,7 @@ where
         self.transport
             .borrow_mut()
             .write_lba(sector, buf)
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::BrokenPipe, e))?;
+            .map_err(std::io::Error::from)?;
         Ok(buf.len())
     }
 }
@@ -416,16 +1841,14 @@ where
                 len
             }
             IOOperation::Eof => {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    "Trying to write past end of area",
-                ))
+                return Err(std::io::Error::other("Trying to write past end of area"))
             }
         };
         self.post_io(r as u64)
     }
 
     fn flush(&mut self) -> std::io::Result<()> {
+        self.take_error()?;
         self.flush_buffer()
     }
 }
@@ -447,12 +1870,49 @@ where
     }
 }
 
+impl<T> BufRead for TransportIO<T>
+where
+    T: BorrowMut<Transport>,
+{
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        self.take_error()?;
+        if self.offset >= self.size {
+            return Ok(&[]);
+        }
+        // A dirty buffer already holds the current sector including the pending data
+        if self.state == BufferState::Invalid {
+            let sector = self.current_sector() as u32;
+            self.transport
+                .borrow_mut()
+                .read_lba(sector, &mut self.buffer)
+                .map_err(std::io::Error::from)?;
+            self.state = BufferState::Valid;
+        }
+        Ok(&self.buffer[(self.offset % SECTOR_SIZE) as usize..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        let offset = (self.offset + amt as u64).min(self.size);
+        if offset / SECTOR_SIZE != self.current_sector() {
+            // Write out pending data before leaving the sector; consume can't fail, so errors
+            // are reported by the next operation and the offset is kept
+            if let Err(e) = self.flush_buffer() {
+                self.error = Some(e);
+                return;
+            }
+            self.state = BufferState::Invalid;
+        }
+        self.offset = offset;
+    }
+}
+
 impl<T> Seek for TransportIO<T>
 where
     T: BorrowMut<Transport>,
 {
     fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
-        self.offset = match pos {
+        self.take_error()?;
+        let offset = match pos {
             SeekFrom::Start(offset) => self.size.min(offset),
             SeekFrom::End(offset) => {
                 if offset > 0 {
@@ -472,6 +1932,12 @@ where
                 }
             }
         };
+        // The buffer only holds the current sector
+        if offset / SECTOR_SIZE != self.current_sector() {
+            self.flush_buffer()?;
+            self.state = BufferState::Invalid;
+        }
+        self.offset = offset;
         Ok(self.offset)
     }
 }
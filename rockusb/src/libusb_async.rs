@@ -0,0 +1,127 @@
+use std::sync::mpsc;
+use std::thread::JoinHandle;
+
+use futures::channel::oneshot;
+
+use crate::flasher::{FlashError, FlashPlan, FlashReport};
+use crate::libusb::{Error, Transport};
+use crate::protocol::{Capability, ChipInfo, FlashId, FlashInfo, ResetOpcode, Storage};
+
+type Result<T> = std::result::Result<T, Error>;
+type Job = Box<dyn FnOnce(&mut Transport) + Send>;
+
+/// Async interface to a libusb [Transport]
+///
+/// The transport is moved to a dedicated thread which executes all operations, so async
+/// applications can use libusb devices without blocking their executor. Operations are executed
+/// in the order they're issued. Data buffers are passed by value as they have to be moved to the
+/// transport thread
+pub struct AsyncTransport {
+    jobs: Option<mpsc::Sender<Job>>,
+    thread: Option<JoinHandle<Transport>>,
+}
+
+impl AsyncTransport {
+    /// Move a transport to its own thread
+    ///
+    /// The thread ends once the [AsyncTransport] is dropped and all pending operations are done
+    pub fn new(mut transport: Transport) -> Self {
+        let (jobs, receiver) = mpsc::channel::<Job>();
+        let thread = std::thread::spawn(move || {
+            for job in receiver {
+                job(&mut transport);
+            }
+            transport
+        });
+        AsyncTransport {
+            jobs: Some(jobs),
+            thread: Some(thread),
+        }
+    }
+
+    /// Run a closure with the transport on the transport thread
+    pub async fn run<F, T>(&self, f: F) -> T
+    where
+        F: FnOnce(&mut Transport) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (result, receiver) = oneshot::channel();
+        self.jobs
+            .as_ref()
+            .expect("Transport thread is running")
+            .send(Box::new(move |transport| {
+                // The caller may have stopped waiting for the result
+                let _ = result.send(f(transport));
+            }))
+            .expect("Transport thread is running");
+        receiver.await.expect("Transport thread panicked")
+    }
+
+    /// Stop the transport thread, returning the transport
+    ///
+    /// Blocks until all operations issued before are finished
+    pub fn into_inner(mut self) -> Transport {
+        self.jobs = None;
+        self.thread
+            .take()
+            .expect("Transport thread is running")
+            .join()
+            .expect("Transport thread panicked")
+    }
+
+    /// Check the device is ready, see [Transport::test_unit_ready]
+    pub async fn test_unit_ready(&self) -> Result<()> {
+        self.run(|t| t.test_unit_ready()).await
+    }
+
+    /// retrieve SoC flash identifier
+    pub async fn flash_id(&self) -> Result<FlashId> {
+        self.run(|t| t.flash_id()).await
+    }
+
+    /// retrieve SoC flash info
+    pub async fn flash_info(&self) -> Result<FlashInfo> {
+        self.run(|t| t.flash_info()).await
+    }
+
+    /// retrieve SoC chip info
+    pub async fn chip_info(&self) -> Result<ChipInfo> {
+        self.run(|t| t.chip_info()).await
+    }
+
+    /// retrieve the capabilities of the usb loader
+    pub async fn capability(&self) -> Result<Capability> {
+        self.run(|t| t.capability()).await
+    }
+
+    /// retrieve the currently selected storage
+    pub async fn storage(&self) -> Result<Storage> {
+        self.run(|t| t.storage()).await
+    }
+
+    /// Read from the flash into `buffer` starting at `start_sector`, returning the filled buffer
+    pub async fn read_lba(&self, start_sector: u32, mut buffer: Vec<u8>) -> Result<Vec<u8>> {
+        self.run(move |t| t.read_lba(start_sector, &mut buffer).map(|_| buffer))
+            .await
+    }
+
+    /// Write `data` to the flash starting at `start_sector`
+    pub async fn write_lba(&self, start_sector: u32, data: Vec<u8>) -> Result<u32> {
+        self.run(move |t| t.write_lba(start_sector, &data)).await
+    }
+
+    /// Erase `sectors` sectors starting at `start_sector`
+    pub async fn erase_lba(&self, start_sector: u32, sectors: u32) -> Result<()> {
+        self.run(move |t| t.erase_lba(start_sector, sectors)).await
+    }
+
+    /// Execute a flash plan, see [Transport::run_plan]
+    pub async fn run_plan(&self, plan: FlashPlan) -> std::result::Result<FlashReport, FlashError> {
+        self.run(move |t| t.run_plan(&plan, |_| {})).await
+    }
+
+    /// Reset the device
+    pub async fn reset_device(&self, opcode: ResetOpcode) -> Result<()> {
+        self.run(move |t| t.reset_device(opcode)).await
+    }
+}
@@ -0,0 +1,385 @@
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::flasher::{hex, FlashPlan, Image, Target};
+use crate::protocol::ResetOpcode;
+
+#[derive(Debug, Error)]
+pub enum ManifestError {
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("Image {0} has no target; Either offset or partition should be set")]
+    NoTarget(PathBuf),
+    #[error("Image {0} has both an offset and a partition as target")]
+    AmbiguousTarget(PathBuf),
+    #[error("Invalid sha256 hash for image {0}")]
+    InvalidHash(PathBuf),
+    #[error("Hash mismatch for image {path}: expected {expected}, got {actual}")]
+    HashMismatch {
+        path: PathBuf,
+        expected: String,
+        actual: String,
+    },
+    #[error("Compression {0:?} not supported")]
+    UnsupportedCompression(Compression),
+    #[error("Expected hash {0} should have either an offset or a partition as target")]
+    InvalidHashTarget(usize),
+    #[error("Expected hash {0} has an invalid sha256")]
+    InvalidExpectedHash(usize),
+}
+
+/// Compression of an image file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+}
+
+/// Action to execute after all images are flashed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PostAction {
+    /// Reset the device
+    Reset,
+    /// Reset the device into maskrom mode
+    ResetMaskrom,
+    /// Power off the device
+    PowerOff,
+    /// Disconnect the device from USB
+    Disconnect,
+}
+
+impl PostAction {
+    pub fn opcode(self) -> ResetOpcode {
+        match self {
+            PostAction::Reset => ResetOpcode::Reset,
+            PostAction::ResetMaskrom => ResetOpcode::Maskrom,
+            PostAction::PowerOff => ResetOpcode::PowerOff,
+            PostAction::Disconnect => ResetOpcode::Disconnect,
+        }
+    }
+}
+
+/// Image to flash as described in a [Manifest]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ManifestImage {
+    /// Path of the image file; Relative paths are relative to the manifest
+    pub path: PathBuf,
+    /// Start sector to write the image to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub offset: Option<u32>,
+    /// Partition to write the image to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub partition: Option<String>,
+    #[serde(default)]
+    pub compression: Compression,
+    /// Expected sha256 of the image file as hex string
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// Read back the image after writing it
+    #[serde(default)]
+    pub verify: bool,
+}
+
+impl ManifestImage {
+    /// Target on the flash of the image
+    pub fn target(&self) -> Result<Target, ManifestError> {
+        match (self.offset, &self.partition) {
+            (Some(offset), None) => Ok(Target::Sector(offset)),
+            (None, Some(partition)) => Ok(Target::Partition(partition.clone())),
+            (None, None) => Err(ManifestError::NoTarget(self.path.clone())),
+            (Some(_), Some(_)) => Err(ManifestError::AmbiguousTarget(self.path.clone())),
+        }
+    }
+
+    fn image(&self, base: &Path) -> Result<Image, ManifestError> {
+        let path = base.join(&self.path);
+        match self.compression {
+            Compression::None => Ok(Image::File(path)),
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => Ok(Image::GzipFile(path)),
+            #[cfg(not(feature = "gzip"))]
+            c => Err(ManifestError::UnsupportedCompression(c)),
+        }
+    }
+
+    fn check_hash(&self, base: &Path) -> Result<(), ManifestError> {
+        let Some(expected) = &self.sha256 else {
+            return Ok(());
+        };
+        let expected = expected.to_ascii_lowercase();
+        if parse_sha256(&expected).is_none() {
+            return Err(ManifestError::InvalidHash(self.path.clone()));
+        }
+
+        let mut file = std::fs::File::open(base.join(&self.path))?;
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let read = file.read(&mut buf)?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buf[..read]);
+        }
+        let actual = hex(&hasher.finalize());
+        if actual != expected {
+            return Err(ManifestError::HashMismatch {
+                path: self.path.clone(),
+                expected,
+                actual,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Declarative description of how to flash a device
+///
+/// A manifest can be stored in any format supported by serde, e.g. TOML:
+/// ```toml
+/// post = ["reset"]
+/// erase_before_write = true
+///
+/// [[image]]
+/// path = "idbloader.img"
+/// offset = 0x40
+/// sha256 = "..."
+///
+/// [[image]]
+/// path = "rootfs.img.gz"
+/// partition = "rootfs"
+/// compression = "gzip"
+/// verify = true
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    /// Images to flash, in order
+    #[serde(default, rename = "image")]
+    pub images: Vec<ManifestImage>,
+    /// Actions to execute after flashing
+    #[serde(default)]
+    pub post: Vec<PostAction>,
+    /// Erase the target sectors of images right before writing them
+    #[serde(default)]
+    pub erase_before_write: bool,
+}
+
+impl Manifest {
+    /// Convert into a [FlashPlan], resolving image paths relative to `base`
+    ///
+    /// The hashes of all images are checked before the plan is created
+    pub fn to_plan(&self, base: &Path) -> Result<FlashPlan, ManifestError> {
+        let mut plan = FlashPlan::new().erase_before_write(self.erase_before_write);
+        for image in &self.images {
+            let target = image.target()?;
+            image.check_hash(base)?;
+            let i = image.image(base)?;
+            plan = plan.write(i.clone(), target.clone());
+            if image.verify {
+                plan = plan.verify(i, target);
+            }
+        }
+        for action in &self.post {
+            plan = plan.reset(action.opcode());
+        }
+        Ok(plan)
+    }
+}
+
+// Parse a sha256 written as 64 hex digits
+fn parse_sha256(text: &str) -> Option<[u8; 32]> {
+    // from_str_radix accepts a leading sign, so check the digits upfront
+    if text.len() != 64 || !text.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let mut sha256 = [0; 32];
+    for (i, b) in sha256.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&text[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(sha256)
+}
+
+/// Expected content of a target as described in a [HashManifest]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExpectedHash {
+    /// Start sector of the content
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub offset: Option<u32>,
+    /// Partition holding the content
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub partition: Option<String>,
+    /// Size of the content in bytes
+    pub size: u64,
+    /// sha256 of the content as hex string
+    pub sha256: String,
+}
+
+impl ExpectedHash {
+    /// Hash an image to verify it at a target later on
+    pub fn compute(image: &Image, target: &Target) -> Result<ExpectedHash, ManifestError> {
+        let (mut reader, size) = image.open()?;
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut reader, &mut hasher)?;
+        let (offset, partition) = match target {
+            Target::Sector(sector) => (Some(*sector), None),
+            Target::Partition(name) => (None, Some(name.clone())),
+        };
+        Ok(ExpectedHash {
+            offset,
+            partition,
+            size,
+            sha256: hex(&hasher.finalize()),
+        })
+    }
+}
+
+/// Detached description of the expected flash content, e.g. produced at build time
+///
+/// Unlike a [Manifest] no images are needed to verify a device against it. It can be stored in
+/// any format supported by serde, e.g. TOML:
+/// ```toml
+/// [[target]]
+/// partition = "rootfs"
+/// size = 1073741824
+/// sha256 = "..."
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HashManifest {
+    #[serde(default, rename = "target")]
+    pub targets: Vec<ExpectedHash>,
+}
+
+impl HashManifest {
+    /// Add steps verifying all targets to a plan
+    pub fn verify(&self, mut plan: FlashPlan) -> Result<FlashPlan, ManifestError> {
+        for (i, expected) in self.targets.iter().enumerate() {
+            let target = match (expected.offset, &expected.partition) {
+                (Some(offset), None) => Target::Sector(offset),
+                (None, Some(partition)) => Target::Partition(partition.clone()),
+                _ => return Err(ManifestError::InvalidHashTarget(i)),
+            };
+            let sha256 =
+                parse_sha256(&expected.sha256).ok_or(ManifestError::InvalidExpectedHash(i))?;
+            plan = plan.verify_hash(target, expected.size, sha256);
+        }
+        Ok(plan)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::flasher::FlashStep;
+
+    #[test]
+    fn plan() {
+        let dir = std::env::temp_dir().join(format!("rockusb-manifest-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("loader.img"), b"abc").unwrap();
+
+        let manifest: Manifest = serde_json::from_str(
+            r#"{
+                "image": [
+                    {
+                        "path": "loader.img",
+                        "offset": 64,
+                        "sha256": "BA7816BF8F01CFEA414140DE5DAE2223B00361A396177A9CB410FF61F20015AD",
+                        "verify": true
+                    },
+                    { "path": "rootfs.img", "partition": "rootfs" }
+                ],
+                "post": ["reset-maskrom"]
+            }"#,
+        )
+        .unwrap();
+        let plan = manifest.to_plan(&dir).unwrap();
+        assert_eq!(plan.steps.len(), 4);
+        assert!(matches!(
+            &plan.steps[0],
+            FlashStep::Write { image: Image::File(p), target: Target::Sector(64) }
+                if *p == dir.join("loader.img")
+        ));
+        assert!(matches!(&plan.steps[1], FlashStep::Verify { .. }));
+        assert!(matches!(
+            &plan.steps[2],
+            FlashStep::Write { target: Target::Partition(p), .. } if p == "rootfs"
+        ));
+        assert!(matches!(
+            plan.steps[3],
+            FlashStep::Reset(ResetOpcode::Maskrom)
+        ));
+
+        let mut bad = manifest.clone();
+        bad.images[0].sha256 = Some("00".repeat(32));
+        assert!(matches!(
+            bad.to_plan(&dir),
+            Err(ManifestError::HashMismatch { .. })
+        ));
+        bad.images[0].sha256 = None;
+        bad.images[0].partition = Some("boot".to_string());
+        assert!(matches!(
+            bad.to_plan(&dir),
+            Err(ManifestError::AmbiguousTarget(_))
+        ));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn hashes() {
+        let image = Image::Data(b"abc".to_vec());
+        let expected = ExpectedHash::compute(&image, &Target::Sector(64)).unwrap();
+        assert_eq!(
+            expected.sha256,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(expected.size, 3);
+
+        let mut manifest: HashManifest = serde_json::from_str(
+            r#"{
+                "target": [
+                    { "partition": "rootfs", "size": 1024, "sha256": "00" }
+                ]
+            }"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            manifest.verify(FlashPlan::new()),
+            Err(ManifestError::InvalidExpectedHash(0))
+        ));
+        manifest.targets[0].sha256 = "+1".repeat(32);
+        assert!(matches!(
+            manifest.verify(FlashPlan::new()),
+            Err(ManifestError::InvalidExpectedHash(0))
+        ));
+        manifest.targets.insert(0, expected);
+        manifest.targets[1].sha256 = "11".repeat(32);
+        let plan = manifest.verify(FlashPlan::new()).unwrap();
+        assert!(matches!(
+            &plan.steps[0],
+            FlashStep::VerifyHash { target: Target::Sector(64), len: 3, sha256 }
+                if sha256[0] == 0xba
+        ));
+        assert!(matches!(
+            &plan.steps[1],
+            FlashStep::VerifyHash { target: Target::Partition(p), len: 1024, .. } if p == "rootfs"
+        ));
+
+        manifest.targets[1].offset = Some(0);
+        assert!(matches!(
+            manifest.verify(FlashPlan::new()),
+            Err(ManifestError::InvalidHashTarget(1))
+        ));
+    }
+}
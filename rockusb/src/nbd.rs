@@ -0,0 +1,87 @@
+use std::borrow::BorrowMut;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::net::TcpListener;
+
+use crate::libusb::{Transport, TransportIO};
+
+/// Default TCP port used by NBD
+pub const NBD_PORT: u16 = 10809;
+
+// Wrapper refusing writes for read-only exports
+struct ReadOnly<T>(T);
+
+impl<T: Read> Read for ReadOnly<T> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl<T: Seek> Seek for ReadOnly<T> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.0.seek(pos)
+    }
+}
+
+impl<T> Write for ReadOnly<T> {
+    fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            "Export is read-only",
+        ))
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Export the flash to a single NBD client over an established connection
+///
+/// Returns once the client disconnects
+pub fn serve<S, T>(mut stream: S, io: TransportIO<T>, readonly: bool) -> std::io::Result<()>
+where
+    S: Read + Write,
+    T: BorrowMut<Transport>,
+{
+    ::nbd::server::handshake(&mut stream, |_name| {
+        Ok(::nbd::Export {
+            size: io.size(),
+            readonly,
+            resizeable: false,
+            rotational: false,
+            send_trim: false,
+            send_flush: !readonly,
+            data: (),
+        })
+    })?;
+    if readonly {
+        ::nbd::server::transmission(stream, ReadOnly(io))
+    } else {
+        ::nbd::server::transmission(stream, io)
+    }
+}
+
+/// Export the flash to NBD clients connecting to a listener, serving one client at a time
+///
+/// A client disconnecting or dropping its connection doesn't stop the export, so clients can
+/// reconnect. Returns when accepting a connection or accessing the device fails
+pub fn serve_clients(
+    transport: &mut Transport,
+    listener: &TcpListener,
+    readonly: bool,
+) -> std::io::Result<()> {
+    for stream in listener.incoming() {
+        let io = TransportIO::new(&mut *transport)?;
+        match serve(stream?, io, readonly) {
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    std::io::ErrorKind::UnexpectedEof
+                        | std::io::ErrorKind::ConnectionReset
+                        | std::io::ErrorKind::BrokenPipe
+                ) => {}
+            r => r?,
+        }
+    }
+    Ok(())
+}
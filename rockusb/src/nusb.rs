@@ -1,24 +1,194 @@
-use std::io::SeekFrom;
+use std::collections::HashMap;
+use std::io::{Read, SeekFrom};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use std::{borrow::BorrowMut, task::Poll};
 
 use crate::{
-    operation::{OperationSteps, UsbStep},
-    protocol::{ChipInfo, FlashId, FlashInfo, ResetOpcode, SECTOR_SIZE},
+    flasher::{FlashError, FlashPlan, FlashReport, Journal, Progress},
+    gpt::{Gpt, GptError, GptRepair},
+    layout::{critical_overlap, Region, FIRST_4M_SECTORS},
+    observer::{observe_read, observe_write, OperationObserver},
+    operation::{
+        transfer_sectors, BulkPhase, Encoding, OperationContext, OperationSteps, Transferred,
+        UsbStep, MAX_LBA_SECTORS, MAX_RESIDUE_RETRIES, SDRAM_ALIGNMENT, SDRAM_CHUNK_SIZE,
+    },
+    protocol::{
+        Area, Capability, ChipInfo, CommandStatus, DeviceFilter, FlashId, FlashInfo, ResetOpcode,
+        SocFamily, Storage, UsbMode, DEFAULT_DEVICE_FILTERS, SECTOR_SIZE,
+    },
+    quirks::Quirks,
+    retry::RetryPolicy,
+    runner::{self, collect_unerased, FlashDevice},
+    stats::Stats,
+    throttle::Throttle,
 };
-use futures::{future::BoxFuture, ready};
-use futures::{AsyncRead, AsyncSeek, AsyncWrite};
+use futures::lock::{Mutex, MutexGuard};
+use futures::{future::BoxFuture, ready, Stream, StreamExt};
+use futures::{AsyncBufRead, AsyncRead, AsyncReadExt, AsyncSeek, AsyncWrite, AsyncWriteExt};
 use nusb::{
-    transfer::{ControlOut, ControlType, Recipient, RequestBuffer},
-    DeviceInfo,
+    transfer::{ControlOut, ControlType, Recipient, RequestBuffer, TransferError},
+    DeviceId, DeviceInfo,
 };
+use rockfile::boot::{BootFile, EntryKind};
 use thiserror::Error;
 
+/// Reason for a device not being available
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum UnavailableKind {
+    /// Claimed by someone else, e.g. another process already talking to the device.
+    ///
+    /// Claiming the interface is exclusive, so two applications can never interleave commands to
+    /// the same device
+    Busy,
+    /// Claimed by a kernel driver; See [DeviceUnavalable::kernel_driver] for the driver
+    ///
+    /// Only detected on Linux, elsewhere such devices are reported as [UnavailableKind::Busy]
+    ClaimedByKernel,
+    /// Not bound to the WinUSB driver; See [DeviceUnavalable::missing_driver] for details
+    MissingDriver,
+    /// Any other failure, see the usb error for details
+    Other,
+}
+
 /// Error indicate a device is not available
 #[derive(Debug, Error)]
 #[error("Device is not available: {error}")]
 pub struct DeviceUnavalable {
-    #[from]
     pub error: nusb::Error,
+    pub kind: UnavailableKind,
+}
+
+impl From<nusb::Error> for DeviceUnavalable {
+    fn from(error: nusb::Error) -> Self {
+        let payload = error.get_ref();
+        let kind = if payload.is_some_and(|p| p.is::<KernelDriverBound>()) {
+            UnavailableKind::ClaimedByKernel
+        } else if payload.is_some_and(|p| p.is::<MissingDriver>()) {
+            UnavailableKind::MissingDriver
+        } else if cfg!(unix) && error.raw_os_error() == Some(16) {
+            // EBUSY
+            UnavailableKind::Busy
+        } else {
+            UnavailableKind::Other
+        };
+        Self { error, kind }
+    }
+}
+
+impl DeviceUnavalable {
+    /// Whether the device is unavailable due to being claimed by someone else, either another
+    /// process or a kernel driver
+    pub fn is_busy(&self) -> bool {
+        matches!(
+            self.kind,
+            UnavailableKind::Busy | UnavailableKind::ClaimedByKernel
+        )
+    }
+
+    /// Driver problem causing the device to be unavailable, if detected
+    ///
+    /// Only detected on Windows, where the device needs to be bound to the WinUSB driver
+    pub fn missing_driver(&self) -> Option<&MissingDriver> {
+        self.error.get_ref()?.downcast_ref()
+    }
+
+    /// Kernel driver claiming the device, if detected
+    ///
+    /// Only detected on Linux; See [AvailableDevice::open_detached] to detach the driver
+    pub fn kernel_driver(&self) -> Option<&KernelDriverBound> {
+        self.error.get_ref()?.downcast_ref()
+    }
+}
+
+/// Device can't be opened as it's claimed by a kernel driver
+///
+/// Loaders can expose a mass-storage like interface, which the OS may bind its usb-storage
+/// driver to (and potentially auto-mount). Unmount any volumes of the device and either unbind
+/// the driver or open the device using [AvailableDevice::open_detached]
+#[derive(Debug, Clone, Error)]
+#[error(
+    "Device is claimed by the {driver} kernel driver; Unmount any volumes on it and unbind the \
+     driver or detach it when opening the device"
+)]
+pub struct KernelDriverBound {
+    /// Name of the bound kernel driver
+    pub driver: String,
+}
+
+// Name of the kernel driver bound to one of the device interfaces, if any
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn kernel_driver(info: &DeviceInfo) -> Option<String> {
+    let path = info.sysfs_path();
+    let prefix = format!("{}:", path.file_name()?.to_str()?);
+    std::fs::read_dir(path)
+        .ok()?
+        .flatten()
+        .filter(|entry| entry.file_name().to_string_lossy().starts_with(&prefix))
+        .find_map(|entry| {
+            let driver = std::fs::read_link(entry.path().join("driver")).ok()?;
+            let driver = driver.file_name()?.to_string_lossy().into_owned();
+            // usbfs is used by userspace applications, i.e. the device is busy instead
+            (driver != "usbfs").then_some(driver)
+        })
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn kernel_driver(_info: &DeviceInfo) -> Option<String> {
+    None
+}
+
+// Check whether failing to claim the device is caused by a kernel driver
+fn check_kernel_driver(info: &DeviceInfo, unavailable: DeviceUnavalable) -> DeviceUnavalable {
+    if !unavailable.is_busy() {
+        return unavailable;
+    }
+    match kernel_driver(info) {
+        Some(driver) => {
+            nusb::Error::new(unavailable.error.kind(), KernelDriverBound { driver }).into()
+        }
+        None => unavailable,
+    }
+}
+
+/// Device can't be opened as it's not bound to the WinUSB driver
+///
+/// On Windows devices have to be bound to WinUSB to be accessible to applications; This isn't
+/// the case by default for Rockchip devices. The driver can be installed using e.g. Zadig or the
+/// Rockchip driver assistant
+#[derive(Debug, Clone, Error)]
+#[error(
+    "Device {instance_id} is bound to driver {} instead of WinUSB; Install the WinUSB driver \
+     for it, e.g. using Zadig (https://zadig.akeo.ie)",
+    driver.as_deref().unwrap_or("<none>")
+)]
+pub struct MissingDriver {
+    /// Windows device instance id
+    pub instance_id: String,
+    /// Driver currently bound to the device, if any
+    pub driver: Option<String>,
+}
+
+// Check whether failing to open a device is caused by it not being bound to WinUSB; Composite
+// devices are bound to the generic parent driver with WinUSB bound to the interface, so those
+// can't be diagnosed here
+#[cfg(target_os = "windows")]
+fn check_driver(info: &DeviceInfo, error: nusb::Error) -> nusb::Error {
+    match info.driver() {
+        Some(d) if d.eq_ignore_ascii_case("winusb") || d.eq_ignore_ascii_case("usbccgp") => error,
+        driver => nusb::Error::new(
+            error.kind(),
+            MissingDriver {
+                instance_id: info.instance_id().to_string_lossy().into_owned(),
+                driver: driver.map(str::to_string),
+            },
+        ),
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+fn check_driver(_info: &DeviceInfo, error: nusb::Error) -> nusb::Error {
+    error
 }
 
 #[derive(Debug, Error)]
@@ -29,193 +199,1687 @@ pub enum Error {
     UsbTransferError(#[from] nusb::transfer::TransferError),
     #[error("Operation error: {0}")]
     OperationError(#[from] crate::operation::UsbOperationError),
+    #[error("Short transfer: expected {expected} bytes, transferred {actual}")]
+    ShortTransfer { expected: usize, actual: usize },
+    #[error("Sector {sector:#x} is beyond the range addressable by the protocol")]
+    AddressOutOfRange { sector: u64 },
+    #[error("Partial write: only {written} of {requested} bytes written")]
+    PartialTransfer { written: usize, requested: usize },
+    #[error("Switching to storage {expected} failed, storage in use: {actual:?}")]
+    StorageMismatch { expected: u8, actual: Option<u8> },
+    #[error("Invalid size {size} bytes for the {region:?} region")]
+    InvalidRegionSize { region: Region, size: usize },
+    #[error("Loader doesn't support {0}")]
+    Unsupported(&'static str),
+    #[error("Access of {len} bytes at offset {offset:#x} is out of bounds")]
+    OutOfBounds { offset: u32, len: usize },
+    #[error("Address {address:#x} isn't aligned to {alignment} bytes")]
+    UnalignedAddress { address: u32, alignment: u32 },
+    #[error("Sectors {sectors:#x?} overlap the critical region {region:#x?}")]
+    CriticalRegion {
+        sectors: std::ops::Range<u32>,
+        region: std::ops::Range<u32>,
+    },
+    #[error("{context} failed: {source}")]
+    Context {
+        context: OperationContext,
+        #[source]
+        source: Box<Error>,
+    },
 }
 type Result<T> = std::result::Result<T, Error>;
 
+impl Error {
+    fn context(self, context: OperationContext) -> Self {
+        Error::Context {
+            context,
+            source: Box::new(self),
+        }
+    }
+
+    /// Context of the operation that failed, if known
+    pub fn operation_context(&self) -> Option<&OperationContext> {
+        match self {
+            Error::Context { context, .. } => Some(context),
+            _ => None,
+        }
+    }
+
+    fn io_error_kind(&self) -> std::io::ErrorKind {
+        match self {
+            Error::UsbError(e) => e.kind(),
+            Error::UsbTransferError(e) => match e {
+                TransferError::Cancelled => std::io::ErrorKind::Interrupted,
+                TransferError::Disconnected => std::io::ErrorKind::NotConnected,
+                TransferError::Fault => std::io::ErrorKind::InvalidData,
+                _ => std::io::ErrorKind::BrokenPipe,
+            },
+            Error::OperationError(crate::operation::UsbOperationError::PartialTransfer {
+                ..
+            }) => std::io::ErrorKind::WriteZero,
+            Error::OperationError(
+                crate::operation::UsbOperationError::InvalidLbaLength(_)
+                | crate::operation::UsbOperationError::InvalidSdramLength(_),
+            ) => std::io::ErrorKind::InvalidInput,
+            Error::OperationError(_) => std::io::ErrorKind::InvalidData,
+            Error::ShortTransfer { .. } => std::io::ErrorKind::UnexpectedEof,
+            Error::AddressOutOfRange { .. } => std::io::ErrorKind::InvalidInput,
+            Error::PartialTransfer { .. } => std::io::ErrorKind::WriteZero,
+            Error::StorageMismatch { .. } => std::io::ErrorKind::Other,
+            Error::InvalidRegionSize { .. } => std::io::ErrorKind::InvalidInput,
+            Error::Unsupported(_) => std::io::ErrorKind::Unsupported,
+            Error::OutOfBounds { .. } => std::io::ErrorKind::InvalidInput,
+            Error::UnalignedAddress { .. } => std::io::ErrorKind::InvalidInput,
+            Error::CriticalRegion { .. } => std::io::ErrorKind::PermissionDenied,
+            Error::Context { source, .. } => source.io_error_kind(),
+        }
+    }
+}
+
+impl From<Error> for std::io::Error {
+    fn from(e: Error) -> Self {
+        std::io::Error::new(e.io_error_kind(), e)
+    }
+}
+
+impl From<Error> for FlashError {
+    fn from(e: Error) -> Self {
+        FlashError::IoError(e.into())
+    }
+}
+
+impl From<Error> for GptError {
+    fn from(e: Error) -> Self {
+        GptError::IoError(e.into())
+    }
+}
+
+// Biggest lba transfer that fits in a single operation
+const MAX_LBA_CHUNK: usize = MAX_LBA_SECTORS as usize * SECTOR_SIZE as usize;
+
+/// Rockchip device found on the bus
+#[derive(Debug, Clone)]
+pub struct AvailableDevice {
+    info: DeviceInfo,
+}
+
+impl AvailableDevice {
+    /// Underlying nusb device information
+    pub fn info(&self) -> &DeviceInfo {
+        &self.info
+    }
+
+    /// Convert into the underlying nusb device information
+    pub fn into_info(self) -> DeviceInfo {
+        self.info
+    }
+
+    /// Bus number of the device
+    pub fn bus_number(&self) -> u8 {
+        self.info.bus_number()
+    }
+
+    /// Address of the device on the bus
+    pub fn device_address(&self) -> u8 {
+        self.info.device_address()
+    }
+
+    /// Hub ports the device is connected through, starting at the root hub
+    ///
+    /// Identifies the physical usb port of the device, which unlike the device address is stable
+    /// over reconnects. Only available on Linux
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn port_chain(&self) -> Option<Vec<u8>> {
+        parse_port_chain(self.info.sysfs_path().file_name()?.to_str()?)
+    }
+
+    /// Hub ports the device is connected through, starting at the root hub
+    ///
+    /// Identifies the physical usb port of the device, which unlike the device address is stable
+    /// over reconnects. Only available on Linux
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    pub fn port_chain(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Name of the SoC family, if known
+    pub fn soc(&self) -> Option<&'static str> {
+        crate::protocol::soc_name(self.info.product_id())
+    }
+
+    /// Mode the device is currently in, see [UsbMode::detect]
+    pub fn mode(&self) -> UsbMode {
+        let classes: Vec<u8> = self.info.interfaces().map(|i| i.class()).collect();
+        UsbMode::detect(
+            self.info.device_version(),
+            self.info.product_string(),
+            &classes,
+        )
+    }
+
+    /// Whether the device is in maskrom mode, i.e. needs a boot download before supporting the
+    /// full rockusb protocol
+    pub fn is_maskrom(&self) -> bool {
+        self.mode() == UsbMode::Maskrom
+    }
+
+    /// Kernel driver bound to the device, if any
+    ///
+    /// A kernel driver (e.g. usb-storage) bound to the device prevents opening it. Only detected
+    /// on Linux
+    pub fn kernel_driver(&self) -> Option<String> {
+        kernel_driver(&self.info)
+    }
+
+    /// Open the device
+    pub fn open(&self) -> std::result::Result<Transport, DeviceUnavalable> {
+        Transport::from_usb_device_info(self.info.clone())
+    }
+
+    /// Open the device, detaching any kernel driver bound to it first
+    ///
+    /// Detaching kernel drivers is only supported on Linux; On other platforms this is the same
+    /// as [AvailableDevice::open]
+    pub fn open_detached(&self) -> std::result::Result<Transport, DeviceUnavalable> {
+        Transport::open_info(self.info.clone(), true)
+    }
+}
+
+impl std::fmt::Display for AvailableDevice {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Bus {:03} Device {:03} ID {:04x}:{:04x} {} ({})",
+            self.bus_number(),
+            self.device_address(),
+            self.info.vendor_id(),
+            self.info.product_id(),
+            self.soc().unwrap_or("Unknown SoC"),
+            self.mode()
+        )
+    }
+}
+
+// Parse the port chain out of a sysfs device name in the form of `<bus>-<port>[.<port>]*`
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn parse_port_chain(name: &str) -> Option<Vec<u8>> {
+    let (_, ports) = name.split_once('-')?;
+    ports.split('.').map(|p| p.parse().ok()).collect()
+}
+
 /// List rockchip devices
-pub fn devices() -> std::result::Result<impl Iterator<Item = DeviceInfo>, nusb::Error> {
-    Ok(nusb::list_devices()?.filter(|d| d.vendor_id() == 0x2207))
+pub fn devices() -> std::result::Result<impl Iterator<Item = AvailableDevice>, nusb::Error> {
+    devices_with_filters(DEFAULT_DEVICE_FILTERS)
+}
+
+/// List devices matching any of the given filters rather then the [DEFAULT_DEVICE_FILTERS], e.g.
+/// for custom bootloaders using their own usb ids
+pub fn devices_with_filters(
+    filters: &[DeviceFilter],
+) -> std::result::Result<impl Iterator<Item = AvailableDevice>, nusb::Error> {
+    let filters = filters.to_vec();
+    Ok(nusb::list_devices()?
+        .filter(move |d| DeviceFilter::any_matches(&filters, d.vendor_id(), d.product_id()))
+        .map(|info| AvailableDevice { info }))
+}
+
+/// List devices of a specific SoC family
+pub fn devices_matching(
+    family: SocFamily,
+) -> std::result::Result<impl Iterator<Item = AvailableDevice>, nusb::Error> {
+    devices_with_filters(&[family.filter()])
+}
+
+/// Hotplug event for a rockchip device
+#[derive(Debug, Clone)]
+pub enum HotplugEvent {
+    /// A device has been connected
+    Connected(AvailableDevice),
+    /// A previously seen device has been disconnected
+    Disconnected(AvailableDevice),
+}
+
+/// Watch for rockchip devices being connected or disconnected
+///
+/// Devices already connected when starting to watch are tracked as well, such that their
+/// disconnection is reported
+pub fn watch_devices() -> std::result::Result<impl Stream<Item = HotplugEvent> + Unpin, nusb::Error>
+{
+    watch_devices_with_filters(DEFAULT_DEVICE_FILTERS)
+}
+
+/// Watch for devices matching any of the given filters being connected or disconnected, see
+/// [watch_devices]
+pub fn watch_devices_with_filters(
+    filters: &[DeviceFilter],
+) -> std::result::Result<impl Stream<Item = HotplugEvent> + Unpin, nusb::Error> {
+    // Start watching before listing to not miss any event in between
+    let watch = nusb::watch_devices()?;
+    let mut known: HashMap<DeviceId, AvailableDevice> = devices_with_filters(filters)?
+        .map(|d| (d.info.id(), d))
+        .collect();
+    let filters = filters.to_vec();
+    Ok(watch.filter_map(move |event| {
+        let event = match event {
+            nusb::hotplug::HotplugEvent::Connected(info)
+                if DeviceFilter::any_matches(&filters, info.vendor_id(), info.product_id()) =>
+            {
+                let device = AvailableDevice { info };
+                known.insert(device.info.id(), device.clone());
+                Some(HotplugEvent::Connected(device))
+            }
+            nusb::hotplug::HotplugEvent::Disconnected(id) => {
+                known.remove(&id).map(HotplugEvent::Disconnected)
+            }
+            _ => None,
+        };
+        futures::future::ready(event)
+    }))
+}
+
+// Vendor and product id of a device as read from its device descriptor
+fn device_ids(device: &nusb::Device) -> Option<(u16, u16)> {
+    const DESCRIPTOR_TYPE_DEVICE: u8 = 0x01;
+    let desc = device
+        .get_descriptor(DESCRIPTOR_TYPE_DEVICE, 0, 0, Duration::from_secs(1))
+        .ok()?;
+    let id = |offset: usize| {
+        Some(u16::from_le_bytes(
+            desc.get(offset..offset + 2)?.try_into().ok()?,
+        ))
+    };
+    Some((id(8)?, id(10)?))
+}
+
+// Convert a sector number to the 32 bits addressable by the protocol
+fn to_lba(sector: u64) -> Result<u32> {
+    u32::try_from(sector).map_err(|_| Error::AddressOutOfRange { sector })
+}
+
+// End of `sectors` sectors starting at start_sector, failing if the last one isn't addressable
+//
+// The end is exclusive, so a range ending at the very last sector has an end of 2^32; That's
+// clamped to u32::MAX for the 32 bit ranges used to check critical regions and report errors,
+// which are all well before it
+fn sectors_end(start_sector: u32, sectors: u64) -> Result<u32> {
+    let end = u64::from(start_sector) + sectors;
+    to_lba(end.saturating_sub(1))?;
+    Ok(end.min(u32::MAX.into()) as u32)
+}
+
+// End of a transfer of `len` bytes starting at start_sector, see sectors_end
+fn lba_end(start_sector: u32, len: usize) -> Result<u32> {
+    sectors_end(start_sector, (len as u64).div_ceil(SECTOR_SIZE))
+}
+
+// Bytes read by an lba read, failing if the device returned less than requested
+fn full_read(transferred: Transferred, len: usize) -> Result<u32> {
+    let actual = u32::from(transferred);
+    if actual as usize == len {
+        Ok(actual)
+    } else {
+        Err(Error::ShortTransfer {
+            expected: len,
+            actual: actual as usize,
+        })
+    }
+}
+
+/// nusb based Transport for rockusb operation
+pub struct Transport {
+    device: nusb::Device,
+    interface: nusb::Interface,
+    ep_in: u8,
+    ep_out: u8,
+    retry: RetryPolicy,
+    stats: Stats,
+    maskrom_encoding: Encoding,
+    info: Option<Box<DeviceInfo>>,
+    chip_info: Option<ChipInfo>,
+    // Cached device information, see [Transport::refresh]
+    flash_info: Option<FlashInfo>,
+    capability: Option<Capability>,
+    allow_critical: bool,
+    max_transfer: u16,
+    quirks: Quirks,
+    // End of the last operation, for keeping the loader alive
+    last_active: Instant,
+    observer: Option<Box<dyn OperationObserver>>,
+    write_throttle: Option<Throttle>,
+}
+
+impl std::fmt::Debug for Transport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut d = f.debug_struct("Transport");
+        if let Some(info) = &self.info {
+            d.field("bus_number", &info.bus_number())
+                .field("device_address", &info.device_address())
+                .field("vendor_id", &format_args!("{:04x}", info.vendor_id()))
+                .field("product_id", &format_args!("{:04x}", info.product_id()))
+                .field("soc", &crate::protocol::soc_name(info.product_id()));
+        }
+        d.field("chip_info", &self.chip_info)
+            .field("ep_in", &self.ep_in)
+            .field("ep_out", &self.ep_out)
+            .finish()
+    }
+}
+
+impl std::fmt::Display for Transport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.info {
+            Some(info) => write!(
+                f,
+                "Bus {:03} Device {:03} ID {:04x}:{:04x} {}",
+                info.bus_number(),
+                info.device_address(),
+                info.vendor_id(),
+                info.product_id(),
+                crate::protocol::soc_name(info.product_id()).unwrap_or("Unknown SoC")
+            )?,
+            None => write!(f, "Unknown device")?,
+        }
+        if let Some(info) = &self.chip_info {
+            write!(f, " (chip {})", info)?;
+        }
+        Ok(())
+    }
 }
 
-/// nusb based Transport for rockusb operation
-pub struct Transport {
-    interface: nusb::Interface,
-    ep_in: u8,
-    ep_out: u8,
-}
+impl Transport {
+    fn new(
+        device: nusb::Device,
+        interface: u8,
+        ep_in: u8,
+        ep_out: u8,
+        max_packet_size: usize,
+        detach: bool,
+    ) -> std::result::Result<Self, DeviceUnavalable> {
+        let interface = if detach {
+            device.detach_and_claim_interface(interface)?
+        } else {
+            device.claim_interface(interface)?
+        };
+        Ok(Self {
+            device,
+            interface,
+            ep_in,
+            ep_out,
+            retry: RetryPolicy::default(),
+            stats: Stats::new(),
+            maskrom_encoding: Encoding::default(),
+            info: None,
+            chip_info: None,
+            flash_info: None,
+            capability: None,
+            allow_critical: false,
+            max_transfer: transfer_sectors(max_packet_size),
+            quirks: Quirks::NONE,
+            last_active: Instant::now(),
+            observer: None,
+            write_throttle: None,
+        })
+    }
+
+    /// Create a new transport from a device info
+    pub fn from_usb_device_info(
+        info: nusb::DeviceInfo,
+    ) -> std::result::Result<Self, DeviceUnavalable> {
+        Self::open_info(info, false)
+    }
+
+    fn open_info(
+        info: nusb::DeviceInfo,
+        detach: bool,
+    ) -> std::result::Result<Self, DeviceUnavalable> {
+        let device = info.open().map_err(|e| check_driver(&info, e))?;
+        let mut transport =
+            Self::open_device(device, detach).map_err(|e| check_kernel_driver(&info, e))?;
+        transport.apply_device_ids(info.vendor_id(), info.product_id());
+        transport.info = Some(Box::new(info));
+        Ok(transport)
+    }
+
+    /// Create a new transport from an existing device
+    ///
+    /// The usb ids needed to look up the device's quirks and maskrom encoding are read from its
+    /// device descriptor
+    pub fn from_usb_device(device: nusb::Device) -> std::result::Result<Self, DeviceUnavalable> {
+        let ids = device_ids(&device);
+        let mut transport = Self::open_device(device, false)?;
+        if let Some((vendor_id, product_id)) = ids {
+            transport.apply_device_ids(vendor_id, product_id);
+        }
+        Ok(transport)
+    }
+
+    // Apply the settings depending on the usb ids of the device
+    fn apply_device_ids(&mut self, vendor_id: u16, product_id: u16) {
+        self.maskrom_encoding = Encoding::for_product_id(product_id);
+        self.apply_quirks(crate::quirks::lookup(vendor_id, product_id));
+    }
+
+    fn open_device(
+        device: nusb::Device,
+        detach: bool,
+    ) -> std::result::Result<Self, DeviceUnavalable> {
+        for config in device.clone().configurations() {
+            for interface in config.interface_alt_settings() {
+                let output = interface.endpoints().find(|e| {
+                    e.direction() == nusb::transfer::Direction::Out
+                        && e.transfer_type() == nusb::transfer::EndpointType::Bulk
+                });
+                let input = interface.endpoints().find(|e| {
+                    e.direction() == nusb::transfer::Direction::In
+                        && e.transfer_type() == nusb::transfer::EndpointType::Bulk
+                });
+
+                if let (Some(input), Some(output)) = (input, output) {
+                    return Transport::new(
+                        device,
+                        interface.interface_number(),
+                        input.address(),
+                        output.address(),
+                        input.max_packet_size().min(output.max_packet_size()),
+                        detach,
+                    );
+                }
+            }
+        }
+        Err(nusb::Error::new(std::io::ErrorKind::NotFound, "Device not found").into())
+    }
+
+    /// Convert into an IO object which implements [AsyncRead],
+    /// [AsyncWrite] and [AsyncSeek]
+    pub async fn into_io(self) -> Result<TransportIO> {
+        TransportIO::new(self).await
+    }
+
+    /// Get a reference to the underlying device
+    pub fn device(&self) -> &nusb::Device {
+        &self.device
+    }
+
+    /// Get a reference to the claimed interface
+    pub fn interface(&self) -> &nusb::Interface {
+        &self.interface
+    }
+
+    /// Address of the bulk in endpoint
+    pub fn ep_in(&self) -> u8 {
+        self.ep_in
+    }
+
+    /// Address of the bulk out endpoint
+    pub fn ep_out(&self) -> u8 {
+        self.ep_out
+    }
+
+    /// Convert into the underlying device, releasing the claimed interface
+    pub fn into_device(self) -> nusb::Device {
+        self.device
+    }
+
+    /// Set the encoding used for writes in maskrom mode
+    ///
+    /// By default the encoding is selected based on the usb product id if known, see
+    /// [Encoding::for_product_id]
+    pub fn set_maskrom_encoding(&mut self, encoding: Encoding) {
+        self.maskrom_encoding = encoding;
+    }
+
+    /// Encoding used for writes in maskrom mode
+    pub fn maskrom_encoding(&self) -> Encoding {
+        self.maskrom_encoding
+    }
+
+    /// Allow writes and erases overlapping the [CRITICAL_SECTORS](crate::layout::CRITICAL_SECTORS)
+    ///
+    /// By default raw sector writes and erases (including those done through [TransportIO] or a
+    /// [FlashPlan]) touching the partition table or IDBlock are refused to prevent accidental
+    /// bricks. Writes meant for these regions, like [Transport::write_idblock],
+    /// [Transport::write_first_4m] or a [FlashStep::WriteGpt](crate::flasher::FlashStep::WriteGpt),
+    /// are always allowed
+    pub fn allow_critical_regions(&mut self) {
+        self.allow_critical = true;
+    }
+
+    // Refuse modifying critical sectors unless allowed
+    fn check_critical(&self, sectors: std::ops::Range<u32>) -> Result<()> {
+        match critical_overlap(&sectors) {
+            Some(region) if !self.allow_critical => Err(Error::CriticalRegion { sectors, region }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Known quirks of the device's loader, see [crate::quirks::lookup]
+    pub fn quirks(&self) -> &Quirks {
+        &self.quirks
+    }
+
+    // Apply quirks looked up for the device
+    fn apply_quirks(&mut self, quirks: Quirks) {
+        if let Some(max) = quirks.max_transfer_sectors {
+            self.max_transfer = self.max_transfer.min(max.max(1));
+        }
+        if quirks.unreliable_lba {
+            self.retry = self.retry.with_min_attempts(3);
+        }
+        self.quirks = quirks;
+    }
+
+    /// Maximum number of sectors transferred by a single operation when streaming data
+    ///
+    /// Used as the chunk size when flashing, reading back or verifying, and as the default maximum
+    /// direct I/O size of [TransportIO]. Derived from the endpoint max packet size, see
+    /// [transfer_sectors]
+    pub fn max_transfer_sectors(&self) -> u16 {
+        self.max_transfer
+    }
+
+    /// Override the maximum number of sectors transferred by a single operation when streaming
+    /// data, e.g. for loaders misbehaving with big transfers
+    pub fn set_max_transfer_sectors(&mut self, sectors: u16) {
+        self.max_transfer = sectors.max(1);
+    }
+
+    // Maximum transfer size in bytes
+    fn max_transfer_size(&self) -> usize {
+        self.max_transfer as usize * SECTOR_SIZE as usize
+    }
+
+    /// Set an observer of the protocol activity of this transport, replacing the current one
+    pub fn set_observer(&mut self, observer: Option<Box<dyn OperationObserver>>) {
+        self.observer = observer;
+    }
+
+    /// Limit the rate of writes to the device to `bytes_per_second`, or remove the limit
+    ///
+    /// Writes are paced between transfers, e.g. for boards that brown out when written to at the
+    /// maximum rate. Applies to all data sent to the device, including flash plans
+    pub fn set_max_write_rate(&mut self, bytes_per_second: Option<u64>) {
+        self.write_throttle = bytes_per_second.map(Throttle::new);
+    }
+
+    /// Current limit of the rate of writes to the device in bytes per second
+    pub fn max_write_rate(&self) -> Option<u64> {
+        self.write_throttle.as_ref().map(Throttle::bytes_per_second)
+    }
+
+    // Wait as required by the write rate limit after writing `bytes`
+    async fn pace_write(&mut self, bytes: usize) {
+        if let Some(throttle) = self.write_throttle.as_mut() {
+            let delay = throttle.delay(bytes);
+            if !delay.is_zero() {
+                futures_timer::Delay::new(delay).await;
+            }
+        }
+    }
+
+    /// Set the policy for retrying failed operations
+    pub fn set_retry_policy(&mut self, retry: RetryPolicy) {
+        self.retry = retry;
+    }
+
+    /// Current policy for retrying failed operations
+    pub fn retry_policy(&self) -> RetryPolicy {
+        self.retry
+    }
+
+    // Wait before retrying if the retry policy allows retrying the failed attempt
+    async fn should_retry(&mut self, error: &Error, attempt: u32, write: bool) -> bool {
+        match self.retry.on_failure(
+            error,
+            error.io_error_kind(),
+            (attempt, write),
+            &mut self.stats,
+            self.observer.as_deref_mut(),
+        ) {
+            Some(delay) => {
+                futures_timer::Delay::new(delay).await;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Statistics of the current session
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Reset the statistics, starting a new session
+    pub fn reset_stats(&mut self) {
+        self.stats = Stats::new();
+    }
+
+    /// Execute a series of sans-io operations in order
+    ///
+    /// Execution stops at the first failing operation; Otherwise the results of all operations
+    /// are returned. Operations are not retried, as their state is consumed by executing them
+    pub async fn handle_operations<O, T, I>(&mut self, operations: I) -> Result<Vec<T>>
+    where
+        O: OperationSteps<T>,
+        I: IntoIterator<Item = O>,
+    {
+        let mut results = Vec::new();
+        for operation in operations {
+            results.push(self.handle_operation(operation).await?);
+        }
+        Ok(results)
+    }
+
+    async fn handle_operation<O, T>(&mut self, operation: O) -> Result<T>
+    where
+        O: OperationSteps<T>,
+    {
+        let start = Instant::now();
+        let r = self.execute_operation(operation).await;
+        self.stats.record_operation(start.elapsed(), r.is_ok());
+        self.last_active = Instant::now();
+        r
+    }
+
+    async fn execute_operation<O, T>(&mut self, mut operation: O) -> Result<T>
+    where
+        O: OperationSteps<T>,
+    {
+        // Short data phase read or write, which is followed by the command status
+        let mut short_read = None;
+        let mut short_write = None;
+        loop {
+            let step = operation.step();
+            match step {
+                UsbStep::WriteBulk { data, phase } => {
+                    let written = self
+                        .interface
+                        .bulk_out(self.ep_out, data.to_vec())
+                        .await
+                        .into_result()?;
+                    let written = written.actual_length();
+                    match phase {
+                        // The device still sends its command status after a short data phase,
+                        // which is read before failing to keep the protocol in sync
+                        BulkPhase::Data if written < data.len() => {
+                            short_write = Some((data.len(), written))
+                        }
+                        _ if written != data.len() => {
+                            return Err(Error::ShortTransfer {
+                                expected: data.len(),
+                                actual: written,
+                            });
+                        }
+                        _ => (),
+                    }
+                    if let Some(observer) = self.observer.as_mut() {
+                        observe_write(observer.as_mut(), phase, &data[..written]);
+                    }
+                    self.pace_write(data.len()).await;
+                }
+                UsbStep::ReadBulk { data, phase } => {
+                    let req = RequestBuffer::new(data.len());
+                    let read = self
+                        .interface
+                        .bulk_in(self.ep_in, req)
+                        .await
+                        .into_result()?;
+                    match phase {
+                        // A device reports a residue by sending less data than requested, which
+                        // still leaves its command status to be read
+                        BulkPhase::Data if read.len() < data.len() => {
+                            short_read = Some((data.len(), read.len()))
+                        }
+                        _ if read.len() != data.len() => {
+                            return Err(Error::ShortTransfer {
+                                expected: data.len(),
+                                actual: read.len(),
+                            });
+                        }
+                        _ => (),
+                    }
+                    data[..read.len()].copy_from_slice(&read);
+                    if let (BulkPhase::Status, Some((expected, actual))) = (phase, short_read) {
+                        // The status must account for exactly the data the device didn't send
+                        let residue = CommandStatus::from_bytes(data).map(|csw| csw.residue);
+                        if residue.is_ok_and(|r| r as usize != expected - actual) {
+                            return Err(Error::ShortTransfer { expected, actual });
+                        }
+                    }
+                    if let Some(observer) = self.observer.as_mut() {
+                        observe_read(observer.as_mut(), phase, &data[..read.len()]);
+                    }
+                    if let (BulkPhase::Status, Some((expected, actual))) = (phase, short_write) {
+                        return Err(Error::ShortTransfer { expected, actual });
+                    }
+                }
+                UsbStep::WriteControl {
+                    request_type,
+                    request,
+                    value,
+                    index,
+                    data,
+                } => {
+                    let (control_type, recipient) = (
+                        match request_type >> 5 & 0x03 {
+                            0 => ControlType::Standard,
+                            1 => ControlType::Class,
+                            2 => ControlType::Vendor,
+                            _ => ControlType::Standard,
+                        },
+                        match request_type & 0x1f {
+                            0 => Recipient::Device,
+                            1 => Recipient::Interface,
+                            2 => Recipient::Endpoint,
+                            3 => Recipient::Other,
+                            _ => Recipient::Device,
+                        },
+                    );
+                    let control = ControlOut {
+                        control_type,
+                        recipient,
+                        request,
+                        value,
+                        index,
+                        data,
+                    };
+                    self.interface.control_out(control).await.into_result()?;
+                    if let Some(observer) = self.observer.as_mut() {
+                        observer.on_data(crate::protocol::Direction::Out, data);
+                    }
+                    self.pace_write(data.len()).await;
+                }
+                UsbStep::Finished(r) => break r.map_err(|e| e.into()),
+            }
+        }
+    }
+
+    /// Check whether the usb loader is ready to handle commands
+    ///
+    /// Not retried, such that it can be used to check whether the loader is alive
+    pub async fn test_unit_ready(&mut self) -> Result<()> {
+        self.handle_operation(crate::operation::test_unit_ready())
+            .await
+            .map_err(|e| e.context(OperationContext::new("test_unit_ready")))
+    }
+
+    /// Time since the last operation finished
+    pub fn idle_time(&self) -> Duration {
+        self.last_active.elapsed()
+    }
+
+    /// Keep the loader alive during idle gaps in long running sessions
+    ///
+    /// Issues a test unit ready if no operation was done for at least `threshold`, as some loaders
+    /// drop their state or time out after a long time of inactivity. Meant to be called
+    /// periodically while the host is busy otherwise, e.g. decompressing an image. Returns whether
+    /// a command was issued
+    pub async fn keep_alive(&mut self, threshold: Duration) -> Result<bool> {
+        if self.idle_time() < threshold {
+            return Ok(false);
+        }
+        self.test_unit_ready().await?;
+        Ok(true)
+    }
+
+    /// retrieve SoC flash identifier
+    pub async fn flash_id(&mut self) -> Result<FlashId> {
+        let mut attempt = 1;
+        loop {
+            match self.handle_operation(crate::operation::flash_id()).await {
+                Err(e) if self.should_retry(&e, attempt, false).await => attempt += 1,
+                r => break r,
+            }
+        }
+        .map_err(|e| e.context(OperationContext::new("flash_id")))
+    }
+
+    /// retrieve SoC flash info
+    ///
+    /// The flash info is only queried from the device once and cached afterwards, see
+    /// [Transport::refresh]
+    pub async fn flash_info(&mut self) -> Result<FlashInfo> {
+        if let Some(info) = self.flash_info {
+            return Ok(info);
+        }
+        let mut attempt = 1;
+        let info = loop {
+            match self.handle_operation(crate::operation::flash_info()).await {
+                Err(e) if self.should_retry(&e, attempt, false).await => attempt += 1,
+                r => break r,
+            }
+        }
+        .map_err(|e| e.context(OperationContext::new("flash_info")))?;
+        self.flash_info = Some(info);
+        Ok(info)
+    }
+
+    /// retrieve the capabilities of the usb loader
+    ///
+    /// The capabilities are only queried from the device once and cached afterwards, see
+    /// [Transport::refresh]
+    pub async fn capability(&mut self) -> Result<Capability> {
+        if let Some(capability) = self.capability {
+            return Ok(capability);
+        }
+        let mut attempt = 1;
+        let capability = loop {
+            match self.handle_operation(crate::operation::capability()).await {
+                Err(e) if self.should_retry(&e, attempt, false).await => attempt += 1,
+                r => break r,
+            }
+        }
+        .map_err(|e| e.context(OperationContext::new("capability")))?;
+        self.capability = Some(capability);
+        Ok(capability)
+    }
+
+    /// Size of the flash in bytes if the flash info was retrieved before
+    pub fn size(&self) -> Option<u64> {
+        self.flash_info.map(|info| info.size())
+    }
+
+    /// Drop the cached device information and query the flash info again
+    ///
+    /// Should be used when the device state changed outside of this transport, e.g. after
+    /// switching storage media. The capabilities are queried again on next use
+    pub async fn refresh(&mut self) -> Result<FlashInfo> {
+        self.flash_info = None;
+        self.capability = None;
+        self.flash_info().await
+    }
+
+    /// retrieve the storage media currently in use
+    pub async fn storage(&mut self) -> Result<Storage> {
+        let mut attempt = 1;
+        loop {
+            match self
+                .handle_operation(crate::operation::read_storage())
+                .await
+            {
+                Err(e) if self.should_retry(&e, attempt, false).await => attempt += 1,
+                r => break r,
+            }
+        }
+        .map_err(|e| e.context(OperationContext::new("storage")))
+    }
+
+    /// Switch the loader to the storage media `storage` (e.g. [Storage::SD])
+    ///
+    /// The switch is verified by reading back the storage in use, after which the flash info of
+    /// the new media is retrieved
+    pub async fn change_storage(&mut self, storage: u8) -> Result<FlashInfo> {
+        self.flash_info = None;
+        self.capability = None;
+        let mut attempt = 1;
+        loop {
+            match self
+                .handle_operation(crate::operation::change_storage(storage))
+                .await
+            {
+                Err(e) if self.should_retry(&e, attempt, false).await => attempt += 1,
+                r => break r,
+            }
+        }
+        .map_err(|e| e.context(OperationContext::new("change_storage")))?;
+        let actual = self.storage().await?.id();
+        if actual != Some(storage) {
+            return Err(Error::StorageMismatch {
+                expected: storage,
+                actual,
+            });
+        }
+        self.flash_info().await
+    }
+
+    /// Run `f` with the loader switched to the storage media `storage`
+    ///
+    /// The original storage media is always restored afterwards, also when switching or `f`
+    /// failed. Errors of `f` take precedence over errors restoring the original media
+    pub async fn with_storage<T, F>(&mut self, storage: u8, f: F) -> Result<T>
+    where
+        F: for<'a> FnOnce(&'a mut Self) -> BoxFuture<'a, Result<T>>,
+    {
+        let original = self.storage().await?.id();
+        if original == Some(storage) {
+            return f(self).await;
+        }
+        let r = match self.change_storage(storage).await {
+            Ok(_) => f(self).await,
+            Err(e) => Err(e),
+        };
+        let restored = match original {
+            Some(original) => self.change_storage(original).await.map(|_| ()),
+            None => self.refresh().await.map(|_| ()),
+        };
+        let r = r?;
+        restored?;
+        Ok(r)
+    }
+
+    /// retrieve SoC chip info
+    pub async fn chip_info(&mut self) -> Result<ChipInfo> {
+        let mut attempt = 1;
+        let info = loop {
+            match self.handle_operation(crate::operation::chip_info()).await {
+                Err(e) if self.should_retry(&e, attempt, false).await => attempt += 1,
+                r => break r,
+            }
+        }
+        .map_err(|e| e.context(OperationContext::new("chip_info")))?;
+        self.chip_info = Some(info);
+        Ok(info)
+    }
+
+    /// read from the flash
+    ///
+    /// start_sector with [SECTOR_SIZE] sectors. the data to be read
+    /// must be a multiple of [SECTOR_SIZE] bytes. Reads bigger then
+    /// [Transport::max_transfer_sectors] are split up in multiple operations
+    ///
+    /// A read the device only partially completes fails with [Error::ShortTransfer]
+    pub async fn read_lba(&mut self, start_sector: u32, read: &mut [u8]) -> Result<u32> {
+        lba_end(start_sector, read.len())?;
+        let mut transferred = 0;
+        let max = self.max_transfer_size();
+        for (i, chunk) in read.chunks_mut(max).enumerate() {
+            let sector = start_sector + i as u32 * u32::from(self.max_transfer);
+            let len = chunk.len();
+            let mut attempt = 1;
+            let t = loop {
+                match self
+                    .handle_operation(crate::operation::read_lba(sector, &mut *chunk)?)
+                    .await
+                    .and_then(|r| full_read(r, len))
+                {
+                    Err(e) if self.should_retry(&e, attempt, false).await => attempt += 1,
+                    r => break r,
+                }
+            }
+            .map_err(|e| {
+                let end = sector.saturating_add((len / SECTOR_SIZE as usize) as u32);
+                e.context(OperationContext::with_sectors("read_lba", sector..end))
+            })?;
+            transferred += t;
+        }
+        self.stats.bytes_read += u64::from(transferred);
+        Ok(transferred)
+    }
+
+    /// Create operation to read an lba from the flash
+    ///
+    /// start_sector based on [SECTOR_SIZE] sectors. the data to be
+    /// written must be a multiple of [SECTOR_SIZE] bytes. Writes bigger then
+    /// [Transport::max_transfer_sectors] are split up in multiple operations
+    ///
+    /// If the device reports a residue, i.e. only part of the data landed, the write of the
+    /// remaining sectors is re-issued up to [MAX_RESIDUE_RETRIES] times, after which
+    /// [Error::PartialTransfer] reports how much of the data was written. Writes overlapping the
+    /// critical regions are refused, see [Transport::allow_critical_regions]
+    pub async fn write_lba(&mut self, start_sector: u32, write: &[u8]) -> Result<u32> {
+        let end = lba_end(start_sector, write.len())?;
+        self.check_critical(start_sector..end)?;
+        self.write_lba_unchecked(start_sector, write).await
+    }
+
+    // Write to the flash without checking for critical regions
+    async fn write_lba_unchecked(&mut self, start_sector: u32, write: &[u8]) -> Result<u32> {
+        lba_end(start_sector, write.len())?;
+        let mut transferred = 0;
+        let max = self.max_transfer_size();
+        for (i, chunk) in write.chunks(max).enumerate() {
+            let sector = start_sector + i as u32 * u32::from(self.max_transfer);
+            let end = sector.saturating_add((chunk.len() / SECTOR_SIZE as usize) as u32);
+            let mut done = 0;
+            let mut residue_retries = 0;
+            while done < chunk.len() {
+                let start = sector + (done / SECTOR_SIZE as usize) as u32;
+                let data = &chunk[done..];
+                let mut attempt = 1;
+                let t: u32 = loop {
+                    match self
+                        .handle_operation(crate::operation::write_lba(start, data)?)
+                        .await
+                    {
+                        Err(e) if self.should_retry(&e, attempt, true).await => attempt += 1,
+                        r => break r,
+                    }
+                }
+                .map_err(|e| e.context(OperationContext::with_sectors("write_lba", start..end)))?
+                .into();
+                if t as usize == data.len() {
+                    break;
+                }
+                // Only whole sectors are known to have landed; Re-issue the write for the rest
+                done += t as usize / SECTOR_SIZE as usize * SECTOR_SIZE as usize;
+                if residue_retries == MAX_RESIDUE_RETRIES {
+                    let written = transferred as usize + done;
+                    self.stats.bytes_written += written as u64;
+                    return Err(Error::PartialTransfer {
+                        written,
+                        requested: write.len(),
+                    }
+                    .context(OperationContext::with_sectors("write_lba", start..end)));
+                }
+                residue_retries += 1;
+                self.stats.retries += 1;
+            }
+            transferred += chunk.len() as u32;
+        }
+        self.stats.bytes_written += u64::from(transferred);
+        Ok(transferred)
+    }
+
+    /// Erase a range of sectors on the flash
+    ///
+    /// Erases bigger then [MAX_LBA_SECTORS] (or the maximum erase size of the device's
+    /// [Quirks]) are split up in multiple operations. Erases
+    /// overlapping the critical regions are refused, see [Transport::allow_critical_regions]
+    pub async fn erase_lba(&mut self, start_sector: u32, sectors: u32) -> Result<()> {
+        self.check_critical(start_sector..sectors_end(start_sector, sectors.into())?)?;
+        let max = self
+            .quirks
+            .max_erase_sectors
+            .unwrap_or(MAX_LBA_SECTORS)
+            .max(1);
+        let end = u64::from(start_sector) + u64::from(sectors);
+        for sector in (u64::from(start_sector)..end).step_by(max.into()) {
+            let count = (end - sector).min(max.into()) as u16;
+            let sector = sector as u32;
+            let mut attempt = 1;
+            loop {
+                match self
+                    .handle_operation(crate::operation::erase_lba(sector, count))
+                    .await
+                {
+                    Err(e) if self.should_retry(&e, attempt, true).await => attempt += 1,
+                    r => break r,
+                }
+            }
+            .map_err(|e| {
+                let end = sector.saturating_add(count.into());
+                e.context(OperationContext::with_sectors("erase_lba", sector..end))
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Erase a range of sectors and read them back to confirm the erase
+    ///
+    /// Returns the sectors that still hold data, i.e. don't read back as all 0x00 or all 0xff
+    /// (depending on the media); An empty list means the whole range was erased. The range is read
+    /// back in chunks of up to [Transport::max_transfer_sectors] sectors
+    pub async fn erase_verified(
+        &mut self,
+        sectors: std::ops::Range<u32>,
+    ) -> Result<Vec<std::ops::Range<u32>>> {
+        self.erase_lba(sectors.start, sectors.len() as u32).await?;
+        let mut buffer = vec![0u8; self.max_transfer_size()];
+        let mut unerased = Vec::new();
+        for sector in sectors.clone().step_by(self.max_transfer.into()) {
+            let chunk = (sectors.end - sector).min(self.max_transfer.into()) as usize;
+            let data = &mut buffer[..chunk * SECTOR_SIZE as usize];
+            self.read_lba(sector, data).await?;
+            collect_unerased(data, sector, &mut unerased);
+        }
+        Ok(unerased)
+    }
+
+    /// Write data to one of the standard flash regions
+    ///
+    /// The data is padded with zeros to a sector boundary; Data that's empty or doesn't fit the
+    /// region is rejected
+    pub async fn write_region(&mut self, region: Region, data: &[u8]) -> Result<()> {
+        if data.is_empty() || data.len() > region.size() as usize * SECTOR_SIZE as usize {
+            return Err(Error::InvalidRegionSize {
+                region,
+                size: data.len(),
+            });
+        }
+        let padded = data.len().next_multiple_of(SECTOR_SIZE as usize);
+        if padded == data.len() {
+            self.write_lba_unchecked(region.offset(), data).await?;
+        } else {
+            let mut buffer = data.to_vec();
+            buffer.resize(padded, 0);
+            self.write_lba_unchecked(region.offset(), &buffer).await?;
+        }
+        Ok(())
+    }
+
+    /// Read from the first 4MiB of the flash at a byte offset
+    ///
+    /// Only available if the loader reports [Capability::first_4m_access]
+    pub async fn read_first_4m(&mut self, offset: u32, read: &mut [u8]) -> Result<()> {
+        let sectors = self.first_4m_sectors(offset, read.len()).await?;
+        let mut data = vec![0; sectors.len() * SECTOR_SIZE as usize];
+        self.read_lba(sectors.start, &mut data).await?;
+        let start = (offset % SECTOR_SIZE as u32) as usize;
+        read.copy_from_slice(&data[start..start + read.len()]);
+        Ok(())
+    }
+
+    /// Write to the first 4MiB of the flash at a byte offset
+    ///
+    /// Data surrounding the written range in partially written sectors is preserved. Only
+    /// available if the loader reports [Capability::first_4m_access]; Meant for IDBlock and
+    /// bootloader surgery, so writes are allowed to touch the critical regions
+    pub async fn write_first_4m(&mut self, offset: u32, write: &[u8]) -> Result<()> {
+        let sectors = self.first_4m_sectors(offset, write.len()).await?;
+        let mut data = vec![0; sectors.len() * SECTOR_SIZE as usize];
+        let start = (offset % SECTOR_SIZE as u32) as usize;
+        if start != 0 || data.len() != write.len() {
+            self.read_lba(sectors.start, &mut data).await?;
+        }
+        data[start..start + write.len()].copy_from_slice(write);
+        self.write_lba_unchecked(sectors.start, &data).await?;
+        Ok(())
+    }
+
+    // Sectors covering a byte range in the first 4MiB, if the loader allows accessing it
+    async fn first_4m_sectors(&mut self, offset: u32, len: usize) -> Result<std::ops::Range<u32>> {
+        if !self.capability().await?.first_4m_access() {
+            return Err(Error::Unsupported("first 4MiB access"));
+        }
+        let end = u64::from(offset) + len as u64;
+        if end > u64::from(FIRST_4M_SECTORS) * SECTOR_SIZE {
+            return Err(Error::OutOfBounds { offset, len });
+        }
+        Ok(offset / SECTOR_SIZE as u32..end.div_ceil(SECTOR_SIZE) as u32)
+    }
+
+    /// Write an IDBlock (e.g. idbloader.img) to the standard offset
+    pub async fn write_idblock(&mut self, data: &[u8]) -> Result<()> {
+        self.write_region(Region::IdBlock, data).await
+    }
+
+    /// Write U-Boot (e.g. uboot.img or u-boot.itb) to the standard offset
+    pub async fn write_uboot(&mut self, itb: &[u8]) -> Result<()> {
+        self.write_region(Region::UBoot, itb).await
+    }
+
+    /// Write the trusted firmware (trust.img) to the standard offset
+    pub async fn write_trust(&mut self, img: &[u8]) -> Result<()> {
+        self.write_region(Region::Trust, img).await
+    }
+
+    /// Write data pulled from a reader to a range of sectors
+    ///
+    /// Writing stops once the reader is exhausted or the end of the range is reached; a partial
+    /// last sector is padded with zeros. As the data is consumed from the reader, failed writes
+    /// are not retried; Writes the device only partially completes are re-issued for the
+    /// remaining sectors up to [MAX_RESIDUE_RETRIES] times. Returns the number of bytes taken from
+    /// the reader
+    ///
+    /// The whole range is refused if it overlaps the critical regions, see
+    /// [Transport::allow_critical_regions]. Note that the reader is read synchronously
+    pub async fn write_lba_from_reader(
+        &mut self,
+        sectors: std::ops::Range<u32>,
+        reader: &mut (dyn std::io::Read + Send),
+    ) -> Result<u64> {
+        self.check_critical(sectors.clone())?;
+        let context = OperationContext::with_sectors("write_lba_from_reader", sectors.clone());
+        let mut operation = crate::operation::write_lba_from_reader(sectors, reader);
+        let r = self.handle_operation(&mut operation).await;
+        self.stats.bytes_written += operation.written();
+        r.map_err(|e| e.context(context))
+    }
+
+    /// Write a specific area while in maskrom mode; typically [Area::Sram] or [Area::Ddr] data as
+    /// retrieved from a rockchip boot file
+    pub async fn write_maskrom_area(&mut self, area: Area, data: &[u8]) -> Result<()> {
+        self.handle_operation(
+            crate::operation::write_area(area, data).with_encoding(self.maskrom_encoding),
+        )
+        .await
+        .map_err(|e| e.context(OperationContext::new("write_maskrom_area")))?;
+        self.stats.bytes_written += data.len() as u64;
+        Ok(())
+    }
+
+    /// Write a specific area while in maskrom mode with data pulled from a reader
+    ///
+    /// Unlike [Transport::write_maskrom_area] the data doesn't need to be fully buffered
+    ///
+    /// Note that the reader is read synchronously
+    pub async fn write_maskrom_area_from_reader(
+        &mut self,
+        area: Area,
+        reader: &mut (dyn std::io::Read + Send),
+    ) -> Result<()> {
+        let mut operation = crate::operation::write_area_from_reader(area, reader)
+            .with_encoding(self.maskrom_encoding);
+        let r = self.handle_operation(&mut operation).await;
+        self.stats.bytes_written += operation.written() as u64;
+        r.map_err(|e| e.context(OperationContext::new("write_maskrom_area")))
+    }
+
+    /// Download a boot file (e.g. as created by boot_merger) to a device in maskrom mode
+    ///
+    /// The SRAM (0x471) and DDR (0x472) entries are written using
+    /// [Transport::write_maskrom_area], waiting for the delay of each entry plus the download
+    /// delay quirk of the device afterwards. Loader entries are skipped
+    pub async fn download_boot(&mut self, boot: &BootFile) -> Result<()> {
+        for (kind, entry) in boot.all_entries() {
+            let area = match kind {
+                EntryKind::Sram471 => Area::Sram,
+                EntryKind::Ddr472 => Area::Ddr,
+                EntryKind::Loader => continue,
+            };
+            let data = boot.entry_data(&entry).ok_or(Error::OutOfBounds {
+                offset: entry.data_offset,
+                len: entry.data_size as usize,
+            })?;
+            self.write_maskrom_area(area, data).await?;
+            futures_timer::Delay::new(
+                Duration::from_millis(entry.data_delay.into()) + self.quirks.download_delay,
+            )
+            .await;
+        }
+        Ok(())
+    }
+
+    /// Read the primary GPT from the flash
+    pub async fn read_gpt(&mut self) -> std::result::Result<Gpt, GptError> {
+        runner::read_gpt(self).await
+    }
+
+    /// Execute a flash plan
+    ///
+    /// `progress` is called while executing the steps of the plan. Execution stops at the first
+    /// step that fails
+    ///
+    /// Note that images are opened and read synchronously, blocking the executor while doing so;
+    /// For gzip images this includes decompressing the whole image upfront to determine its size
+    pub async fn run_plan<P>(
+        &mut self,
+        plan: &FlashPlan,
+        mut progress: P,
+    ) -> std::result::Result<FlashReport, FlashError>
+    where
+        P: FnMut(&Progress),
+    {
+        runner::run_plan(self, plan, None, &mut progress).await
+    }
+
+    /// Execute a flash plan, recording progress in a journal
+    ///
+    /// Steps the journal records as complete are skipped and interrupted image writes are resumed
+    /// from the last recorded chunk. Skipped steps are reported as having processed 0 bytes
+    ///
+    /// Note that besides reading images (see [Transport::run_plan]) the journal is read and
+    /// synced to disk synchronously
+    pub async fn run_plan_journaled<P>(
+        &mut self,
+        plan: &FlashPlan,
+        journal: &mut Journal,
+        mut progress: P,
+    ) -> std::result::Result<FlashReport, FlashError>
+    where
+        P: FnMut(&Progress),
+    {
+        runner::run_plan(self, plan, Some(journal), &mut progress).await
+    }
+
+    /// Repair the GPT on the flash
+    ///
+    /// The primary header and partition entries are validated, falling back to the backup at the
+    /// end of the flash if they're corrupted. The table is then written back with recalculated
+    /// CRCs and the backup at the true end of the flash, e.g. to fix up an image built for
+    /// smaller media; A valid backup found elsewhere is cleared
+    pub async fn repair_gpt(&mut self) -> std::result::Result<GptRepair, GptError> {
+        runner::repair_gpt(self).await
+    }
 
-impl Transport {
-    fn new(
-        device: nusb::Device,
-        interface: u8,
-        ep_in: u8,
-        ep_out: u8,
-    ) -> std::result::Result<Self, DeviceUnavalable> {
-        let interface = device.claim_interface(interface)?;
-        Ok(Self {
-            interface,
-            ep_in,
-            ep_out,
-        })
+    /// Fix up the GPT after writing a disk image smaller than the flash
+    ///
+    /// The backup GPT is moved to the end of the flash and, if `grow_last` is set, the last
+    /// partition is grown to fill the remaining space. Returns the updated partition table
+    pub async fn fixup_gpt_after_image(
+        &mut self,
+        grow_last: bool,
+    ) -> std::result::Result<Gpt, GptError> {
+        runner::fixup_gpt_after_image(self, grow_last).await
     }
 
-    /// Create a new transport from a device info
-    pub fn from_usb_device_info(
-        info: nusb::DeviceInfo,
-    ) -> std::result::Result<Self, DeviceUnavalable> {
-        let device = info.open()?;
-        Self::from_usb_device(device)
+    /// Reset the device
+    pub async fn reset_device(&mut self, opcode: ResetOpcode) -> Result<()> {
+        self.flash_info = None;
+        self.capability = None;
+        self.handle_operation(crate::operation::reset_device(opcode))
+            .await
+            .map_err(|e| e.context(OperationContext::new("reset_device")))
     }
 
-    /// Create a new transport from an existing device
-    pub fn from_usb_device(device: nusb::Device) -> std::result::Result<Self, DeviceUnavalable> {
-        for config in device.clone().configurations() {
-            for interface in config.interface_alt_settings() {
-                let output = interface.endpoints().find(|e| {
-                    e.direction() == nusb::transfer::Direction::Out
-                        && e.transfer_type() == nusb::transfer::EndpointType::Bulk
-                });
-                let input = interface.endpoints().find(|e| {
-                    e.direction() == nusb::transfer::Direction::In
-                        && e.transfer_type() == nusb::transfer::EndpointType::Bulk
+    /// Upload code to the sdram and jump to it
+    ///
+    /// The payload is written to `address`, relative to the start of the sdram as used by the
+    /// loader, in chunks of at most [SDRAM_CHUNK_SIZE] bytes; The address must be aligned to
+    /// [SDRAM_ALIGNMENT] bytes and the payload is padded with zeros to a multiple of it. Useful
+    /// for running DDR testers or custom stage-2 tools from a loader
+    pub async fn run_code(&mut self, address: u32, payload: &[u8]) -> Result<()> {
+        if address & (SDRAM_ALIGNMENT - 1) != 0 {
+            return Err(Error::UnalignedAddress {
+                address,
+                alignment: SDRAM_ALIGNMENT,
+            });
+        }
+        let len = payload.len().next_multiple_of(SDRAM_ALIGNMENT as usize);
+        if u64::from(address) + len as u64 > u64::from(u32::MAX) + 1 {
+            return Err(Error::OutOfBounds {
+                offset: address,
+                len: payload.len(),
+            });
+        }
+        let mut padded = payload.to_vec();
+        padded.resize(len, 0);
+        for (i, chunk) in padded.chunks(SDRAM_CHUNK_SIZE).enumerate() {
+            let chunk_address = address + (i * SDRAM_CHUNK_SIZE) as u32;
+            let written: u32 = self
+                .handle_operation(crate::operation::write_sdram(chunk_address, chunk)?)
+                .await
+                .map_err(|e| e.context(OperationContext::new("write_sdram")))?
+                .into();
+            if written as usize != chunk.len() {
+                return Err(Error::ShortTransfer {
+                    expected: chunk.len(),
+                    actual: written as usize,
                 });
+            }
+        }
+        // The uploaded code may take over from the loader
+        self.flash_info = None;
+        self.capability = None;
+        self.handle_operation(crate::operation::execute_sdram(address))
+            .await
+            .map_err(|e| e.context(OperationContext::new("execute_sdram")))
+    }
 
-                if let (Some(input), Some(output)) = (input, output) {
-                    return Transport::new(
-                        device,
-                        interface.interface_number(),
-                        input.address(),
-                        output.address(),
-                    );
-                }
+    /// Write `len` bytes from a reader to the flash starting at start_sector
+    ///
+    /// The data is written in chunks of up to [Transport::max_transfer_sectors] sectors, aligned
+    /// to the erase block size of the flash where possible. If `len` isn't a multiple of [SECTOR_SIZE] the
+    /// remainder of the last sector is preserved by reading it back from the flash first. After
+    /// each chunk `progress` is called with the total number of bytes written
+    pub async fn write_from_reader<R, P>(
+        &mut self,
+        start_sector: u32,
+        mut reader: R,
+        len: u64,
+        mut progress: P,
+    ) -> std::io::Result<()>
+    where
+        R: AsyncRead + Unpin,
+        P: FnMut(u64),
+    {
+        let info = self.flash_info().await?;
+        let mut buffer = vec![0u8; self.max_transfer_size()];
+        let mut sector = start_sector;
+        let mut written = 0;
+        while written < len {
+            let sectors = (len - written)
+                .div_ceil(SECTOR_SIZE)
+                .min(self.max_transfer.into()) as u32;
+            let sectors = info.block_aligned_sectors(sector, sectors);
+            let chunk = (len - written).min(u64::from(sectors) * SECTOR_SIZE) as usize;
+            let padded = chunk.next_multiple_of(SECTOR_SIZE as usize);
+            if padded != chunk {
+                let last = padded - SECTOR_SIZE as usize;
+                let last_sector = sector + (last / SECTOR_SIZE as usize) as u32;
+                self.read_lba(last_sector, &mut buffer[last..padded])
+                    .await?;
             }
+            reader.read_exact(&mut buffer[..chunk]).await?;
+            self.write_lba(sector, &buffer[..padded]).await?;
+            sector += (padded / SECTOR_SIZE as usize) as u32;
+            written += chunk as u64;
+            progress(written);
         }
-        Err(DeviceUnavalable {
-            error: nusb::Error::new(std::io::ErrorKind::NotFound, "Device not found"),
-        })
+        Ok(())
     }
 
-    /// Convert into an IO object which implements [AsyncRead],
-    /// [AsyncWrite] and [AsyncSeek]
-    pub async fn into_io(self) -> Result<TransportIO> {
-        TransportIO::new(self).await
+    /// Read a range of sectors from the flash into a writer
+    ///
+    /// The data is read in chunks of up to [Transport::max_transfer_sectors] sectors. After each
+    /// chunk `progress` is called with the total number of bytes read
+    pub async fn read_to_writer<W, P>(
+        &mut self,
+        sectors: std::ops::Range<u32>,
+        mut writer: W,
+        mut progress: P,
+    ) -> std::io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+        P: FnMut(u64),
+    {
+        let mut buffer = vec![0u8; self.max_transfer_size()];
+        let mut read = 0;
+        for sector in sectors.clone().step_by(self.max_transfer.into()) {
+            let chunk = (sectors.end - sector).min(self.max_transfer.into()) as usize;
+            let chunk = &mut buffer[..chunk * SECTOR_SIZE as usize];
+            self.read_lba(sector, chunk).await?;
+            writer.write_all(chunk).await?;
+            read += chunk.len() as u64;
+            progress(read);
+        }
+        Ok(())
+    }
+
+    // Fill the remainder of the partial last sector of `buffer`, which holds `len` bytes of data
+    // to be written at start_sector, from the flash
+    async fn read_tail(&mut self, start_sector: u32, buffer: &mut [u8], len: usize) -> Result<()> {
+        let last = buffer.len() - SECTOR_SIZE as usize;
+        let mut tail = [0; SECTOR_SIZE as usize];
+        self.read_lba(
+            start_sector + (last / SECTOR_SIZE as usize) as u32,
+            &mut tail,
+        )
+        .await?;
+        buffer[len..].copy_from_slice(&tail[len - last..]);
+        Ok(())
     }
 
-    async fn handle_operation<O, T>(&mut self, mut operation: O) -> Result<T>
+    /// Write all data from a reader to the flash starting at start_sector
+    ///
+    /// Reading the next chunk from the reader (e.g. decompressing) is done concurrently with
+    /// transferring the current chunk to the device. Chunks are up to
+    /// [Transport::max_transfer_sectors] sectors. If the data isn't a multiple of [SECTOR_SIZE]
+    /// the remainder of the last sector is preserved by reading it back from the flash first.
+    /// Returns the number of bytes read from the reader.
+    pub async fn write_stream<R>(
+        &mut self,
+        start_sector: u32,
+        mut reader: R,
+    ) -> std::io::Result<u64>
     where
-        O: OperationSteps<T>,
+        R: AsyncRead + Unpin,
     {
-        loop {
-            let step = operation.step();
-            match step {
-                UsbStep::WriteBulk { data } => {
-                    let _written = self
-                        .interface
-                        .bulk_out(self.ep_out, data.to_vec())
-                        .await
-                        .into_result()?;
-                }
-                UsbStep::ReadBulk { data } => {
-                    let req = RequestBuffer::new(data.len());
-                    let read = self
-                        .interface
-                        .bulk_in(self.ep_in, req)
-                        .await
-                        .into_result()?;
-                    data.copy_from_slice(&read);
-                }
-                UsbStep::WriteControl {
-                    request_type,
-                    request,
-                    value,
-                    index,
-                    data,
-                } => {
-                    let (control_type, recipient) = (
-                        match request_type >> 5 & 0x03 {
-                            0 => ControlType::Standard,
-                            1 => ControlType::Class,
-                            2 => ControlType::Vendor,
-                            _ => ControlType::Standard,
-                        },
-                        match request_type & 0x1f {
-                            0 => Recipient::Device,
-                            1 => Recipient::Interface,
-                            2 => Recipient::Endpoint,
-                            3 => Recipient::Other,
-                            _ => Recipient::Device,
-                        },
-                    );
-                    let data = ControlOut {
-                        control_type,
-                        recipient,
-                        request,
-                        value,
-                        index,
-                        data,
-                    };
-                    self.interface.control_out(data).await.into_result()?;
-                }
-                UsbStep::Finished(r) => break r.map_err(|e| e.into()),
+        let mut current = vec![0u8; self.max_transfer_size()];
+        let mut next = vec![0u8; self.max_transfer_size()];
+        let mut sector = start_sector;
+        let mut written = 0;
+
+        let mut len = read_full(&mut reader, &mut current).await?;
+        while len > 0 {
+            let padded = len.next_multiple_of(SECTOR_SIZE as usize);
+            if padded != len {
+                self.read_tail(sector, &mut current[..padded], len).await?;
             }
+            let (w, r) = futures::join!(self.write_lba(sector, &current[..padded]), async {
+                if len == current.len() {
+                    read_full(&mut reader, &mut next).await
+                } else {
+                    Ok(0)
+                }
+            });
+            w.map_err(std::io::Error::from)?;
+            sector += (padded / SECTOR_SIZE as usize) as u32;
+            written += len as u64;
+
+            len = r?;
+            std::mem::swap(&mut current, &mut next);
         }
+        Ok(written)
+    }
+}
+
+impl FlashDevice for Transport {
+    fn max_transfer_size(&self) -> usize {
+        Transport::max_transfer_size(self)
     }
 
-    /// retrieve SoC flash identifier
-    pub async fn flash_id(&mut self) -> Result<FlashId> {
-        self.handle_operation(crate::operation::flash_id()).await
+    fn to_lba(sector: u64) -> std::io::Result<u32> {
+        Ok(to_lba(sector)?)
     }
 
-    /// retrieve SoC flash info
-    pub async fn flash_info(&mut self) -> Result<FlashInfo> {
-        self.handle_operation(crate::operation::flash_info()).await
+    async fn flash_info(&mut self) -> std::io::Result<FlashInfo> {
+        Ok(Transport::flash_info(self).await?)
     }
 
-    /// retrieve SoC chip info
-    pub async fn chip_info(&mut self) -> Result<ChipInfo> {
-        self.handle_operation(crate::operation::chip_info()).await
+    async fn read_lba(&mut self, start_sector: u32, data: &mut [u8]) -> std::io::Result<()> {
+        Transport::read_lba(self, start_sector, data).await?;
+        Ok(())
     }
 
-    /// read from the flash
-    ///
-    /// start_sector with [SECTOR_SIZE] sectors. the data to be read
-    /// must be a multiple of [SECTOR_SIZE] bytes
-    pub async fn read_lba(&mut self, start_sector: u32, read: &mut [u8]) -> Result<u32> {
-        self.handle_operation(crate::operation::read_lba(start_sector, read))
-            .await
-            .map(|t| t.into())
+    async fn write_lba_unchecked(&mut self, start_sector: u32, data: &[u8]) -> std::io::Result<()> {
+        Transport::write_lba_unchecked(self, start_sector, data).await?;
+        Ok(())
     }
 
-    /// Create operation to read an lba from the flash
+    async fn erase_lba(&mut self, start_sector: u32, sectors: u32) -> std::io::Result<()> {
+        Ok(Transport::erase_lba(self, start_sector, sectors).await?)
+    }
+
+    async fn reset_device(&mut self, opcode: ResetOpcode) -> std::io::Result<()> {
+        Ok(Transport::reset_device(self, opcode).await?)
+    }
+
+    async fn write_from_reader(
+        &mut self,
+        start_sector: u32,
+        reader: &mut (dyn Read + Send),
+        len: u64,
+        progress: &mut dyn FnMut(u64),
+    ) -> std::io::Result<()> {
+        let reader = futures::io::AllowStdIo::new(reader);
+        Transport::write_from_reader(self, start_sector, reader, len, progress).await
+    }
+}
+
+/// Cloneable handle to a [Transport] shared between tasks
+///
+/// Operations issued through any of the handles are serialized, such that e.g. one task can
+/// query device information while another task is transferring data. Longer sequences of
+/// operations can be done exclusively by holding the guard returned by [SharedTransport::lock]
+#[derive(Debug, Clone)]
+pub struct SharedTransport(Arc<Mutex<Transport>>);
+
+impl SharedTransport {
+    pub fn new(transport: Transport) -> Self {
+        Self(Arc::new(Mutex::new(transport)))
+    }
+
+    /// Get exclusive access to the transport
+    pub async fn lock(&self) -> MutexGuard<'_, Transport> {
+        self.0.lock().await
+    }
+
+    /// Get back the transport if this is the only handle left
+    pub fn try_unwrap(self) -> std::result::Result<Transport, Self> {
+        Arc::try_unwrap(self.0).map(Mutex::into_inner).map_err(Self)
+    }
+
+    /// Check whether the usb loader is ready, see [Transport::test_unit_ready]
+    pub async fn test_unit_ready(&self) -> Result<()> {
+        self.lock().await.test_unit_ready().await
+    }
+
+    /// Issue a test unit ready if the transport was idle for at least `threshold`, see
+    /// [Transport::keep_alive]
+    pub async fn keep_alive(&self, threshold: Duration) -> Result<bool> {
+        self.lock().await.keep_alive(threshold).await
+    }
+
+    /// Keep the loader alive while the transport is idle
     ///
-    /// start_sector based on [SECTOR_SIZE] sectors. the data to be
-    /// written must be a multiple of [SECTOR_SIZE] bytes
-    pub async fn write_lba(&mut self, start_sector: u32, write: &[u8]) -> Result<u32> {
-        self.handle_operation(crate::operation::write_lba(start_sector, write))
-            .await
-            .map(|t| t.into())
+    /// Waits until the transport was idle for `threshold` and issues a test unit ready, over and
+    /// over until a keep alive command fails. Meant to run alongside the actual work, e.g. by
+    /// joining or selecting on both; Dropping the future stops the keep alive. `threshold` should
+    /// be well above zero, as otherwise the loader is kept busy
+    pub async fn run_keep_alive(&self, threshold: Duration) -> Result<()> {
+        loop {
+            let idle = self.lock().await.idle_time();
+            futures_timer::Delay::new(threshold.saturating_sub(idle)).await;
+            self.keep_alive(threshold).await?;
+        }
     }
 
-    /// Write a specific area while in maskrom mode; typically 0x471 or 0x472 data as retrieved from a
-    /// rockchip boot file
-    pub async fn write_maskrom_area(&mut self, area: u16, data: &[u8]) -> Result<()> {
-        self.handle_operation(crate::operation::write_area(area, data))
-            .await
+    /// retrieve SoC flash identifier, see [Transport::flash_id]
+    pub async fn flash_id(&self) -> Result<FlashId> {
+        self.lock().await.flash_id().await
     }
 
-    /// Reset the device
-    pub async fn reset_device(&mut self, opcode: ResetOpcode) -> Result<()> {
-        self.handle_operation(crate::operation::reset_device(opcode))
-            .await
+    /// retrieve SoC flash info, see [Transport::flash_info]
+    pub async fn flash_info(&self) -> Result<FlashInfo> {
+        self.lock().await.flash_info().await
+    }
+
+    /// retrieve the capabilities of the usb loader, see [Transport::capability]
+    pub async fn capability(&self) -> Result<Capability> {
+        self.lock().await.capability().await
+    }
+
+    /// retrieve the storage media currently in use, see [Transport::storage]
+    pub async fn storage(&self) -> Result<Storage> {
+        self.lock().await.storage().await
+    }
+
+    /// retrieve SoC chip info, see [Transport::chip_info]
+    pub async fn chip_info(&self) -> Result<ChipInfo> {
+        self.lock().await.chip_info().await
+    }
+
+    /// read from the flash, see [Transport::read_lba]
+    pub async fn read_lba(&self, start_sector: u32, read: &mut [u8]) -> Result<u32> {
+        self.lock().await.read_lba(start_sector, read).await
+    }
+
+    /// write to the flash, see [Transport::write_lba]
+    pub async fn write_lba(&self, start_sector: u32, write: &[u8]) -> Result<u32> {
+        self.lock().await.write_lba(start_sector, write).await
+    }
+
+    /// reset the device, see [Transport::reset_device]
+    pub async fn reset_device(&self, opcode: ResetOpcode) -> Result<()> {
+        self.lock().await.reset_device(opcode).await
     }
 }
 
+// Read until the buffer is full or the end of the stream is reached
+async fn read_full<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    buffer: &mut [u8],
+) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]).await {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
 type ReadResult = std::io::Result<(Vec<u8>, usize)>;
+// The transport is boxed to keep the idle state small
 enum IoState {
-    Idle(Option<TransportIOInner>),
-    Read(BoxFuture<'static, (TransportIOInner, ReadResult)>),
-    Write(BoxFuture<'static, (TransportIOInner, std::io::Result<usize>)>),
-    Flush(BoxFuture<'static, (TransportIOInner, std::io::Result<()>)>),
+    Idle(Option<Box<TransportIOInner>>),
+    Read(BoxFuture<'static, (Box<TransportIOInner>, ReadResult)>),
+    Write(BoxFuture<'static, (Box<TransportIOInner>, std::io::Result<usize>)>),
+    Flush(BoxFuture<'static, (Box<TransportIOInner>, std::io::Result<()>)>),
+    Fill(BoxFuture<'static, (Box<TransportIOInner>, std::io::Result<()>)>),
 }
 
 struct TransportIOInner {
@@ -224,38 +1888,79 @@ struct TransportIOInner {
     offset: u64,
     buffer: Box<[u8; 512]>,
     size: u64,
+    // Maximum size of a single direct I/O operation in bytes
+    maxio_size: u64,
     // Whether or not the buffer is dirty
     state: BufferState,
+    // Offset consumed up to past a dirty buffer; consume can't do IO, so the buffer is written out
+    // and the offset moved by the next operation
+    consumed: Option<u64>,
 }
 
-/// IO object which implements [AsyncRead], [AsyncWrite] and [AsyncSeek]
+/// IO object which implements [AsyncRead], [AsyncBufRead], [AsyncWrite] and [AsyncSeek]
+///
+/// The [AsyncBufRead] implementation exposes the internal sector buffer, so parsers reading small
+/// structures can use it without adding another layer of buffering
 pub struct TransportIO {
     // io execution state
     io_state: IoState,
     size: u64,
+    maxio_size: u64,
 }
 
 impl TransportIO {
+    /// Maximum size of a single direct I/O operation in bytes for full speed devices; Faster
+    /// devices use bigger operations by default, see [Transport::max_transfer_sectors]
+    pub const DEFAULT_MAXIO_SIZE: u64 = 128 * crate::protocol::SECTOR_SIZE;
+
     /// Create a new IO object around a given transport
-    pub async fn new(mut transport: Transport) -> Result<Self> {
+    ///
+    /// The maximum size of direct I/O operations is the transport's
+    /// [Transport::max_transfer_sectors]
+    pub async fn new(transport: Transport) -> Result<Self> {
+        let maxio_size = u64::from(transport.max_transfer_sectors()) * SECTOR_SIZE;
+        Self::new_with_maxio_size(transport, maxio_size).await
+    }
+
+    /// Create a new IO object around a given transport using a specific maximum size for
+    /// single direct I/O operations
+    ///
+    /// The size is rounded down to a multiple of [SECTOR_SIZE] and clamped between one sector and
+    /// [MAX_LBA_SECTORS] sectors
+    pub async fn new_with_maxio_size(mut transport: Transport, maxio_size: u64) -> Result<Self> {
         let info = transport.borrow_mut().flash_info().await?;
         let size = info.size();
+        let maxio_size =
+            (maxio_size / SECTOR_SIZE * SECTOR_SIZE).clamp(SECTOR_SIZE, MAX_LBA_CHUNK as u64);
         let inner = TransportIOInner {
             transport,
             offset: 0,
             buffer: Box::new([0u8; 512]),
             size,
+            maxio_size,
             state: BufferState::Invalid,
+            consumed: None,
         };
         Ok(Self {
             size,
-            io_state: IoState::Idle(Some(inner)),
+            maxio_size,
+            io_state: IoState::Idle(Some(Box::new(inner))),
         })
     }
 
-    // Convert into the inner transport
-    //
-    // Panics if the the TransportIO is currently executing I/O operations
+    /// Get a reference to the inner transport
+    ///
+    /// Panics if the the TransportIO is currently executing I/O operations
+    pub fn inner(&mut self) -> &mut Transport {
+        match self.io_state {
+            IoState::Idle(Some(ref mut i)) => &mut i.transport,
+            _ => panic!("TransportIO is currently executing I/O operations"),
+        }
+    }
+
+    /// Convert into the inner transport
+    ///
+    /// Panics if the the TransportIO is currently executing I/O operations
     pub fn into_inner(self) -> Transport {
         let inner = match self.io_state {
             IoState::Idle(Some(i)) => i,
@@ -268,16 +1973,32 @@ impl TransportIO {
     pub fn size(&self) -> u64 {
         self.size
     }
+
+    /// Maximum size of a single direct I/O operation in bytes
+    pub fn maxio_size(&self) -> u64 {
+        self.maxio_size
+    }
 }
 
 impl TransportIOInner {
-    const MAXIO_SIZE: u64 = 128 * crate::protocol::SECTOR_SIZE;
     fn current_sector(&self) -> u64 {
         self.offset / SECTOR_SIZE
     }
 
+    // Write out the buffer left behind by consuming past it and move to the consumed offset
+    async fn settle(&mut self) -> std::io::Result<()> {
+        if let Some(offset) = self.consumed {
+            self.flush_buffer().await?;
+            self.consumed = None;
+            self.offset = offset;
+            self.state = BufferState::Invalid;
+        }
+        Ok(())
+    }
+
     // Want to start an i/o operation with a given maximum length
     async fn pre_io(&mut self, len: u64) -> std::result::Result<IOOperation, std::io::Error> {
+        self.settle().await?;
         if self.offset >= self.size {
             return Ok(IOOperation::Eof);
         }
@@ -292,9 +2013,18 @@ impl TransportIOInner {
         if sector_offset == 0 && len >= SECTOR_SIZE {
             // At most read the amount of bytes left
             let left = self.size - self.offset;
-            let io_len = len.min(left) / SECTOR_SIZE * SECTOR_SIZE;
+            let sectors = len.min(left).min(self.maxio_size) / SECTOR_SIZE;
+            // End on an erase block boundary if possible so following operations are aligned;
+            // The flash info is cached by the transport
+            let info = self
+                .transport
+                .borrow_mut()
+                .flash_info()
+                .await
+                .map_err(std::io::Error::from)?;
+            let sectors = info.block_aligned_sectors(self.current_sector() as u32, sectors as u32);
             Ok(IOOperation::Direct {
-                len: io_len.min(Self::MAXIO_SIZE) as usize,
+                len: (u64::from(sectors) * SECTOR_SIZE) as usize,
             })
         } else {
             if self.state == BufferState::Invalid {
@@ -303,7 +2033,7 @@ impl TransportIOInner {
                     .borrow_mut()
                     .read_lba(sector, self.buffer.as_mut())
                     .await
-                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::BrokenPipe, e))?;
+                    .map_err(std::io::Error::from)?;
                 self.state = BufferState::Valid;
             }
             Ok(IOOperation::Buffered {
@@ -335,7 +2065,26 @@ impl TransportIOInner {
                 .borrow_mut()
                 .write_lba(sector, self.buffer.as_mut())
                 .await
-                .map_err(|e| std::io::Error::new(std::io::ErrorKind::BrokenPipe, e))?;
+                .map_err(std::io::Error::from)?;
+            self.state = BufferState::Valid;
+        }
+        Ok(())
+    }
+
+    // Load the current sector into the buffer; A dirty buffer already holds the current sector
+    // including the pending data
+    async fn fill_buffer(&mut self) -> std::io::Result<()> {
+        self.settle().await?;
+        if self.offset >= self.size {
+            return Ok(());
+        }
+        if self.state == BufferState::Invalid {
+            let sector = self.current_sector() as u32;
+            self.transport
+                .borrow_mut()
+                .read_lba(sector, self.buffer.as_mut())
+                .await
+                .map_err(std::io::Error::from)?;
             self.state = BufferState::Valid;
         }
         Ok(())
@@ -347,7 +2096,7 @@ impl TransportIOInner {
             .borrow_mut()
             .read_lba(sector, buf)
             .await
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::BrokenPipe, e))?;
+            .map_err(std::io::Error::from)?;
         Ok(buf.len())
     }
 
@@ -357,7 +2106,7 @@ impl TransportIOInner {
             .borrow_mut()
             .write_lba(sector, buf)
             .await
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::BrokenPipe, e))?;
+            .map_err(std::io::Error::from)?;
         Ok(buf.len())
     }
 }
@@ -389,8 +2138,7 @@ impl AsyncWrite for TransportIO {
             match me.io_state {
                 IoState::Idle(ref mut inner) => {
                     let mut inner = inner.take().unwrap();
-                    let buf =
-                        Vec::from(&buf[0..buf.len().min(TransportIOInner::MAXIO_SIZE as usize)]);
+                    let buf = Vec::from(&buf[0..buf.len().min(me.maxio_size as usize)]);
                     me.io_state = IoState::Write(Box::pin(async move {
                         let io = match inner.pre_io(buf.len() as u64).await {
                             Ok(io) => io,
@@ -411,10 +2159,7 @@ impl AsyncWrite for TransportIO {
                             IOOperation::Eof => {
                                 return (
                                     inner,
-                                    Err(std::io::Error::new(
-                                        std::io::ErrorKind::Other,
-                                        "Trying to write past end of area",
-                                    )),
+                                    Err(std::io::Error::other("Trying to write past end of area")),
                                 )
                             }
                         };
@@ -447,7 +2192,10 @@ impl AsyncWrite for TransportIO {
                 IoState::Idle(ref mut inner) => {
                     let mut inner = inner.take().unwrap();
                     me.io_state = IoState::Flush(Box::pin(async move {
-                        let r = inner.flush_buffer().await;
+                        let r = match inner.settle().await {
+                            Ok(()) => inner.flush_buffer().await,
+                            Err(e) => Err(e),
+                        };
                         (inner, r)
                     }))
                 }
@@ -526,16 +2274,80 @@ impl AsyncRead for TransportIO {
     }
 }
 
+impl AsyncBufRead for TransportIO {
+    fn poll_fill_buf(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<futures::io::Result<&[u8]>> {
+        let me = self.get_mut();
+        loop {
+            match me.io_state {
+                IoState::Idle(ref mut inner) => {
+                    let filled = inner.as_ref().is_some_and(|i| {
+                        i.consumed.is_none()
+                            && (i.offset >= i.size || i.state != BufferState::Invalid)
+                    });
+                    if filled {
+                        break;
+                    }
+                    let mut inner = inner.take().unwrap();
+                    me.io_state = IoState::Fill(Box::pin(async move {
+                        let r = inner.fill_buffer().await;
+                        (inner, r)
+                    }))
+                }
+                IoState::Fill(ref mut f) => {
+                    let (inner, r) = ready!(f.as_mut().poll(cx));
+                    me.io_state = IoState::Idle(Some(inner));
+                    if let Err(e) = r {
+                        return Poll::Ready(Err(e));
+                    }
+                }
+                _ => {
+                    return Poll::Ready(Err(std::io::Error::new(
+                        std::io::ErrorKind::BrokenPipe,
+                        "Invalid transport state",
+                    )))
+                }
+            }
+        }
+        let IoState::Idle(Some(ref inner)) = me.io_state else {
+            unreachable!()
+        };
+        if inner.offset >= inner.size {
+            return Poll::Ready(Ok(&[]));
+        }
+        Poll::Ready(Ok(&inner.buffer[(inner.offset % SECTOR_SIZE) as usize..]))
+    }
+
+    fn consume(self: std::pin::Pin<&mut Self>, amt: usize) {
+        if let IoState::Idle(Some(ref mut inner)) = self.get_mut().io_state {
+            let offset = (inner.consumed.unwrap_or(inner.offset) + amt as u64).min(inner.size);
+            if offset / SECTOR_SIZE == inner.current_sector() {
+                inner.offset = offset;
+            } else if inner.state == BufferState::Dirty {
+                // Pending data is written out by the next operation before leaving the sector
+                inner.consumed = Some(offset);
+            } else {
+                inner.offset = offset;
+                inner.state = BufferState::Invalid;
+            }
+        }
+    }
+}
+
 impl AsyncSeek for TransportIO {
     fn poll_seek(
-        self: std::pin::Pin<&mut Self>,
-        _cx: &mut std::task::Context<'_>,
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
         pos: SeekFrom,
     ) -> std::task::Poll<futures::io::Result<u64>> {
+        // The buffer only holds the current sector, so write out pending data first
+        ready!(self.as_mut().poll_flush(cx))?;
         let me = self.get_mut();
         match me.io_state {
             IoState::Idle(Some(ref mut inner)) => {
-                inner.offset = match pos {
+                let offset = match pos {
                     SeekFrom::Start(offset) => inner.size.min(offset),
                     SeekFrom::End(offset) => {
                         if offset > 0 {
@@ -555,6 +2367,10 @@ impl AsyncSeek for TransportIO {
                         }
                     }
                 };
+                if offset / SECTOR_SIZE != inner.current_sector() {
+                    inner.state = BufferState::Invalid;
+                }
+                inner.offset = offset;
                 Poll::Ready(Ok(inner.offset))
             }
             _ => Poll::Ready(Err(std::io::Error::new(
@@ -0,0 +1,104 @@
+#[cfg(any(feature = "libusb", feature = "nusb"))]
+use crate::operation::BulkPhase;
+use crate::protocol::{CommandBlock, CommandStatus, Direction};
+
+/// Observer of the protocol activity of a transport
+///
+/// All methods have empty default implementations, so observers only need to implement the
+/// events they're interested in. Observers are called synchronously from the transport, so they
+/// should return quickly
+pub trait OperationObserver: Send + Sync {
+    /// A command block was sent to the device
+    fn on_command_block(&mut self, _command: &CommandBlock) {}
+
+    /// Data was transferred to (`Direction::Out`) or from (`Direction::In`) the device
+    fn on_data(&mut self, _direction: Direction, _data: &[u8]) {}
+
+    /// A command status was received from the device
+    fn on_status(&mut self, _status: &CommandStatus) {}
+
+    /// A failed operation is about to be retried; `attempt` is the number of the failed attempt
+    fn on_retry(&mut self, _attempt: u32, _error: &(dyn std::error::Error + 'static)) {}
+}
+
+// Report a bulk transfer to the device
+#[cfg(any(feature = "libusb", feature = "nusb"))]
+pub(crate) fn observe_write(observer: &mut dyn OperationObserver, phase: BulkPhase, data: &[u8]) {
+    match phase {
+        BulkPhase::Command => {
+            if let Ok(command) = CommandBlock::from_bytes(data) {
+                observer.on_command_block(&command)
+            }
+        }
+        _ => observer.on_data(Direction::Out, data),
+    }
+}
+
+// Report a bulk transfer from the device; Invalid statuses are left to the operation to report
+#[cfg(any(feature = "libusb", feature = "nusb"))]
+pub(crate) fn observe_read(observer: &mut dyn OperationObserver, phase: BulkPhase, data: &[u8]) {
+    match phase {
+        BulkPhase::Status => {
+            if let Ok(status) = CommandStatus::from_bytes(data) {
+                observer.on_status(&status)
+            }
+        }
+        _ => observer.on_data(Direction::In, data),
+    }
+}
+
+#[cfg(all(test, any(feature = "libusb", feature = "nusb")))]
+mod test {
+    use super::*;
+    use crate::protocol::{COMMAND_BLOCK_BYTES, COMMAND_STATUS_BYTES};
+
+    #[derive(Default)]
+    struct Recorder {
+        commands: usize,
+        data: Vec<(Direction, usize)>,
+        statuses: usize,
+    }
+
+    impl OperationObserver for Recorder {
+        fn on_command_block(&mut self, _command: &CommandBlock) {
+            self.commands += 1;
+        }
+
+        fn on_data(&mut self, direction: Direction, data: &[u8]) {
+            self.data.push((direction, data.len()));
+        }
+
+        fn on_status(&mut self, _status: &CommandStatus) {
+            self.statuses += 1;
+        }
+    }
+
+    #[test]
+    fn classify() {
+        let mut recorder = Recorder::default();
+        let command = CommandBlock::read_lba(0, 1);
+        let mut b = [0u8; COMMAND_BLOCK_BYTES];
+        command.to_bytes(&mut b);
+        observe_write(&mut recorder, BulkPhase::Command, &b);
+        observe_read(&mut recorder, BulkPhase::Data, &[0; 512]);
+        let mut s = [0u8; COMMAND_STATUS_BYTES];
+        CommandStatus::success_for(&command).to_bytes(&mut s);
+        observe_read(&mut recorder, BulkPhase::Status, &s);
+        observe_write(&mut recorder, BulkPhase::Data, &[0; 512]);
+        // Data that happens to look like a command block or status is still data
+        observe_write(&mut recorder, BulkPhase::Data, &b);
+        observe_read(&mut recorder, BulkPhase::Data, &s);
+
+        assert_eq!(recorder.commands, 1);
+        assert_eq!(recorder.statuses, 1);
+        assert_eq!(
+            recorder.data,
+            vec![
+                (Direction::In, 512),
+                (Direction::Out, 512),
+                (Direction::Out, COMMAND_BLOCK_BYTES),
+                (Direction::In, COMMAND_STATUS_BYTES)
+            ]
+        );
+    }
+}
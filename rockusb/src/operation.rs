@@ -1,8 +1,8 @@
-use std::marker::PhantomData;
+use std::{io::Read, marker::PhantomData, ops::Range};
 
 use crate::protocol::{
-    self, ChipInfo, CommandBlock, CommandStatus, CommandStatusParseError, Direction, FlashId,
-    FlashInfo, ResetOpcode,
+    self, Area, Capability, ChipInfo, CommandBlock, CommandStatus, CommandStatusParseError,
+    Direction, FlashId, FlashInfo, ResetOpcode, SocFamily, Storage,
 };
 use thiserror::Error;
 
@@ -13,14 +13,22 @@ pub enum UsbOperationError {
     TagMismatch,
     #[error("Incorrect status Signature receveived: {0:?}")]
     InvalidStatusSignature([u8; 4]),
-    #[error("Invalid status status: {0}")]
-    InvalidStatusStatus(u8),
     #[error("Invalid status data length")]
     InvalidStatusLength,
     #[error("Failed to parse reply")]
     ReplyParseFailure,
-    #[error("Device indicated operation failed")]
-    FailedStatus,
+    #[error("Device indicated operation failed (status: {:?}, residue: {})", .0.status, .0.residue)]
+    FailedStatus(CommandStatus),
+    #[error("Residue of {residue} bytes exceeds transfer length of {transfer_length} bytes")]
+    InvalidResidue { residue: u32, transfer_length: u32 },
+    #[error("Failed to read data to write: {0}")]
+    ReadError(std::io::ErrorKind),
+    #[error("Partial write: only {written} of {requested} bytes written")]
+    PartialTransfer { written: u64, requested: u64 },
+    #[error("Invalid lba transfer of {0} bytes")]
+    InvalidLbaLength(usize),
+    #[error("Invalid sdram transfer of {0} bytes")]
+    InvalidSdramLength(usize),
 }
 
 impl From<CommandStatusParseError> for UsbOperationError {
@@ -30,11 +38,101 @@ impl From<CommandStatusParseError> for UsbOperationError {
                 UsbOperationError::InvalidStatusSignature(s)
             }
             CommandStatusParseError::InvalidLength(_) => UsbOperationError::InvalidStatusLength,
-            CommandStatusParseError::InvalidStatus(s) => UsbOperationError::InvalidStatusStatus(s),
         }
     }
 }
 
+// io::ErrorKind can't be formatted by defmt, so it's left out
+#[cfg(feature = "defmt")]
+impl defmt::Format for UsbOperationError {
+    fn format(&self, f: defmt::Formatter) {
+        match self {
+            UsbOperationError::TagMismatch => defmt::write!(f, "TagMismatch"),
+            UsbOperationError::InvalidStatusSignature(s) => {
+                defmt::write!(f, "InvalidStatusSignature({=[u8]:x})", &s[..])
+            }
+            UsbOperationError::InvalidStatusLength => defmt::write!(f, "InvalidStatusLength"),
+            UsbOperationError::ReplyParseFailure => defmt::write!(f, "ReplyParseFailure"),
+            UsbOperationError::FailedStatus(status) => defmt::write!(f, "FailedStatus({})", status),
+            UsbOperationError::InvalidResidue {
+                residue,
+                transfer_length,
+            } => defmt::write!(
+                f,
+                "InvalidResidue {{ residue: {}, transfer_length: {} }}",
+                residue,
+                transfer_length
+            ),
+            UsbOperationError::ReadError(_) => defmt::write!(f, "ReadError"),
+            UsbOperationError::PartialTransfer { written, requested } => defmt::write!(
+                f,
+                "PartialTransfer {{ written: {}, requested: {} }}",
+                written,
+                requested
+            ),
+            UsbOperationError::InvalidLbaLength(len) => {
+                defmt::write!(f, "InvalidLbaLength({})", len)
+            }
+            UsbOperationError::InvalidSdramLength(len) => {
+                defmt::write!(f, "InvalidSdramLength({})", len)
+            }
+        }
+    }
+}
+
+/// Description of an operation, used to give context to errors
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct OperationContext {
+    /// Name of the operation
+    pub operation: &'static str,
+    /// Range of sectors the operation applied to, if any
+    pub sectors: Option<Range<u32>>,
+}
+
+impl OperationContext {
+    /// Context for an operation not tied to a range of sectors
+    pub fn new(operation: &'static str) -> Self {
+        Self {
+            operation,
+            sectors: None,
+        }
+    }
+
+    /// Context for an operation on a range of sectors
+    pub fn with_sectors(operation: &'static str, sectors: Range<u32>) -> Self {
+        Self {
+            operation,
+            sectors: Some(sectors),
+        }
+    }
+}
+
+impl std::fmt::Display for OperationContext {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.sectors {
+            Some(sectors) => write!(
+                f,
+                "{} of sectors {:#x}..{:#x}",
+                self.operation, sectors.start, sectors.end
+            ),
+            None => write!(f, "{}", self.operation),
+        }
+    }
+}
+
+/// Phase of the bulk-only protocol a bulk transfer belongs to
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum BulkPhase {
+    /// Command block sent to the device
+    Command,
+    /// Data sent to or received from the device
+    Data,
+    /// Command status received from the device
+    Status,
+}
+
 /// Step to take by the transport implementation
 #[derive(Debug, Eq, PartialEq)]
 pub enum UsbStep<'a, T> {
@@ -47,9 +145,12 @@ pub enum UsbStep<'a, T> {
         data: &'a [u8],
     },
     /// Write USB data using a bulk transfer
-    WriteBulk { data: &'a [u8] },
+    WriteBulk { data: &'a [u8], phase: BulkPhase },
     /// Read USB data using a bulk transfer
-    ReadBulk { data: &'a mut [u8] },
+    ReadBulk {
+        data: &'a mut [u8],
+        phase: BulkPhase,
+    },
     /// Operation is finished with a given result or failure
     Finished(Result<T, UsbOperationError>),
 }
@@ -57,7 +158,82 @@ pub enum UsbStep<'a, T> {
 /// steps to take to finish an operation
 pub trait OperationSteps<T> {
     /// Next step to execute by a transport
-    fn step(&mut self) -> UsbStep<T>;
+    fn step(&mut self) -> UsbStep<'_, T>;
+
+    /// Total number of data bytes transferred by the operation, excluding protocol overhead
+    ///
+    /// None if not known in advance, e.g. for data pulled from a reader
+    fn expected_bytes(&self) -> Option<u64> {
+        None
+    }
+}
+
+impl<T, O: OperationSteps<T>> OperationSteps<T> for &mut O {
+    fn step(&mut self) -> UsbStep<'_, T> {
+        (**self).step()
+    }
+
+    fn expected_bytes(&self) -> Option<u64> {
+        (**self).expected_bytes()
+    }
+}
+
+/// Encoding of data written in maskrom mode
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Encoding {
+    /// Data is written as-is
+    #[default]
+    Plain,
+    /// Each sector of data is RC4 coded with the fixed Rockchip key, as required by older SoCs
+    Rc4,
+}
+
+impl Encoding {
+    /// Encoding expected by the bootrom of a SoC family
+    ///
+    /// Older SoCs (RK29xx, RK3066 and RK3188 era) require [Encoding::Rc4]; All others use
+    /// [Encoding::Plain]
+    pub fn for_soc(family: SocFamily) -> Encoding {
+        match family {
+            SocFamily::Rk2918
+            | SocFamily::Rk2928
+            | SocFamily::Rk3066
+            | SocFamily::Rk3168
+            | SocFamily::Rk3066B
+            | SocFamily::Rk3188 => Encoding::Rc4,
+            _ => Encoding::Plain,
+        }
+    }
+
+    /// Encoding expected by the SoC with the given maskrom usb product id
+    ///
+    /// See [Encoding::for_soc]; [Encoding::Plain] is returned for unknown product ids
+    pub fn for_product_id(product_id: u16) -> Encoding {
+        SocFamily::from_product_id(product_id).map_or(Encoding::Plain, Encoding::for_soc)
+    }
+}
+
+const RC4_KEY: [u8; 16] = [124, 78, 3, 4, 85, 5, 9, 7, 45, 44, 123, 56, 23, 13, 23, 17];
+
+// RC4 code a sector with the fixed Rockchip key
+fn rc4_sector(data: &mut [u8]) {
+    let mut s: [u8; 256] = std::array::from_fn(|i| i as u8);
+    let mut j = 0u8;
+    for i in 0..256 {
+        j = j
+            .wrapping_add(s[i])
+            .wrapping_add(RC4_KEY[i % RC4_KEY.len()]);
+        s.swap(i, j as usize);
+    }
+
+    let (mut i, mut j) = (0u8, 0u8);
+    for b in data {
+        i = i.wrapping_add(1);
+        j = j.wrapping_add(s[i as usize]);
+        s.swap(i as usize, j as usize);
+        *b ^= s[s[i as usize].wrapping_add(s[j as usize]) as usize];
+    }
 }
 
 enum MaskRomSteps {
@@ -66,37 +242,87 @@ enum MaskRomSteps {
     Done,
 }
 
+enum MaskRomSource<'a> {
+    Slice(&'a [u8]),
+    Reader(&'a mut (dyn Read + Send)),
+}
+
 /// Operations that can be executed when the SoC is in MaskRom mode
 pub struct MaskRomOperation<'a> {
     written: usize,
     block: [u8; 4096],
-    data: &'a [u8],
+    source: MaskRomSource<'a>,
     area: u16,
+    encoding: Encoding,
     steps: MaskRomSteps,
 }
 
 const CRC: crc::Crc<u16> = crc::Crc::<u16>::new(&crc::CRC_16_IBM_3740);
 impl<'a> MaskRomOperation<'a> {
-    fn new(area: u16, data: &'a [u8]) -> Self {
+    fn new(area: Area, source: MaskRomSource<'a>) -> Self {
         Self {
             written: 0,
             block: [0; 4096],
-            data,
-            area,
+            source,
+            area: area.into(),
+            encoding: Encoding::Plain,
             steps: MaskRomSteps::Writing(CRC.digest()),
         }
     }
+
+    /// Encode the data with the given encoding before writing
+    pub fn with_encoding(mut self, encoding: Encoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Number of data bytes written so far
+    pub fn written(&self) -> usize {
+        self.written
+    }
+
+    // Fill the block with the next chunk of data, returning its size
+    fn fill_block(&mut self) -> std::io::Result<usize> {
+        match &mut self.source {
+            MaskRomSource::Slice(data) => {
+                let chunksize = 4096.min(data.len() - self.written);
+                self.block[..chunksize]
+                    .copy_from_slice(&data[self.written..self.written + chunksize]);
+                Ok(chunksize)
+            }
+            MaskRomSource::Reader(reader) => {
+                let mut filled = 0;
+                while filled < self.block.len() {
+                    match reader.read(&mut self.block[filled..]) {
+                        Ok(0) => break,
+                        Ok(r) => filled += r,
+                        Err(e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+                        Err(e) => return Err(e),
+                    }
+                }
+                Ok(filled)
+            }
+        }
+    }
 }
 
 impl OperationSteps<()> for MaskRomOperation<'_> {
-    fn step(&mut self) -> UsbStep<()> {
+    fn step(&mut self) -> UsbStep<'_, ()> {
         let mut current = MaskRomSteps::Done;
         std::mem::swap(&mut self.steps, &mut current);
         match current {
             MaskRomSteps::Writing(mut crc) => {
-                let chunksize = 4096.min(self.data.len() - self.written);
-                self.block[..chunksize]
-                    .copy_from_slice(&self.data[self.written..self.written + chunksize]);
+                let chunksize = match self.fill_block() {
+                    Ok(chunksize) => chunksize,
+                    Err(e) => {
+                        return UsbStep::Finished(Err(UsbOperationError::ReadError(e.kind())))
+                    }
+                };
+                if self.encoding == Encoding::Rc4 {
+                    self.block[..chunksize]
+                        .chunks_mut(protocol::SECTOR_SIZE as usize)
+                        .for_each(rc4_sector);
+                }
                 self.written += chunksize;
                 let chunk = match chunksize {
                     4096 => {
@@ -149,11 +375,26 @@ impl OperationSteps<()> for MaskRomOperation<'_> {
             MaskRomSteps::Done => UsbStep::Finished(Ok(())),
         }
     }
+
+    fn expected_bytes(&self) -> Option<u64> {
+        match &self.source {
+            MaskRomSource::Slice(data) => Some(data.len() as u64),
+            MaskRomSource::Reader(_) => None,
+        }
+    }
 }
 
-/// Write a specific area; typically 0x471 or 0x472 data as retrieved from a rockchip boot file
-pub fn write_area(area: u16, data: &[u8]) -> MaskRomOperation {
-    MaskRomOperation::new(area, data)
+/// Write a specific area; typically [Area::Sram] or [Area::Ddr] data as retrieved from a rockchip
+/// boot file
+pub fn write_area(area: Area, data: &[u8]) -> MaskRomOperation<'_> {
+    MaskRomOperation::new(area, MaskRomSource::Slice(data))
+}
+
+/// Write a specific area with data pulled from a reader in 4096 byte chunks
+///
+/// This avoids having to buffer e.g. big DDR blobs completely in memory
+pub fn write_area_from_reader(area: Area, reader: &mut (dyn Read + Send)) -> MaskRomOperation<'_> {
+    MaskRomOperation::new(area, MaskRomSource::Reader(reader))
 }
 
 trait FromOperation {
@@ -176,6 +417,23 @@ enum IOBytes<'a> {
     Write(&'a [u8]),
 }
 
+// Parse and validate the command status returned for a command
+fn check_status(command: &CommandBlock, bytes: &[u8]) -> Result<CommandStatus, UsbOperationError> {
+    let csw = CommandStatus::from_bytes(bytes)?;
+    if csw.status != protocol::Status::SUCCESS {
+        Err(UsbOperationError::FailedStatus(csw))
+    } else if csw.tag != command.tag() {
+        Err(UsbOperationError::TagMismatch)
+    } else if csw.residue > command.transfer_length() {
+        Err(UsbOperationError::InvalidResidue {
+            residue: csw.residue,
+            transfer_length: command.transfer_length(),
+        })
+    } else {
+        Ok(csw)
+    }
+}
+
 /// Operation to execute using the "full" USB protocol
 pub struct UsbOperation<'a, T> {
     command: CommandBlock,
@@ -238,7 +496,7 @@ where
     T: FromOperation,
     T: std::fmt::Debug,
 {
-    fn step(&mut self) -> UsbStep<T> {
+    fn step(&mut self) -> UsbStep<'_, T> {
         let mut next = Operation::CommandBlock;
         std::mem::swap(&mut self.next, &mut next);
         match next {
@@ -253,6 +511,7 @@ where
                 }
                 UsbStep::WriteBulk {
                     data: &self.command_bytes[..len],
+                    phase: BulkPhase::Command,
                 }
             }
             Operation::IO => {
@@ -261,9 +520,11 @@ where
                 match self.command.direction() {
                     Direction::Out => UsbStep::WriteBulk {
                         data: &self.io_data()[..len],
+                        phase: BulkPhase::Data,
                     },
                     Direction::In => UsbStep::ReadBulk {
                         data: &mut self.io_data_mut()[..len],
+                        phase: BulkPhase::Data,
                     },
                 }
             }
@@ -271,25 +532,22 @@ where
                 self.next = Operation::Finish;
                 UsbStep::ReadBulk {
                     data: &mut self.command_bytes[..protocol::COMMAND_STATUS_BYTES],
+                    phase: BulkPhase::Status,
                 }
             }
             Operation::Finish => {
-                let r = CommandStatus::from_bytes(&self.command_bytes)
-                    .map_err(UsbOperationError::from)
-                    .and_then(|csw| {
-                        if csw.status == protocol::Status::FAILED {
-                            Err(UsbOperationError::FailedStatus)
-                        } else if csw.tag == self.command.tag() {
-                            let transfer = self.command.transfer_length() as usize;
-                            T::from_operation(&self.io_data()[..transfer], &csw)
-                        } else {
-                            Err(UsbOperationError::TagMismatch)
-                        }
-                    });
+                let r = check_status(&self.command, &self.command_bytes).and_then(|csw| {
+                    let transfer = self.command.transfer_length() as usize;
+                    T::from_operation(&self.io_data()[..transfer], &csw)
+                });
                 UsbStep::Finished(r)
             }
         }
     }
+
+    fn expected_bytes(&self) -> Option<u64> {
+        Some(self.command.transfer_length() as u64)
+    }
 }
 
 impl FromOperation for ChipInfo {
@@ -343,6 +601,47 @@ pub fn flash_info() -> UsbOperation<'static, FlashInfo> {
     UsbOperation::new(CommandBlock::flash_info())
 }
 
+impl FromOperation for Capability {
+    fn from_operation(io: &[u8], _status: &CommandStatus) -> Result<Self, UsbOperationError>
+    where
+        Self: Sized,
+    {
+        let data = io
+            .try_into()
+            .map_err(|_e| UsbOperationError::ReplyParseFailure)?;
+        Ok(Capability::from_bytes(data))
+    }
+}
+
+/// Create operation to retrieve the capabilities of the usb loader
+pub fn capability() -> UsbOperation<'static, Capability> {
+    UsbOperation::new(CommandBlock::capability())
+}
+
+impl FromOperation for Storage {
+    fn from_operation(io: &[u8], _status: &CommandStatus) -> Result<Self, UsbOperationError>
+    where
+        Self: Sized,
+    {
+        let data = io
+            .try_into()
+            .map_err(|_e| UsbOperationError::ReplyParseFailure)?;
+        Ok(Storage::from_bytes(data))
+    }
+}
+
+/// Create operation to retrieve the storage media currently in use
+pub fn read_storage() -> UsbOperation<'static, Storage> {
+    UsbOperation::new(CommandBlock::read_storage())
+}
+
+/// Create operation to switch the storage media used by the usb loader
+///
+/// `storage` is the storage identifier as reported by [Storage::id], e.g. [Storage::SD]
+pub fn change_storage(storage: u8) -> UsbOperation<'static, ()> {
+    UsbOperation::new(CommandBlock::change_storage(storage))
+}
+
 impl FromOperation for () {
     fn from_operation(_io: &[u8], _status: &CommandStatus) -> Result<Self, UsbOperationError>
     where
@@ -352,6 +651,11 @@ impl FromOperation for () {
     }
 }
 
+/// Create operation to check whether the usb loader is ready to handle commands
+pub fn test_unit_ready() -> UsbOperation<'static, ()> {
+    UsbOperation::new(CommandBlock::test_unit_ready())
+}
+
 /// Create operation to reset the SoC
 pub fn reset_device(opcode: ResetOpcode) -> UsbOperation<'static, ()> {
     UsbOperation::new(CommandBlock::reset_device(opcode))
@@ -359,18 +663,16 @@ pub fn reset_device(opcode: ResetOpcode) -> UsbOperation<'static, ()> {
 
 /// Bytes transferred
 #[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct Transferred(u32);
 impl FromOperation for Transferred {
     fn from_operation(io: &[u8], status: &CommandStatus) -> Result<Self, UsbOperationError>
     where
         Self: Sized,
     {
+        // Residue is validated against the transfer length before getting here
         let totransfer = io.len() as u32;
-        if status.residue > totransfer {
-            Err(UsbOperationError::ReplyParseFailure)
-        } else {
-            Ok(Transferred(totransfer - status.residue))
-        }
+        Ok(Transferred(totransfer - status.residue))
     }
 }
 
@@ -380,38 +682,389 @@ impl From<Transferred> for u32 {
     }
 }
 
+/// Maximum number of sectors that can be transferred by a single lba read or write operation
+pub const MAX_LBA_SECTORS: u16 = u16::MAX;
+
+/// Number of sectors per lba transfer suited to a device with the given bulk endpoint max packet
+/// size
+///
+/// The bulk max packet size is fixed by the usb speed (64 bytes for full speed, 512 for high speed
+/// and 1024 for SuperSpeed), so this scales transfers with the link speed: From 128 sectors
+/// (64KiB) for full speed devices up to 2048 sectors (1MiB) for SuperSpeed loaders. This keeps
+/// each transfer well within the transfer timeout on slow links while avoiding per command
+/// overhead on fast ones
+pub fn transfer_sectors(max_packet_size: usize) -> u16 {
+    max_packet_size
+        .saturating_mul(2)
+        .clamp(128, MAX_LBA_SECTORS as usize) as u16
+}
+
+/// Maximum number of times the unwritten tail of an lba write is re-issued when the device
+/// reports a residue
+pub const MAX_RESIDUE_RETRIES: u32 = 3;
+
+fn lba_sectors(len: usize) -> Result<u16, UsbOperationError> {
+    let sector_size = protocol::SECTOR_SIZE as usize;
+    match u16::try_from(len / sector_size) {
+        Ok(sectors) if usize::from(sectors) * sector_size == len => Ok(sectors),
+        _ => Err(UsbOperationError::InvalidLbaLength(len)),
+    }
+}
+
 /// Create operation to read an lba from the flash
 ///
 /// start_sector with [protocol::SECTOR_SIZE] sectors. the data to be read must be a multiple of
-/// [protocol::SECTOR_SIZE] bytes
-pub fn read_lba(start_sector: u32, read: &mut [u8]) -> UsbOperation<'_, Transferred> {
-    assert_eq!(read.len() % 512, 0, "Not a multiple of 512: {}", read.len());
-    UsbOperation::new_read(
-        CommandBlock::read_lba(start_sector, (read.len() / 512) as u16),
+/// [protocol::SECTOR_SIZE] bytes and at most [MAX_LBA_SECTORS] sectors, otherwise
+/// [UsbOperationError::InvalidLbaLength] is returned
+pub fn read_lba(
+    start_sector: u32,
+    read: &mut [u8],
+) -> Result<UsbOperation<'_, Transferred>, UsbOperationError> {
+    Ok(UsbOperation::new_read(
+        CommandBlock::read_lba(start_sector, lba_sectors(read.len())?),
         read,
-    )
+    ))
 }
 
 /// Create operation to read an lba from the flash
 ///
 /// start_sector with [protocol::SECTOR_SIZE] sectors. the data to be written must be a multiple of
-/// [protocol::SECTOR_SIZE] bytes
-pub fn write_lba(start_sector: u32, write: &[u8]) -> UsbOperation<'_, Transferred> {
-    assert_eq!(
-        write.len() % 512,
-        0,
-        "Not a multiple of 512: {}",
-        write.len()
-    );
-    UsbOperation::new_write(
-        CommandBlock::write_lba(start_sector, (write.len() / 512) as u16),
+/// [protocol::SECTOR_SIZE] bytes and at most [MAX_LBA_SECTORS] sectors, otherwise
+/// [UsbOperationError::InvalidLbaLength] is returned
+pub fn write_lba(
+    start_sector: u32,
+    write: &[u8],
+) -> Result<UsbOperation<'_, Transferred>, UsbOperationError> {
+    Ok(UsbOperation::new_write(
+        CommandBlock::write_lba(start_sector, lba_sectors(write.len())?),
+        write,
+    ))
+}
+
+// Sectors of a chunk of a stream; The buffer holds at most STREAM_CHUNK_SECTORS sectors
+fn stream_sectors(len: usize) -> u16 {
+    (len / protocol::SECTOR_SIZE as usize) as u16
+}
+
+/// Create operation to erase a range of sectors on the flash
+///
+/// start_sector with [protocol::SECTOR_SIZE] sectors
+pub fn erase_lba(start_sector: u32, sectors: u16) -> UsbOperation<'static, ()> {
+    UsbOperation::new(CommandBlock::erase_lba(start_sector, sectors))
+}
+
+/// Maximum number of bytes written by a single [write_sdram] operation
+pub const SDRAM_CHUNK_SIZE: usize = 16 * 1024;
+
+/// Alignment in bytes of sdram addresses and transfers
+pub const SDRAM_ALIGNMENT: u32 = 4;
+
+/// Create operation to write data to the sdram
+///
+/// address is relative to the start of the sdram, as used by the loader. The data must be a
+/// multiple of [SDRAM_ALIGNMENT] bytes and at most [SDRAM_CHUNK_SIZE] bytes, otherwise
+/// [UsbOperationError::InvalidSdramLength] is returned
+pub fn write_sdram(
+    address: u32,
+    write: &[u8],
+) -> Result<UsbOperation<'_, Transferred>, UsbOperationError> {
+    if write.len() > SDRAM_CHUNK_SIZE || write.len() & (SDRAM_ALIGNMENT as usize - 1) != 0 {
+        return Err(UsbOperationError::InvalidSdramLength(write.len()));
+    }
+    Ok(UsbOperation::new_write(
+        CommandBlock::write_sdram(address, write.len() as u16),
         write,
-    )
+    ))
+}
+
+/// Create operation to jump to code in the sdram
+///
+/// address is relative to the start of the sdram, as used by the loader
+pub fn execute_sdram(address: u32) -> UsbOperation<'static, ()> {
+    UsbOperation::new(CommandBlock::execute_sdram(address))
+}
+
+/// Number of sectors written per round by a [WriteLbaStream] operation
+pub const STREAM_CHUNK_SECTORS: u16 = 2048;
+
+enum StreamState {
+    Fill,
+    CommandBlock,
+    IO,
+    CommandStatus,
+    Finish,
+}
+
+/// Operation writing data pulled from a reader to a range of sectors
+///
+/// The operation repeatedly reads up to [STREAM_CHUNK_SECTORS] sectors of data from the reader
+/// and writes them out with an lba write, until either the reader is exhausted or the end of the
+/// sector range is reached. A partial last sector is padded with zeros. The result is the number
+/// of bytes taken from the reader.
+///
+/// If the device reports a residue the write of the remaining sectors is re-issued up to
+/// [MAX_RESIDUE_RETRIES] times, after which the operation fails with
+/// [UsbOperationError::PartialTransfer].
+pub struct WriteLbaStream<'a> {
+    reader: &'a mut (dyn Read + Send),
+    sectors: Range<u32>,
+    buffer: Vec<u8>,
+    len: usize,
+    // Bytes of the current chunk known to be written
+    offset: usize,
+    residue_retries: u32,
+    written: u64,
+    command: CommandBlock,
+    command_bytes: [u8; protocol::COMMAND_BLOCK_BYTES],
+    next: StreamState,
+}
+
+impl WriteLbaStream<'_> {
+    /// Number of bytes taken from the reader and written so far
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    // Read until the buffer is full or the end of the stream is reached
+    fn fill(&mut self) -> std::io::Result<usize> {
+        let max = (self.sectors.len() * protocol::SECTOR_SIZE as usize).min(self.buffer.len());
+        let mut filled = 0;
+        while filled < max {
+            match self.reader.read(&mut self.buffer[filled..max]) {
+                Ok(0) => break,
+                Ok(r) => filled += r,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(filled)
+    }
+}
+
+impl OperationSteps<u64> for WriteLbaStream<'_> {
+    fn step(&mut self) -> UsbStep<'_, u64> {
+        let mut next = StreamState::Fill;
+        std::mem::swap(&mut self.next, &mut next);
+        match next {
+            StreamState::Fill => {
+                self.len = match self.fill() {
+                    Ok(0) => return UsbStep::Finished(Ok(self.written)),
+                    Ok(len) => len,
+                    Err(e) => {
+                        return UsbStep::Finished(Err(UsbOperationError::ReadError(e.kind())))
+                    }
+                };
+                let padded = self.len.next_multiple_of(protocol::SECTOR_SIZE as usize);
+                self.buffer[self.len..padded].fill(0);
+                self.offset = 0;
+                self.residue_retries = 0;
+                self.command = CommandBlock::write_lba(self.sectors.start, stream_sectors(padded));
+                self.next = StreamState::CommandBlock;
+                self.step()
+            }
+            StreamState::CommandBlock => {
+                self.next = StreamState::IO;
+                let len = self.command.to_bytes(&mut self.command_bytes);
+                UsbStep::WriteBulk {
+                    data: &self.command_bytes[..len],
+                    phase: BulkPhase::Command,
+                }
+            }
+            StreamState::IO => {
+                self.next = StreamState::CommandStatus;
+                let end = self.offset + self.command.transfer_length() as usize;
+                UsbStep::WriteBulk {
+                    data: &self.buffer[self.offset..end],
+                    phase: BulkPhase::Data,
+                }
+            }
+            StreamState::CommandStatus => {
+                self.next = StreamState::Finish;
+                UsbStep::ReadBulk {
+                    data: &mut self.command_bytes[..protocol::COMMAND_STATUS_BYTES],
+                    phase: BulkPhase::Status,
+                }
+            }
+            StreamState::Finish => match check_status(&self.command, &self.command_bytes) {
+                Ok(csw) => {
+                    // Only whole sectors are known to have landed
+                    let transferred = self.command.transfer_length() - csw.residue;
+                    let sectors = transferred / protocol::SECTOR_SIZE as u32;
+                    self.sectors.start += sectors;
+                    self.offset += sectors as usize * protocol::SECTOR_SIZE as usize;
+                    let padded = self.len.next_multiple_of(protocol::SECTOR_SIZE as usize);
+                    if self.offset < padded {
+                        if self.residue_retries == MAX_RESIDUE_RETRIES {
+                            let requested = self.written + self.len as u64;
+                            self.written += self.offset.min(self.len) as u64;
+                            return UsbStep::Finished(Err(UsbOperationError::PartialTransfer {
+                                written: self.written,
+                                requested,
+                            }));
+                        }
+                        // Re-issue the write for the rest
+                        self.residue_retries += 1;
+                        self.command = CommandBlock::write_lba(
+                            self.sectors.start,
+                            stream_sectors(padded - self.offset),
+                        );
+                        self.next = StreamState::CommandBlock;
+                        return self.step();
+                    }
+                    self.written += self.len as u64;
+                    self.next = StreamState::Fill;
+                    self.step()
+                }
+                Err(e) => UsbStep::Finished(Err(e)),
+            },
+        }
+    }
+}
+
+/// Create operation to write data pulled from a reader to a range of sectors
+pub fn write_lba_from_reader(
+    sectors: Range<u32>,
+    reader: &mut (dyn Read + Send),
+) -> WriteLbaStream<'_> {
+    WriteLbaStream {
+        reader,
+        sectors,
+        buffer: vec![0; STREAM_CHUNK_SECTORS as usize * protocol::SECTOR_SIZE as usize],
+        len: 0,
+        offset: 0,
+        residue_retries: 0,
+        written: 0,
+        command: CommandBlock::write_lba(0, 0),
+        command_bytes: [0u8; protocol::COMMAND_BLOCK_BYTES],
+        next: StreamState::Fill,
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+
+    fn maskrom_writes(mut o: MaskRomOperation) -> Vec<Vec<u8>> {
+        let mut writes = vec![];
+        loop {
+            match o.step() {
+                UsbStep::WriteControl { data, .. } => writes.push(data.to_vec()),
+                UsbStep::Finished(r) => {
+                    r.unwrap();
+                    break writes;
+                }
+                o => panic!("Unexpected step: {:?}", o),
+            }
+        }
+    }
+
+    #[test]
+    fn maskrom_reader() {
+        let data: Vec<u8> = (0..5000u32).map(|i| i as u8).collect();
+        let writes = maskrom_writes(write_area(Area::Ddr, &data));
+        assert_eq!(writes.len(), 2);
+        assert_eq!(writes[1].len(), 5000 - 4096 + 2);
+
+        let mut reader = &data[..];
+        assert_eq!(
+            maskrom_writes(write_area_from_reader(Area::Ddr, &mut reader)),
+            writes
+        );
+    }
+
+    #[test]
+    fn write_lba_stream() {
+        let data = [0xaau8; 1000];
+        let mut reader = &data[..];
+        let mut o = write_lba_from_reader(0x10..0x20, &mut reader);
+        let mut command = None;
+        let mut writes = vec![];
+        let written = loop {
+            match o.step() {
+                UsbStep::WriteBulk {
+                    data,
+                    phase: BulkPhase::Command,
+                } => {
+                    let cb = CommandBlock::from_bytes(data).unwrap();
+                    assert_eq!(cb.transfer_length(), 1024);
+                    command = Some(cb);
+                }
+                UsbStep::WriteBulk { data, .. } => writes.push(data.to_vec()),
+                UsbStep::ReadBulk { data, .. } => {
+                    CommandStatus::success_for(command.as_ref().unwrap()).to_bytes(data);
+                }
+                UsbStep::Finished(r) => break r.unwrap(),
+                o => panic!("Unexpected step: {:?}", o),
+            }
+        };
+        assert_eq!(written, 1000);
+        assert_eq!(writes.len(), 1);
+        assert_eq!(writes[0][..1000], data);
+        assert!(writes[0][1000..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn write_lba_stream_residue() {
+        let data = [0x55u8; 4 * 512];
+        let mut reader = &data[..];
+        let mut o = write_lba_from_reader(0x10..0x20, &mut reader);
+        let mut command = None;
+        let mut commands = vec![];
+        let written = loop {
+            match o.step() {
+                UsbStep::WriteBulk {
+                    data,
+                    phase: BulkPhase::Command,
+                } => {
+                    let cb = CommandBlock::from_bytes(data).unwrap();
+                    commands.push((cb.address(), cb.length()));
+                    command = Some(cb);
+                }
+                UsbStep::WriteBulk { .. } => (),
+                UsbStep::ReadBulk { data, .. } => {
+                    // Only one and a half sector lands on the first attempt
+                    let mut csw = CommandStatus::success_for(command.as_ref().unwrap());
+                    if commands.len() == 1 {
+                        csw.residue = 2 * 512 + 256;
+                    }
+                    csw.to_bytes(data);
+                }
+                UsbStep::Finished(r) => break r.unwrap(),
+                o => panic!("Unexpected step: {:?}", o),
+            }
+        };
+        assert_eq!(written, data.len() as u64);
+        assert_eq!(commands, vec![(0x10, 4), (0x11, 3)]);
+    }
+
+    #[test]
+    fn rc4() {
+        let mut data = [0u8; 528];
+        rc4_sector(&mut data[..512]);
+        rc4_sector(&mut data[512..]);
+        // Key is reset for each sector
+        assert_eq!(data[..16], data[512..]);
+        assert_eq!(
+            data[..16],
+            [
+                0x6e, 0x26, 0x2c, 0xf3, 0xbe, 0x9f, 0x9d, 0x51, 0xea, 0x30, 0x34, 0xce, 0x20, 0x51,
+                0x1f, 0x98
+            ]
+        );
+    }
+
+    #[test]
+    fn encoding() {
+        assert_eq!(Encoding::for_product_id(0x310b), Encoding::Rc4);
+        assert_eq!(Encoding::for_product_id(0x350b), Encoding::Plain);
+        assert_eq!(Encoding::for_product_id(0x1234), Encoding::Plain);
+        let rc4 = SocFamily::ALL
+            .into_iter()
+            .filter(|&f| Encoding::for_soc(f) == Encoding::Rc4)
+            .count();
+        assert_eq!(rc4, 6);
+    }
+
     #[test]
     fn chip_info_operation() {
         let mut o = chip_info();
@@ -420,7 +1073,10 @@ mod test {
         cb.to_bytes(&mut cb_bytes);
         // Write the command block
         let tag = match o.step() {
-            UsbStep::WriteBulk { data } if data.len() == protocol::COMMAND_BLOCK_BYTES => {
+            UsbStep::WriteBulk {
+                data,
+                phase: BulkPhase::Command,
+            } if data.len() == protocol::COMMAND_BLOCK_BYTES => {
                 [data[4], data[5], data[6], data[7]]
             }
             o => panic!("Unexpected step: {:?}", o),
@@ -428,7 +1084,10 @@ mod test {
 
         // Reading the chip info
         match o.step() {
-            UsbStep::ReadBulk { data } if data.len() as u32 == cb.transfer_length() => {
+            UsbStep::ReadBulk {
+                data,
+                phase: BulkPhase::Data,
+            } if data.len() as u32 == cb.transfer_length() => {
                 data.fill(0);
                 /* 3588 */
                 data[0] = 0x38;
@@ -441,7 +1100,10 @@ mod test {
 
         // reading status
         match o.step() {
-            UsbStep::ReadBulk { data } if data.len() == protocol::COMMAND_STATUS_BYTES => {
+            UsbStep::ReadBulk {
+                data,
+                phase: BulkPhase::Status,
+            } if data.len() == protocol::COMMAND_STATUS_BYTES => {
                 data.fill(0);
                 /* signature */
                 data[0] = b'U';
@@ -472,4 +1134,65 @@ mod test {
             o => panic!("Unexpected step: {:?}", o),
         }
     }
+
+    #[test]
+    fn expected_bytes() {
+        let data = [0u8; 5000];
+        assert_eq!(write_area(Area::Ddr, &data).expected_bytes(), Some(5000));
+        let mut reader = &data[..];
+        assert_eq!(
+            write_area_from_reader(Area::Ddr, &mut reader).expected_bytes(),
+            None
+        );
+        let mut read = [0u8; 2048];
+        assert_eq!(read_lba(0, &mut read).unwrap().expected_bytes(), Some(2048));
+        assert_eq!(chip_info().expected_bytes(), Some(16));
+        assert_eq!(erase_lba(0, 16).expected_bytes(), Some(0));
+        assert_eq!(
+            write_lba_from_reader(0..8, &mut reader).expected_bytes(),
+            None
+        );
+    }
+
+    #[test]
+    fn transfer_size() {
+        assert_eq!(transfer_sectors(8), 128);
+        assert_eq!(transfer_sectors(64), 128);
+        assert_eq!(transfer_sectors(512), 1024);
+        assert_eq!(transfer_sectors(1024), 2048);
+        assert_eq!(transfer_sectors(usize::MAX), MAX_LBA_SECTORS);
+    }
+
+    #[test]
+    fn read_lba_too_many_sectors() {
+        let len = (MAX_LBA_SECTORS as usize + 1) * 512;
+        let mut data = vec![0u8; len];
+        assert!(matches!(
+            read_lba(0, &mut data),
+            Err(UsbOperationError::InvalidLbaLength(l)) if l == len
+        ));
+        assert!(matches!(
+            write_lba(0, &data[..1000]),
+            Err(UsbOperationError::InvalidLbaLength(1000))
+        ));
+    }
+
+    #[test]
+    fn write_sdram_length() {
+        let data = vec![0u8; SDRAM_CHUNK_SIZE + 4];
+        assert_eq!(
+            write_sdram(0, &data[..SDRAM_CHUNK_SIZE])
+                .unwrap()
+                .expected_bytes(),
+            Some(SDRAM_CHUNK_SIZE as u64)
+        );
+        assert!(matches!(
+            write_sdram(0, &data),
+            Err(UsbOperationError::InvalidSdramLength(_))
+        ));
+        assert!(matches!(
+            write_sdram(0, &data[..6]),
+            Err(UsbOperationError::InvalidSdramLength(6))
+        ));
+    }
 }
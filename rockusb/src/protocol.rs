@@ -1,21 +1,24 @@
 use std::borrow::Cow;
 
 use bytes::{Buf, BufMut};
-use num_enum::{IntoPrimitive, TryFromPrimitive};
+use num_enum::{FromPrimitive, IntoPrimitive, TryFromPrimitive};
 
 pub const SECTOR_SIZE: u64 = 512;
 
 #[repr(u8)]
 #[derive(Debug, Eq, PartialEq, Clone, Copy, IntoPrimitive, TryFromPrimitive)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum Direction {
     In = 0x80,
     Out = 0x0,
 }
 
+/// Command code carried in the command data block
 #[non_exhaustive]
 #[repr(u8)]
 #[derive(Debug, Eq, PartialEq, Clone, Copy, IntoPrimitive, TryFromPrimitive)]
-enum CommandCode {
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CommandCode {
     TestUnitReady = 0,
     ReadFlashId = 0x01,
     TestBadBlock = 0x03,
@@ -39,12 +42,55 @@ enum CommandCode {
     WriteNewEfuse = 0x23,
     ReadNewEfuse = 0x24,
     EraseLBA = 0x25,
+    ChangeStorage = 0x2A,
+    ReadStorage = 0x2B,
     ReadCapability = 0xAA,
     DeviceReset = 0xFF,
 }
 
+impl CommandCode {
+    /// Length of the command data block used with this command
+    pub fn cdb_length(self) -> u8 {
+        match self {
+            CommandCode::TestUnitReady
+            | CommandCode::ReadFlashId
+            | CommandCode::ReadFlashInfo
+            | CommandCode::ReadChipInfo
+            | CommandCode::ReadEFuse
+            | CommandCode::ReadCapability
+            | CommandCode::ReadStorage
+            | CommandCode::ChangeStorage
+            | CommandCode::DeviceReset
+            | CommandCode::EraseSystemDisk
+            | CommandCode::SetResetFlag => 0x6,
+            _ => 0xa,
+        }
+    }
+
+    /// Direction of the data phase of this command
+    pub fn direction(self) -> Direction {
+        match self {
+            CommandCode::TestUnitReady
+            | CommandCode::ReadFlashId
+            | CommandCode::ReadFlashInfo
+            | CommandCode::ReadChipInfo
+            | CommandCode::ReadEFuse
+            | CommandCode::ReadCapability
+            | CommandCode::ReadStorage
+            | CommandCode::TestBadBlock
+            | CommandCode::ReadSector
+            | CommandCode::ReadLBA
+            | CommandCode::ReadSDram
+            | CommandCode::ReadSPIFlash
+            | CommandCode::ReadNewEfuse => Direction::In,
+            _ => Direction::Out,
+        }
+    }
+}
+
 #[repr(u8)]
 #[derive(Debug, Eq, PartialEq, Clone, Copy, IntoPrimitive, TryFromPrimitive)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum ResetOpcode {
     /// Reset
     Reset = 0,
@@ -58,25 +104,271 @@ pub enum ResetOpcode {
     Disconnect,
 }
 
+/// Usb vendor id used by Rockchip devices
+pub const ROCKCHIP_VENDOR_ID: u16 = 0x2207;
+
+/// Filter used to discover devices based on their usb vendor and product id
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DeviceFilter {
+    pub vendor_id: u16,
+    /// Product id to match; Any product of the vendor matches if None
+    pub product_id: Option<u16>,
+}
+
+impl DeviceFilter {
+    /// Filter matching all products of a vendor
+    pub const fn vendor(vendor_id: u16) -> Self {
+        Self {
+            vendor_id,
+            product_id: None,
+        }
+    }
+
+    /// Filter matching one specific product
+    pub const fn product(vendor_id: u16, product_id: u16) -> Self {
+        Self {
+            vendor_id,
+            product_id: Some(product_id),
+        }
+    }
+
+    pub fn matches(&self, vendor_id: u16, product_id: u16) -> bool {
+        self.vendor_id == vendor_id
+            && (self.product_id.is_none() || self.product_id == Some(product_id))
+    }
+
+    /// Whether any of the filters matches
+    pub fn any_matches(filters: &[DeviceFilter], vendor_id: u16, product_id: u16) -> bool {
+        filters.iter().any(|f| f.matches(vendor_id, product_id))
+    }
+}
+
+/// Filters used for device discovery by default, matching all Rockchip devices
+pub const DEFAULT_DEVICE_FILTERS: &[DeviceFilter] = &[DeviceFilter::vendor(ROCKCHIP_VENDOR_ID)];
+
+/// Usb mode of a rockchip device
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum UsbMode {
+    /// Running the bootrom; Only maskrom operations are supported
+    Maskrom,
+    /// Running a usb loader implementing the full rockusb protocol
+    Loader,
+}
+
+/// Usb interface class of mass-storage interfaces
+pub const USB_CLASS_MASS_STORAGE: u8 = 0x08;
+
+impl UsbMode {
+    /// Determine the mode based on the usb device release number (bcdDevice)
+    pub fn from_device_version(version: u16) -> UsbMode {
+        if version & 0x1 == 0 {
+            UsbMode::Maskrom
+        } else {
+            UsbMode::Loader
+        }
+    }
+
+    /// Classify a device based on its enumeration data, without opening it
+    ///
+    /// The bootrom only exposes the vendor specific rockusb interface, so a device with a
+    /// mass-storage interface is running a loader. Otherwise the product string is used if it
+    /// names the mode, falling back to the device release number (see
+    /// [UsbMode::from_device_version])
+    pub fn detect(device_version: u16, product: Option<&str>, interface_classes: &[u8]) -> UsbMode {
+        if interface_classes.contains(&USB_CLASS_MASS_STORAGE) {
+            return UsbMode::Loader;
+        }
+        match product.map(|p| p.to_ascii_lowercase()) {
+            Some(p) if p.contains("maskrom") => UsbMode::Maskrom,
+            Some(p) if p.contains("loader") => UsbMode::Loader,
+            _ => UsbMode::from_device_version(device_version),
+        }
+    }
+}
+
+impl std::fmt::Display for UsbMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UsbMode::Maskrom => write!(f, "Maskrom"),
+            UsbMode::Loader => write!(f, "Loader"),
+        }
+    }
+}
+
+/// SoC family of a device, as identified by its usb product id
+#[non_exhaustive]
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SocFamily {
+    Rk2918,
+    Rk2928,
+    Rk3066,
+    Rk3168,
+    Rk3036,
+    Rk3066B,
+    Rk3188,
+    Rk3128,
+    Rk3288,
+    Rk3229,
+    Rk3328,
+    Rk3368,
+    Rk3399,
+    Px30,
+    Rk3308,
+    Rk3568,
+    Rk3588,
+}
+
+impl SocFamily {
+    /// All known SoC families
+    pub const ALL: [SocFamily; 17] = [
+        SocFamily::Rk2918,
+        SocFamily::Rk2928,
+        SocFamily::Rk3066,
+        SocFamily::Rk3168,
+        SocFamily::Rk3036,
+        SocFamily::Rk3066B,
+        SocFamily::Rk3188,
+        SocFamily::Rk3128,
+        SocFamily::Rk3288,
+        SocFamily::Rk3229,
+        SocFamily::Rk3328,
+        SocFamily::Rk3368,
+        SocFamily::Rk3399,
+        SocFamily::Px30,
+        SocFamily::Rk3308,
+        SocFamily::Rk3568,
+        SocFamily::Rk3588,
+    ];
+
+    /// SoC family for a given usb product id, if known
+    pub fn from_product_id(product_id: u16) -> Option<SocFamily> {
+        Self::ALL.into_iter().find(|f| f.product_id() == product_id)
+    }
+
+    /// Usb product id used by the bootrom and loader of this SoC family
+    pub const fn product_id(self) -> u16 {
+        match self {
+            SocFamily::Rk2918 => 0x290a,
+            SocFamily::Rk2928 => 0x292a,
+            SocFamily::Rk3066 => 0x300a,
+            SocFamily::Rk3168 => 0x300b,
+            SocFamily::Rk3036 => 0x301a,
+            SocFamily::Rk3066B => 0x310a,
+            SocFamily::Rk3188 => 0x310b,
+            SocFamily::Rk3128 => 0x310c,
+            SocFamily::Rk3288 => 0x320a,
+            SocFamily::Rk3229 => 0x320b,
+            SocFamily::Rk3328 => 0x320c,
+            SocFamily::Rk3368 => 0x330a,
+            SocFamily::Rk3399 => 0x330c,
+            SocFamily::Px30 => 0x330d,
+            SocFamily::Rk3308 => 0x330e,
+            SocFamily::Rk3568 => 0x350a,
+            SocFamily::Rk3588 => 0x350b,
+        }
+    }
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            SocFamily::Rk2918 => "RK2918",
+            SocFamily::Rk2928 => "RK2928",
+            SocFamily::Rk3066 => "RK3066",
+            SocFamily::Rk3168 => "RK3168",
+            SocFamily::Rk3036 => "RK3036",
+            SocFamily::Rk3066B => "RK3066B",
+            SocFamily::Rk3188 => "RK3188",
+            SocFamily::Rk3128 => "RK3128",
+            SocFamily::Rk3288 => "RK3288",
+            SocFamily::Rk3229 => "RK3229",
+            SocFamily::Rk3328 => "RK3328",
+            SocFamily::Rk3368 => "RK3368",
+            SocFamily::Rk3399 => "RK3399",
+            SocFamily::Px30 => "PX30",
+            SocFamily::Rk3308 => "RK3308",
+            SocFamily::Rk3568 => "RK3568",
+            SocFamily::Rk3588 => "RK3588",
+        }
+    }
+
+    /// Filter to discover devices of this SoC family
+    pub const fn filter(self) -> DeviceFilter {
+        DeviceFilter::product(ROCKCHIP_VENDOR_ID, self.product_id())
+    }
+}
+
+impl std::fmt::Display for SocFamily {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Name of the SoC family for a given usb product id, if known
+pub fn soc_name(product_id: u16) -> Option<&'static str> {
+    SocFamily::from_product_id(product_id).map(SocFamily::name)
+}
+
+/// Area to write to while in maskrom mode
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Area {
+    /// SoC internal sram; typically used for the DDR initialisation blob (0x471)
+    Sram,
+    /// DDR memory; typically used for the usb loader (0x472)
+    Ddr,
+    /// Any other area code
+    Raw(u16),
+}
+
+impl From<Area> for u16 {
+    fn from(area: Area) -> u16 {
+        match area {
+            Area::Sram => 0x471,
+            Area::Ddr => 0x472,
+            Area::Raw(code) => code,
+        }
+    }
+}
+
+impl From<u16> for Area {
+    fn from(code: u16) -> Area {
+        match code {
+            0x471 => Area::Sram,
+            0x472 => Area::Ddr,
+            code => Area::Raw(code),
+        }
+    }
+}
+
 #[derive(Debug, thiserror::Error, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum CommandStatusParseError {
     #[error("Invalid signature: {0:x?}")]
     InvalidSignature([u8; 4]),
     #[error("Invalid length: {0}")]
     InvalidLength(usize),
-    #[error("Invalid status: {0}")]
-    InvalidStatus(u8),
 }
 
+/// Status of a command as reported by the device
+///
+/// Values not defined by the mass storage specification are preserved as [Status::Unknown]
 #[repr(u8)]
-#[derive(Debug, Eq, PartialEq, Clone, Copy, IntoPrimitive, TryFromPrimitive)]
+#[derive(Debug, Eq, PartialEq, Clone, Copy, IntoPrimitive, FromPrimitive)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
 pub enum Status {
     SUCCESS = 0,
     FAILED = 1,
+    PhaseError = 2,
+    #[num_enum(catch_all)]
+    Unknown(u8),
 }
 
 pub const COMMAND_STATUS_BYTES: usize = 13;
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct CommandStatus {
     pub tag: u32,
     pub residue: u32,
@@ -84,6 +376,24 @@ pub struct CommandStatus {
 }
 
 impl CommandStatus {
+    /// Status reporting success of `command` with all of its data transferred
+    pub fn success_for(command: &CommandBlock) -> CommandStatus {
+        CommandStatus {
+            tag: command.tag(),
+            residue: 0,
+            status: Status::SUCCESS,
+        }
+    }
+
+    /// Status reporting failure of `command` with none of its data transferred
+    pub fn failed_for(command: &CommandBlock) -> CommandStatus {
+        CommandStatus {
+            tag: command.tag(),
+            residue: command.transfer_length(),
+            status: Status::FAILED,
+        }
+    }
+
     pub fn to_bytes(&self, bytes: &mut [u8]) -> usize {
         let mut bytes = &mut bytes[..];
         bytes.put_slice(b"USBS");
@@ -104,8 +414,7 @@ impl CommandStatus {
         }
         let tag = bytes.get_u32();
         let residue = bytes.get_u32_le();
-        let status = Status::try_from(bytes.get_u8())
-            .map_err(|e| CommandStatusParseError::InvalidStatus(e.number))?;
+        let status = Status::from(bytes.get_u8());
         Ok(CommandStatus {
             tag,
             residue,
@@ -115,6 +424,7 @@ impl CommandStatus {
 }
 
 #[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct ChipInfo([u8; 16]);
 impl ChipInfo {
     pub fn from_bytes(data: [u8; 16]) -> Self {
@@ -126,7 +436,20 @@ impl ChipInfo {
     }
 }
 
+impl std::fmt::Display for ChipInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // The chip identifier is stored as reversed ascii, e.g. "8853" for the rk3588
+        let id: String = self.0[..4]
+            .iter()
+            .rev()
+            .map(|&c| if c.is_ascii_graphic() { c as char } else { '.' })
+            .collect();
+        write!(f, "{id}")
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct FlashId([u8; 5]);
 impl FlashId {
     pub fn from_bytes(data: [u8; 5]) -> Self {
@@ -139,6 +462,7 @@ impl FlashId {
 }
 
 #[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct FlashInfo([u8; 11]);
 impl FlashInfo {
     pub fn from_bytes(data: [u8; 11]) -> Self {
@@ -160,12 +484,128 @@ impl FlashInfo {
         (&self.0[4..]).get_u16_le()
     }
 
+    /// Number of sectors of a transfer of at most `max_sectors` starting at `start_sector`
+    ///
+    /// The transfer is shortened to end on an erase block boundary if one lies within it, such
+    /// that the transfers following it are block aligned
+    pub fn block_aligned_sectors(&self, start_sector: u32, max_sectors: u32) -> u32 {
+        let block = u64::from(self.block_size_sectors());
+        if block <= 1 {
+            return max_sectors;
+        }
+        let start = u64::from(start_sector);
+        let end = (start + u64::from(max_sectors)) / block * block;
+        if end > start {
+            (end - start) as u32
+        } else {
+            max_sectors
+        }
+    }
+
     pub fn inner(&self) -> &[u8] {
         &self.0
     }
 }
 
+/// Capabilities of the usb loader
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Capability([u8; 8]);
+impl Capability {
+    pub fn from_bytes(data: [u8; 8]) -> Self {
+        Capability(data)
+    }
+
+    pub fn inner(&self) -> &[u8] {
+        &self.0
+    }
+
+    fn bit(&self, byte: usize, bit: u8) -> bool {
+        self.0[byte] & (1 << bit) != 0
+    }
+
+    /// Flash is accessed directly by LBA
+    pub fn direct_lba(&self) -> bool {
+        self.bit(0, 0)
+    }
+
+    /// Vendor storage can be accessed
+    pub fn vendor_storage(&self) -> bool {
+        self.bit(0, 1)
+    }
+
+    /// The first 4MB of the flash can be accessed
+    pub fn first_4m_access(&self) -> bool {
+        self.bit(0, 2)
+    }
+
+    /// LBA reads are supported
+    pub fn read_lba(&self) -> bool {
+        self.bit(0, 3)
+    }
+
+    /// The loader log (COM log) can be read
+    pub fn read_com_log(&self) -> bool {
+        self.bit(0, 5)
+    }
+
+    /// The IDB configuration can be read
+    pub fn read_idb_config(&self) -> bool {
+        self.bit(0, 6)
+    }
+
+    /// The secure mode can be read
+    pub fn read_secure_mode(&self) -> bool {
+        self.bit(0, 7)
+    }
+
+    /// The new IDB format is used
+    pub fn new_idb(&self) -> bool {
+        self.bit(1, 0)
+    }
+}
+
+/// Storage media currently used by the usb loader
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Storage([u8; 4]);
+impl Storage {
+    /// Identifier of eMMC storage
+    pub const EMMC: u8 = 1;
+    /// Identifier of SD card storage
+    pub const SD: u8 = 2;
+    /// Identifier of SPI NOR storage
+    pub const SPI_NOR: u8 = 9;
+
+    pub fn from_bytes(data: [u8; 4]) -> Self {
+        Storage(data)
+    }
+
+    pub fn inner(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Storage identifier; the index of the bit set in the reply
+    pub fn id(&self) -> Option<u8> {
+        let bits = (&self.0[..]).get_u32_le();
+        (bits != 0).then(|| bits.trailing_zeros() as u8)
+    }
+}
+
+impl std::fmt::Display for Storage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.id() {
+            Some(Storage::EMMC) => write!(f, "eMMC"),
+            Some(Storage::SD) => write!(f, "SD"),
+            Some(Storage::SPI_NOR) => write!(f, "SPI NOR"),
+            Some(id) => write!(f, "Storage {id}"),
+            None => write!(f, "None"),
+        }
+    }
+}
+
 #[derive(Debug, thiserror::Error, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum CommandBlockParseError {
     #[error("Invalid Command block signature: {0:x?}")]
     InvalidSignature([u8; 4]),
@@ -175,6 +615,15 @@ pub enum CommandBlockParseError {
     UnknownFlags(u8),
     #[error("Invalid command block length: {0}")]
     InvalidLength(usize),
+    #[error("Invalid command data block length {length:x} for command {code:?}")]
+    InvalidCdbLength { code: CommandCode, length: u8 },
+    #[error("Invalid direction {direction:?} for command {code:?}")]
+    InvalidDirection {
+        code: CommandCode,
+        direction: Direction,
+    },
+    #[error("Invalid transfer length {actual}, expected {expected}")]
+    InvalidTransferLength { expected: u32, actual: u32 },
 }
 
 /// Total size of a CBW command
@@ -184,6 +633,7 @@ pub const COMMAND_BLOCK_BYTES: usize = 31;
 /// Storage class specification. It carries a SCSI command inside the 'CBWCB'
 /// bytes that is referred to in the code as 'command data block'.
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct CommandBlock {
     tag: u32,
     transfer_length: u32,
@@ -199,6 +649,20 @@ pub struct CommandBlock {
 }
 
 impl CommandBlock {
+    pub fn test_unit_ready() -> CommandBlock {
+        CommandBlock {
+            tag: fastrand::u32(..),
+            transfer_length: 0,
+            flags: Direction::In,
+            lun: 0,
+            cdb_length: 0x6,
+            cd_code: CommandCode::TestUnitReady,
+            cd_opcode: 0,
+            cd_address: 0,
+            cd_length: 0x0,
+        }
+    }
+
     pub fn flash_id() -> CommandBlock {
         CommandBlock {
             tag: fastrand::u32(..),
@@ -227,6 +691,48 @@ impl CommandBlock {
         }
     }
 
+    pub fn capability() -> CommandBlock {
+        CommandBlock {
+            tag: fastrand::u32(..),
+            transfer_length: 8,
+            flags: Direction::In,
+            lun: 0,
+            cdb_length: 0x6,
+            cd_code: CommandCode::ReadCapability,
+            cd_opcode: 0,
+            cd_address: 0,
+            cd_length: 0x0,
+        }
+    }
+
+    pub fn read_storage() -> CommandBlock {
+        CommandBlock {
+            tag: fastrand::u32(..),
+            transfer_length: 4,
+            flags: Direction::In,
+            lun: 0,
+            cdb_length: 0x6,
+            cd_code: CommandCode::ReadStorage,
+            cd_opcode: 0,
+            cd_address: 0,
+            cd_length: 0x0,
+        }
+    }
+
+    pub fn change_storage(storage: u8) -> CommandBlock {
+        CommandBlock {
+            tag: fastrand::u32(..),
+            transfer_length: 0,
+            flags: Direction::Out,
+            lun: 0,
+            cdb_length: 0x6,
+            cd_code: CommandCode::ChangeStorage,
+            cd_opcode: storage,
+            cd_address: 0,
+            cd_length: 0x0,
+        }
+    }
+
     pub fn chip_info() -> CommandBlock {
         CommandBlock {
             tag: fastrand::u32(..),
@@ -269,6 +775,50 @@ impl CommandBlock {
         }
     }
 
+    pub fn erase_lba(start_sector: u32, sectors: u16) -> CommandBlock {
+        CommandBlock {
+            tag: fastrand::u32(..),
+            transfer_length: 0,
+            flags: Direction::Out,
+            lun: 0,
+            cdb_length: 0xa,
+            cd_code: CommandCode::EraseLBA,
+            cd_opcode: 0,
+            cd_address: start_sector,
+            cd_length: sectors,
+        }
+    }
+
+    /// Write `len` bytes to the sdram at `address`, relative to the start of the sdram
+    pub fn write_sdram(address: u32, len: u16) -> CommandBlock {
+        CommandBlock {
+            tag: fastrand::u32(..),
+            transfer_length: u32::from(len),
+            flags: Direction::Out,
+            lun: 0,
+            cdb_length: 0xa,
+            cd_code: CommandCode::WriteSDram,
+            cd_opcode: 0,
+            cd_address: address,
+            cd_length: len,
+        }
+    }
+
+    /// Jump to the code at `address`, relative to the start of the sdram
+    pub fn execute_sdram(address: u32) -> CommandBlock {
+        CommandBlock {
+            tag: fastrand::u32(..),
+            transfer_length: 0,
+            flags: Direction::Out,
+            lun: 0,
+            cdb_length: 0xa,
+            cd_code: CommandCode::ExecuteSDram,
+            cd_opcode: 0,
+            cd_address: address,
+            cd_length: 0x0,
+        }
+    }
+
     pub fn reset_device(opcode: ResetOpcode) -> CommandBlock {
         CommandBlock {
             tag: fastrand::u32(..),
@@ -287,10 +837,29 @@ impl CommandBlock {
         self.tag
     }
 
+    pub fn code(&self) -> CommandCode {
+        self.cd_code
+    }
+
     pub fn direction(&self) -> Direction {
         self.flags
     }
 
+    /// Opcode of the command, e.g. the [ResetOpcode] for a reset
+    pub fn opcode(&self) -> u8 {
+        self.cd_opcode
+    }
+
+    /// Start sector of lba commands or address of sdram commands
+    pub fn address(&self) -> u32 {
+        self.cd_address
+    }
+
+    /// Number of sectors of lba commands or bytes of sdram commands
+    pub fn length(&self) -> u16 {
+        self.cd_length
+    }
+
     pub fn transfer_length(&self) -> u32 {
         self.transfer_length
     }
@@ -331,6 +900,35 @@ impl CommandBlock {
         let cd_address = bytes.get_u32();
         bytes.advance(1);
         let cd_length = bytes.get_u16();
+
+        if cdb_length != cd_code.cdb_length() {
+            return Err(CommandBlockParseError::InvalidCdbLength {
+                code: cd_code,
+                length: cdb_length,
+            });
+        }
+        // Commands without a data phase don't care about the direction
+        if transfer_length > 0 && flags != cd_code.direction() {
+            return Err(CommandBlockParseError::InvalidDirection {
+                code: cd_code,
+                direction: flags,
+            });
+        }
+        let expected = match cd_code {
+            CommandCode::ReadLBA | CommandCode::WriteLBA => {
+                Some(u32::from(cd_length) * SECTOR_SIZE as u32)
+            }
+            CommandCode::WriteSDram => Some(u32::from(cd_length)),
+            CommandCode::EraseLBA | CommandCode::ExecuteSDram => Some(0),
+            _ => None,
+        };
+        if let Some(expected) = expected.filter(|&e| e != transfer_length) {
+            return Err(CommandBlockParseError::InvalidTransferLength {
+                expected,
+                actual: transfer_length,
+            });
+        }
+
         Ok(CommandBlock {
             tag,
             transfer_length,
@@ -359,6 +957,24 @@ mod test {
         c.to_bytes(&mut b);
         let c2 = CommandStatus::from_bytes(&b).unwrap();
         assert_eq!(c, c2);
+
+        b[12] = 2;
+        let c3 = CommandStatus::from_bytes(&b).unwrap();
+        assert_eq!(c3.status, Status::PhaseError);
+    }
+
+    #[test]
+    fn csw_for() {
+        let command = CommandBlock::read_lba(0x40, 8);
+        let c = CommandStatus::success_for(&command);
+        assert_eq!(c.tag, command.tag());
+        assert_eq!(c.residue, 0);
+        assert_eq!(c.status, Status::SUCCESS);
+
+        let c = CommandStatus::failed_for(&command);
+        assert_eq!(c.tag, command.tag());
+        assert_eq!(c.residue, 4096);
+        assert_eq!(c.status, Status::FAILED);
     }
 
     #[test]
@@ -368,7 +984,7 @@ mod test {
             transfer_length: 0x11223344,
             flags: Direction::Out,
             lun: 0x66,
-            cdb_length: 0x77,
+            cdb_length: 0xa,
             cd_code: CommandCode::EraseForce,
             cd_opcode: 0x10,
             cd_address: 0x11223344,
@@ -378,5 +994,250 @@ mod test {
         c.to_bytes(&mut b);
         let c2 = CommandBlock::from_bytes(&b).unwrap();
         assert_eq!(c, c2);
+        assert_eq!(c2.code(), CommandCode::EraseForce);
+    }
+
+    #[test]
+    fn cbw_validation() {
+        let mut b = [0u8; 31];
+        CommandBlock::read_lba(0x40, 8).to_bytes(&mut b);
+        CommandBlock::from_bytes(&b).unwrap();
+
+        let c = CommandBlock::change_storage(Storage::SD);
+        c.to_bytes(&mut b);
+        assert_eq!(CommandBlock::from_bytes(&b).unwrap(), c);
+
+        let c = CommandBlock::write_sdram(0x200000, 0x4000);
+        c.to_bytes(&mut b);
+        assert_eq!(b[15..24], [0x18, 0, 0, 0x20, 0, 0, 0, 0x40, 0]);
+        assert_eq!(CommandBlock::from_bytes(&b).unwrap(), c);
+
+        let mut c = CommandBlock::read_lba(0x40, 8);
+        c.cdb_length = 0x6;
+        c.to_bytes(&mut b);
+        assert!(matches!(
+            CommandBlock::from_bytes(&b),
+            Err(CommandBlockParseError::InvalidCdbLength {
+                code: CommandCode::ReadLBA,
+                length: 0x6
+            })
+        ));
+
+        let mut c = CommandBlock::read_lba(0x40, 8);
+        c.flags = Direction::Out;
+        c.to_bytes(&mut b);
+        assert!(matches!(
+            CommandBlock::from_bytes(&b),
+            Err(CommandBlockParseError::InvalidDirection {
+                code: CommandCode::ReadLBA,
+                ..
+            })
+        ));
+
+        let mut c = CommandBlock::write_lba(0x40, 8);
+        c.transfer_length = 512;
+        c.to_bytes(&mut b);
+        assert!(matches!(
+            CommandBlock::from_bytes(&b),
+            Err(CommandBlockParseError::InvalidTransferLength {
+                expected: 4096,
+                actual: 512
+            })
+        ));
+    }
+
+    #[test]
+    fn device_filter() {
+        let filters = [
+            DeviceFilter::vendor(ROCKCHIP_VENDOR_ID),
+            DeviceFilter::product(0x1234, 0x350b),
+        ];
+        assert!(DeviceFilter::any_matches(&filters, 0x2207, 0x350b));
+        assert!(DeviceFilter::any_matches(&filters, 0x1234, 0x350b));
+        assert!(!DeviceFilter::any_matches(&filters, 0x1234, 0x350a));
+        assert!(!DeviceFilter::any_matches(
+            DEFAULT_DEVICE_FILTERS,
+            0x1234,
+            0x350b
+        ));
+    }
+
+    #[test]
+    fn soc_family() {
+        assert_eq!(SocFamily::from_product_id(0x350b), Some(SocFamily::Rk3588));
+        assert_eq!(SocFamily::from_product_id(0x1234), None);
+        assert_eq!(soc_name(0x330d), Some("PX30"));
+        let filter = SocFamily::Rk3568.filter();
+        assert!(filter.matches(ROCKCHIP_VENDOR_ID, 0x350a));
+        assert!(!filter.matches(ROCKCHIP_VENDOR_ID, 0x350b));
+    }
+
+    #[test]
+    fn usb_mode() {
+        assert_eq!(UsbMode::detect(0x0100, None, &[0xff]), UsbMode::Maskrom);
+        assert_eq!(UsbMode::detect(0x0101, None, &[0xff]), UsbMode::Loader);
+        assert_eq!(
+            UsbMode::detect(0x0100, None, &[0xff, USB_CLASS_MASS_STORAGE]),
+            UsbMode::Loader
+        );
+        assert_eq!(
+            UsbMode::detect(0x0101, Some("RK3588 MaskROM"), &[0xff]),
+            UsbMode::Maskrom
+        );
+        assert_eq!(
+            UsbMode::detect(0x0100, Some("Rockchip Loader"), &[]),
+            UsbMode::Loader
+        );
+        assert_eq!(
+            UsbMode::detect(0x0100, Some("USB-MSC"), &[]),
+            UsbMode::Maskrom
+        );
+    }
+
+    #[test]
+    fn chip_info_display() {
+        let mut data = [0u8; 16];
+        data[..4].copy_from_slice(b"8853");
+        assert_eq!(ChipInfo::from_bytes(data).to_string(), "3588");
+    }
+
+    #[test]
+    fn block_aligned() {
+        let mut data = [0u8; 11];
+        data[..4].copy_from_slice(&0x100000u32.to_le_bytes());
+        data[4..6].copy_from_slice(&0x400u16.to_le_bytes());
+        let info = FlashInfo::from_bytes(data);
+        assert_eq!(info.block_aligned_sectors(0, 0xffff), 0xfc00);
+        assert_eq!(info.block_aligned_sectors(0x10, 0xffff), 0x10000 - 0x10);
+        assert_eq!(info.block_aligned_sectors(0x800, 0x400), 0x400);
+        // No block boundary within the transfer
+        assert_eq!(info.block_aligned_sectors(0x10, 0x100), 0x100);
+
+        data[4..6].copy_from_slice(&0u16.to_le_bytes());
+        let info = FlashInfo::from_bytes(data);
+        assert_eq!(info.block_aligned_sectors(0x10, 0xffff), 0xffff);
+    }
+
+    mod proptests {
+        use super::*;
+        use proptest::prelude::*;
+
+        fn command_code() -> impl Strategy<Value = CommandCode> {
+            let codes: Vec<_> = (0..=u8::MAX)
+                .filter_map(|c| CommandCode::try_from(c).ok())
+                .collect();
+            prop::sample::select(codes)
+        }
+
+        fn command_block() -> impl Strategy<Value = CommandBlock> {
+            (
+                command_code(),
+                any::<u32>(),
+                any::<u32>(),
+                any::<u8>(),
+                any::<u8>(),
+                any::<u32>(),
+                any::<u16>(),
+            )
+                .prop_map(
+                    |(cd_code, tag, transfer_length, lun, cd_opcode, cd_address, cd_length)| {
+                        let transfer_length = match cd_code {
+                            CommandCode::ReadLBA | CommandCode::WriteLBA => {
+                                u32::from(cd_length) * SECTOR_SIZE as u32
+                            }
+                            CommandCode::WriteSDram => u32::from(cd_length),
+                            CommandCode::EraseLBA | CommandCode::ExecuteSDram => 0,
+                            _ => transfer_length,
+                        };
+                        CommandBlock {
+                            tag,
+                            transfer_length,
+                            flags: cd_code.direction(),
+                            lun,
+                            cdb_length: cd_code.cdb_length(),
+                            cd_code,
+                            cd_opcode,
+                            cd_address,
+                            cd_length,
+                        }
+                    },
+                )
+        }
+
+        fn cbw_bytes(c: &CommandBlock) -> [u8; COMMAND_BLOCK_BYTES] {
+            let mut b = [0u8; COMMAND_BLOCK_BYTES];
+            assert_eq!(c.to_bytes(&mut b), COMMAND_BLOCK_BYTES);
+            b
+        }
+
+        proptest! {
+            #[test]
+            fn cbw_roundtrip(c in command_block()) {
+                let b = cbw_bytes(&c);
+                prop_assert_eq!(CommandBlock::from_bytes(&b).unwrap(), c);
+            }
+
+            #[test]
+            fn cbw_unknown_code(c in command_block(), code in any::<u8>()) {
+                prop_assume!(CommandCode::try_from(code).is_err());
+                let mut b = cbw_bytes(&c);
+                b[15] = code;
+                prop_assert!(matches!(
+                    CommandBlock::from_bytes(&b),
+                    Err(CommandBlockParseError::UnknownCommandCode(c)) if c == code
+                ));
+            }
+
+            #[test]
+            fn cbw_unknown_flags(c in command_block(), flags in any::<u8>()) {
+                prop_assume!(Direction::try_from(flags).is_err());
+                let mut b = cbw_bytes(&c);
+                b[12] = flags;
+                prop_assert!(matches!(
+                    CommandBlock::from_bytes(&b),
+                    Err(CommandBlockParseError::UnknownFlags(f)) if f == flags
+                ));
+            }
+
+            #[test]
+            fn cbw_arbitrary(b in prop::collection::vec(any::<u8>(), 0..64)) {
+                // Must never panic; Anything accepted has to encode back to the same bytes
+                if let Ok(c) = CommandBlock::from_bytes(&b) {
+                    // The reserved byte before the length isn't preserved
+                    let mut expected = b[..COMMAND_BLOCK_BYTES].to_vec();
+                    expected[26] = 0;
+                    prop_assert_eq!(cbw_bytes(&c).to_vec(), expected);
+                }
+            }
+
+            #[test]
+            fn csw_roundtrip(tag in any::<u32>(), residue in any::<u32>(), failed in any::<bool>()) {
+                let c = CommandStatus {
+                    tag,
+                    residue,
+                    status: if failed { Status::FAILED } else { Status::SUCCESS },
+                };
+                let mut b = [0u8; COMMAND_STATUS_BYTES];
+                assert_eq!(c.to_bytes(&mut b), COMMAND_STATUS_BYTES);
+                prop_assert_eq!(CommandStatus::from_bytes(&b).unwrap(), c);
+            }
+
+            #[test]
+            fn csw_unknown_status(tag in any::<u32>(), status in 3..=u8::MAX) {
+                let c = CommandStatus {
+                    tag,
+                    residue: 0,
+                    status: Status::SUCCESS,
+                };
+                let mut b = [0u8; COMMAND_STATUS_BYTES];
+                c.to_bytes(&mut b);
+                b[12] = status;
+                let parsed = CommandStatus::from_bytes(&b).unwrap();
+                prop_assert_eq!(parsed.status, Status::Unknown(status));
+                let mut b2 = [0u8; COMMAND_STATUS_BYTES];
+                parsed.to_bytes(&mut b2);
+                prop_assert_eq!(b, b2);
+            }
+        }
     }
 }
@@ -0,0 +1,117 @@
+use std::sync::RwLock;
+use std::time::Duration;
+
+use crate::protocol::{DeviceFilter, ROCKCHIP_VENDOR_ID};
+
+/// Oddities of the loader of a specific device
+///
+/// Quirks are looked up by the transports when opening a device (see [lookup]) and applied
+/// automatically where possible
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Quirks {
+    /// Maximum number of sectors erased by a single erase operation
+    pub max_erase_sectors: Option<u16>,
+    /// Maximum number of sectors transferred by a single operation when streaming data
+    pub max_transfer_sectors: Option<u16>,
+    /// Lba reads and writes occasionally fail and should be retried
+    ///
+    /// Operations are tried at least 3 times unless a retry policy is set explicitly afterwards;
+    /// Writes are only retried if the retry policy enables it
+    pub unreliable_lba: bool,
+    /// Time to wait after downloading a loader before it's usable, on top of the delay specified
+    /// by the boot file
+    pub download_delay: Duration,
+}
+
+impl Quirks {
+    /// Device without any known quirks
+    pub const NONE: Quirks = Quirks {
+        max_erase_sectors: None,
+        max_transfer_sectors: None,
+        unreliable_lba: false,
+        download_delay: Duration::ZERO,
+    };
+}
+
+// Older SoCs only reliably handle transfers of up to 32 sectors, as used by rkflashtool
+const SMALL_TRANSFERS: Quirks = Quirks {
+    max_transfer_sectors: Some(32),
+    ..Quirks::NONE
+};
+
+/// Built-in quirks of Rockchip devices, by usb product id
+pub const BUILTIN_QUIRKS: &[(u16, Quirks)] = &[
+    // RK2918, RK2928, RK3066, RK3168, RK3066B, RK3188
+    (0x290a, SMALL_TRANSFERS),
+    (0x292a, SMALL_TRANSFERS),
+    (0x300a, SMALL_TRANSFERS),
+    (0x300b, SMALL_TRANSFERS),
+    (0x310a, SMALL_TRANSFERS),
+    (0x310b, SMALL_TRANSFERS),
+];
+
+static REGISTERED: RwLock<Vec<(DeviceFilter, Quirks)>> = RwLock::new(Vec::new());
+
+/// Register quirks for devices matching a filter, e.g. for custom loaders
+///
+/// Registered quirks take precedence over the [BUILTIN_QUIRKS]; If multiple registrations match
+/// a device the most recent one is used. Only affects devices opened afterwards
+pub fn register(filter: DeviceFilter, quirks: Quirks) {
+    REGISTERED
+        .write()
+        .unwrap_or_else(|e| e.into_inner())
+        .push((filter, quirks));
+}
+
+/// Quirks of a device with the given usb ids
+pub fn lookup(vendor_id: u16, product_id: u16) -> Quirks {
+    let registered = REGISTERED.read().unwrap_or_else(|e| e.into_inner());
+    if let Some((_, quirks)) = registered
+        .iter()
+        .rev()
+        .find(|(f, _)| f.matches(vendor_id, product_id))
+    {
+        return *quirks;
+    }
+    if vendor_id != ROCKCHIP_VENDOR_ID {
+        return Quirks::NONE;
+    }
+    BUILTIN_QUIRKS
+        .iter()
+        .find(|(p, _)| *p == product_id)
+        .map_or(Quirks::NONE, |(_, quirks)| *quirks)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn builtin() {
+        assert_eq!(
+            lookup(ROCKCHIP_VENDOR_ID, 0x310b).max_transfer_sectors,
+            Some(32)
+        );
+        assert_eq!(lookup(ROCKCHIP_VENDOR_ID, 0x350b), Quirks::NONE);
+        assert_eq!(lookup(0x1234, 0x310b), Quirks::NONE);
+    }
+
+    #[test]
+    fn registered() {
+        // Use a vendor id no other test uses as the registry is global
+        let quirks = Quirks {
+            download_delay: Duration::from_millis(500),
+            ..Quirks::NONE
+        };
+        register(DeviceFilter::vendor(0xf00d), quirks);
+        assert_eq!(lookup(0xf00d, 0x1), quirks);
+
+        let specific = Quirks {
+            max_erase_sectors: Some(8),
+            ..quirks
+        };
+        register(DeviceFilter::product(0xf00d, 0x2), specific);
+        assert_eq!(lookup(0xf00d, 0x2), specific);
+        assert_eq!(lookup(0xf00d, 0x1), quirks);
+    }
+}
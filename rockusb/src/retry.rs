@@ -0,0 +1,201 @@
+use std::time::Duration;
+
+#[cfg(any(feature = "libusb", feature = "nusb"))]
+use crate::{observer::OperationObserver, stats::Stats};
+
+/// Policy on retrying failed operations
+///
+/// By default operations are never retried. When retrying is enabled only idempotent operations
+/// (reads and info queries) are retried unless retrying writes is explicitly enabled as well.
+///
+/// ```
+/// # use std::time::Duration;
+/// # use rockusb::retry::RetryPolicy;
+/// let policy = RetryPolicy::new(3)
+///     .with_backoff(Duration::from_millis(50))
+///     .with_retry_on(|kind| kind == std::io::ErrorKind::TimedOut);
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    backoff: Duration,
+    retry_writes: bool,
+    retry_on: fn(std::io::ErrorKind) -> bool,
+}
+
+impl RetryPolicy {
+    /// Policy which tries operations at most max_attempts times
+    pub fn new(max_attempts: u32) -> Self {
+        Self {
+            max_attempts: max_attempts.max(1),
+            backoff: Duration::from_millis(100),
+            retry_writes: false,
+            retry_on: Self::default_retry_on,
+        }
+    }
+
+    /// Policy which never retries operations
+    pub fn never() -> Self {
+        Self::new(1)
+    }
+
+    /// Raise the number of times an operation is tried to at least min_attempts, keeping the
+    /// other settings
+    pub fn with_min_attempts(mut self, min_attempts: u32) -> Self {
+        self.max_attempts = self.max_attempts.max(min_attempts);
+        self
+    }
+
+    /// Delay before the first retry; The delay doubles for each subsequent retry
+    pub fn with_backoff(mut self, backoff: Duration) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Whether to also retry (non-idempotent) write operations
+    pub fn with_writes(mut self, retry_writes: bool) -> Self {
+        self.retry_writes = retry_writes;
+        self
+    }
+
+    /// Only retry errors for which the given function returns true
+    ///
+    /// By default timeouts, short transfers and interruptions are retried
+    pub fn with_retry_on(mut self, retry_on: fn(std::io::ErrorKind) -> bool) -> Self {
+        self.retry_on = retry_on;
+        self
+    }
+
+    /// Maximum number of times an operation is tried
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    /// Delay before the first retry
+    pub fn backoff(&self) -> Duration {
+        self.backoff
+    }
+
+    /// Whether write operations are retried
+    pub fn retry_writes(&self) -> bool {
+        self.retry_writes
+    }
+
+    fn default_retry_on(kind: std::io::ErrorKind) -> bool {
+        matches!(
+            kind,
+            std::io::ErrorKind::TimedOut
+                | std::io::ErrorKind::UnexpectedEof
+                | std::io::ErrorKind::Interrupted
+        )
+    }
+
+    // Delay to wait before retrying if the failed attempt should be retried
+    #[cfg(any(feature = "libusb", feature = "nusb"))]
+    pub(crate) fn retry_delay(
+        &self,
+        kind: std::io::ErrorKind,
+        attempt: u32,
+        write: bool,
+    ) -> Option<Duration> {
+        if attempt >= self.max_attempts || (write && !self.retry_writes) || !(self.retry_on)(kind) {
+            return None;
+        }
+        Some(self.backoff.saturating_mul(1 << (attempt - 1).min(16)))
+    }
+
+    // Handle a failed attempt for a transport; If it's to be retried the retry is recorded in the
+    // statistics and reported to the observer, returning the delay to wait before retrying
+    #[cfg(any(feature = "libusb", feature = "nusb"))]
+    pub(crate) fn on_failure(
+        &self,
+        error: &(dyn std::error::Error + 'static),
+        kind: std::io::ErrorKind,
+        (attempt, write): (u32, bool),
+        stats: &mut Stats,
+        observer: Option<&mut (dyn OperationObserver + 'static)>,
+    ) -> Option<Duration> {
+        let delay = self.retry_delay(kind, attempt, write)?;
+        if let Some(observer) = observer {
+            observer.on_retry(attempt, error);
+        }
+        stats.retries += 1;
+        Some(delay)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::never()
+    }
+}
+
+#[cfg(all(test, any(feature = "libusb", feature = "nusb")))]
+mod test {
+    use super::*;
+    use std::io::ErrorKind;
+
+    #[test]
+    fn retry_delay() {
+        let p = RetryPolicy::new(3).with_backoff(Duration::from_millis(10));
+        assert_eq!(
+            p.retry_delay(ErrorKind::TimedOut, 1, false),
+            Some(Duration::from_millis(10))
+        );
+        assert_eq!(
+            p.retry_delay(ErrorKind::TimedOut, 2, false),
+            Some(Duration::from_millis(20))
+        );
+        assert_eq!(p.retry_delay(ErrorKind::TimedOut, 3, false), None);
+        assert_eq!(p.retry_delay(ErrorKind::TimedOut, 1, true), None);
+        assert_eq!(p.retry_delay(ErrorKind::InvalidData, 1, false), None);
+        assert!(p
+            .with_writes(true)
+            .retry_delay(ErrorKind::TimedOut, 1, true)
+            .is_some());
+        assert_eq!(
+            RetryPolicy::default().retry_delay(ErrorKind::TimedOut, 1, false),
+            None
+        );
+        let merged = p.with_min_attempts(2);
+        assert_eq!(merged.max_attempts(), 3);
+        assert!(!merged.retry_writes());
+        assert_eq!(RetryPolicy::never().with_min_attempts(3).max_attempts(), 3);
+    }
+
+    #[derive(Default)]
+    struct Retries(Vec<u32>);
+
+    impl OperationObserver for Retries {
+        fn on_retry(&mut self, attempt: u32, _error: &(dyn std::error::Error + 'static)) {
+            self.0.push(attempt);
+        }
+    }
+
+    // Both transports handle failed attempts through on_failure
+    #[test]
+    fn on_failure() {
+        let p = RetryPolicy::new(3).with_backoff(Duration::from_millis(10));
+        let error = std::io::Error::from(ErrorKind::TimedOut);
+        let mut stats = Stats::new();
+        let mut observer = Retries::default();
+        for attempt in 1..=3 {
+            let delay = p.on_failure(
+                &error,
+                error.kind(),
+                (attempt, false),
+                &mut stats,
+                Some(&mut observer),
+            );
+            assert_eq!(delay.is_some(), attempt < 3);
+        }
+        assert_eq!(stats.retries, 2);
+        assert_eq!(observer.0, vec![1, 2]);
+
+        assert_eq!(
+            p.on_failure(&error, error.kind(), (1, true), &mut stats, None),
+            None
+        );
+        assert_eq!(stats.retries, 2);
+    }
+}
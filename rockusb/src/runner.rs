@@ -0,0 +1,796 @@
+use std::collections::VecDeque;
+use std::io::Read;
+use std::ops::Range;
+use std::sync::mpsc;
+use std::thread::JoinHandle;
+use std::time::Instant;
+
+use sha2::{Digest, Sha256};
+
+use crate::flasher::{
+    hex, journal_checksum, FlashError, FlashPlan, FlashReport, FlashStep, Image, Journal,
+    JournalEntry, Progress, StepResult, Target, JOURNAL_CHUNK,
+};
+use crate::gpt::{check_protective_mbr, Gpt, GptError, GptHeader, GptPartition, GptRepair};
+use crate::layout::GPT_PRIMARY_HEADER;
+use crate::protocol::{FlashInfo, ResetOpcode, SECTOR_SIZE};
+
+impl FlashError {
+    pub(crate) fn step(self, step: usize, flash_step: &FlashStep) -> Self {
+        FlashError::Step {
+            step,
+            description: flash_step.to_string(),
+            source: Box::new(self),
+        }
+    }
+}
+
+// Check an image fits in the range of sectors of its target
+pub(crate) fn check_fits(len: u64, sectors: &Range<u32>) -> Result<(), FlashError> {
+    let available = sectors.len() as u64 * SECTOR_SIZE;
+    if len > available {
+        return Err(FlashError::ImageTooLarge {
+            size: len,
+            available,
+        });
+    }
+    Ok(())
+}
+
+// Compare image data with data read back from the flash starting at start_sector
+pub(crate) fn compare(expected: &[u8], actual: &[u8], start_sector: u32) -> Result<(), FlashError> {
+    match expected
+        .chunks(SECTOR_SIZE as usize)
+        .zip(actual.chunks(SECTOR_SIZE as usize))
+        .position(|(e, a)| a[..e.len()] != *e)
+    {
+        Some(i) => Err(FlashError::VerifyMismatch(start_sector + i as u32)),
+        None => Ok(()),
+    }
+}
+
+// Maximum number of chunks queued for a verification worker, bounding the memory used when
+// reading from the device is faster than checking
+const VERIFY_QUEUE: usize = 4;
+
+// Content a verification worker checks the data read back from the flash against
+pub(crate) enum Expected {
+    Image(Image),
+    Sha256 { len: u64, sha256: [u8; 32] },
+}
+
+// Data read back from the flash for a verification running on a worker thread
+pub(crate) struct VerifyJob {
+    /// Number of bytes to read back
+    pub(crate) len: u64,
+    data: mpsc::SyncSender<Vec<u8>>,
+}
+
+impl VerifyJob {
+    // Pass the next chunk of data read back, trimmed to the verified length; Returns false if
+    // the worker already failed and no more data is needed
+    pub(crate) fn push(&self, data: Vec<u8>) -> bool {
+        self.data.send(data).is_ok()
+    }
+}
+
+fn verify_worker(
+    expected: Expected,
+    start_sector: u32,
+    len: mpsc::Sender<Result<u64, FlashError>>,
+    data: mpsc::Receiver<Vec<u8>>,
+) -> Result<(), FlashError> {
+    match expected {
+        Expected::Image(image) => {
+            let mut reader = match image.open() {
+                Ok((reader, l)) => {
+                    let _ = len.send(Ok(l));
+                    reader
+                }
+                Err(e) => {
+                    // The error is reported to the transport instead
+                    let _ = len.send(Err(e.into()));
+                    return Ok(());
+                }
+            };
+            let mut expected = vec![];
+            let mut sector = start_sector;
+            for actual in data {
+                expected.resize(actual.len(), 0);
+                reader.read_exact(&mut expected)?;
+                compare(&expected, &actual, sector)?;
+                sector += actual.len().div_ceil(SECTOR_SIZE as usize) as u32;
+            }
+            Ok(())
+        }
+        Expected::Sha256 { sha256, .. } => {
+            let mut hasher = Sha256::new();
+            for actual in data {
+                hasher.update(&actual);
+            }
+            let actual: [u8; 32] = hasher.finalize().into();
+            if actual != sha256 {
+                return Err(FlashError::HashMismatch {
+                    expected: hex(&sha256),
+                    actual: hex(&actual),
+                });
+            }
+            Ok(())
+        }
+    }
+}
+
+// Pool of threads checking data read back by verification steps
+//
+// The device can only be read sequentially, but comparing and hashing the data is done on worker
+// threads such that the next verification can read from the device in the meantime
+pub(crate) struct Verifier {
+    workers: usize,
+    pending: VecDeque<(usize, JoinHandle<Result<(), FlashError>>)>,
+}
+
+impl Verifier {
+    pub(crate) fn new() -> Self {
+        let workers = std::thread::available_parallelism().map_or(1, |n| n.get().min(4));
+        Verifier {
+            workers,
+            pending: VecDeque::new(),
+        }
+    }
+
+    // Number of verifications to keep pending before starting the given step
+    pub(crate) fn keep_before(&self, step: &FlashStep) -> usize {
+        if step.is_verify() {
+            self.workers - 1
+        } else {
+            0
+        }
+    }
+
+    // Wait for the oldest pending verification if more than `keep` are pending; Returns the
+    // index of its step and its result
+    fn wait(&mut self, keep: usize) -> Option<(usize, Result<(), FlashError>)> {
+        if self.pending.len() <= keep {
+            return None;
+        }
+        let (step, worker) = self.pending.pop_front()?;
+        let result = worker
+            .join()
+            .unwrap_or_else(|_| Err(std::io::Error::other("verification worker panicked").into()));
+        Some((step, result))
+    }
+
+    // Wait until at most `keep` verifications of `plan` are pending, recording the finished ones
+    // as complete in the journal
+    pub(crate) fn finish(
+        &mut self,
+        keep: usize,
+        plan: &FlashPlan,
+        mut journal: Option<&mut Journal>,
+    ) -> Result<(), FlashError> {
+        while let Some((i, result)) = self.wait(keep) {
+            let step = &plan.steps[i];
+            result.map_err(|e| e.step(i, step))?;
+            if let Some(journal) = journal.as_deref_mut() {
+                journal
+                    .complete(i)
+                    .map_err(|e| FlashError::from(e).step(i, step))?;
+            }
+        }
+        Ok(())
+    }
+
+    // Start verifying a step whose data starts at `start_sector`
+    pub(crate) fn start(
+        &mut self,
+        step: usize,
+        expected: Expected,
+        start_sector: u32,
+    ) -> Result<VerifyJob, FlashError> {
+        let known_len = match &expected {
+            Expected::Image(_) => None,
+            Expected::Sha256 { len, .. } => Some(*len),
+        };
+        let (len_tx, len_rx) = mpsc::channel();
+        let (data, data_rx) = mpsc::sync_channel(VERIFY_QUEUE);
+        let worker =
+            std::thread::spawn(move || verify_worker(expected, start_sector, len_tx, data_rx));
+        let len = match known_len {
+            Some(len) => len,
+            None => len_rx
+                .recv()
+                .map_err(|_| std::io::Error::other("verification worker panicked"))??,
+        };
+        self.pending.push_back((step, worker));
+        Ok(VerifyJob { len, data })
+    }
+}
+
+// Collect the sectors of data read back from the flash starting at start_sector which don't read
+// as erased (all 0x00 or all 0xff depending on the media), merging adjacent sectors
+pub(crate) fn collect_unerased(data: &[u8], start_sector: u32, unerased: &mut Vec<Range<u32>>) {
+    for (i, sector) in data.chunks(SECTOR_SIZE as usize).enumerate() {
+        if sector.iter().all(|&b| b == 0) || sector.iter().all(|&b| b == 0xff) {
+            continue;
+        }
+        let sector = start_sector + i as u32;
+        match unerased.last_mut() {
+            Some(last) if last.end == sector => last.end += 1,
+            _ => unerased.push(sector..sector + 1),
+        }
+    }
+}
+
+/// Flash access needed to execute a [FlashPlan], implemented by the transports
+///
+/// Errors are converted to [std::io::Error] such that the logic driving the steps is shared
+/// between the blocking and async transports
+pub(crate) trait FlashDevice {
+    /// Biggest transfer in bytes done in one lba command
+    fn max_transfer_size(&self) -> usize;
+    /// Convert a sector to a 32 bit logical block address
+    fn to_lba(sector: u64) -> std::io::Result<u32>;
+    async fn flash_info(&mut self) -> std::io::Result<FlashInfo>;
+    async fn read_lba(&mut self, start_sector: u32, data: &mut [u8]) -> std::io::Result<()>;
+    /// Write without checking critical regions, e.g. for partition tables
+    async fn write_lba_unchecked(&mut self, start_sector: u32, data: &[u8]) -> std::io::Result<()>;
+    async fn erase_lba(&mut self, start_sector: u32, sectors: u32) -> std::io::Result<()>;
+    async fn reset_device(&mut self, opcode: ResetOpcode) -> std::io::Result<()>;
+    /// Write `len` bytes from a reader, preserving the remainder of a partial last sector
+    async fn write_from_reader(
+        &mut self,
+        start_sector: u32,
+        reader: &mut (dyn Read + Send),
+        len: u64,
+        progress: &mut dyn FnMut(u64),
+    ) -> std::io::Result<()>;
+}
+
+// Read the primary GPT from the flash
+pub(crate) async fn read_gpt<D: FlashDevice>(device: &mut D) -> Result<Gpt, GptError> {
+    let mut start = vec![0; 2 * SECTOR_SIZE as usize];
+    device.read_lba(0, &mut start).await?;
+    check_protective_mbr(&start[..SECTOR_SIZE as usize])?;
+    let header = GptHeader::from_bytes(&start[SECTOR_SIZE as usize..])?;
+    let mut entries = vec![0; (header.entries_sectors() * SECTOR_SIZE) as usize];
+    device
+        .read_lba(D::to_lba(header.partition_entry_lba)?, &mut entries)
+        .await?;
+    let partitions = header.parse_entries(&entries)?;
+    Ok(Gpt {
+        header,
+        backup: None,
+        partitions,
+    })
+}
+
+// Execute a flash plan, optionally recording progress in a journal
+pub(crate) async fn run_plan<D: FlashDevice>(
+    device: &mut D,
+    plan: &FlashPlan,
+    mut journal: Option<&mut Journal>,
+    progress: &mut dyn FnMut(&Progress),
+) -> Result<FlashReport, FlashError> {
+    let mut report = FlashReport::default();
+    let mut gpt = None;
+    let mut verifier = Verifier::new();
+    for (i, step) in plan.steps.iter().enumerate() {
+        verifier.finish(verifier.keep_before(step), plan, journal.as_deref_mut())?;
+        let start = Instant::now();
+        let mut progress = |done, total| {
+            progress(&Progress {
+                step: i,
+                steps: plan.steps.len(),
+                done,
+                total,
+            })
+        };
+        let bytes = if journal.as_ref().is_some_and(|j| j.is_complete(i)) {
+            // A written partition table may be needed to resolve later targets
+            if let FlashStep::WriteGpt(new) = step {
+                gpt = Some(new.as_ref().clone());
+            }
+            0
+        } else {
+            let bytes = run_step(
+                device,
+                step,
+                plan.erase_before_write,
+                &mut gpt,
+                journal.as_deref_mut().map(|j| (j, i)),
+                (&mut verifier, i),
+                &mut progress,
+            )
+            .await
+            .map_err(|e| e.step(i, step))?;
+            // Verifications are recorded once their worker finished
+            if let Some(journal) = journal.as_deref_mut().filter(|_| !step.is_verify()) {
+                journal
+                    .complete(i)
+                    .map_err(|e| FlashError::from(e).step(i, step))?;
+            }
+            bytes
+        };
+        report.steps.push(StepResult {
+            step: i,
+            description: step.to_string(),
+            bytes,
+            duration: start.elapsed(),
+        });
+    }
+    verifier.finish(0, plan, journal)?;
+    Ok(report)
+}
+
+async fn run_step<D: FlashDevice>(
+    device: &mut D,
+    step: &FlashStep,
+    erase: bool,
+    gpt: &mut Option<Gpt>,
+    mut journal: Option<(&mut Journal, usize)>,
+    (verifier, index): (&mut Verifier, usize),
+    progress: &mut dyn FnMut(u64, u64),
+) -> Result<u64, FlashError> {
+    match step {
+        FlashStep::Erase { sectors } => {
+            let bytes = sectors.len() as u64 * SECTOR_SIZE;
+            device
+                .erase_lba(sectors.start, sectors.len() as u32)
+                .await?;
+            progress(bytes, bytes);
+            Ok(bytes)
+        }
+        FlashStep::Write { image, target } => {
+            let sectors = resolve_target(device, target, gpt).await?;
+            let (mut reader, len) = image.open()?;
+            check_fits(len, &sectors)?;
+            if journal.is_none() && !erase {
+                device
+                    .write_from_reader(sectors.start, &mut reader, len, &mut |done| {
+                        progress(done, len)
+                    })
+                    .await?;
+                return Ok(len);
+            }
+
+            let (mut done, mut checksum) = match &mut journal {
+                Some((journal, step)) => match journal.resume(*step, &mut reader, len)? {
+                    Some(resume) => resume,
+                    None => {
+                        (reader, _) = image.open()?;
+                        (0, journal_checksum())
+                    }
+                },
+                None => (0, journal_checksum()),
+            };
+            let mut buffer = vec![0; JOURNAL_CHUNK];
+            while done < len {
+                let chunk = (len - done).min(JOURNAL_CHUNK as u64) as usize;
+                reader.read_exact(&mut buffer[..chunk])?;
+                checksum.update(&buffer[..chunk]);
+                let sector = sectors.start + (done / SECTOR_SIZE) as u32;
+                // A partial last sector isn't erased, keeping the data following the image
+                let full_sectors = (chunk as u64 / SECTOR_SIZE) as u32;
+                if erase && full_sectors > 0 {
+                    device.erase_lba(sector, full_sectors).await?;
+                }
+                device
+                    .write_from_reader(sector, &mut &buffer[..chunk], chunk as u64, &mut |d| {
+                        progress(done + d, len)
+                    })
+                    .await?;
+                done += chunk as u64;
+                if let Some((journal, step)) = &mut journal {
+                    journal.record(
+                        *step,
+                        JournalEntry {
+                            done,
+                            checksum: checksum.clone().finalize(),
+                            complete: false,
+                        },
+                    )?;
+                }
+            }
+            Ok(len)
+        }
+        FlashStep::WriteGpt(new) => {
+            let mut mbr = vec![0; SECTOR_SIZE as usize];
+            device.read_lba(0, &mut mbr).await?;
+            let sectors = new.to_sectors(&mbr);
+            let bytes = sectors.iter().map(|(_, data)| data.len() as u64).sum();
+            for (lba, data) in sectors {
+                device.write_lba_unchecked(D::to_lba(lba)?, &data).await?;
+            }
+            progress(bytes, bytes);
+            *gpt = Some(new.as_ref().clone());
+            Ok(bytes)
+        }
+        FlashStep::Verify { image, target } => {
+            let sectors = resolve_target(device, target, gpt).await?;
+            let job = verifier.start(index, Expected::Image(image.clone()), sectors.start)?;
+            read_back(device, sectors, job, progress).await
+        }
+        FlashStep::VerifyHash {
+            target,
+            len,
+            sha256,
+        } => {
+            let sectors = resolve_target(device, target, gpt).await?;
+            let expected = Expected::Sha256 {
+                len: *len,
+                sha256: *sha256,
+            };
+            let job = verifier.start(index, expected, sectors.start)?;
+            read_back(device, sectors, job, progress).await
+        }
+        FlashStep::Reset(opcode) => {
+            device.reset_device(*opcode).await?;
+            progress(0, 0);
+            Ok(0)
+        }
+    }
+}
+
+// Read back the flash for a verification checking the data on a worker thread
+async fn read_back<D: FlashDevice>(
+    device: &mut D,
+    sectors: Range<u32>,
+    job: VerifyJob,
+    progress: &mut dyn FnMut(u64, u64),
+) -> Result<u64, FlashError> {
+    check_fits(job.len, &sectors)?;
+    let transfer = device.max_transfer_size();
+    let mut done = 0;
+    let mut sector = sectors.start;
+    while done < job.len {
+        let chunk = (job.len - done).min(transfer as u64) as usize;
+        let padded = chunk.next_multiple_of(SECTOR_SIZE as usize);
+        let mut data = vec![0; padded];
+        device.read_lba(sector, &mut data).await?;
+        data.truncate(chunk);
+        // The worker stops early on a mismatch, which is reported once it's joined
+        if !job.push(data) {
+            break;
+        }
+        sector += (padded / SECTOR_SIZE as usize) as u32;
+        done += chunk as u64;
+        progress(done, job.len);
+    }
+    Ok(job.len)
+}
+
+// Resolve the sectors of a target, reading the partition table if needed
+async fn resolve_target<D: FlashDevice>(
+    device: &mut D,
+    target: &Target,
+    gpt: &mut Option<Gpt>,
+) -> Result<Range<u32>, FlashError> {
+    if matches!(target, Target::Partition(_)) && gpt.is_none() {
+        *gpt = Some(read_gpt(device).await?);
+    }
+    target.resolve(device.flash_info().await?.sectors(), gpt.as_ref())
+}
+
+// Read and validate a GPT header and its partition entries
+async fn read_gpt_table<D: FlashDevice>(
+    device: &mut D,
+    lba: u64,
+) -> Result<(GptHeader, Vec<GptPartition>), GptError> {
+    let mut sector = vec![0; SECTOR_SIZE as usize];
+    device.read_lba(D::to_lba(lba)?, &mut sector).await?;
+    let header = GptHeader::from_bytes(&sector)?;
+    let mut entries = vec![0; (header.entries_sectors() * SECTOR_SIZE) as usize];
+    device
+        .read_lba(D::to_lba(header.partition_entry_lba)?, &mut entries)
+        .await?;
+    let partitions = header.parse_entries(&entries)?;
+    Ok((header, partitions))
+}
+
+// Repair the GPT on the flash, falling back to the backup if the primary is corrupted
+pub(crate) async fn repair_gpt<D: FlashDevice>(device: &mut D) -> Result<GptRepair, GptError> {
+    let sectors = device.flash_info().await?.sectors() as u64;
+    let mut mbr = vec![0; SECTOR_SIZE as usize];
+    device.read_lba(0, &mut mbr).await?;
+    check_protective_mbr(&mbr)?;
+
+    let primary = read_gpt_table(device, GPT_PRIMARY_HEADER as u64).await;
+    let old_backup_lba = primary
+        .as_ref()
+        .map_or(sectors - 1, |(header, _)| header.alternate_lba);
+    let backup = if old_backup_lba < sectors {
+        read_gpt_table(device, old_backup_lba).await
+    } else {
+        Err(GptError::TooSmall(sectors))
+    };
+    let primary_valid = primary.is_ok();
+    let backup_valid = backup.is_ok();
+    let (header, partitions) = match primary {
+        Ok(primary) => primary,
+        Err(e) => backup.map_err(|_| e)?,
+    };
+
+    let mut gpt = Gpt {
+        header,
+        backup: None,
+        partitions,
+    };
+    gpt.relocate(sectors)?;
+
+    // Clear the stale backup header so it can't be mistaken for a valid one
+    if backup_valid && old_backup_lba != sectors - 1 {
+        device
+            .write_lba_unchecked(D::to_lba(old_backup_lba)?, &[0; SECTOR_SIZE as usize])
+            .await?;
+    }
+    for (lba, data) in gpt.to_sectors(&mbr) {
+        device.write_lba_unchecked(D::to_lba(lba)?, &data).await?;
+    }
+    Ok(GptRepair {
+        gpt,
+        primary_valid,
+        backup_valid,
+        old_backup_lba,
+    })
+}
+
+// Move the backup GPT to the end of the flash after writing a smaller disk image, optionally
+// growing the last partition
+pub(crate) async fn fixup_gpt_after_image<D: FlashDevice>(
+    device: &mut D,
+    grow_last: bool,
+) -> Result<Gpt, GptError> {
+    let sectors = device.flash_info().await?.sectors() as u64;
+    let mut start = vec![0; 2 * SECTOR_SIZE as usize];
+    device.read_lba(0, &mut start).await?;
+    check_protective_mbr(&start[..SECTOR_SIZE as usize])?;
+    let header = GptHeader::from_bytes(&start[SECTOR_SIZE as usize..])?;
+    let mut entries = vec![0; (header.entries_sectors() * SECTOR_SIZE) as usize];
+    device
+        .read_lba(D::to_lba(header.partition_entry_lba)?, &mut entries)
+        .await?;
+    let partitions = header.parse_entries(&entries)?;
+
+    let old_backup = header.alternate_lba;
+    let mut gpt = Gpt {
+        header,
+        backup: None,
+        partitions,
+    };
+    gpt.relocate(sectors)?;
+    if grow_last {
+        gpt.grow_last_partition()?;
+    }
+
+    // Clear the stale backup header so it can't be mistaken for a valid one
+    if old_backup < sectors - 1 {
+        device
+            .write_lba_unchecked(D::to_lba(old_backup)?, &[0; SECTOR_SIZE as usize])
+            .await?;
+    }
+    for (lba, data) in gpt.to_sectors(&start) {
+        device.write_lba_unchecked(D::to_lba(lba)?, &data).await?;
+    }
+    Ok(gpt)
+}
+
+// Run the shared flasher logic for a blocking device; The futures never wait as all IO is done
+// blocking, so polling them once finishes them
+#[cfg(feature = "libusb")]
+pub(crate) fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    struct Noop;
+    impl std::task::Wake for Noop {
+        fn wake(self: std::sync::Arc<Self>) {}
+    }
+    let waker = std::task::Waker::from(std::sync::Arc::new(Noop));
+    let mut context = std::task::Context::from_waker(&waker);
+    let mut future = std::pin::pin!(future);
+    loop {
+        if let std::task::Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            return output;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn fits() {
+        check_fits(0x4000 * 512, &(0x8000..0xc000)).unwrap();
+        assert!(check_fits(0x4000 * 512 + 1, &(0x8000..0xc000)).is_err());
+    }
+
+    #[test]
+    fn verify() {
+        let expected = vec![0xaa; 1000];
+        let mut actual = vec![0xaa; 1024];
+        compare(&expected, &actual, 0x10).unwrap();
+        actual[1020] = 0;
+        compare(&expected, &actual, 0x10).unwrap();
+        actual[700] = 0;
+        assert!(matches!(
+            compare(&expected, &actual, 0x10),
+            Err(FlashError::VerifyMismatch(0x11))
+        ));
+    }
+
+    #[test]
+    fn erased() {
+        let mut data = vec![0xff; 8 * 512];
+        data[..512].fill(0);
+        let mut unerased = Vec::new();
+        collect_unerased(&data, 0x10, &mut unerased);
+        assert!(unerased.is_empty());
+
+        data[512 + 3] = 0;
+        data[1024] = 0xaa;
+        data[7 * 512 + 511] = 0;
+        collect_unerased(&data, 0x10, &mut unerased);
+        collect_unerased(&[0x55; 512], 0x18, &mut unerased);
+        assert_eq!(unerased, vec![0x11..0x13, 0x17..0x19]);
+    }
+
+    #[test]
+    fn verify_workers() {
+        let data = vec![0x5a; 1536];
+        let plan = FlashPlan::new()
+            .verify(Image::Data(data.clone()), Target::Sector(8))
+            .verify_hash(Target::Sector(8), 3, [0; 32]);
+        let mut verifier = Verifier::new();
+
+        let job = verifier
+            .start(0, Expected::Image(Image::Data(data.clone())), 8)
+            .unwrap();
+        assert_eq!(job.len, 1536);
+        for chunk in data.chunks(1024) {
+            assert!(job.push(chunk.to_vec()));
+        }
+        drop(job);
+        verifier.finish(0, &plan, None).unwrap();
+
+        let job = verifier
+            .start(0, Expected::Image(Image::Data(data.clone())), 8)
+            .unwrap();
+        let mut corrupted = data.clone();
+        corrupted[1030] = 0;
+        for chunk in corrupted.chunks(1024) {
+            job.push(chunk.to_vec());
+        }
+        drop(job);
+        assert!(matches!(
+            verifier.finish(0, &plan, None),
+            Err(FlashError::Step { step: 0, source, .. })
+                if matches!(*source, FlashError::VerifyMismatch(10))
+        ));
+
+        // sha256 of "abc"
+        let sha256 = [
+            0xba, 0x78, 0x16, 0xbf, 0x8f, 0x01, 0xcf, 0xea, 0x41, 0x41, 0x40, 0xde, 0x5d, 0xae,
+            0x22, 0x23, 0xb0, 0x03, 0x61, 0xa3, 0x96, 0x17, 0x7a, 0x9c, 0xb4, 0x10, 0xff, 0x61,
+            0xf2, 0x00, 0x15, 0xad,
+        ];
+        for (expected, ok) in [(sha256, true), ([0; 32], false)] {
+            let job = verifier
+                .start(
+                    1,
+                    Expected::Sha256 {
+                        len: 3,
+                        sha256: expected,
+                    },
+                    8,
+                )
+                .unwrap();
+            assert_eq!(job.len, 3);
+            job.push(b"ab".to_vec());
+            job.push(b"c".to_vec());
+            drop(job);
+            assert_eq!(verifier.finish(0, &plan, None).is_ok(), ok);
+        }
+    }
+
+    // Flash kept in memory to run plans against
+    #[cfg(feature = "libusb")]
+    struct MemoryDevice(Vec<u8>);
+
+    #[cfg(feature = "libusb")]
+    impl MemoryDevice {
+        fn range(&mut self, start_sector: u32, len: usize) -> &mut [u8] {
+            let start = start_sector as usize * SECTOR_SIZE as usize;
+            &mut self.0[start..start + len]
+        }
+    }
+
+    #[cfg(feature = "libusb")]
+    impl FlashDevice for MemoryDevice {
+        fn max_transfer_size(&self) -> usize {
+            8 * SECTOR_SIZE as usize
+        }
+
+        fn to_lba(sector: u64) -> std::io::Result<u32> {
+            u32::try_from(sector).map_err(std::io::Error::other)
+        }
+
+        async fn flash_info(&mut self) -> std::io::Result<FlashInfo> {
+            let mut info = [0; 11];
+            info[..4].copy_from_slice(&(self.0.len() as u32 / SECTOR_SIZE as u32).to_le_bytes());
+            Ok(FlashInfo::from_bytes(info))
+        }
+
+        async fn read_lba(&mut self, start_sector: u32, data: &mut [u8]) -> std::io::Result<()> {
+            data.copy_from_slice(self.range(start_sector, data.len()));
+            Ok(())
+        }
+
+        async fn write_lba_unchecked(
+            &mut self,
+            start_sector: u32,
+            data: &[u8],
+        ) -> std::io::Result<()> {
+            self.range(start_sector, data.len()).copy_from_slice(data);
+            Ok(())
+        }
+
+        async fn erase_lba(&mut self, start_sector: u32, sectors: u32) -> std::io::Result<()> {
+            self.range(start_sector, (sectors as u64 * SECTOR_SIZE) as usize)
+                .fill(0xff);
+            Ok(())
+        }
+
+        async fn reset_device(&mut self, _opcode: ResetOpcode) -> std::io::Result<()> {
+            Ok(())
+        }
+
+        async fn write_from_reader(
+            &mut self,
+            start_sector: u32,
+            reader: &mut (dyn Read + Send),
+            len: u64,
+            progress: &mut dyn FnMut(u64),
+        ) -> std::io::Result<()> {
+            reader.read_exact(self.range(start_sector, len as usize))?;
+            progress(len);
+            Ok(())
+        }
+    }
+
+    #[cfg(feature = "libusb")]
+    #[test]
+    fn run() {
+        let mut device = MemoryDevice(vec![0xee; 0x100 * SECTOR_SIZE as usize]);
+        let data: Vec<u8> = (0..3000).map(|i| i as u8).collect();
+        let sha256 = Sha256::digest(&data).into();
+        let plan = FlashPlan::new()
+            .erase_before_write(true)
+            .write(Image::Data(data.clone()), Target::Sector(0x40))
+            .verify(Image::Data(data.clone()), Target::Sector(0x40))
+            .verify_hash(Target::Sector(0x40), data.len() as u64, sha256)
+            .reset(ResetOpcode::Reset);
+        let mut steps = vec![];
+        let report = block_on(run_plan(&mut device, &plan, None, &mut |p| {
+            steps.push(p.step)
+        }))
+        .unwrap();
+        assert_eq!(report.steps.len(), 4);
+        assert_eq!(report.bytes(), 3 * data.len() as u64);
+        assert!(steps.windows(2).all(|w| w[0] <= w[1]));
+        assert_eq!(&device.range(0x40, data.len())[..], &data[..]);
+        // Full sectors are erased before writing, the remainder of the last one is kept
+        assert!(device.range(0x40, 6 * 512)[data.len()..]
+            .iter()
+            .all(|&b| b == 0xee));
+
+        let mut other = data.clone();
+        other[1500] ^= 1;
+        let plan = FlashPlan::new()
+            .verify(Image::Data(other), Target::Sector(0x40))
+            .reset(ResetOpcode::Reset);
+        let r = block_on(run_plan(&mut device, &plan, None, &mut |_| ()));
+        assert!(matches!(
+            r,
+            Err(FlashError::Step { step: 0, source, .. })
+                if matches!(*source, FlashError::VerifyMismatch(0x42))
+        ));
+    }
+}
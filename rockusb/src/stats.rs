@@ -0,0 +1,84 @@
+use std::time::{Duration, Instant};
+
+/// Statistics of a transport session
+#[derive(Debug, Clone)]
+pub struct Stats {
+    /// Start of the session
+    pub started: Instant,
+    /// Number of operations executed
+    pub operations: u64,
+    /// Number of operations that failed
+    pub failed_operations: u64,
+    /// Number of times an operation was retried
+    pub retries: u64,
+    /// Bytes read from the device
+    pub bytes_read: u64,
+    /// Bytes written to the device
+    pub bytes_written: u64,
+    /// Total time spent executing operations
+    pub busy: Duration,
+}
+
+impl Stats {
+    #[cfg(any(feature = "libusb", feature = "nusb"))]
+    pub(crate) fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            operations: 0,
+            failed_operations: 0,
+            retries: 0,
+            bytes_read: 0,
+            bytes_written: 0,
+            busy: Duration::ZERO,
+        }
+    }
+
+    /// Time elapsed since the start of the session
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Effective read throughput in bytes per second while executing operations
+    pub fn read_throughput(&self) -> f64 {
+        Self::throughput(self.bytes_read, self.busy)
+    }
+
+    /// Effective write throughput in bytes per second while executing operations
+    pub fn write_throughput(&self) -> f64 {
+        Self::throughput(self.bytes_written, self.busy)
+    }
+
+    fn throughput(bytes: u64, duration: Duration) -> f64 {
+        if duration.is_zero() {
+            0.0
+        } else {
+            bytes as f64 / duration.as_secs_f64()
+        }
+    }
+
+    #[cfg(any(feature = "libusb", feature = "nusb"))]
+    pub(crate) fn record_operation(&mut self, duration: Duration, success: bool) {
+        self.operations += 1;
+        if !success {
+            self.failed_operations += 1;
+        }
+        self.busy += duration;
+    }
+}
+
+impl std::fmt::Display for Stats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} operations ({} failed, {} retries); read {} bytes ({:.2} MB/s), wrote {} bytes ({:.2} MB/s) in {:.2?}",
+            self.operations,
+            self.failed_operations,
+            self.retries,
+            self.bytes_read,
+            self.read_throughput() / 1_000_000.0,
+            self.bytes_written,
+            self.write_throughput() / 1_000_000.0,
+            self.elapsed()
+        )
+    }
+}
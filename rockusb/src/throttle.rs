@@ -0,0 +1,73 @@
+use std::time::{Duration, Instant};
+
+/// Pacing of transfers to a maximum average rate
+///
+/// Transfers are accounted for after they're done; The returned delay is the time to wait before
+/// the next transfer to stay within the rate. Idle time doesn't build up credit for later bursts,
+/// so the rate is never exceeded for longer than a single transfer
+#[derive(Debug, Clone)]
+pub struct Throttle {
+    bytes_per_second: u64,
+    start: Instant,
+    bytes: u64,
+}
+
+impl Throttle {
+    /// Throttle to at most `bytes_per_second`
+    pub fn new(bytes_per_second: u64) -> Self {
+        Self {
+            bytes_per_second: bytes_per_second.max(1),
+            start: Instant::now(),
+            bytes: 0,
+        }
+    }
+
+    /// Maximum rate in bytes per second
+    pub fn bytes_per_second(&self) -> u64 {
+        self.bytes_per_second
+    }
+
+    /// Account for a transfer of `bytes`, returning the time to wait before the next transfer
+    pub fn delay(&mut self, bytes: usize) -> Duration {
+        self.delay_at(bytes, Instant::now())
+    }
+
+    fn delay_at(&mut self, bytes: usize, now: Instant) -> Duration {
+        self.bytes += bytes as u64;
+        let budget = Duration::from_secs_f64(self.bytes as f64 / self.bytes_per_second as f64);
+        let elapsed = now.saturating_duration_since(self.start);
+        if budget > elapsed {
+            budget - elapsed
+        } else {
+            // Behind schedule (e.g. after being idle); Start a new window from here
+            self.start = now;
+            self.bytes = 0;
+            Duration::ZERO
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pacing() {
+        let mut throttle = Throttle::new(1000);
+        let start = throttle.start;
+
+        // Back to back transfers wait for their budget
+        assert_eq!(throttle.delay_at(500, start), Duration::from_millis(500));
+        let now = start + Duration::from_millis(500);
+        assert_eq!(throttle.delay_at(1000, now), Duration::from_millis(1000));
+
+        // Slow transfers don't wait
+        let now = start + Duration::from_millis(2000);
+        assert_eq!(throttle.delay_at(100, now), Duration::ZERO);
+
+        // Idle time doesn't allow bursts afterwards
+        let now = start + Duration::from_secs(60);
+        assert_eq!(throttle.delay_at(0, now), Duration::ZERO);
+        assert_eq!(throttle.delay_at(2000, now), Duration::from_secs(2));
+    }
+}
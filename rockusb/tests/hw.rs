@@ -0,0 +1,157 @@
+//! Hardware-in-the-loop tests against a sacrificial board
+//!
+//! Built with the `hw-tests` feature; The tests only talk to a device when it is specified using
+//! environment variables and are skipped otherwise:
+//! * `ROCKUSB_TEST_DEVICE`: `<bus>:<address>` of a device running a loader
+//! * `ROCKUSB_TEST_SCRATCH`: start sector of a scratch area which may be overwritten; Without it
+//!   only read-only tests are run
+//! * `ROCKUSB_TEST_SCRATCH_SECTORS`: size of the scratch area in sectors, defaults to 0x800
+//!
+//! The original content of the scratch area is restored at the end of the test. For example:
+//! `ROCKUSB_TEST_DEVICE=1:23 ROCKUSB_TEST_SCRATCH=0x100000 cargo test --features hw-tests --test hw`
+#![cfg(feature = "hw-tests")]
+
+use std::io::{BufRead, Seek, SeekFrom, Write};
+
+use rockusb::layout::Region;
+use rockusb::libusb::{Devices, Transport};
+use rockusb::protocol::SECTOR_SIZE;
+
+const SECTOR: usize = SECTOR_SIZE as usize;
+
+fn parse_number(s: &str) -> u32 {
+    match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .unwrap_or_else(|_| panic!("Invalid number: {}", s))
+}
+
+fn env_number(var: &str) -> Option<u32> {
+    std::env::var(var).ok().map(|v| parse_number(&v))
+}
+
+fn open_device() -> Option<Transport> {
+    let Ok(device) = std::env::var("ROCKUSB_TEST_DEVICE") else {
+        eprintln!("ROCKUSB_TEST_DEVICE not set; Skipping hardware tests");
+        return None;
+    };
+    let (bus, address) = device
+        .split_once(':')
+        .expect("ROCKUSB_TEST_DEVICE should be <bus>:<address>");
+    let (bus, address) = (parse_number(bus) as u8, parse_number(address) as u8);
+
+    let devices = Devices::new().unwrap();
+    let transport = devices
+        .iter()
+        .find(|d| match d {
+            Ok(t) => t.bus_number() == bus && t.address() == address,
+            Err(e) => e.device.bus_number() == bus && e.device.address() == address,
+        })
+        .expect("Test device not found")
+        .expect("Test device unavailable");
+    Some(transport)
+}
+
+fn pattern(len: usize, seed: u8) -> Vec<u8> {
+    (0..len)
+        .map(|i| (i / SECTOR) as u8 ^ (i as u8).wrapping_mul(31) ^ seed)
+        .collect()
+}
+
+// The device is shared, so everything runs as a single scripted sequence
+#[test]
+fn scripted_sequence() {
+    let Some(mut transport) = open_device() else {
+        return;
+    };
+
+    let chip_info = transport.chip_info().unwrap();
+    eprintln!("Chip: {}", chip_info);
+    let flash_id = transport.flash_id().unwrap();
+    eprintln!("Flash id: {}", flash_id.to_str());
+    let info = transport.flash_info().unwrap();
+    eprintln!("Flash: {} sectors", info.sectors());
+    let capability = transport.capability().unwrap();
+    eprintln!("Capability: {:?}", capability);
+
+    let mut mbr = vec![0; SECTOR];
+    assert_eq!(transport.read_lba(0, &mut mbr).unwrap() as usize, SECTOR);
+
+    let Some(scratch) = env_number("ROCKUSB_TEST_SCRATCH") else {
+        eprintln!("ROCKUSB_TEST_SCRATCH not set; Skipping write tests");
+        return;
+    };
+    let sectors = env_number("ROCKUSB_TEST_SCRATCH_SECTORS").unwrap_or(0x800);
+    assert!(
+        scratch
+            .checked_add(sectors)
+            .is_some_and(|end| end <= info.sectors()),
+        "Scratch area outside of the flash"
+    );
+    let len = sectors as usize * SECTOR;
+
+    let mut original = vec![0; len];
+    transport
+        .read_to_writer(scratch..scratch + sectors, &mut original[..], |_| {})
+        .unwrap();
+
+    let data = pattern(len, 0x5a);
+    transport
+        .write_from_reader(scratch, &data[..], len as u64, |_| {})
+        .unwrap();
+    let mut read = vec![0; len];
+    transport
+        .read_to_writer(scratch..scratch + sectors, &mut read[..], |_| {})
+        .unwrap();
+    assert!(read == data, "Read back data differs from written data");
+
+    // Unaligned write preserving the rest of the last sector
+    let partial = pattern(SECTOR + 100, 0xa5);
+    transport
+        .write_from_reader(scratch, &partial[..], partial.len() as u64, |_| {})
+        .unwrap();
+    let mut head = vec![0; 2 * SECTOR];
+    transport.read_lba(scratch, &mut head).unwrap();
+    assert_eq!(head[..partial.len()], partial[..]);
+    assert_eq!(head[partial.len()..], data[partial.len()..2 * SECTOR]);
+
+    // Writes to the first 4MiB may touch the IDBlock; Its content is written back unchanged
+    if capability.first_4m_access() {
+        let offset = Region::IdBlock.offset() * SECTOR_SIZE as u32 + 100;
+        let mut idb = vec![0; SECTOR + 100];
+        transport.read_first_4m(offset, &mut idb).unwrap();
+        transport.write_first_4m(offset, &idb).unwrap();
+        let mut check = vec![0; idb.len()];
+        transport.read_first_4m(offset, &mut check).unwrap();
+        assert!(check == idb, "IDBlock changed by writing it back");
+    }
+
+    // Data written between filling and consuming the io buffer survives consuming past it
+    let expected = head.clone();
+    let mut io = transport.io().unwrap();
+    io.seek(SeekFrom::Start(u64::from(scratch) * SECTOR_SIZE))
+        .unwrap();
+    assert_eq!(io.fill_buf().unwrap(), &expected[..SECTOR]);
+    io.write_all(b"rockusb").unwrap();
+    io.consume(SECTOR - 7);
+    assert_eq!(io.fill_buf().unwrap(), &expected[SECTOR..]);
+    drop(io);
+    transport.read_lba(scratch, &mut head).unwrap();
+    assert_eq!(&head[..7], b"rockusb");
+    assert_eq!(head[7..], expected[7..]);
+
+    transport.erase_lba(scratch, sectors).unwrap();
+    transport
+        .read_to_writer(scratch..scratch + sectors, &mut read[..], |_| {})
+        .unwrap();
+    assert!(read != data, "Data still present after erase");
+
+    transport
+        .write_from_reader(scratch, &original[..], len as u64, |_| {})
+        .unwrap();
+    transport
+        .read_to_writer(scratch..scratch + sectors, &mut read[..], |_| {})
+        .unwrap();
+    assert!(read == original, "Failed to restore the scratch area");
+}
@@ -0,0 +1,309 @@
+//! Loopback tests running the sans-io operations against an in-memory device
+use rockusb::operation::{
+    self, OperationSteps, UsbOperationError, UsbStep, MAX_LBA_SECTORS, MAX_RESIDUE_RETRIES,
+    STREAM_CHUNK_SECTORS,
+};
+use rockusb::protocol::{
+    CommandBlock, CommandCode, CommandStatus, Direction, ResetOpcode, Status, SECTOR_SIZE,
+};
+
+const SECTOR: usize = SECTOR_SIZE as usize;
+
+/// Device side of the protocol backed by an in-memory flash
+struct Responder {
+    flash: Vec<u8>,
+    command: Option<CommandBlock>,
+    status: Status,
+    residue: u32,
+    data_done: bool,
+    resets: Vec<u8>,
+    // Number of upcoming lba writes of which the last sector doesn't land
+    lossy_writes: usize,
+}
+
+impl Responder {
+    fn new(sectors: usize) -> Self {
+        Self {
+            flash: (0..sectors * SECTOR).map(|i| (i / SECTOR) as u8).collect(),
+            command: None,
+            status: Status::SUCCESS,
+            residue: 0,
+            data_done: false,
+            resets: vec![],
+            lossy_writes: 0,
+        }
+    }
+
+    fn sectors(&self) -> usize {
+        self.flash.len() / SECTOR
+    }
+
+    // Byte range on the flash addressed by an lba command, if valid
+    fn lba_range(&self, command: &CommandBlock) -> Option<std::ops::Range<usize>> {
+        let start = command.address() as usize;
+        let end = start + command.length() as usize;
+        (end <= self.sectors()).then_some(start * SECTOR..end * SECTOR)
+    }
+
+    fn write_bulk(&mut self, data: &[u8]) {
+        let Some(command) = self.command.clone() else {
+            let command = CommandBlock::from_bytes(data).expect("Invalid command block");
+            self.status = Status::SUCCESS;
+            self.residue = 0;
+            self.data_done = command.transfer_length() == 0;
+            if self.data_done {
+                self.execute(&command);
+            }
+            self.command = Some(command);
+            return;
+        };
+
+        assert!(!self.data_done, "Unexpected data");
+        assert_eq!(command.direction(), Direction::Out);
+        assert_eq!(data.len(), command.transfer_length() as usize);
+        match command.code() {
+            CommandCode::WriteLBA => match self.lba_range(&command) {
+                Some(range) if self.lossy_writes > 0 => {
+                    self.lossy_writes -= 1;
+                    let landed = data.len() - SECTOR;
+                    self.flash[range.start..range.start + landed].copy_from_slice(&data[..landed]);
+                    self.residue = SECTOR as u32;
+                }
+                Some(range) => self.flash[range].copy_from_slice(data),
+                None => self.status = Status::FAILED,
+            },
+            c => panic!("Unexpected data for {:?}", c),
+        }
+        self.data_done = true;
+    }
+
+    fn read_bulk(&mut self, data: &mut [u8]) {
+        let command = self.command.clone().expect("Read without command");
+        if self.data_done {
+            let csw = if self.status == Status::SUCCESS {
+                CommandStatus {
+                    residue: self.residue,
+                    ..CommandStatus::success_for(&command)
+                }
+            } else {
+                CommandStatus::failed_for(&command)
+            };
+            csw.to_bytes(data);
+            self.command = None;
+            return;
+        }
+
+        assert_eq!(command.direction(), Direction::In);
+        assert_eq!(data.len(), command.transfer_length() as usize);
+        data.fill(0);
+        match command.code() {
+            CommandCode::ReadFlashInfo => {
+                data[..4].copy_from_slice(&(self.sectors() as u32).to_le_bytes());
+                data[4..6].copy_from_slice(&64u16.to_le_bytes());
+            }
+            CommandCode::ReadChipInfo => data[..4].copy_from_slice(b"8853"),
+            CommandCode::ReadCapability => data[0] = 0x1,
+            CommandCode::ReadLBA => match self.lba_range(&command) {
+                Some(range) => data.copy_from_slice(&self.flash[range]),
+                None => self.status = Status::FAILED,
+            },
+            c => panic!("Unexpected read for {:?}", c),
+        }
+        self.data_done = true;
+    }
+
+    // Execute a command without data phase
+    fn execute(&mut self, command: &CommandBlock) {
+        match command.code() {
+            CommandCode::TestUnitReady => (),
+            CommandCode::EraseLBA => match self.lba_range(command) {
+                Some(range) => self.flash[range].fill(0xff),
+                None => self.status = Status::FAILED,
+            },
+            CommandCode::DeviceReset => self.resets.push(command.opcode()),
+            c => panic!("Unexpected command {:?}", c),
+        }
+    }
+
+    /// Run an operation to completion against the responder, like a transport would
+    fn run<T, O: OperationSteps<T>>(&mut self, mut operation: O) -> Result<T, UsbOperationError> {
+        loop {
+            match operation.step() {
+                UsbStep::WriteBulk { data, .. } => self.write_bulk(data),
+                UsbStep::ReadBulk { data, .. } => self.read_bulk(data),
+                UsbStep::WriteControl { .. } => panic!("Unexpected maskrom transfer"),
+                UsbStep::Finished(r) => {
+                    assert!(self.command.is_none(), "Command not finished");
+                    return r;
+                }
+            }
+        }
+    }
+
+    /// Write data in chunks of at most `chunk` sectors like the transports do
+    fn write(&mut self, start: u32, data: &[u8], chunk: usize) {
+        for (i, c) in data.chunks(chunk * SECTOR).enumerate() {
+            let written = self
+                .run(operation::write_lba(start + (i * chunk) as u32, c).unwrap())
+                .unwrap();
+            assert_eq!(u32::from(written) as usize, c.len());
+        }
+    }
+
+    /// Read data in chunks of at most `chunk` sectors like the transports do
+    fn read(&mut self, start: u32, data: &mut [u8], chunk: usize) {
+        for (i, c) in data.chunks_mut(chunk * SECTOR).enumerate() {
+            let len = c.len();
+            let read = self
+                .run(operation::read_lba(start + (i * chunk) as u32, c).unwrap())
+                .unwrap();
+            assert_eq!(u32::from(read) as usize, len);
+        }
+    }
+}
+
+#[test]
+fn info() {
+    let mut responder = Responder::new(0x1000);
+    let info = responder.run(operation::flash_info()).unwrap();
+    assert_eq!(info.sectors(), 0x1000);
+    assert_eq!(info.block_size_sectors(), 64);
+    let chip = responder.run(operation::chip_info()).unwrap();
+    assert_eq!(&chip.inner()[..4], b"8853");
+    let capability = responder.run(operation::capability()).unwrap();
+    assert!(capability.direct_lba());
+    assert!(!capability.first_4m_access());
+}
+
+#[test]
+fn write_read() {
+    let mut responder = Responder::new(0x1000);
+    let data: Vec<u8> = (0..0x300 * SECTOR).map(|i| (i % 251) as u8).collect();
+    responder.write(0x100, &data, 0x70);
+    assert_eq!(
+        responder.flash[0x100 * SECTOR..0x400 * SECTOR],
+        data[..],
+        "Data not written"
+    );
+    // Surrounding data is untouched
+    assert!(responder.flash[0xff * SECTOR..0x100 * SECTOR]
+        .iter()
+        .all(|b| *b == 0xff));
+    assert!(responder.flash[0x400 * SECTOR..0x401 * SECTOR]
+        .iter()
+        .all(|b| *b == 0x00));
+
+    let mut read = vec![0; data.len()];
+    responder.read(0x100, &mut read, 0x41);
+    assert_eq!(read, data);
+}
+
+#[test]
+fn large_transfer() {
+    let sectors = MAX_LBA_SECTORS as usize + 0x10;
+    let mut responder = Responder::new(sectors);
+    let data = vec![0x5a; sectors * SECTOR];
+    responder.write(0, &data, MAX_LBA_SECTORS as usize);
+    let mut read = vec![0; data.len()];
+    responder.read(0, &mut read, MAX_LBA_SECTORS as usize);
+    assert_eq!(read, data);
+}
+
+#[test]
+fn stream() {
+    let mut responder = Responder::new(0x2000);
+    let len = STREAM_CHUNK_SECTORS as usize * SECTOR * 2 + 1000;
+    let data: Vec<u8> = (0..len).map(|i| (i % 7) as u8).collect();
+    let mut reader = &data[..];
+    let written = responder
+        .run(operation::write_lba_from_reader(0x10..0x2000, &mut reader))
+        .unwrap();
+    assert_eq!(written, len as u64);
+    let start = 0x10 * SECTOR;
+    assert_eq!(responder.flash[start..start + len], data[..]);
+    // The last sector is padded with zeros
+    let end = start + len.next_multiple_of(SECTOR);
+    assert!(responder.flash[start + len..end].iter().all(|b| *b == 0));
+}
+
+#[test]
+fn stream_residue() {
+    let mut responder = Responder::new(0x2000);
+    let len = STREAM_CHUNK_SECTORS as usize * SECTOR * 2;
+    let data: Vec<u8> = (0..len).map(|i| (i % 13) as u8).collect();
+    // Every chunk loses its last sector once, which is re-issued
+    responder.lossy_writes = 3;
+    let mut reader = &data[..];
+    let written = responder
+        .run(operation::write_lba_from_reader(0x10..0x2000, &mut reader))
+        .unwrap();
+    assert_eq!(written, len as u64);
+    assert_eq!(
+        responder.flash[0x10 * SECTOR..0x10 * SECTOR + len],
+        data[..]
+    );
+}
+
+#[test]
+fn stream_partial() {
+    let mut responder = Responder::new(0x100);
+    let data = vec![0xa5; 0x10 * SECTOR];
+    responder.lossy_writes = usize::MAX;
+    let mut reader = &data[..];
+    let r = responder.run(operation::write_lba_from_reader(0..0x100, &mut reader));
+    // The last sector never lands, however often it's re-issued
+    let landed = data.len() - SECTOR;
+    assert_eq!(
+        r.unwrap_err(),
+        UsbOperationError::PartialTransfer {
+            written: landed as u64,
+            requested: data.len() as u64
+        }
+    );
+    assert_eq!(responder.flash[..landed], data[..landed]);
+    assert_eq!(responder.flash[landed], (landed / SECTOR) as u8);
+    assert_eq!(
+        usize::MAX - responder.lossy_writes,
+        MAX_RESIDUE_RETRIES as usize + 1
+    );
+}
+
+#[test]
+fn erase() {
+    let mut responder = Responder::new(0x100);
+    responder.run(operation::erase_lba(0x20, 0x10)).unwrap();
+    assert!(responder.flash[0x20 * SECTOR..0x30 * SECTOR]
+        .iter()
+        .all(|b| *b == 0xff));
+    assert_eq!(responder.flash[0x30 * SECTOR], 0x30);
+    assert_eq!(responder.flash[0x1f * SECTOR], 0x1f);
+}
+
+#[test]
+fn out_of_range() {
+    let mut responder = Responder::new(0x100);
+    let mut data = vec![0; 0x10 * SECTOR];
+    assert!(matches!(
+        responder.run(operation::read_lba(0xf8, &mut data).unwrap()),
+        Err(UsbOperationError::FailedStatus(_))
+    ));
+    assert!(matches!(
+        responder.run(operation::write_lba(0xf8, &data).unwrap()),
+        Err(UsbOperationError::FailedStatus(_))
+    ));
+    assert!(matches!(
+        responder.run(operation::erase_lba(0xff, 2)),
+        Err(UsbOperationError::FailedStatus(_))
+    ));
+    // The device keeps working after a failure
+    responder.run(operation::erase_lba(0xff, 1)).unwrap();
+}
+
+#[test]
+fn reset() {
+    let mut responder = Responder::new(0x100);
+    responder
+        .run(operation::reset_device(ResetOpcode::Maskrom))
+        .unwrap();
+    assert_eq!(responder.resets, vec![ResetOpcode::Maskrom as u8]);
+}